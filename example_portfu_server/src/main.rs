@@ -1,14 +1,20 @@
 use log::{info, LevelFilter};
 use portfu::filters::method::*;
 use portfu::filters::{any, has_header};
-use portfu::macros::{files, get, interval, post, static_files, task, websocket};
-use portfu::pfcore::service::{IncomingRequest, ServiceGroup};
-use portfu::prelude::http::{HeaderName, Response};
+use portfu::macros::{files, get, interval, post, static_files, task, websocket, FromRequest};
+use portfu::pfcore::service::{IncomingRequest, ServiceBuilder, ServiceGroup};
+use portfu::pfcore::{ServiceHandler, ServiceRegister};
+use portfu::prelude::futures_util::{stream, StreamExt};
+use portfu::prelude::http::header::CONTENT_TYPE;
+use portfu::prelude::http::{HeaderName, HeaderValue, Response};
+use portfu::prelude::http_body_util::{BodyStream, StreamBody};
+use portfu::prelude::hyper::body::{Bytes, Frame};
 use portfu::prelude::*;
 use portfu::wrappers::sessions::SessionWrapper;
 use portfu_admin::PortfuAdmin;
 use simple_logger::SimpleLogger;
 use std::io::Error;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -30,13 +36,26 @@ pub async fn example_get(path_variable: Path) -> Result<String, Error> {
     Ok(path_variable.inner())
 }
 
+//Extractors can be bundled into a struct, each field is extracted in order
+#[derive(FromRequest)]
+pub struct EchoRequest {
+    #[from_request(var = "path_variable")]
+    value: Path,
+    address: SocketAddr,
+}
+
+#[get("/echo_from/{path_variable}")]
+pub async fn example_echo_from(echo: EchoRequest) -> Result<String, Error> {
+    Ok(format!("{} from {}", echo.value.inner(), echo.address))
+}
+
 #[post("/counter")]
 pub async fn example_post(get_counter: State<AtomicUsize>) -> Result<String, Error> {
     let val = get_counter.inner().fetch_add(1, Ordering::Relaxed) + 1;
     Ok(val.to_string())
 }
 
-#[interval(500u64)]
+#[interval(500u64, after_started = "example_task")]
 pub async fn example_interval(state: State<AtomicUsize>) -> Result<(), Error> {
     state.inner().fetch_add(1, Ordering::Relaxed);
     info!("Tick");
@@ -51,7 +70,7 @@ pub async fn example_task() -> Result<(), Error> {
     Ok(())
 }
 
-#[websocket("/ws/{test2}")]
+#[websocket("/ws/{test2}", allow_unused_path_vars = true)]
 pub async fn example_websocket(websocket: WebSocket) -> Result<(), Error> {
     while let Ok(msg) = websocket.next_message().await {
         match msg {
@@ -66,13 +85,62 @@ pub async fn example_websocket(websocket: WebSocket) -> Result<(), Error> {
     Ok(())
 }
 
+//Streams the number of connected websocket peers as Server Sent Events
+pub struct Presence {
+    peers: Peers,
+}
+#[async_trait::async_trait]
+impl ServiceHandler for Presence {
+    fn name(&self) -> &str {
+        "presence"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let peers = self.peers.clone();
+        let counts = stream::once(async {})
+            .chain(self.peers.events().map(|_| ()))
+            .then(move |_| {
+                let peers = peers.clone();
+                async move { peers.len().await }
+            })
+            .map(|count| {
+                Ok::<_, &'static str>(Frame::data(Bytes::from(format!("data: {count}\n\n"))))
+            });
+        data.response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        *data.response.body_mut() =
+            StreamBody::new(BodyStream::new(Box::pin(StreamBody::new(counts))));
+        Ok(data)
+    }
+}
+impl From<Presence> for Service {
+    fn from(presence: Presence) -> Service {
+        ServiceBuilder::new("/presence")
+            .name("presence")
+            .filter(GET.clone())
+            .handler(Arc::new(presence))
+            .build()
+    }
+}
+impl ServiceRegister for Presence {
+    fn register(self, service_registry: &mut ServiceRegistry) {
+        service_registry.register(self.into());
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    SimpleLogger::new()
-        .with_level(LevelFilter::Debug)
-        .init()
-        .unwrap(); //Init your logger of choice
+    //Wrap your logger of choice in a LogControl so levels can be changed at runtime through /pf_admin/logging
+    let log_control = LogControl::install(
+        Box::new(SimpleLogger::new().with_level(LevelFilter::Trace)),
+        LevelFilter::Debug,
+    )
+    .unwrap();
+    let settings = Settings::default(); //Runtime tunables, edit them through /pf_admin/settings without a restart
+    let peers = Peers::default(); //Peers are cheap to clone, clones share the same connections and events
     let server = ServerBuilder::default() //Start building the Server
+        .shared_state(log_control)
+        .shared_state(settings)
         .shared_state(RwLock::new(AtomicUsize::new(0))) //Shared State Data is auto wrapped in an Arc
         .shared_state("This value gets Overridden") //Only one version of a type can exist in the Shared data, to get around this use a wrapper struct/enum
         .shared_state("By this value")
@@ -89,6 +157,7 @@ async fn main() -> Result<(), Error> {
             ServiceGroup::default() //Services can be grouped into ServiceGroups to make it easier to apply shared wrappers or filters.
                 //Filters at the ServiceGroup level apply to service defined below them only, this is the same with any wrappers
                 .service(example_get) //This service is defined above the filter and will not have the filter applied
+                .service(example_echo_from)
                 .filter(has_header(HeaderName::from_static("content-length")))
                 .service(example_post) //This service is defined below the filter and will have the filter applied
                 .wrap(Arc::new(SessionWrapper::default())) //The session wrapper will create a session using cookies for each connection
@@ -97,10 +166,11 @@ async fn main() -> Result<(), Error> {
                     //Add another group to this group
                     ServiceGroup::default().service(example_websocket {
                         //Peers Need to be defined for a websocket, to share peers pass the same map to multiple websockets
-                        peers: Default::default(),
+                        peers: peers.clone(),
                     }),
                 ),
         )
+        .register(Presence { peers }) //Anything holding the Peers can follow connects and disconnects
         .task(example_task) //Add a background task to start when the server is started
         .task(example_interval) //Intervals are also tasks
         .build();
@@ -0,0 +1,87 @@
+use crate::to_json;
+use portfu::breaker::Breakers;
+use portfu::macros::{get, post};
+use portfu::pfcore::ServiceRegister;
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use std::io::Error;
+use std::sync::Arc;
+
+fn breakers(data: &mut ServiceData) -> Option<Breakers> {
+    let breakers = data
+        .request
+        .get::<Arc<Breakers>>()
+        .map(|b| b.as_ref().clone());
+    if breakers.is_none() {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    breakers
+}
+
+#[get("/pf_admin/breakers")]
+pub async fn list_breakers(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match breakers(data) {
+        Some(breakers) => to_json(&breakers.statuses()),
+        None => Ok(b"Breakers are not installed".to_vec()),
+    }
+}
+
+#[post("/pf_admin/breakers/{name}/trip")]
+pub async fn trip_breaker(name: Path, data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let breaker = match breakers(data) {
+        Some(breakers) => breakers.get(&name.inner()),
+        None => return Ok(b"Breakers are not installed".to_vec()),
+    };
+    match breaker {
+        Some(breaker) => {
+            breaker.trip();
+            to_json(&breaker.status())
+        }
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
+        }
+    }
+}
+
+#[post("/pf_admin/breakers/{name}/reset")]
+pub async fn reset_breaker(name: Path, data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let breaker = match breakers(data) {
+        Some(breakers) => breakers.get(&name.inner()),
+        None => return Ok(b"Breakers are not installed".to_vec()),
+    };
+    match breaker {
+        Some(breaker) => {
+            breaker.reset();
+            to_json(&breaker.status())
+        }
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
+        }
+    }
+}
+
+pub struct BreakerControls {
+    services: ServiceGroup,
+}
+impl Default for BreakerControls {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(list_breakers)
+                .service(trip_breaker)
+                .service(reset_breaker),
+        }
+    }
+}
+impl ServiceRegister for BreakerControls {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<BreakerControls> for ServiceGroup {
+    fn from(value: BreakerControls) -> Self {
+        value.services
+    }
+}
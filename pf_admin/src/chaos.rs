@@ -0,0 +1,74 @@
+use crate::to_json;
+use portfu::macros::{get, put};
+use portfu::pfcore::clock::clock;
+use portfu::pfcore::profile::profile;
+use portfu::pfcore::{FromBody, Json, ServiceRegister};
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use portfu::wrappers::chaos::{ChaosControl, ChaosRule};
+use std::io::Error;
+use std::sync::Arc;
+
+fn chaos_control(data: &mut ServiceData) -> Option<ChaosControl> {
+    let control = data
+        .request
+        .get::<Arc<ChaosControl>>()
+        .map(|c| c.as_ref().clone());
+    if control.is_none() {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    control
+}
+
+#[get("/pf_admin/chaos")]
+pub async fn get_chaos_rules(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match chaos_control(data) {
+        Some(control) => to_json(&control.rules_at(clock(&data.request).now())),
+        None => Ok(b"ChaosControl is not installed".to_vec()),
+    }
+}
+
+#[put("/pf_admin/chaos")]
+pub async fn update_chaos_rules(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let control = match chaos_control(data) {
+        Some(control) => control,
+        None => return Ok(b"ChaosControl is not installed".to_vec()),
+    };
+    if !control.is_enabled(profile(&data.request)) {
+        *data.response.status_mut() = StatusCode::FORBIDDEN;
+        return Ok(b"Fault injection is disabled for this process".to_vec());
+    }
+    let rules: Vec<ChaosRule> = match Json::from_body(&mut data.request.request.body()).await {
+        Ok(rules) => rules.inner(),
+        Err(e) => {
+            *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            return Ok(format!("Invalid chaos rules: {e}").into_bytes());
+        }
+    };
+    let now = clock(&data.request).now();
+    control.set_rules_at(rules, now);
+    to_json(&control.rules_at(now))
+}
+
+pub struct ChaosRules {
+    services: ServiceGroup,
+}
+impl Default for ChaosRules {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(get_chaos_rules)
+                .service(update_chaos_rules),
+        }
+    }
+}
+impl ServiceRegister for ChaosRules {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<ChaosRules> for ServiceGroup {
+    fn from(value: ChaosRules) -> Self {
+        value.services
+    }
+}
@@ -0,0 +1,139 @@
+use portfu::breaker::Breakers;
+use portfu::macros::get;
+use portfu::pfcore::ServiceRegister;
+use portfu::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bumped whenever a section changes shape so the frontend can tell payloads apart
+pub const DASHBOARD_VERSION: u32 = 1;
+const SECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SectionStatus {
+    Ok,
+    Error,
+    Unavailable,
+}
+
+#[derive(Serialize)]
+struct Section {
+    status: SectionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Collects one section, None from `collect` means its source is not installed
+async fn section<F: Future<Output = Result<Option<Value>, Error>>>(collect: F) -> Section {
+    match tokio::time::timeout(SECTION_TIMEOUT, collect).await {
+        Ok(Ok(Some(data))) => Section {
+            status: SectionStatus::Ok,
+            data: Some(data),
+            error: None,
+        },
+        Ok(Ok(None)) => Section {
+            status: SectionStatus::Unavailable,
+            data: None,
+            error: None,
+        },
+        Ok(Err(e)) => Section {
+            status: SectionStatus::Error,
+            data: None,
+            error: Some(e.to_string()),
+        },
+        Err(_) => Section {
+            status: SectionStatus::Error,
+            data: None,
+            error: Some(format!("Timed out after {SECTION_TIMEOUT:?}")),
+        },
+    }
+}
+
+/// Everything the overview page needs in one call, each section reports its own status
+#[get("/pf_admin/dashboard")]
+pub async fn dashboard(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let server = data.server.clone();
+    let breakers = data.request.get::<Arc<Breakers>>().cloned();
+    let peers = data.request.get::<Arc<Peers>>().cloned();
+    let (server_section, tasks, breakers, peers) = futures_util::join!(
+        section(async {
+            Ok(Some(json!({
+                "host": server.config.host,
+                "port": server.config.port,
+                "bind": server.config.bind,
+                "tls": server.config.ssl_config.is_some(),
+                "running": server.run.load(Ordering::Relaxed),
+                "services": server.registry.load().services().len(),
+            })))
+        }),
+        section(async {
+            let tasks: Vec<Value> = server
+                .task_graph()
+                .into_iter()
+                .map(|(name, dependencies)| json!({"name": name, "dependencies": dependencies}))
+                .collect();
+            Ok(Some(Value::from(tasks)))
+        }),
+        section(async {
+            match breakers {
+                Some(breakers) => serde_json::to_value(breakers.statuses())
+                    .map(Some)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+                None => Ok(None),
+            }
+        }),
+        section(async {
+            match peers {
+                Some(peers) => Ok(Some(json!({
+                    "connected": peers.len().await,
+                    "flood": peers.flood_stats(),
+                }))),
+                None => Ok(None),
+            }
+        }),
+    );
+    serde_json::to_vec(&json!({
+        "version": DASHBOARD_VERSION,
+        "sections": {
+            "server": server_section,
+            "tasks": tasks,
+            "breakers": breakers,
+            "peers": peers,
+        }
+    }))
+    .map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Failed to Convert to JSON: {e:?}"),
+        )
+    })
+}
+
+pub struct Dashboard {
+    services: ServiceGroup,
+}
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default().service(dashboard),
+        }
+    }
+}
+impl ServiceRegister for Dashboard {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<Dashboard> for ServiceGroup {
+    fn from(value: Dashboard) -> Self {
+        value.services
+    }
+}
@@ -1,32 +1,93 @@
-use portfu::macros::{get, put};
+mod stream;
+
+use crate::to_json;
+use portfu::macros::{get, post, put};
 use portfu::pfcore::editable::EditResult;
-use portfu::pfcore::{FromBody, Json, ServiceRegister};
+use portfu::pfcore::{FromBody, Json, ServiceHandler, ServiceRegister};
 use portfu::prelude::http::StatusCode;
+use portfu::prelude::uuid::Uuid;
 use portfu::prelude::*;
-use serde::Deserialize;
-use std::io::{Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct EditableService {
+    uuid: String,
+    name: String,
+}
 
 #[get("/pf_admin/editor/list")]
 pub async fn list_editable(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
     let mut editable = vec![];
-    for service in &data.server.registry.services {
+    for service in data.server.registry.load().services() {
         if let Some(handle) = &service.handler {
             if handle.is_editable() {
-                editable.push(service.name());
+                editable.push(EditableService {
+                    uuid: service.uuid.to_string(),
+                    name: service.name().to_string(),
+                });
             }
         }
     }
-    serde_json::to_vec(&editable).map_err(|e| {
-        Error::new(
-            ErrorKind::InvalidData,
-            format!("Failed to Convert to JSON: {e:?}"),
-        )
-    })
+    to_json(&editable)
+}
+
+enum Lookup {
+    Found(Arc<Service>),
+    NotFound,
+    Invalid(String),
+    Ambiguous(Vec<String>),
+}
+
+/// Resolves the target service by uuid, falling back to the name when it is unique
+fn find_service(
+    data: &ServiceData,
+    service_uuid: &Option<String>,
+    service_name: &Option<String>,
+) -> Lookup {
+    let registry = data.server.registry.load();
+    if let Some(uuid) = service_uuid {
+        match Uuid::parse_str(uuid) {
+            Ok(uuid) => match registry.find_by_uuid(&uuid) {
+                Some(service) => Lookup::Found(service.clone()),
+                None => Lookup::NotFound,
+            },
+            Err(e) => Lookup::Invalid(format!("Invalid service_uuid: {e}")),
+        }
+    } else if let Some(name) = service_name {
+        let mut services = registry.find_by_name(name);
+        match services.len() {
+            0 => Lookup::NotFound,
+            1 => Lookup::Found(services.remove(0).clone()),
+            _ => Lookup::Ambiguous(services.iter().map(|s| s.uuid.to_string()).collect()),
+        }
+    } else {
+        Lookup::Invalid("One of service_uuid or service_name is required".to_string())
+    }
+}
+
+fn lookup_failed(data: &mut ServiceData, lookup: Lookup) -> Result<Vec<u8>, Error> {
+    match lookup {
+        Lookup::Found(_) | Lookup::NotFound => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
+        }
+        Lookup::Invalid(s) => {
+            *data.response.status_mut() = StatusCode::BAD_REQUEST;
+            Ok(s.into_bytes())
+        }
+        Lookup::Ambiguous(candidates) => {
+            *data.response.status_mut() = StatusCode::CONFLICT;
+            to_json(&candidates)
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct LoadRequest {
-    service_name: String,
+    service_uuid: Option<String>,
+    service_name: Option<String>,
 }
 
 #[get("/pf_admin/editor/load")]
@@ -34,37 +95,45 @@ pub async fn get_service_value(data: &mut ServiceData) -> Result<Vec<u8>, Error>
     let load_request: LoadRequest = Json::from_body(&mut data.request.request.body())
         .await?
         .inner();
-    for service in &data.server.registry.services {
-        if service.name == load_request.service_name {
-            if let Some(handle) = service.handler.clone() {
-                if handle.is_editable() {
-                    match handle.current_value().await {
-                        EditResult::Failed(s) => {
-                            *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                            return Ok(s.into_bytes());
-                        }
-                        EditResult::Success(v) => {
-                            return Ok(v);
-                        }
-                        EditResult::NotEditable => {
-                            *data.response.status_mut() = StatusCode::FORBIDDEN;
-                            return Ok(vec![]);
-                        }
-                    }
-                } else {
-                    *data.response.status_mut() = StatusCode::FORBIDDEN;
-                    return Ok(vec![]);
-                }
+    let service = match find_service(data, &load_request.service_uuid, &load_request.service_name) {
+        Lookup::Found(service) => service,
+        lookup => return lookup_failed(data, lookup),
+    };
+    match service.handler.clone() {
+        Some(handle) if handle.is_editable() => match handle.current_value().await {
+            EditResult::Failed(s) => {
+                *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                Ok(s.into_bytes())
+            }
+            EditResult::Invalid(s) => {
+                *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                Ok(s.into_bytes())
+            }
+            EditResult::Conflict(current) => {
+                *data.response.status_mut() = StatusCode::PRECONDITION_FAILED;
+                Ok(current.into_bytes())
             }
+            EditResult::Success(v) => Ok(v),
+            EditResult::NotEditable => {
+                *data.response.status_mut() = StatusCode::FORBIDDEN;
+                Ok(vec![])
+            }
+        },
+        Some(_) => {
+            *data.response.status_mut() = StatusCode::FORBIDDEN;
+            Ok(vec![])
+        }
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
         }
     }
-    *data.response.status_mut() = StatusCode::NOT_FOUND;
-    Ok(vec![])
 }
 
 #[derive(Deserialize)]
 pub struct EditRequest {
-    service_name: String,
+    service_uuid: Option<String>,
+    service_name: Option<String>,
     new_value: Vec<u8>,
     current_value: Option<Vec<u8>>,
 }
@@ -74,35 +143,109 @@ pub async fn update_service_value(data: &mut ServiceData) -> Result<Vec<u8>, Err
     let edit_request: EditRequest = Json::from_body(&mut data.request.request.body())
         .await?
         .inner();
-    for service in &data.server.registry.services {
-        if service.name == edit_request.service_name {
-            if let Some(handle) = service.handler.clone() {
-                if handle.is_editable() {
-                    match handle
-                        .update_value(edit_request.new_value, edit_request.current_value)
-                        .await
-                    {
-                        EditResult::Failed(s) => {
-                            *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                            return Ok(s.into_bytes());
-                        }
-                        EditResult::Success(v) => {
-                            return Ok(v);
-                        }
-                        EditResult::NotEditable => {
-                            *data.response.status_mut() = StatusCode::FORBIDDEN;
-                            return Ok(vec![]);
-                        }
-                    }
-                } else {
-                    *data.response.status_mut() = StatusCode::FORBIDDEN;
-                    return Ok(vec![]);
-                }
+    let service = match find_service(data, &edit_request.service_uuid, &edit_request.service_name) {
+        Lookup::Found(service) => service,
+        lookup => return lookup_failed(data, lookup),
+    };
+    match service.handler.clone() {
+        Some(handle) if handle.is_editable() => match handle
+            .update_value(edit_request.new_value, edit_request.current_value)
+            .await
+        {
+            EditResult::Failed(s) => {
+                *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                Ok(s.into_bytes())
             }
+            EditResult::Invalid(s) => {
+                *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                Ok(s.into_bytes())
+            }
+            EditResult::Conflict(current) => {
+                *data.response.status_mut() = StatusCode::PRECONDITION_FAILED;
+                Ok(current.into_bytes())
+            }
+            EditResult::Success(v) => Ok(v),
+            EditResult::NotEditable => {
+                *data.response.status_mut() = StatusCode::FORBIDDEN;
+                Ok(vec![])
+            }
+        },
+        Some(_) => {
+            *data.response.status_mut() = StatusCode::FORBIDDEN;
+            Ok(vec![])
+        }
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
+        }
+    }
+}
+
+fn editable_handler(
+    data: &mut ServiceData,
+    uuid: &str,
+) -> Result<Arc<dyn ServiceHandler + Send + Sync>, Vec<u8>> {
+    let service = match find_service(data, &Some(uuid.to_string()), &None) {
+        Lookup::Found(service) => service,
+        lookup => return Err(lookup_failed(data, lookup).unwrap_or_default()),
+    };
+    match service.handler.clone() {
+        Some(handle) if handle.is_editable() => Ok(handle),
+        Some(_) => {
+            *data.response.status_mut() = StatusCode::FORBIDDEN;
+            Err(vec![])
+        }
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Err(vec![])
+        }
+    }
+}
+
+#[get("/pf_admin/editor/{uuid}/versions")]
+pub async fn list_versions(uuid: Path, data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let handle = match editable_handler(data, &uuid.inner()) {
+        Ok(handle) => handle,
+        Err(body) => return Ok(body),
+    };
+    match handle.versions().await {
+        Ok(versions) => to_json(&versions),
+        Err(s) => {
+            *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(s.into_bytes())
+        }
+    }
+}
+
+#[post("/pf_admin/editor/{uuid}/restore/{version}")]
+pub async fn restore_version(
+    uuid: Path,
+    version: Path,
+    data: &mut ServiceData,
+) -> Result<Vec<u8>, Error> {
+    let handle = match editable_handler(data, &uuid.inner()) {
+        Ok(handle) => handle,
+        Err(body) => return Ok(body),
+    };
+    match handle.restore_version(&version.inner()).await {
+        EditResult::Failed(s) => {
+            *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(s.into_bytes())
+        }
+        EditResult::Invalid(s) => {
+            *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            Ok(s.into_bytes())
+        }
+        EditResult::Conflict(current) => {
+            *data.response.status_mut() = StatusCode::PRECONDITION_FAILED;
+            Ok(current.into_bytes())
+        }
+        EditResult::Success(v) => Ok(v),
+        EditResult::NotEditable => {
+            *data.response.status_mut() = StatusCode::FORBIDDEN;
+            Ok(vec![])
         }
     }
-    *data.response.status_mut() = StatusCode::NOT_FOUND;
-    Ok(vec![])
 }
 
 pub struct ServiceEditor {
@@ -114,7 +257,10 @@ impl Default for ServiceEditor {
             services: ServiceGroup::default()
                 .service(list_editable)
                 .service(get_service_value)
-                .service(update_service_value),
+                .service(update_service_value)
+                .service(list_versions)
+                .service(restore_version)
+                .service(stream::EditorStream::service()),
         }
     }
 }
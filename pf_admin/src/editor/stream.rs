@@ -0,0 +1,132 @@
+use crate::editor::{find_service, lookup_failed, Lookup};
+use portfu::pfcore::editable::{EditResult, EditStream};
+use portfu::pfcore::service::ServiceBuilder;
+use portfu::pfcore::{IntoStreamBody, ServiceHandler};
+use portfu::prelude::async_trait::async_trait;
+use portfu::prelude::http::header::{ETAG, IF_MATCH};
+use portfu::prelude::http::{HeaderValue, Method, StatusCode, Uri};
+use portfu::prelude::hyper::body::Bytes;
+use portfu::prelude::*;
+use std::io::Error;
+use std::sync::Arc;
+
+/// Streams editable values in both directions at /pf_admin/editor/stream.
+/// The target is picked with the service_uuid or service_name query parameters,
+/// GET returns the raw value with its content hash as the ETag and PUT replaces it
+/// with the raw request body, only when If-Match carries the current hash if it is set.
+/// A stale If-Match is a 412 with the current hash as the ETag and body.
+pub struct EditorStream;
+impl EditorStream {
+    pub fn service() -> Service {
+        ServiceBuilder::new("/pf_admin/editor/stream")
+            .name("editor_stream")
+            .method(Method::GET)
+            .method(Method::PUT)
+            .handler(Arc::new(EditorStream))
+            .build()
+    }
+}
+
+fn target(uri: &Uri) -> (Option<String>, Option<String>) {
+    let mut service_uuid = None;
+    let mut service_name = None;
+    for (key, value) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "service_uuid" => service_uuid = Some(value.to_string()),
+            "service_name" => service_name = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    (service_uuid, service_name)
+}
+
+fn respond(data: &mut ServiceData, status: StatusCode, body: Vec<u8>) {
+    *data.response.status_mut() = status;
+    *data.response.body_mut() = Bytes::from(body).stream_body();
+}
+
+fn set_etag(data: &mut ServiceData, hash: &str) {
+    if let Ok(value) = HeaderValue::from_str(&format!("\"{hash}\"")) {
+        data.response.headers_mut().insert(ETAG, value);
+    }
+}
+
+#[async_trait]
+impl ServiceHandler for EditorStream {
+    fn name(&self) -> &str {
+        "EditorStream"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let (service_uuid, service_name) = target(data.request.request.uri());
+        let service = match find_service(&data, &service_uuid, &service_name) {
+            Lookup::Found(service) => service,
+            lookup => {
+                let body = lookup_failed(&mut data, lookup).unwrap_or_default();
+                let status = data.response.status();
+                respond(&mut data, status, body);
+                return Ok(data);
+            }
+        };
+        let handle = match service.handler.clone() {
+            Some(handle) if handle.is_editable() => handle,
+            Some(_) => {
+                respond(&mut data, StatusCode::FORBIDDEN, vec![]);
+                return Ok(data);
+            }
+            None => {
+                respond(&mut data, StatusCode::NOT_FOUND, vec![]);
+                return Ok(data);
+            }
+        };
+        if data.request.request.method() == Method::PUT {
+            let expected_hash = data
+                .request
+                .request
+                .headers()
+                .and_then(|headers| headers.get(IF_MATCH))
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().trim_matches('"').to_string())
+                .filter(|value| value != "*");
+            let body = match data.request.consume() {
+                Ok(body) => body,
+                Err(e) => return Err((data, e)),
+            };
+            match handle.update_value_stream(body, expected_hash).await {
+                EditResult::Success(hash) => {
+                    set_etag(&mut data, &String::from_utf8_lossy(&hash));
+                    respond(&mut data, StatusCode::OK, hash);
+                }
+                EditResult::Failed(s) => {
+                    respond(&mut data, StatusCode::INTERNAL_SERVER_ERROR, s.into_bytes())
+                }
+                EditResult::Invalid(s) => {
+                    respond(&mut data, StatusCode::UNPROCESSABLE_ENTITY, s.into_bytes())
+                }
+                EditResult::Conflict(current) => {
+                    set_etag(&mut data, &current);
+                    respond(
+                        &mut data,
+                        StatusCode::PRECONDITION_FAILED,
+                        current.into_bytes(),
+                    )
+                }
+                EditResult::NotEditable => respond(&mut data, StatusCode::FORBIDDEN, vec![]),
+            }
+        } else {
+            match handle.current_value_stream().await {
+                EditStream::Success { body, hash } => {
+                    set_etag(&mut data, &hash);
+                    *data.response.body_mut() = body;
+                }
+                EditStream::Failed(s) => {
+                    respond(&mut data, StatusCode::INTERNAL_SERVER_ERROR, s.into_bytes())
+                }
+                EditStream::Invalid(s) => {
+                    respond(&mut data, StatusCode::UNPROCESSABLE_ENTITY, s.into_bytes())
+                }
+                EditStream::NotEditable => respond(&mut data, StatusCode::FORBIDDEN, vec![]),
+            }
+        }
+        Ok(data)
+    }
+}
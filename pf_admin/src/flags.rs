@@ -0,0 +1,74 @@
+use crate::to_json;
+use portfu::flags::FlagRule;
+use portfu::macros::{get, put};
+use portfu::pfcore::{FromBody, Json, ServiceRegister};
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Arc;
+
+fn feature_flags(data: &mut ServiceData) -> Option<FeatureFlags> {
+    let flags = data
+        .request
+        .get::<Arc<FeatureFlags>>()
+        .map(|f| f.as_ref().clone());
+    if flags.is_none() {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    flags
+}
+
+#[get("/pf_admin/flags")]
+pub async fn get_flags(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match feature_flags(data) {
+        Some(flags) => to_json(&flags.flags()),
+        None => Ok(b"FeatureFlags are not installed".to_vec()),
+    }
+}
+
+#[put("/pf_admin/flags")]
+pub async fn update_flags(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let flags = match feature_flags(data) {
+        Some(flags) => flags,
+        None => return Ok(b"FeatureFlags are not installed".to_vec()),
+    };
+    let updates: HashMap<String, Option<FlagRule>> =
+        match Json::from_body(&mut data.request.request.body()).await {
+            Ok(updates) => updates.inner(),
+            Err(e) => {
+                *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                return Ok(format!("Invalid flag definitions: {e}").into_bytes());
+            }
+        };
+    match flags.update(updates) {
+        Ok(_) => to_json(&flags.flags()),
+        Err(invalid) => {
+            *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            to_json(&invalid)
+        }
+    }
+}
+
+pub struct FlagEditor {
+    services: ServiceGroup,
+}
+impl Default for FlagEditor {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(get_flags)
+                .service(update_flags),
+        }
+    }
+}
+impl ServiceRegister for FlagEditor {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<FlagEditor> for ServiceGroup {
+    fn from(value: FlagEditor) -> Self {
+        value.services
+    }
+}
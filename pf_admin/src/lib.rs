@@ -1,19 +1,59 @@
+use crate::breakers::BreakerControls;
+use crate::dashboard::Dashboard;
 use crate::editor::ServiceEditor;
+use crate::flags::FlagEditor;
+use crate::logging::LogLevels;
+use crate::reload::Reload;
+use crate::routes::RouteInfo;
+use crate::settings::SettingsEditor;
+use crate::static_responses::StaticResponseControls;
+use crate::uploads::Uploads;
 use portfu::pfcore::ServiceRegister;
 use portfu::prelude::ServiceGroup;
+use std::io::{Error, ErrorKind};
 
+mod breakers;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod dashboard;
 mod editor;
+mod flags;
+mod logging;
+mod reload;
+mod routes;
+mod settings;
+mod static_responses;
+mod uploads;
+
+pub(crate) fn to_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    serde_json::to_vec(value).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Failed to Convert to JSON: {e:?}"),
+        )
+    })
+}
 
 pub struct PortfuAdmin {
     services: ServiceGroup,
 }
 impl Default for PortfuAdmin {
     fn default() -> Self {
-        Self {
-            services: ServiceGroup::default()
-                //.wrap() AUTH HERE
-                .sub_group(ServiceEditor::default()),
-        }
+        let services = ServiceGroup::default()
+            //.wrap() AUTH HERE
+            .sub_group(ServiceEditor::default())
+            .sub_group(LogLevels::default())
+            .sub_group(SettingsEditor::default())
+            .sub_group(Uploads::default())
+            .sub_group(FlagEditor::default())
+            .sub_group(Reload::default())
+            .sub_group(BreakerControls::default())
+            .sub_group(Dashboard::default())
+            .sub_group(RouteInfo::default())
+            .sub_group(StaticResponseControls::default());
+        #[cfg(feature = "chaos")]
+        let services = services.sub_group(chaos::ChaosRules::default());
+        Self { services }
     }
 }
 impl ServiceRegister for PortfuAdmin {
@@ -0,0 +1,86 @@
+use crate::to_json;
+use portfu::macros::{get, put};
+use portfu::pfcore::{FromBody, Json, ServiceRegister};
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Arc;
+
+fn log_control(data: &mut ServiceData) -> Option<LogControl> {
+    let control = data
+        .request
+        .get::<Arc<LogControl>>()
+        .map(|c| c.as_ref().clone());
+    if control.is_none() {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    control
+}
+
+#[get("/pf_admin/logging")]
+pub async fn get_log_levels(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match log_control(data) {
+        Some(control) => to_json(&control.filters()),
+        None => Ok(b"LogControl is not installed".to_vec()),
+    }
+}
+
+#[put("/pf_admin/logging")]
+pub async fn update_log_levels(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let control = match log_control(data) {
+        Some(control) => control,
+        None => return Ok(b"LogControl is not installed".to_vec()),
+    };
+    let updates: HashMap<String, Option<String>> =
+        Json::from_body(&mut data.request.request.body())
+            .await?
+            .inner();
+    match control.update(updates) {
+        Ok(_) => to_json(&control.filters()),
+        Err(invalid) => {
+            *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            to_json(&invalid)
+        }
+    }
+}
+
+#[cfg(feature = "log_shipper")]
+#[get("/pf_admin/logging/shipper")]
+pub async fn get_shipper_stats(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let control = match log_control(data) {
+        Some(control) => control,
+        None => return Ok(b"LogControl is not installed".to_vec()),
+    };
+    match control.shipper_stats() {
+        Some(stats) => to_json(&stats),
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(b"No log shipper is set".to_vec())
+        }
+    }
+}
+
+pub struct LogLevels {
+    services: ServiceGroup,
+}
+impl Default for LogLevels {
+    fn default() -> Self {
+        let services = ServiceGroup::default()
+            .service(get_log_levels)
+            .service(update_log_levels);
+        #[cfg(feature = "log_shipper")]
+        let services = services.service(get_shipper_stats);
+        Self { services }
+    }
+}
+impl ServiceRegister for LogLevels {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<LogLevels> for ServiceGroup {
+    fn from(value: LogLevels) -> Self {
+        value.services
+    }
+}
@@ -0,0 +1,33 @@
+use crate::to_json;
+use portfu::macros::post;
+use portfu::pfcore::ServiceRegister;
+use portfu::prelude::*;
+use std::io::Error;
+
+/// Runs every registered reload handler, the alternative to SIGHUP where signals are unavailable
+#[post("/pf_admin/reload")]
+pub async fn reload_config(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let report = data.server.reload().await;
+    to_json(&report)
+}
+
+pub struct Reload {
+    services: ServiceGroup,
+}
+impl Default for Reload {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default().service(reload_config),
+        }
+    }
+}
+impl ServiceRegister for Reload {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<Reload> for ServiceGroup {
+    fn from(value: Reload) -> Self {
+        value.services
+    }
+}
@@ -0,0 +1,78 @@
+use crate::to_json;
+use portfu::macros::get;
+use portfu::pfcore::ServiceRegister;
+use portfu::prelude::http::{Method, StatusCode};
+use portfu::prelude::*;
+use std::io::Error;
+
+fn query_param(data: &ServiceData, name: &str) -> Option<String> {
+    let query = data.request.request.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Methods served on the `path` query parameter, 404 when no service matches it
+#[get("/pf_admin/routes/methods")]
+pub async fn route_methods(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let path = match query_param(data, "path") {
+        Some(path) => path,
+        None => {
+            *data.response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(b"Missing path query parameter".to_vec());
+        }
+    };
+    match data.server.methods_for(&path) {
+        Some(methods) => to_json(&methods),
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
+        }
+    }
+}
+
+/// Dry run of routing the `path` and `method` query parameters, method defaults to GET
+#[get("/pf_admin/routes/plan")]
+pub async fn route_plan(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let path = match query_param(data, "path") {
+        Some(path) => path,
+        None => {
+            *data.response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(b"Missing path query parameter".to_vec());
+        }
+    };
+    let method = match query_param(data, "method")
+        .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+        .unwrap_or(Ok(Method::GET))
+    {
+        Ok(method) => method,
+        Err(_) => {
+            *data.response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(b"Invalid method query parameter".to_vec());
+        }
+    };
+    to_json(&data.server.route_plan(&path, &method))
+}
+
+pub struct RouteInfo {
+    services: ServiceGroup,
+}
+impl Default for RouteInfo {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(route_methods)
+                .service(route_plan),
+        }
+    }
+}
+impl ServiceRegister for RouteInfo {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<RouteInfo> for ServiceGroup {
+    fn from(value: RouteInfo) -> Self {
+        value.services
+    }
+}
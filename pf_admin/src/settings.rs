@@ -0,0 +1,87 @@
+use crate::to_json;
+use portfu::macros::{get, put};
+use portfu::pfcore::{FromBody, Json, ServiceRegister};
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Arc;
+
+fn settings(data: &mut ServiceData) -> Option<Settings> {
+    let settings = data
+        .request
+        .get::<Arc<Settings>>()
+        .map(|s| s.as_ref().clone());
+    if settings.is_none() {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    settings
+}
+
+#[get("/pf_admin/settings")]
+pub async fn get_settings(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match settings(data) {
+        Some(settings) => to_json(settings.snapshot().as_ref()),
+        None => Ok(b"Settings are not installed".to_vec()),
+    }
+}
+
+#[put("/pf_admin/settings")]
+pub async fn update_settings(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let settings = match settings(data) {
+        Some(settings) => settings,
+        None => return Ok(b"Settings are not installed".to_vec()),
+    };
+    let updates: HashMap<String, Value> =
+        match Json::from_body(&mut data.request.request.body()).await {
+            Ok(updates) => updates.inner(),
+            Err(e) => {
+                *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                return Ok(format!("Invalid settings document: {e}").into_bytes());
+            }
+        };
+    match settings.update(updates) {
+        Ok(_) => to_json(settings.snapshot().as_ref()),
+        Err(invalid) => {
+            *data.response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            to_json(&invalid)
+        }
+    }
+}
+
+/// Every setting with its source for the active profile, secrets are redacted
+#[get("/pf_admin/settings/report")]
+pub async fn settings_report(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match settings(data) {
+        Some(settings) => to_json(&serde_json::json!({
+            "profile": settings.profile(),
+            "settings": settings.report(),
+        })),
+        None => Ok(b"Settings are not installed".to_vec()),
+    }
+}
+
+pub struct SettingsEditor {
+    services: ServiceGroup,
+}
+impl Default for SettingsEditor {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(get_settings)
+                .service(update_settings)
+                .service(settings_report),
+        }
+    }
+}
+impl ServiceRegister for SettingsEditor {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<SettingsEditor> for ServiceGroup {
+    fn from(value: SettingsEditor) -> Self {
+        value.services
+    }
+}
@@ -0,0 +1,120 @@
+use crate::to_json;
+use portfu::macros::{get, post};
+use portfu::pfcore::data_store::SharedDataStore;
+use portfu::pfcore::static_response::{Maintenance, StaticResponses};
+use portfu::pfcore::ServiceRegister;
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use std::io::Error;
+use std::sync::Arc;
+
+fn maintenance(data: &mut ServiceData) -> Option<Arc<Maintenance>> {
+    let maintenance = data.request.get::<Arc<Maintenance>>().cloned();
+    if maintenance.is_none() {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    maintenance
+}
+
+fn set_maintenance(data: &mut ServiceData, enabled: bool) -> Result<Vec<u8>, Error> {
+    match maintenance(data) {
+        Some(maintenance) => {
+            maintenance.set_enabled(enabled);
+            if let Some(store) = data.request.get::<SharedDataStore>() {
+                maintenance.persist(store.as_ref())?;
+            }
+            to_json(&maintenance.status())
+        }
+        None => Ok(b"Maintenance mode is not installed".to_vec()),
+    }
+}
+
+#[get("/pf_admin/maintenance")]
+pub async fn maintenance_status(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    match maintenance(data) {
+        Some(maintenance) => to_json(&maintenance.status()),
+        None => Ok(b"Maintenance mode is not installed".to_vec()),
+    }
+}
+
+#[post("/pf_admin/maintenance/enable")]
+pub async fn enable_maintenance(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    set_maintenance(data, true)
+}
+
+#[post("/pf_admin/maintenance/disable")]
+pub async fn disable_maintenance(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    set_maintenance(data, false)
+}
+
+fn set_static_response(
+    data: &mut ServiceData,
+    name: &str,
+    enabled: bool,
+) -> Result<Vec<u8>, Error> {
+    let responses = data
+        .request
+        .get::<Arc<StaticResponses>>()
+        .cloned()
+        .unwrap_or_default();
+    match responses.get(name) {
+        Some(toggle) => {
+            toggle.set_enabled(enabled);
+            if let Some(store) = data.request.get::<SharedDataStore>() {
+                responses.persist(store.as_ref())?;
+            }
+            to_json(&toggle.status())
+        }
+        None => {
+            *data.response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(vec![])
+        }
+    }
+}
+
+#[get("/pf_admin/static_responses")]
+pub async fn list_static_responses(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let statuses = data
+        .request
+        .get::<Arc<StaticResponses>>()
+        .map(|responses| responses.statuses())
+        .unwrap_or_default();
+    to_json(&statuses)
+}
+
+#[post("/pf_admin/static_responses/{name}/enable")]
+pub async fn enable_static_response(name: Path, data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    set_static_response(data, &name.inner(), true)
+}
+
+#[post("/pf_admin/static_responses/{name}/disable")]
+pub async fn disable_static_response(name: Path, data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    set_static_response(data, &name.inner(), false)
+}
+
+pub struct StaticResponseControls {
+    services: ServiceGroup,
+}
+impl Default for StaticResponseControls {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(maintenance_status)
+                .service(enable_maintenance)
+                .service(disable_maintenance)
+                .service(list_static_responses)
+                .service(enable_static_response)
+                .service(disable_static_response),
+        }
+    }
+}
+impl ServiceRegister for StaticResponseControls {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<StaticResponseControls> for ServiceGroup {
+    fn from(value: StaticResponseControls) -> Self {
+        value.services
+    }
+}
@@ -0,0 +1,76 @@
+use crate::to_json;
+use portfu::macros::{delete, get, post};
+use portfu::pfcore::uploads::{UploadSnapshot, UploadStats, UPLOADS};
+use portfu::pfcore::{FromBody, Json, ServiceRegister};
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::uuid::Uuid;
+use portfu::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::time::Duration;
+
+#[derive(Serialize)]
+struct UploadListing {
+    active: Vec<UploadSnapshot>,
+    stats: UploadStats,
+}
+
+#[get("/pf_admin/uploads")]
+pub async fn list_uploads() -> Result<Vec<u8>, Error> {
+    to_json(&UploadListing {
+        active: UPLOADS.active(),
+        stats: UPLOADS.stats(),
+    })
+}
+
+#[delete("/pf_admin/uploads/{upload_id}")]
+pub async fn cancel_upload(upload_id: Path, data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let upload_id = match Uuid::parse_str(&upload_id.inner()) {
+        Ok(upload_id) => upload_id,
+        Err(e) => {
+            *data.response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(format!("Invalid upload id: {e}").into_bytes());
+        }
+    };
+    if !UPLOADS.cancel(&upload_id) {
+        *data.response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    Ok(vec![])
+}
+
+#[derive(Deserialize)]
+pub struct CancelStalled {
+    idle_seconds: u64,
+}
+
+#[post("/pf_admin/uploads/cancel_stalled")]
+pub async fn cancel_stalled_uploads(data: &mut ServiceData) -> Result<Vec<u8>, Error> {
+    let request: CancelStalled = Json::from_body(&mut data.request.request.body())
+        .await?
+        .inner();
+    to_json(&UPLOADS.cancel_stalled(Duration::from_secs(request.idle_seconds)))
+}
+
+pub struct Uploads {
+    services: ServiceGroup,
+}
+impl Default for Uploads {
+    fn default() -> Self {
+        Self {
+            services: ServiceGroup::default()
+                .service(list_uploads)
+                .service(cancel_upload)
+                .service(cancel_stalled_uploads),
+        }
+    }
+}
+impl ServiceRegister for Uploads {
+    fn register(self, service_registry: &mut portfu::pfcore::ServiceRegistry) {
+        self.services.register(service_registry);
+    }
+}
+impl From<Uploads> for ServiceGroup {
+    fn from(value: Uploads) -> Self {
+        value.services
+    }
+}
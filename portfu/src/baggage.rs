@@ -0,0 +1,318 @@
+use async_trait::async_trait;
+use http::{HeaderMap, HeaderName, Request};
+use hyper::body::Incoming;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use pfcore::service::ServiceRequest;
+use pfcore::wrappers::{RequestScope, ScopeNext, ScopedFuture, WrapperFn, WrapperResult};
+use pfcore::{FromRequest, PathVars, ServiceData};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io::Error;
+use std::sync::{Arc, RwLock};
+
+pub static BAGGAGE_HEADER: HeaderName = HeaderName::from_static("baggage");
+/// Limits from the W3C Baggage specification
+pub const MAX_BAGGAGE_ENTRIES: usize = 180;
+pub const MAX_BAGGAGE_BYTES: usize = 8192;
+
+/// Characters escaped in values, everything outside the baggage-octet range
+const VALUE_ESCAPES: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b',')
+    .add(b';')
+    .add(b'\\')
+    .add(b'%');
+
+tokio::task_local! {
+    static CURRENT_BAGGAGE: Baggage;
+}
+
+#[derive(Debug, Default)]
+struct BaggageEntries {
+    entries: Vec<(String, String)>,
+    truncated: bool,
+}
+
+/// Request scoped key/values propagated through the W3C `baggage` header.
+/// Clones share the same entries, use `snapshot` to hand a copy to work that outlives the request.
+#[derive(Debug, Clone, Default)]
+pub struct Baggage {
+    inner: Arc<RwLock<BaggageEntries>>,
+}
+impl Baggage {
+    /// Parses a `baggage` header value, members past the limits are dropped and flagged as truncated
+    pub fn parse(header: &str) -> Self {
+        let baggage = Baggage::default();
+        for member in header.split(',') {
+            //Properties after ';' are not kept
+            let pair = member.split(';').next().unwrap_or_default();
+            if let Some((key, value)) = pair.split_once('=') {
+                let key = key.trim();
+                if key.is_empty() {
+                    continue;
+                }
+                let value = percent_decode_str(value.trim()).decode_utf8_lossy();
+                baggage.insert(key, value);
+            }
+        }
+        baggage
+    }
+    /// Sets `key`, returns false without changing anything when it would break the limits
+    pub fn insert<K: AsRef<str>, V: AsRef<str>>(&self, key: K, value: V) -> bool {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        let existing = inner.entries.iter().position(|(k, _)| k == key);
+        let size: usize = inner
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != existing)
+            .map(|(_, (k, v))| encoded_len(k, v) + 1)
+            .sum::<usize>()
+            + encoded_len(key, value);
+        let count = inner.entries.len() + usize::from(existing.is_none());
+        if size > MAX_BAGGAGE_BYTES || count > MAX_BAGGAGE_ENTRIES {
+            inner.truncated = true;
+            return false;
+        }
+        match existing {
+            Some(index) => inner.entries[index].1 = value.to_string(),
+            None => inner.entries.push((key.to_string(), value.to_string())),
+        }
+        true
+    }
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+    pub fn remove(&self, key: &str) {
+        self.inner
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entries
+            .retain(|(k, _)| k != key);
+    }
+    /// Entries in insertion order
+    pub fn entries(&self) -> Vec<(String, String)> {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .entries
+            .clone()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .entries
+            .is_empty()
+    }
+    /// True once an entry was dropped because of the size or count limits
+    pub fn is_truncated(&self) -> bool {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .truncated
+    }
+    /// Independent copy of the current entries
+    pub fn snapshot(&self) -> Baggage {
+        let inner = self.inner.read().unwrap_or_else(|e| e.into_inner());
+        Baggage {
+            inner: Arc::new(RwLock::new(BaggageEntries {
+                entries: inner.entries.clone(),
+                truncated: inner.truncated,
+            })),
+        }
+    }
+    /// Runs `future` with this baggage as the current baggage, log lines it writes through
+    /// LogControl carry the entries and outbound calls can read it with `Baggage::current`
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_BAGGAGE.scope(self, future).await
+    }
+    pub fn current() -> Option<Baggage> {
+        CURRENT_BAGGAGE.try_with(Baggage::clone).ok()
+    }
+}
+impl Display for Baggage {
+    /// Formats as a `baggage` header value
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (index, (key, value)) in self.entries().iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{key}={}", utf8_percent_encode(value, VALUE_ESCAPES))?;
+        }
+        Ok(())
+    }
+}
+
+fn encoded_len(key: &str, value: &str) -> usize {
+    key.len()
+        + 1
+        + utf8_percent_encode(value, VALUE_ESCAPES)
+            .map(str::len)
+            .sum::<usize>()
+}
+
+impl PathVars for Baggage {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+#[async_trait]
+impl<'a> FromRequest<'a> for Baggage {
+    async fn from_request(request: &'a mut ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        if let Some(baggage) = request.get::<Baggage>() {
+            return Ok(baggage.clone());
+        }
+        let baggage = Baggage::default();
+        request.insert(baggage.clone());
+        Ok(baggage)
+    }
+}
+
+fn from_headers(headers: &HeaderMap) -> Baggage {
+    let joined = headers
+        .get_all(&BAGGAGE_HEADER)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    Baggage::parse(&joined)
+}
+
+/// Reads the incoming `baggage` header into a Baggage in the request extensions.
+/// Added with ServerBuilder::request_scope it also becomes `Baggage::current` for the whole
+/// request so log lines and client calls carry it, as a wrapper only extractors see it.
+pub struct BaggageWrapper;
+impl RequestScope for BaggageWrapper {
+    fn name(&self) -> &str {
+        "BaggageWrapper"
+    }
+    fn scope(&self, mut request: Request<Incoming>, next: ScopeNext) -> ScopedFuture {
+        let baggage = from_headers(request.headers());
+        request.extensions_mut().insert(baggage.clone());
+        Box::pin(baggage.scope(next(request)))
+    }
+}
+#[async_trait]
+impl WrapperFn for BaggageWrapper {
+    fn name(&self) -> &str {
+        "BaggageWrapper"
+    }
+    fn infrastructure(&self) -> bool {
+        true
+    }
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        //Already read when the wrapper is also the request scope
+        if data.request.get::<Baggage>().is_some() {
+            return WrapperResult::Continue;
+        }
+        let baggage = data
+            .request
+            .request
+            .headers()
+            .map(from_headers)
+            .unwrap_or_default();
+        data.request.insert(baggage);
+        WrapperResult::Continue
+    }
+    async fn after(&self, _: &mut ServiceData) -> WrapperResult {
+        WrapperResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::request_builder;
+    use crate::test_server::start;
+    use http::Method;
+    use pfcore::server::ServerBuilder;
+    use pfcore::service::ServiceBuilder;
+    use pfcore::{IntoStreamBody, ServiceHandler};
+
+    /// Adds an entry and answers with the baggage header of an outbound request
+    struct Forward;
+    #[async_trait]
+    impl ServiceHandler for Forward {
+        fn name(&self) -> &str {
+            "forward"
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            let extracted = match Baggage::from_request(&mut data.request, "").await {
+                Ok(baggage) => baggage,
+                Err(e) => return Err((data, e)),
+            };
+            extracted.insert("hop", "server");
+            let outbound = request_builder(Method::GET, "/upstream")
+                .body(())
+                .unwrap()
+                .headers()
+                .get(&BAGGAGE_HEADER)
+                .map(|v| v.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            *data.response.body_mut() = outbound.stream_body();
+            Ok(data)
+        }
+    }
+
+    fn forwarding() -> ServerBuilder {
+        ServerBuilder::default().register(
+            ServiceBuilder::new("/forward")
+                .name("forward")
+                .handler(Arc::new(Forward))
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn request_scope_propagates_to_outbound_calls() {
+        let url = start(forwarding().request_scope(Arc::new(BaggageWrapper))).await;
+        let forwarded = reqwest::Client::new()
+            .get(format!("{url}/forward"))
+            .header("baggage", "user=a%20b;prop=1,tenant=t1")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(forwarded, "user=a%20b,tenant=t1,hop=server");
+    }
+
+    #[tokio::test]
+    async fn wrapper_alone_does_not_scope() {
+        let url = start(forwarding().wrap(Arc::new(BaggageWrapper))).await;
+        let forwarded = reqwest::Client::new()
+            .get(format!("{url}/forward"))
+            .header("baggage", "tenant=t1")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(forwarded, "");
+        assert!(Baggage::current().is_none());
+    }
+
+    #[test]
+    fn parse_and_format_round_trip() {
+        let baggage = Baggage::parse("a=1, b = two%2Cparts ;p, =skipped");
+        assert_eq!(
+            baggage.entries(),
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "two,parts".to_string())
+            ]
+        );
+        assert_eq!(baggage.to_string(), "a=1,b=two%2Cparts");
+        assert!(!baggage.insert("big", "x".repeat(MAX_BAGGAGE_BYTES)));
+        assert!(baggage.is_truncated());
+    }
+}
@@ -0,0 +1,442 @@
+use http::header::RETRY_AFTER;
+use http::{HeaderValue, StatusCode};
+use log::{info, warn};
+use pfcore::clock::{SharedClock, SystemClock};
+use pfcore::errors::HttpError;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Number of recent calls the rates are computed over
+    pub window: usize,
+    /// Calls needed in the window before the breaker can open
+    pub min_calls: usize,
+    /// Failure rate between 0 and 1 that opens the breaker
+    pub failure_rate: f64,
+    /// Calls taking at least this long count as slow
+    pub slow_call: Duration,
+    /// Slow call rate between 0 and 1 that opens the breaker
+    pub slow_call_rate: f64,
+    /// Time spent open before trial calls are let through
+    pub cooldown: Duration,
+    /// Trial calls allowed while half open, all must succeed to close again. At least one is allowed.
+    pub half_open_trials: usize,
+}
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_calls: 10,
+            failure_rate: 0.5,
+            slow_call: Duration::from_secs(2),
+            slow_call_rate: 1.0,
+            cooldown: Duration::from_secs(30),
+            half_open_trials: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+    pub name: String,
+    pub state: BreakerState,
+    pub calls: usize,
+    pub failure_rate: f64,
+    pub slow_call_rate: f64,
+    /// Calls rejected without running since startup
+    pub rejected: u64,
+    /// Times the breaker opened since startup
+    pub opened: u64,
+    /// Seconds until trial calls are allowed, only set while open
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+struct Outcome {
+    failed: bool,
+    slow: bool,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    outcomes: VecDeque<Outcome>,
+    opened_at: Instant,
+    trials_started: usize,
+    trials_succeeded: usize,
+}
+impl BreakerInner {
+    fn rates(&self) -> (f64, f64) {
+        if self.outcomes.is_empty() {
+            return (0.0, 0.0);
+        }
+        let calls = self.outcomes.len() as f64;
+        let failed = self.outcomes.iter().filter(|o| o.failed).count() as f64;
+        let slow = self.outcomes.iter().filter(|o| o.slow).count() as f64;
+        (failed / calls, slow / calls)
+    }
+}
+
+/// Fails calls to a dependency fast while it is unhealthy.
+/// Closed lets every call through and opens once the failure or slow call rate over the window
+/// passes its threshold. Open rejects calls with a 503 until the cooldown passes, then half open
+/// lets a few trial calls through which close the breaker if they all succeed or open it again.
+pub struct Breaker {
+    name: String,
+    config: BreakerConfig,
+    inner: Mutex<BreakerInner>,
+    rejected: AtomicU64,
+    opened: AtomicU64,
+    clock: SharedClock,
+}
+impl Breaker {
+    pub fn new<S: AsRef<str>>(name: S, config: BreakerConfig) -> Self {
+        Self {
+            name: name.as_ref().to_string(),
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: Instant::now(),
+                trials_started: 0,
+                trials_succeeded: 0,
+            }),
+            rejected: AtomicU64::new(0),
+            opened: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+        }
+    }
+    /// Where cooldowns and call durations are measured, SystemClock by default
+    pub fn clock(self, clock: SharedClock) -> Self {
+        let mut s = self;
+        s.inner
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .opened_at = clock.now();
+        s.clock = clock;
+        s
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn transition(&self, inner: &mut BreakerInner, state: BreakerState) {
+        if inner.state == state {
+            return;
+        }
+        match state {
+            BreakerState::Open => {
+                warn!("Breaker {} opened", self.name);
+                self.opened.fetch_add(1, Ordering::Relaxed);
+                inner.opened_at = self.clock.now();
+            }
+            BreakerState::HalfOpen => info!("Breaker {} half open, probing", self.name),
+            BreakerState::Closed => {
+                info!("Breaker {} closed", self.name);
+                inner.outcomes.clear();
+            }
+        }
+        inner.trials_started = 0;
+        inner.trials_succeeded = 0;
+        inner.state = state;
+    }
+    /// Zero trials would keep the breaker half open forever
+    fn trials(&self) -> usize {
+        self.config.half_open_trials.max(1)
+    }
+    fn retry_after(&self, inner: &BreakerInner) -> Duration {
+        self.config
+            .cooldown
+            .saturating_sub(self.clock.now().saturating_duration_since(inner.opened_at))
+    }
+    pub fn state(&self) -> BreakerState {
+        self.lock().state
+    }
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.lock();
+        let (failure_rate, slow_call_rate) = inner.rates();
+        BreakerStatus {
+            name: self.name.clone(),
+            state: inner.state,
+            calls: inner.outcomes.len(),
+            failure_rate,
+            slow_call_rate,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            opened: self.opened.load(Ordering::Relaxed),
+            retry_after: (inner.state == BreakerState::Open)
+                .then(|| self.retry_after(&inner).as_secs().max(1)),
+        }
+    }
+    /// Opens the breaker regardless of the recent calls
+    pub fn trip(&self) {
+        let mut inner = self.lock();
+        self.transition(&mut inner, BreakerState::Open);
+    }
+    /// Closes the breaker and forgets the recent calls
+    pub fn reset(&self) {
+        let mut inner = self.lock();
+        self.transition(&mut inner, BreakerState::Closed);
+        inner.outcomes.clear();
+    }
+    /// Admits one call, the error carries a 503 HttpError with Retry-After when the breaker is open
+    pub fn try_acquire(&self) -> Result<BreakerPermit<'_>, Error> {
+        let mut inner = self.lock();
+        if inner.state == BreakerState::Open && self.retry_after(&inner).is_zero() {
+            self.transition(&mut inner, BreakerState::HalfOpen);
+        }
+        let trial = match inner.state {
+            BreakerState::Closed => false,
+            BreakerState::HalfOpen if inner.trials_started < self.trials() => {
+                inner.trials_started += 1;
+                true
+            }
+            _ => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                let retry_after = match inner.state {
+                    BreakerState::Open => self.retry_after(&inner).as_secs().max(1),
+                    _ => 1,
+                };
+                return Err(HttpError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("{} is unavailable", self.name),
+                )
+                .header(RETRY_AFTER, HeaderValue::from(retry_after))
+                .into());
+            }
+        };
+        Ok(BreakerPermit {
+            breaker: self,
+            started: self.clock.now(),
+            trial,
+            recorded: false,
+        })
+    }
+    /// Runs `call` when the breaker admits it, the outer error means the call was rejected
+    pub async fn call<T, E, F: Future<Output = Result<T, E>>>(
+        &self,
+        call: F,
+    ) -> Result<Result<T, E>, Error> {
+        let permit = self.try_acquire()?;
+        let result = call.await;
+        permit.record(result.is_err());
+        Ok(result)
+    }
+    fn record(&self, failed: bool, elapsed: Duration, trial: bool) {
+        let mut inner = self.lock();
+        let slow = elapsed >= self.config.slow_call;
+        match inner.state {
+            BreakerState::HalfOpen if trial => {
+                if failed || slow {
+                    self.transition(&mut inner, BreakerState::Open);
+                } else {
+                    inner.trials_succeeded += 1;
+                    if inner.trials_succeeded >= self.trials() {
+                        self.transition(&mut inner, BreakerState::Closed);
+                    }
+                }
+            }
+            BreakerState::Closed => {
+                if inner.outcomes.len() >= self.config.window.max(1) {
+                    inner.outcomes.pop_front();
+                }
+                inner.outcomes.push_back(Outcome { failed, slow });
+                let (failure_rate, slow_call_rate) = inner.rates();
+                if inner.outcomes.len() >= self.config.min_calls
+                    && (failure_rate >= self.config.failure_rate
+                        || slow_call_rate >= self.config.slow_call_rate)
+                {
+                    self.transition(&mut inner, BreakerState::Open);
+                }
+            }
+            //Calls admitted before a transition do not count towards the new state
+            _ => {}
+        }
+    }
+    fn release_trial(&self) {
+        let mut inner = self.lock();
+        if inner.state == BreakerState::HalfOpen {
+            inner.trials_started = inner.trials_started.saturating_sub(1);
+        }
+    }
+}
+
+/// One admitted call, record its outcome when it finishes.
+/// Dropping it unrecorded, for example when the call is cancelled, counts as neither outcome.
+pub struct BreakerPermit<'a> {
+    breaker: &'a Breaker,
+    started: Instant,
+    trial: bool,
+    recorded: bool,
+}
+impl BreakerPermit<'_> {
+    pub fn record(mut self, failed: bool) {
+        self.recorded = true;
+        let elapsed = self
+            .breaker
+            .clock
+            .now()
+            .saturating_duration_since(self.started);
+        self.breaker.record(failed, elapsed, self.trial);
+    }
+    pub fn success(self) {
+        self.record(false)
+    }
+    pub fn failure(self) {
+        self.record(true)
+    }
+}
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded && self.trial {
+            self.breaker.release_trial();
+        }
+    }
+}
+
+/// Named breakers, register as shared state so every call site of a dependency shares one breaker
+#[derive(Clone)]
+pub struct Breakers {
+    breakers: Arc<RwLock<HashMap<String, Arc<Breaker>>>>,
+    clock: SharedClock,
+}
+impl Default for Breakers {
+    fn default() -> Self {
+        Self {
+            breakers: Default::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl Breakers {
+    /// The clock given to every breaker created from now on
+    pub fn clock(self, clock: SharedClock) -> Self {
+        let mut s = self;
+        s.clock = clock;
+        s
+    }
+    /// The breaker for `name`, created with `config` the first time
+    pub fn breaker<S: AsRef<str>>(&self, name: S, config: BreakerConfig) -> Arc<Breaker> {
+        if let Some(breaker) = self.get(name.as_ref()) {
+            return breaker;
+        }
+        self.breakers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(name.as_ref().to_string())
+            .or_insert_with(|| Arc::new(Breaker::new(name, config).clock(self.clock.clone())))
+            .clone()
+    }
+    pub fn get(&self, name: &str) -> Option<Arc<Breaker>> {
+        self.breakers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .cloned()
+    }
+    pub fn statuses(&self) -> Vec<BreakerStatus> {
+        let mut statuses: Vec<BreakerStatus> = self
+            .breakers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|b| b.status())
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pfcore::clock::ManualClock;
+
+    fn probing(half_open_trials: usize) -> Breaker {
+        let breaker = Breaker::new(
+            "dependency",
+            BreakerConfig {
+                cooldown: Duration::ZERO,
+                half_open_trials,
+                ..Default::default()
+            },
+        );
+        breaker.trip();
+        breaker
+    }
+
+    #[test]
+    fn zero_trials_still_probe_once() {
+        let breaker = probing(0);
+        let permit = breaker.try_acquire().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire().is_err());
+        permit.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn failed_trial_opens_again() {
+        let breaker = probing(2);
+        let first = breaker.try_acquire().unwrap();
+        let second = breaker.try_acquire().unwrap();
+        first.success();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        second.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn cooldown_and_slow_calls_follow_the_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let breaker = Breaker::new(
+            "dependency",
+            BreakerConfig {
+                min_calls: 1,
+                slow_call_rate: 0.5,
+                half_open_trials: 1,
+                ..Default::default()
+            },
+        )
+        .clock(clock.clone());
+        let permit = breaker.try_acquire().unwrap();
+        clock.advance(Duration::from_secs(2));
+        permit.success();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.status().retry_after, Some(30));
+
+        clock.advance(Duration::from_secs(29));
+        assert!(breaker.try_acquire().is_err());
+        assert_eq!(breaker.status().retry_after, Some(1));
+        clock.advance(Duration::from_secs(1));
+        breaker.try_acquire().unwrap().success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn opens_at_the_failure_rate() {
+        let breaker = Breaker::new("dependency", BreakerConfig::default());
+        for _ in 0..9 {
+            breaker.try_acquire().unwrap().failure();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.try_acquire().unwrap().failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.try_acquire().is_err());
+        assert_eq!(breaker.status().rejected, 1);
+    }
+}
@@ -1,21 +1,31 @@
+use crate::baggage::{Baggage, BAGGAGE_HEADER};
+use crate::resolver::DEFAULT_RESOLVER;
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
 use http::{Method, Request, Response, Uri};
 use http_body_util::{BodyStream, Empty, Full, StreamBody};
 use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
-use log::error;
-use pfcore::service::ConsumedBodyType;
+use log::{debug, error};
+use pfcore::service::{ConsumedBodyType, RequestBody};
 use rustls::client::ClientConfig;
 use rustls::pki_types::ServerName;
 use rustls::RootCertStore;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::select;
 use tokio_rustls::TlsConnector;
 
 pub enum SupportedBody {
     Empty(Empty<Bytes>),
     Full(Full<Bytes>),
     Incoming(StreamBody<BodyStream<Incoming>>),
+    /// A request body passed through, as taken from ConsumedBodyType
+    Request(RequestBody),
 }
 
 impl Body for SupportedBody {
@@ -36,6 +46,9 @@ impl Body for SupportedBody {
             SupportedBody::Incoming(b) => Pin::new(b)
                 .poll_frame(cx)
                 .map_err(|_| "Failed to Poll Incoming"),
+            SupportedBody::Request(b) => Pin::new(b)
+                .poll_frame(cx)
+                .map_err(|_| "Failed to Poll Request Body"),
         }
     }
 
@@ -44,6 +57,7 @@ impl Body for SupportedBody {
             SupportedBody::Empty(b) => Pin::new(b).is_end_stream(),
             SupportedBody::Full(b) => Pin::new(b).is_end_stream(),
             SupportedBody::Incoming(b) => Pin::new(b).is_end_stream(),
+            SupportedBody::Request(b) => b.is_end_stream(),
         }
     }
 
@@ -52,6 +66,7 @@ impl Body for SupportedBody {
             SupportedBody::Empty(b) => Pin::new(b).size_hint(),
             SupportedBody::Full(b) => Pin::new(b).size_hint(),
             SupportedBody::Incoming(b) => Body::size_hint(b),
+            SupportedBody::Request(b) => Body::size_hint(b),
         }
     }
 }
@@ -73,11 +88,7 @@ impl From<StreamBody<BodyStream<Incoming>>> for SupportedBody {
 impl From<ConsumedBodyType> for SupportedBody {
     fn from(value: ConsumedBodyType) -> Self {
         match value {
-            ConsumedBodyType::Stream(value) => {
-                let body_stream = BodyStream::new(value);
-                let body = StreamBody::new(body_stream);
-                SupportedBody::Incoming(body)
-            }
+            ConsumedBodyType::Stream(value) => SupportedBody::Request(value),
             ConsumedBodyType::Sized(value) => SupportedBody::Full(value),
             ConsumedBodyType::Empty => SupportedBody::Empty(Empty::default()),
         }
@@ -106,15 +117,14 @@ pub async fn send_request<T: Into<SupportedBody>>(
     let body = body.into();
     let host = url.host().expect("uri has no host").to_string();
     let port = url.port_u16().unwrap_or(80);
-    let addr = format!("{}:{}", host, port);
     let mut root_cert_store = RootCertStore::empty();
     root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     let config = ClientConfig::builder()
         .with_root_certificates(root_cert_store)
         .with_no_client_auth();
     let connector = TlsConnector::from(Arc::new(config));
-    let dnsname = ServerName::try_from(host)?;
-    let inner_stream = TcpStream::connect(&addr).await?;
+    let dnsname = ServerName::try_from(host.clone())?;
+    let inner_stream = DEFAULT_RESOLVER.connect(&host, port).await?;
     let stream = connector.connect(dnsname, inner_stream).await?;
     let io = ::hyper_util::rt::tokio::TokioIo::new(stream);
     let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
@@ -123,7 +133,216 @@ pub async fn send_request<T: Into<SupportedBody>>(
             error!("Connection failed: {:?}", err);
         }
     });
-    let path = url.path();
-    let req = Request::builder().method(method).uri(path).body(body)?;
+    let req = request_builder(method, url.path()).body(body)?;
     Ok(sender.send_request(req).await?)
 }
+
+/// Outbound request carrying the current Baggage
+pub fn request_builder(method: Method, path: &str) -> http::request::Builder {
+    let req = Request::builder().method(method).uri(path);
+    match Baggage::current().filter(|b| !b.is_empty()) {
+        Some(baggage) => req.header(&BAGGAGE_HEADER, baggage.to_string()),
+        None => req,
+    }
+}
+
+type ClientError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Most attempts a hedged request will make, including the first
+pub const MAX_HEDGE_ATTEMPTS: usize = 4;
+const HEDGE_WINDOW: usize = 100;
+
+#[derive(Default)]
+struct HedgeStats {
+    requests: AtomicU64,
+    hedged: AtomicU64,
+    wins: [AtomicU64; MAX_HEDGE_ATTEMPTS],
+    /// Recent outcomes, true for failures
+    outcomes: Mutex<VecDeque<bool>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HedgeSnapshot {
+    pub requests: u64,
+    /// Requests that fired at least one extra attempt
+    pub hedged: u64,
+    /// Successful responses per winning attempt, the first entry is the original request
+    pub wins: [u64; MAX_HEDGE_ATTEMPTS],
+    pub error_rate: f64,
+}
+
+/// Hedging policy for idempotent outbound requests.
+/// When an attempt has not answered within `delay` another identical request is sent and the first
+/// response wins, the losers are dropped which closes their connections.
+/// Hedging turns itself off while the recent error rate is above `error_rate_threshold` so a
+/// failing upstream does not receive extra load.
+pub struct Hedging {
+    delay: Duration,
+    max_extra_attempts: usize,
+    methods: HashSet<Method>,
+    max_body_bytes: usize,
+    deadline: Option<Duration>,
+    error_rate_threshold: f64,
+    stats: HedgeStats,
+}
+impl Default for Hedging {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(100),
+            max_extra_attempts: 1,
+            methods: HashSet::from([Method::GET, Method::HEAD, Method::OPTIONS]),
+            max_body_bytes: 64 * 1024,
+            deadline: None,
+            error_rate_threshold: 0.5,
+            stats: HedgeStats::default(),
+        }
+    }
+}
+impl Hedging {
+    pub fn delay(self, delay: Duration) -> Self {
+        let mut s = self;
+        s.delay = delay;
+        s
+    }
+    /// Capped at MAX_HEDGE_ATTEMPTS - 1
+    pub fn max_extra_attempts(self, max_extra_attempts: usize) -> Self {
+        let mut s = self;
+        s.max_extra_attempts = max_extra_attempts.min(MAX_HEDGE_ATTEMPTS - 1);
+        s
+    }
+    /// Methods allowed to be hedged, only add methods that are idempotent for the upstream
+    pub fn methods(self, methods: &[Method]) -> Self {
+        let mut s = self;
+        s.methods = methods.iter().cloned().collect();
+        s
+    }
+    /// Larger bodies are sent once since every attempt replays the body from memory
+    pub fn max_body_bytes(self, max_body_bytes: usize) -> Self {
+        let mut s = self;
+        s.max_body_bytes = max_body_bytes;
+        s
+    }
+    /// Total time allowed across all attempts
+    pub fn deadline(self, deadline: Duration) -> Self {
+        let mut s = self;
+        s.deadline = Some(deadline);
+        s
+    }
+    pub fn error_rate_threshold(self, error_rate_threshold: f64) -> Self {
+        let mut s = self;
+        s.error_rate_threshold = error_rate_threshold;
+        s
+    }
+    pub fn stats(&self) -> HedgeSnapshot {
+        let mut wins = [0; MAX_HEDGE_ATTEMPTS];
+        for (win, count) in wins.iter_mut().zip(self.stats.wins.iter()) {
+            *win = count.load(Ordering::Relaxed);
+        }
+        HedgeSnapshot {
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            hedged: self.stats.hedged.load(Ordering::Relaxed),
+            wins,
+            error_rate: self.error_rate(),
+        }
+    }
+    fn error_rate(&self) -> f64 {
+        let outcomes = self
+            .stats
+            .outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if outcomes.is_empty() {
+            0.0
+        } else {
+            outcomes.iter().filter(|failed| **failed).count() as f64 / outcomes.len() as f64
+        }
+    }
+    fn record(&self, failed: bool) {
+        let mut outcomes = self
+            .stats
+            .outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if outcomes.len() >= HEDGE_WINDOW {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(failed);
+    }
+    fn should_hedge(&self, method: &Method, body: &Bytes) -> bool {
+        self.max_extra_attempts > 0
+            && self.methods.contains(method)
+            && body.len() <= self.max_body_bytes
+            && self.error_rate() <= self.error_rate_threshold
+    }
+    /// Sends the request, hedging it when the policy allows
+    pub async fn send(
+        &self,
+        method: Method,
+        url: Uri,
+        body: Bytes,
+    ) -> Result<Response<Incoming>, ClientError> {
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+        let attempts = async {
+            if self.should_hedge(&method, &body) {
+                self.send_hedged(method, url, body).await
+            } else {
+                let response = send_request(method, url, Full::new(body)).await;
+                if response.is_ok() {
+                    self.stats.wins[0].fetch_add(1, Ordering::Relaxed);
+                }
+                response
+            }
+        };
+        let result = match self.deadline {
+            Some(deadline) => match tokio::time::timeout(deadline, attempts).await {
+                Ok(result) => result,
+                Err(_) => Err("Hedged request exceeded its deadline".into()),
+            },
+            None => attempts.await,
+        };
+        self.record(result.is_err());
+        result
+    }
+    async fn send_hedged(
+        &self,
+        method: Method,
+        url: Uri,
+        body: Bytes,
+    ) -> Result<Response<Incoming>, ClientError> {
+        let attempt = |index: usize| {
+            let request = send_request(method.clone(), url.clone(), Full::new(body.clone()));
+            async move { (index, request.await) }
+        };
+        let mut in_flight = FuturesUnordered::new();
+        in_flight.push(attempt(0));
+        let mut started = 1;
+        let mut last_error: Option<ClientError> = None;
+        loop {
+            let can_hedge = started <= self.max_extra_attempts;
+            select! {
+                Some((index, result)) = in_flight.next() => match result {
+                    Ok(response) => {
+                        self.stats.wins[index].fetch_add(1, Ordering::Relaxed);
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        debug!("Hedged attempt {index} failed: {e:?}");
+                        last_error = Some(e);
+                        if in_flight.is_empty() && !can_hedge {
+                            break;
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(self.delay), if can_hedge => {
+                    if started == 1 {
+                        self.stats.hedged.fetch_add(1, Ordering::Relaxed);
+                    }
+                    in_flight.push(attempt(started));
+                    started += 1;
+                }
+                else => break,
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "All hedged attempts failed".into()))
+    }
+}
@@ -5,6 +5,8 @@ use std::io::Error;
 
 pub mod oauth_login;
 
+// Handler results, the error half carries the whole ServiceData
+#[allow(clippy::result_large_err)]
 pub fn send_internal_error(
     mut data: ServiceData,
     error: String,
@@ -14,6 +16,7 @@ pub fn send_internal_error(
     Ok(data)
 }
 
+#[allow(clippy::result_large_err)]
 pub fn redirect_to_url(
     mut data: ServiceData,
     url: String,
@@ -1,8 +1,7 @@
 use crate::endpoints::{redirect_to_url, send_internal_error};
-use crate::filters::method::GET;
 use crate::prelude::Body;
 use crate::wrappers::sessions::Session;
-use http::HeaderValue;
+use http::{HeaderValue, Method};
 use hyper::{header, StatusCode};
 use oauth2::basic::BasicClient;
 use oauth2::reqwest::async_http_client;
@@ -323,14 +322,14 @@ impl OAuthLoginBuilder {
         });
         let login_service = ServiceBuilder::new("/")
             .name("index")
-            .filter(GET.clone())
+            .method(Method::GET)
             .handler(Arc::new(OAuthLoginHandler {
                 config: config.clone(),
             }))
             .build();
         let auth_service = ServiceBuilder::new("/")
             .name("index")
-            .filter(GET.clone())
+            .method(Method::GET)
             .handler(Arc::new(OAuthAuthHandler {
                 config: config.clone(),
             }))
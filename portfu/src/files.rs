@@ -0,0 +1,575 @@
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use http::{HeaderValue, Method, Request};
+use hyper::body::Incoming;
+use log::{error, info};
+use pfcore::files::{
+    drop_encoded_variants, find_encoded_variants, get_mime_type, read_directories, read_directory,
+    DirectoryListing, DirectoryWatcherTask, EditPolicy, FileDownloadOptions, FileLoader,
+    TrailingSlashRedirect,
+};
+use pfcore::filters::{FilterFn, FilterResult};
+use pfcore::service::{Service, ServiceBuilder, ServiceGroup};
+use pfcore::settings::{Setting, Settings};
+use pfcore::task::Task;
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::{IntoStreamBody, ServiceData, ServiceHandler, ServiceRegister, ServiceRegistry};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Settings key holding the largest file size DynamicFiles will keep in memory
+pub const FILES_CACHE_THRESHOLD: &str = "files.cache_threshold";
+
+#[derive(Clone)]
+pub struct DynamicFiles {
+    pub root_directory: String,
+    pub editable: bool,
+    pub cache_threshold: u64,
+    pub manifest: Option<AssetManifest>,
+    pub settings: Option<Settings>,
+    pub edit_policy: Arc<EditPolicy>,
+    /// Renders a listing for directories without an index.html
+    pub show_listings: bool,
+    /// Serves files added under the root after startup and stops serving them once removed
+    pub watch: bool,
+    /// Glob patterns of files served as attachments, see DynamicFiles::attachment
+    pub attachments: Vec<String>,
+}
+impl DynamicFiles {
+    pub fn new<S: AsRef<str>>(root_directory: S) -> Self {
+        Self {
+            root_directory: root_directory.as_ref().to_string(),
+            editable: true,
+            cache_threshold: 65536,
+            manifest: None,
+            settings: None,
+            edit_policy: Arc::new(EditPolicy::default()),
+            show_listings: false,
+            watch: false,
+            attachments: vec![],
+        }
+    }
+    /// Additionally registers every file under a content hashed path with immutable cache headers
+    /// and records logical path -> hashed path in `manifest`.
+    pub fn fingerprint(self, manifest: AssetManifest) -> Self {
+        let mut s = self;
+        s.manifest = Some(manifest);
+        s
+    }
+    pub fn editable(self, editable: bool) -> Self {
+        let mut s = self;
+        s.editable = editable;
+        s
+    }
+    /// Size limits, extension rules and backups applied to edits
+    pub fn edit_policy(self, edit_policy: EditPolicy) -> Self {
+        let mut s = self;
+        s.edit_policy = Arc::new(edit_policy);
+        s
+    }
+    pub fn cache_threshold(self, cache_threshold: u64) -> Self {
+        let mut s = self;
+        s.cache_threshold = cache_threshold;
+        s
+    }
+    pub fn show_listings(self, show_listings: bool) -> Self {
+        let mut s = self;
+        s.show_listings = show_listings;
+        s
+    }
+    pub fn watch(self, watch: bool) -> Self {
+        let mut s = self;
+        s.watch = watch;
+        s
+    }
+    /// Serves files matching `pattern` as downloads. `*` and `?` stop at `/`, `**` does not,
+    /// patterns without a `/` are matched against the file name only.
+    pub fn attachment<S: AsRef<str>>(self, pattern: S) -> Self {
+        let mut s = self;
+        s.attachments.push(pattern.as_ref().to_string());
+        s
+    }
+    /// Reads the cache threshold from FILES_CACHE_THRESHOLD in `settings`, cache_threshold is the default
+    pub fn settings(self, settings: Settings) -> Self {
+        let mut s = self;
+        s.settings = Some(settings);
+        s
+    }
+}
+impl DynamicFiles {
+    fn threshold_setting(&self) -> Option<Setting<u64>> {
+        self.settings
+            .as_ref()
+            .map(|settings| settings.setting(FILES_CACHE_THRESHOLD, self.cache_threshold))
+    }
+    fn loader(&self, name: &str, path: &str, editable: bool) -> FileLoader {
+        FileLoader {
+            name: name.to_string(),
+            mime: get_mime_type(path),
+            path: path.to_string(),
+            editable,
+            cache_threshold: self.cache_threshold,
+            cache_status: AtomicBool::default(),
+            cached_value: Arc::default(),
+            encoded_variants: find_encoded_variants(path),
+            threshold_setting: self.threshold_setting(),
+            edit_policy: self.edit_policy.clone(),
+            download: self.download_options(name, path),
+        }
+    }
+    /// `name` is the request path, the suggested filename comes from `path` so directory indexes get one
+    fn download_options(&self, name: &str, path: &str) -> FileDownloadOptions {
+        let filename = path.rsplit('/').next().unwrap_or(path);
+        let attachment = self
+            .attachments
+            .iter()
+            .any(|pattern| match pattern.contains('/') {
+                true => glob_matches(pattern.as_bytes(), name.as_bytes()),
+                false => glob_matches(pattern.as_bytes(), filename.as_bytes()),
+            });
+        match attachment {
+            true => FileDownloadOptions::attachment(filename),
+            false => FileDownloadOptions::default(),
+        }
+    }
+    /// `index.html` for each directory that has one, and listings for the rest when enabled.
+    /// Directory paths without their trailing slash redirect to it.
+    fn directory_services(&self, files: &HashMap<String, String>) -> Vec<Service> {
+        let root_path = Path::new(&self.root_directory);
+        let directories = match read_directories(root_path) {
+            Ok(directories) => directories,
+            Err(e) => {
+                error!("Error Loading directories: {e:?}");
+                return vec![];
+            }
+        };
+        let listing = match self.show_listings {
+            true => match DirectoryListing::new(root_path) {
+                Ok(listing) => Some(Arc::new(listing)),
+                Err(e) => {
+                    error!("Error Loading directory listings: {e:?}");
+                    None
+                }
+            },
+            false => None,
+        };
+        let mut services = vec![];
+        for directory in directories {
+            let index = files.get(&format!("{directory}index.html"));
+            let handler: Arc<dyn ServiceHandler + Send + Sync> = match (index, &listing) {
+                (Some(index), _) => Arc::new(self.loader(&directory, index, false)),
+                (None, Some(listing)) => listing.clone(),
+                (None, None) => continue,
+            };
+            services.push(
+                ServiceBuilder::new(&directory)
+                    .name(&directory)
+                    .method(Method::GET)
+                    .handler(handler)
+                    .build(),
+            );
+            if directory != "/" {
+                let without_slash = directory.trim_end_matches('/');
+                services.push(
+                    ServiceBuilder::new(without_slash)
+                        .name(without_slash)
+                        .method(Method::GET)
+                        .handler(Arc::new(TrailingSlashRedirect))
+                        .build(),
+                );
+            }
+        }
+        services
+    }
+    /// Serves `path` under its content hashed name with immutable cache headers, a request for any
+    /// other hash of the file is a 404 so a changed file never answers under its old hash.
+    fn fingerprinted_service(
+        &self,
+        manifest: &AssetManifest,
+        name: &str,
+        path: &str,
+    ) -> Result<Service, Error> {
+        let route = hashed_name(name, "{fingerprint}");
+        let loader = Arc::new(self.loader(&route, path, false));
+        let fingerprint = Fingerprint::new(name, loader.clone(), manifest.clone())?;
+        Ok(ServiceBuilder::new(&route)
+            .name(&route)
+            .method(Method::GET)
+            .filter(Arc::new(CurrentFingerprint(fingerprint)))
+            .wrap(Arc::new(ImmutableCache))
+            .handler(loader)
+            .build())
+    }
+}
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|skip| glob_matches(rest, &path[skip..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|b| *b == b'/').unwrap_or(path.len());
+            (0..=segment).any(|skip| glob_matches(rest, &path[skip..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(path, [c, tail @ ..] if *c != b'/' && glob_matches(rest, tail))
+        }
+        [p, rest @ ..] => matches!(path, [c, tail @ ..] if c == p && glob_matches(rest, tail)),
+    }
+}
+impl From<DynamicFiles> for ServiceGroup {
+    fn from(value: DynamicFiles) -> Self {
+        let mut files = HashMap::new();
+        let root_path = Path::new(&value.root_directory);
+        info!("Searching for files at: {root_path:?}");
+        if let Err(e) = read_directory(root_path, root_path, &mut files) {
+            error!("Error Loading files: {e:?}");
+        }
+        drop_encoded_variants(&mut files);
+        let mut group = ServiceGroup::default();
+        let mut served = HashMap::new();
+        for (name, path) in files.iter() {
+            if let Some(manifest) = &value.manifest {
+                match value.fingerprinted_service(manifest, name, path) {
+                    Ok(service) => group = group.service(service),
+                    Err(e) => error!("Failed to fingerprint {path}: {e:?}"),
+                }
+            }
+            let service = ServiceBuilder::new(name)
+                .name(name)
+                .method(Method::GET)
+                .handler(Arc::new(value.loader(name, path, value.editable)))
+                .build();
+            served.insert(name.clone(), (path.clone(), service.uuid));
+            group = group.service(service);
+        }
+        for service in value.directory_services(&files) {
+            group = group.service(service);
+        }
+        if value.watch {
+            let template = value.clone();
+            let service = Box::new(move |name: &str, path: &str| {
+                ServiceBuilder::new(name)
+                    .name(name)
+                    .method(Method::GET)
+                    .handler(Arc::new(template.loader(name, path, template.editable)))
+                    .build()
+            });
+            let watcher = DirectoryWatcherTask::new(root_path, served, service);
+            group = group.task(Task::new(
+                format!("DirectoryWatcher {}", value.root_directory),
+                Arc::new(watcher),
+            ));
+        }
+        group
+    }
+}
+impl ServiceRegister for DynamicFiles {
+    fn register(self, service_registry: &mut ServiceRegistry) {
+        let group: ServiceGroup = self.into();
+        group.register(service_registry);
+    }
+}
+
+/// Maps logical asset paths like `/site.css` to their fingerprinted path like `/site.1a2b3c4d.css`.
+/// Clones share the same table, register one with the server as shared state to use it from handlers.
+#[derive(Clone, Default)]
+pub struct AssetManifest {
+    entries: Arc<ArcSwap<HashMap<String, String>>>,
+}
+impl AssetManifest {
+    pub fn url_for(&self, path: &str) -> String {
+        self.entries
+            .load()
+            .get(path)
+            .cloned()
+            .unwrap_or_else(|| path.to_string())
+    }
+    pub fn entries(&self) -> HashMap<String, String> {
+        self.entries.load().as_ref().clone()
+    }
+    /// Replaces every `<!--{asset:/path}-->` token in `html` with the fingerprinted url
+    pub fn rewrite(&self, html: &str) -> String {
+        const START: &str = "<!--{asset:";
+        const END: &str = "}-->";
+        let mut output = String::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = rest.find(START) {
+            let (before, token) = rest.split_at(start);
+            match token.find(END) {
+                Some(end) => {
+                    output.push_str(before);
+                    output.push_str(&self.url_for(&token[START.len()..end]));
+                    rest = &token[end + END.len()..];
+                }
+                None => break,
+            }
+        }
+        output.push_str(rest);
+        output
+    }
+    /// Serves the manifest as JSON at `path`
+    pub fn service(&self, path: &str) -> Service {
+        ServiceBuilder::new(path)
+            .name(path)
+            .method(Method::GET)
+            .handler(Arc::new(self.clone()))
+            .build()
+    }
+    fn insert(&self, name: &str, hashed: &str) {
+        self.entries.rcu(|current| {
+            let mut updated = current.as_ref().clone();
+            updated.insert(name.to_string(), hashed.to_string());
+            updated
+        });
+    }
+}
+#[async_trait]
+impl ServiceHandler for AssetManifest {
+    fn name(&self) -> &str {
+        "AssetManifest"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        match serde_json::to_vec(self.entries.load().as_ref()) {
+            Ok(json) => {
+                data.response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                *data.response.body_mut() = json.stream_body();
+                Ok(data)
+            }
+            Err(e) => Err((data, Error::new(ErrorKind::InvalidData, e))),
+        }
+    }
+}
+
+fn fingerprint_path(name: &str, path: &str) -> Result<String, Error> {
+    let contents = std::fs::read(path)?;
+    let hash = hex::encode(Sha256::digest(contents));
+    Ok(hashed_name(name, &hash[..8]))
+}
+/// `/site.css` becomes `/site.{hash}.css`
+fn hashed_name(name: &str, hash: &str) -> String {
+    let file_start = name.rfind('/').map(|i| i + 1).unwrap_or_default();
+    match name[file_start..].rfind('.') {
+        Some(dot) => {
+            let (stem, extension) = name.split_at(file_start + dot);
+            format!("{stem}.{hash}{extension}")
+        }
+        None => format!("{name}.{hash}"),
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct FingerprintState {
+    modified: Option<SystemTime>,
+    len: u64,
+    hashed: String,
+}
+impl FingerprintState {
+    fn read(name: &str, path: &str) -> Result<Self, Error> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hashed: fingerprint_path(name, path)?,
+        })
+    }
+}
+
+/// The hashed path of one file, hashed again once its modification time or size changes
+struct Fingerprint {
+    name: String,
+    loader: Arc<FileLoader>,
+    manifest: AssetManifest,
+    state: std::sync::Mutex<FingerprintState>,
+}
+impl Fingerprint {
+    fn new(
+        name: &str,
+        loader: Arc<FileLoader>,
+        manifest: AssetManifest,
+    ) -> Result<Arc<Self>, Error> {
+        let state = FingerprintState::read(name, &loader.path)?;
+        manifest.insert(name, &state.hashed);
+        Ok(Arc::new(Self {
+            name: name.to_string(),
+            loader,
+            manifest,
+            state: std::sync::Mutex::new(state),
+        }))
+    }
+    /// The hashed path for the file as it is now, the manifest is updated and the cached
+    /// contents dropped when it changed
+    async fn current(&self) -> Result<String, Error> {
+        let metadata = tokio::fs::metadata(&self.loader.path).await?;
+        {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.modified == metadata.modified().ok() && state.len == metadata.len() {
+                return Ok(state.hashed.clone());
+            }
+        }
+        let (name, path) = (self.name.clone(), self.loader.path.clone());
+        let fresh = tokio::task::spawn_blocking(move || FingerprintState::read(&name, &path))
+            .await
+            .map_err(Error::other)??;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.hashed != fresh.hashed {
+            info!("{} changed, now served as {}", self.name, fresh.hashed);
+            self.manifest.insert(&self.name, &fresh.hashed);
+            self.loader.invalidate();
+        }
+        *state = fresh;
+        Ok(state.hashed.clone())
+    }
+}
+
+/// Lets through requests for the current hash of a file only
+struct CurrentFingerprint(Arc<Fingerprint>);
+#[async_trait]
+impl FilterFn for CurrentFingerprint {
+    fn name(&self) -> &str {
+        "CurrentFingerprint"
+    }
+    async fn filter(&self, request: &Request<Incoming>) -> FilterResult {
+        match self.0.current().await {
+            Ok(hashed) => (request.uri().path() == hashed).into(),
+            Err(e) => {
+                error!("Failed to fingerprint {}: {e:?}", self.0.loader.path);
+                FilterResult::Block
+            }
+        }
+    }
+}
+
+/// Marks responses as cacheable forever, used for content hashed paths
+pub struct ImmutableCache;
+#[async_trait]
+impl WrapperFn for ImmutableCache {
+    fn name(&self) -> &str {
+        "ImmutableCache"
+    }
+    async fn before(&self, _: &mut ServiceData) -> WrapperResult {
+        WrapperResult::Continue
+    }
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        if data.response.status().is_success() {
+            data.response.headers_mut().insert(
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=31536000, immutable"),
+            );
+        }
+        WrapperResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::start;
+    use http::header::CONTENT_DISPOSITION;
+    use pfcore::server::ServerBuilder;
+
+    fn temp_root() -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("portfu-files-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn changed_files_get_a_new_hash() {
+        let root = temp_root();
+        std::fs::write(root.join("site.css"), "body {}").unwrap();
+        let manifest = AssetManifest::default();
+        let url = start(
+            ServerBuilder::default()
+                .register(DynamicFiles::new(root.to_string_lossy()).fingerprint(manifest.clone())),
+        )
+        .await;
+        let old = manifest.url_for("/site.css");
+        assert_ne!(old, "/site.css");
+        let response = reqwest::get(format!("{url}{old}")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()[CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(response.text().await.unwrap(), "body {}");
+
+        std::fs::write(root.join("site.css"), "body { color: red }").unwrap();
+        let response = reqwest::get(format!("{url}{old}")).await.unwrap();
+        assert_eq!(response.status(), 404);
+        let new = manifest.url_for("/site.css");
+        assert_ne!(new, old);
+        let response = reqwest::get(format!("{url}{new}")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "body { color: red }");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_matching_files_are_attachments() {
+        let root = temp_root();
+        std::fs::create_dir_all(root.join("reports")).unwrap();
+        std::fs::write(root.join("page.html"), "page").unwrap();
+        std::fs::write(root.join("reports/summary.pdf"), "pdf").unwrap();
+        std::fs::write(root.join("reports/notes.txt"), "notes").unwrap();
+        let url = start(
+            ServerBuilder::default()
+                .register(DynamicFiles::new(root.to_string_lossy()).attachment("*.pdf")),
+        )
+        .await;
+        for inline in ["/page.html", "/reports/notes.txt"] {
+            let response = reqwest::get(format!("{url}{inline}")).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert!(
+                !response.headers().contains_key(CONTENT_DISPOSITION),
+                "{inline}"
+            );
+        }
+        let response = reqwest::get(format!("{url}/reports/summary.pdf"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"summary.pdf\""
+        );
+        assert_eq!(response.text().await.unwrap(), "pdf");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn file_services_declare_get() {
+        let root = std::env::temp_dir().join(format!("portfu-files-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("docs/page.txt"), "page").unwrap();
+        let mut registry = ServiceRegistry::default();
+        DynamicFiles::new(root.to_string_lossy())
+            .show_listings(true)
+            .register(&mut registry);
+        AssetManifest::default()
+            .service("/manifest.json")
+            .register(&mut registry);
+        std::fs::remove_dir_all(&root).unwrap();
+        for path in [
+            "/index.html",
+            "/",
+            "/docs/page.txt",
+            "/docs/",
+            "/docs",
+            "/manifest.json",
+        ] {
+            assert_eq!(
+                registry.allowed_methods(path),
+                Some(vec![Method::GET]),
+                "{path}"
+            );
+        }
+        assert_eq!(registry.allowed_methods("/missing.txt"), None);
+    }
+}
@@ -5,6 +5,7 @@ use portfu_core::filters::{Filter, FilterFn, FilterMode, FilterResult};
 use std::sync::Arc;
 
 pub mod method;
+pub mod tls;
 
 pub fn any(name: String, filter: &[Arc<dyn FilterFn + Sync + Send>]) -> Filter {
     Filter {
@@ -0,0 +1,65 @@
+use async_trait::async_trait;
+use http::Request;
+use hyper::body::Incoming;
+use portfu_core::filters::{FilterFn, FilterResult};
+use portfu_core::tls::TlsInfo;
+use rustls::ProtocolVersion;
+use std::sync::Arc;
+
+struct TlsRequired;
+#[async_trait]
+impl FilterFn for TlsRequired {
+    fn name(&self) -> &str {
+        "tls_required"
+    }
+    async fn filter(&self, request: &Request<Incoming>) -> FilterResult {
+        request.extensions().get::<Arc<TlsInfo>>().is_some().into()
+    }
+}
+
+/// Blocks plaintext connections
+pub fn tls_required() -> Arc<dyn FilterFn + Send + Sync> {
+    Arc::new(TlsRequired)
+}
+
+struct MinTlsVersion(ProtocolVersion);
+#[async_trait]
+impl FilterFn for MinTlsVersion {
+    fn name(&self) -> &str {
+        "min_tls_version"
+    }
+    async fn filter(&self, request: &Request<Incoming>) -> FilterResult {
+        request
+            .extensions()
+            .get::<Arc<TlsInfo>>()
+            .map(|info| info.at_least(self.0))
+            .unwrap_or_default()
+            .into()
+    }
+}
+
+/// Blocks plaintext connections and TLS connections older than `version`
+pub fn min_tls_version(version: ProtocolVersion) -> Arc<dyn FilterFn + Send + Sync> {
+    Arc::new(MinTlsVersion(version))
+}
+
+struct ClientCertRequired;
+#[async_trait]
+impl FilterFn for ClientCertRequired {
+    fn name(&self) -> &str {
+        "client_cert_required"
+    }
+    async fn filter(&self, request: &Request<Incoming>) -> FilterResult {
+        request
+            .extensions()
+            .get::<Arc<TlsInfo>>()
+            .map(|info| info.peer_id().is_some())
+            .unwrap_or_default()
+            .into()
+    }
+}
+
+/// Blocks connections that did not present a client certificate
+pub fn client_cert_required() -> Arc<dyn FilterFn + Send + Sync> {
+    Arc::new(ClientCertRequired)
+}
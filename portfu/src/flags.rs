@@ -0,0 +1,253 @@
+use crate::wrappers::sessions::SESSION_HEADER;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use cookie::Cookie;
+use http::{header, HeaderMap, Request};
+use hyper::body::Incoming;
+use pfcore::filters::{FilterFn, FilterResult};
+use pfcore::service::ServiceRequest;
+use pfcore::{FromRequestRef, PathVars};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum FlagRule {
+    Boolean {
+        enabled: bool,
+    },
+    /// Enabled for `percent` of rollout keys, a key always gets the same answer for a flag
+    Percentage {
+        percent: f64,
+    },
+    /// Enabled only for the listed rollout keys
+    AllowList {
+        keys: HashSet<String>,
+    },
+}
+impl FlagRule {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            FlagRule::Percentage { percent } if !(0.0..=100.0).contains(percent) => Err(format!(
+                "percent must be between 0 and 100, found {percent}"
+            )),
+            _ => Ok(()),
+        }
+    }
+    fn enabled(&self, flag: &str, key: Option<&str>) -> bool {
+        match self {
+            FlagRule::Boolean { enabled } => *enabled,
+            FlagRule::Percentage { percent } => match key {
+                Some(key) => (rollout_bucket(flag, key) as f64) < percent * 100.0,
+                None => false,
+            },
+            FlagRule::AllowList { keys } => key.map(|k| keys.contains(k)).unwrap_or_default(),
+        }
+    }
+}
+
+/// Stable bucket between 0 and 9999 for `key` under `flag`
+fn rollout_bucket(flag: &str, key: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(flag.as_bytes());
+    hasher.update([0]);
+    hasher.update(key.as_bytes());
+    let digest = hasher.finalize();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix) % 10_000
+}
+
+/// Identifies the client for rollouts, the hex SHA-256 of the session cookie when present otherwise
+/// the client ip. The session id itself never becomes a key, Flags::key is safe to log.
+pub fn rollout_key(headers: &HeaderMap, address: Option<&SocketAddr>) -> Option<String> {
+    for value in headers.get_all(header::COOKIE) {
+        if let Ok(value) = value.to_str() {
+            for cookie in Cookie::split_parse(value).flatten() {
+                if cookie.name() == SESSION_HEADER {
+                    return Some(hex::encode(Sha256::digest(cookie.value().as_bytes())));
+                }
+            }
+        }
+    }
+    address.map(|a| a.ip().to_string())
+}
+
+/// Runtime feature flags, register as shared state to use the Flags extractor.
+/// Clones share the same definitions and unknown flags are disabled.
+#[derive(Clone, Default)]
+pub struct FeatureFlags {
+    flags: Arc<ArcSwap<HashMap<String, FlagRule>>>,
+}
+impl FeatureFlags {
+    pub fn flag<S: AsRef<str>>(self, name: S, rule: FlagRule) -> Self {
+        self.set(name, rule);
+        self
+    }
+    pub fn set<S: AsRef<str>>(&self, name: S, rule: FlagRule) {
+        let name = name.as_ref().to_string();
+        self.flags.rcu(|flags| {
+            let mut flags = flags.as_ref().clone();
+            flags.insert(name.clone(), rule.clone());
+            flags
+        });
+    }
+    pub fn remove(&self, name: &str) {
+        self.flags.rcu(|flags| {
+            let mut flags = flags.as_ref().clone();
+            flags.remove(name);
+            flags
+        });
+    }
+    /// Applies updates atomically, a None rule removes the flag.
+    /// Nothing is changed when any rule is invalid, the failures are returned instead.
+    pub fn update(
+        &self,
+        updates: HashMap<String, Option<FlagRule>>,
+    ) -> Result<(), HashMap<String, String>> {
+        let invalid: HashMap<String, String> = updates
+            .iter()
+            .filter_map(|(name, rule)| {
+                rule.as_ref()
+                    .and_then(|r| r.validate().err())
+                    .map(|e| (name.clone(), e))
+            })
+            .collect();
+        if !invalid.is_empty() {
+            return Err(invalid);
+        }
+        self.flags.rcu(|flags| {
+            let mut flags = flags.as_ref().clone();
+            for (name, rule) in updates.iter() {
+                match rule {
+                    Some(rule) => {
+                        flags.insert(name.clone(), rule.clone());
+                    }
+                    None => {
+                        flags.remove(name);
+                    }
+                }
+            }
+            flags
+        });
+        Ok(())
+    }
+    pub fn flags(&self) -> HashMap<String, FlagRule> {
+        self.flags.load().as_ref().clone()
+    }
+    pub fn is_enabled(&self, name: &str, key: Option<&str>) -> bool {
+        self.flags
+            .load()
+            .get(name)
+            .map(|rule| rule.enabled(name, key))
+            .unwrap_or_default()
+    }
+    /// Filter allowing requests only while `name` is enabled for them
+    pub fn gate<S: AsRef<str>>(&self, name: S) -> Arc<dyn FilterFn + Send + Sync> {
+        Arc::new(FlagGate {
+            flags: self.clone(),
+            name: name.as_ref().to_string(),
+            enabled: true,
+        })
+    }
+    /// Filter allowing requests only while `name` is disabled for them, pair it with [`FeatureFlags::gate`]
+    pub fn gate_disabled<S: AsRef<str>>(&self, name: S) -> Arc<dyn FilterFn + Send + Sync> {
+        Arc::new(FlagGate {
+            flags: self.clone(),
+            name: name.as_ref().to_string(),
+            enabled: false,
+        })
+    }
+}
+
+pub struct FlagGate {
+    flags: FeatureFlags,
+    name: String,
+    enabled: bool,
+}
+#[async_trait]
+impl FilterFn for FlagGate {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    async fn filter(&self, request: &Request<Incoming>) -> FilterResult {
+        let key = rollout_key(request.headers(), request.extensions().get());
+        (self.flags.is_enabled(&self.name, key.as_deref()) == self.enabled).into()
+    }
+}
+
+/// Feature flags as seen by the current request
+pub struct Flags {
+    flags: FeatureFlags,
+    key: Option<String>,
+}
+impl Flags {
+    pub fn enabled(&self, name: &str) -> bool {
+        self.flags.is_enabled(name, self.key.as_deref())
+    }
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+}
+impl PathVars for Flags {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+#[async_trait]
+impl<'a> FromRequestRef<'a> for Flags {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        let flags = request
+            .get::<Arc<FeatureFlags>>()
+            .map(|f| f.as_ref().clone())
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                "Failed to find FeatureFlags",
+            ))?;
+        let address = request.get::<SocketAddr>();
+        let key = match request.request.headers() {
+            Some(headers) => rollout_key(headers, address),
+            None => address.map(|a| a.ip().to_string()),
+        };
+        Ok(Flags { flags, key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn rollout_key_hides_the_session_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; {SESSION_HEADER}=secret-session")).unwrap(),
+        );
+        let address: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let key = rollout_key(&headers, Some(&address)).unwrap();
+        assert!(!key.contains("secret-session"));
+        assert_eq!(key, hex::encode(Sha256::digest(b"secret-session")));
+        assert_eq!(
+            rollout_key(&HeaderMap::new(), Some(&address)).as_deref(),
+            Some("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn percentage_rollouts_are_stable() {
+        let rule = FlagRule::Percentage { percent: 50.0 };
+        let enabled = (0..1000)
+            .filter(|i| rule.enabled("beta", Some(&i.to_string())))
+            .count();
+        assert!((400..600).contains(&enabled));
+        assert_eq!(
+            rule.enabled("beta", Some("7")),
+            rule.enabled("beta", Some("7"))
+        );
+        assert!(!rule.enabled("beta", None));
+    }
+}
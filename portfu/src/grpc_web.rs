@@ -0,0 +1,449 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::header::{ACCESS_CONTROL_EXPOSE_HEADERS, CONTENT_TYPE};
+use http::{HeaderValue, Method, StatusCode};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::Bytes;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use pfcore::service::{Service, ServiceBuilder};
+use pfcore::{IntoStreamBody, ServiceData, ServiceHandler, ServiceRegister, ServiceRegistry};
+use std::io::Error;
+use std::sync::Arc;
+
+pub const GRPC_WEB_PROTO: &str = "application/grpc-web+proto";
+pub const GRPC_WEB_TEXT_PROTO: &str = "application/grpc-web-text+proto";
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
+const FRAME_TRAILERS: u8 = 0x80;
+const FRAME_COMPRESSED: u8 = 0x01;
+/// grpc-message is percent encoded outside printable ASCII
+const MESSAGE_ESCAPES: &AsciiSet = &CONTROLS.add(b'%');
+
+/// A gRPC status, code 0 is OK
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrpcStatus {
+    pub code: u32,
+    pub message: String,
+}
+impl GrpcStatus {
+    pub fn new<S: AsRef<str>>(code: u32, message: S) -> Self {
+        Self {
+            code,
+            message: message.as_ref().to_string(),
+        }
+    }
+    pub fn invalid_argument<S: AsRef<str>>(message: S) -> Self {
+        Self::new(3, message)
+    }
+    pub fn not_found<S: AsRef<str>>(message: S) -> Self {
+        Self::new(5, message)
+    }
+    pub fn resource_exhausted<S: AsRef<str>>(message: S) -> Self {
+        Self::new(8, message)
+    }
+    pub fn unimplemented<S: AsRef<str>>(message: S) -> Self {
+        Self::new(12, message)
+    }
+    pub fn internal<S: AsRef<str>>(message: S) -> Self {
+        Self::new(13, message)
+    }
+    pub fn unavailable<S: AsRef<str>>(message: S) -> Self {
+        Self::new(14, message)
+    }
+    fn trailers(&self) -> Vec<u8> {
+        let mut trailers = format!("grpc-status:{}\r\n", self.code);
+        if !self.message.is_empty() {
+            trailers.push_str(&format!(
+                "grpc-message:{}\r\n",
+                utf8_percent_encode(&self.message, MESSAGE_ESCAPES)
+            ));
+        }
+        trailers.into_bytes()
+    }
+}
+
+/// One unary gRPC method, receives and returns the encoded protobuf message
+#[async_trait]
+pub trait GrpcMethod {
+    async fn handle(&self, message: Bytes) -> Result<Bytes, GrpcStatus>;
+}
+
+fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(payload.len() + 5);
+    framed.push(flag);
+    framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// The message of the only data frame a unary call carries. Framing errors are INTERNAL as the
+/// gRPC protocol asks, the declared length is checked against `max_message_bytes` before it is read.
+fn deframe(
+    body: &[u8],
+    encoding: Option<&str>,
+    max_message_bytes: usize,
+) -> Result<Bytes, GrpcStatus> {
+    if body.len() < 5 {
+        return Err(GrpcStatus::internal("Truncated gRPC-web frame"));
+    }
+    if body[0] & FRAME_COMPRESSED != 0 {
+        return Err(match encoding {
+            None | Some("identity") => {
+                GrpcStatus::internal("Compressed message without a grpc-encoding")
+            }
+            Some(encoding) => {
+                GrpcStatus::unimplemented(format!("Message encoding {encoding} is not supported"))
+            }
+        });
+    }
+    let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    if length > max_message_bytes {
+        return Err(GrpcStatus::resource_exhausted(format!(
+            "Message of {length} bytes is larger than {max_message_bytes}"
+        )));
+    }
+    match body.len() - 5 {
+        read if read < length => Err(GrpcStatus::internal("Truncated gRPC-web frame")),
+        read if read > length => Err(GrpcStatus::internal(
+            "Unary call carried more than one message",
+        )),
+        _ => Ok(Bytes::copy_from_slice(&body[5..])),
+    }
+}
+
+/// Text mode bodies may be several padded base64 chunks back to back
+fn decode_text(body: &[u8]) -> Result<Vec<u8>, GrpcStatus> {
+    let clean: Vec<u8> = body
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if !clean.len().is_multiple_of(4) {
+        return Err(GrpcStatus::internal("Invalid base64 body length"));
+    }
+    let mut decoded = vec![];
+    let mut start = 0;
+    for (index, quantum) in clean.chunks(4).enumerate() {
+        let end = (index + 1) * 4;
+        //Padding ends a chunk, decode it on its own
+        if quantum.ends_with(b"=") || end == clean.len() {
+            STANDARD
+                .decode_vec(&clean[start..end], &mut decoded)
+                .map_err(|e| GrpcStatus::internal(format!("Invalid base64 body: {e}")))?;
+            start = end;
+        }
+    }
+    Ok(decoded)
+}
+
+struct GrpcWebHandler {
+    path: String,
+    method: Arc<dyn GrpcMethod + Send + Sync>,
+    max_message_bytes: usize,
+}
+impl GrpcWebHandler {
+    async fn call(&self, data: &mut ServiceData, text: bool) -> Result<Bytes, GrpcStatus> {
+        let body = data
+            .request
+            .consume()
+            .map_err(|e| GrpcStatus::internal(e.to_string()))?;
+        //Room for one frame header, and for its base64 form in text mode
+        let framed = self.max_message_bytes.saturating_add(5);
+        let limit = if text {
+            framed.div_ceil(3).saturating_mul(4)
+        } else {
+            framed
+        };
+        let body = Limited::new(body, limit)
+            .collect()
+            .await
+            .map_err(|e| {
+                if e.is::<LengthLimitError>() {
+                    GrpcStatus::resource_exhausted(format!("Request is larger than {limit} bytes"))
+                } else {
+                    GrpcStatus::unavailable(format!("Failed to read request: {e}"))
+                }
+            })?
+            .to_bytes();
+        let encoding = data
+            .request
+            .request
+            .headers()
+            .and_then(|h| h.get("grpc-encoding"))
+            .and_then(|v| v.to_str().ok());
+        let message = if text {
+            deframe(&decode_text(&body)?, encoding, self.max_message_bytes)?
+        } else {
+            deframe(&body, encoding, self.max_message_bytes)?
+        };
+        self.method.handle(message).await
+    }
+}
+#[async_trait]
+impl ServiceHandler for GrpcWebHandler {
+    fn name(&self) -> &str {
+        &self.path
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let content_type = data
+            .request
+            .request
+            .headers()
+            .and_then(|h| h.get(CONTENT_TYPE))
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let text = content_type.starts_with("application/grpc-web-text");
+        if !text && !content_type.starts_with("application/grpc-web") {
+            *data.response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+            return Ok(data);
+        }
+        let mut body = vec![];
+        let status = match self.call(&mut data, text).await {
+            Ok(message) => {
+                body.extend(frame(0, &message));
+                GrpcStatus::new(0, "")
+            }
+            Err(status) => status,
+        };
+        body.extend(frame(FRAME_TRAILERS, &status.trailers()));
+        if text {
+            body = STANDARD.encode(body).into_bytes();
+        }
+        let headers = data.response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(if text {
+                GRPC_WEB_TEXT_PROTO
+            } else {
+                GRPC_WEB_PROTO
+            }),
+        );
+        headers.insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static("grpc-status, grpc-message"),
+        );
+        *data.response.body_mut() = body.stream_body();
+        Ok(data)
+    }
+}
+
+/// Serves unary gRPC methods to gRPC-web clients over HTTP/1.1 without a proxy.
+/// Trailers are sent in the body as gRPC-web requires, both binary and base64 text modes are accepted.
+pub struct GrpcWebBridge {
+    methods: Vec<(String, Arc<dyn GrpcMethod + Send + Sync>)>,
+    max_message_bytes: usize,
+}
+impl Default for GrpcWebBridge {
+    fn default() -> Self {
+        Self {
+            methods: vec![],
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+}
+impl GrpcWebBridge {
+    /// Registers `method` at its gRPC path, `/package.Service/Method`
+    pub fn method<S: AsRef<str>>(self, path: S, method: Arc<dyn GrpcMethod + Send + Sync>) -> Self {
+        let mut s = self;
+        s.methods.push((path.as_ref().to_string(), method));
+        s
+    }
+    pub fn max_message_bytes(self, max_message_bytes: usize) -> Self {
+        let mut s = self;
+        s.max_message_bytes = max_message_bytes;
+        s
+    }
+    pub fn services(self) -> Vec<Service> {
+        self.methods
+            .into_iter()
+            .map(|(path, method)| {
+                ServiceBuilder::new(&path)
+                    .name(&path)
+                    .method(Method::POST)
+                    .handler(Arc::new(GrpcWebHandler {
+                        path: path.clone(),
+                        method,
+                        max_message_bytes: self.max_message_bytes,
+                    }))
+                    .build()
+            })
+            .collect()
+    }
+}
+impl ServiceRegister for GrpcWebBridge {
+    fn register(self, service_registry: &mut ServiceRegistry) {
+        for service in self.services() {
+            service_registry.register(service);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::start;
+    use pfcore::server::ServerBuilder;
+
+    const ECHO: &str = "/echo.Echo/Say";
+
+    /// Answers with the request message, or with the status named by it
+    struct Echo;
+    #[async_trait]
+    impl GrpcMethod for Echo {
+        async fn handle(&self, message: Bytes) -> Result<Bytes, GrpcStatus> {
+            match message.as_ref() {
+                b"missing" => Err(GrpcStatus::not_found("caf\u{e9} 100%")),
+                _ => Ok(message),
+            }
+        }
+    }
+
+    async fn bridge(max_message_bytes: usize) -> String {
+        let bridge = GrpcWebBridge::default()
+            .max_message_bytes(max_message_bytes)
+            .method(ECHO, Arc::new(Echo));
+        start(ServerBuilder::default().register(bridge)).await
+    }
+
+    /// (flag, payload) of every frame in a response body
+    fn frames(mut body: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = vec![];
+        while body.len() >= 5 {
+            let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+            frames.push((body[0], body[5..5 + length].to_vec()));
+            body = &body[5 + length..];
+        }
+        assert!(body.is_empty(), "trailing bytes after the last frame");
+        frames
+    }
+
+    /// The data messages and the trailers of a response, as a gRPC-web client reads them
+    fn read(body: &[u8]) -> (Vec<Vec<u8>>, Vec<(String, String)>) {
+        let mut messages = vec![];
+        let mut trailers = vec![];
+        for (flag, payload) in frames(body) {
+            if flag & FRAME_TRAILERS == 0 {
+                messages.push(payload);
+                continue;
+            }
+            for line in String::from_utf8(payload).unwrap().split("\r\n") {
+                if let Some((name, value)) = line.split_once(':') {
+                    trailers.push((name.to_string(), value.trim().to_string()));
+                }
+            }
+        }
+        (messages, trailers)
+    }
+
+    async fn call(
+        url: &str,
+        content_type: &str,
+        body: Vec<u8>,
+        encoding: Option<&str>,
+    ) -> reqwest::Response {
+        let mut request = reqwest::Client::new()
+            .post(format!("{url}{ECHO}"))
+            .header("content-type", content_type)
+            .header("x-grpc-web", "1");
+        if let Some(encoding) = encoding {
+            request = request.header("grpc-encoding", encoding);
+        }
+        request.body(body).send().await.unwrap()
+    }
+
+    async fn status(url: &str, body: Vec<u8>, encoding: Option<&str>) -> String {
+        let response = call(url, GRPC_WEB_PROTO, body, encoding).await;
+        let (_, trailers) = read(&response.bytes().await.unwrap());
+        trailers
+            .into_iter()
+            .find(|(name, _)| name == "grpc-status")
+            .unwrap()
+            .1
+    }
+
+    #[tokio::test]
+    async fn binary_unary_call() {
+        let url = bridge(DEFAULT_MAX_MESSAGE_BYTES).await;
+        let response = call(&url, GRPC_WEB_PROTO, frame(0, b"\x0a\x05hello"), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], GRPC_WEB_PROTO);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_EXPOSE_HEADERS],
+            "grpc-status, grpc-message"
+        );
+        let (messages, trailers) = read(&response.bytes().await.unwrap());
+        assert_eq!(messages, vec![b"\x0a\x05hello".to_vec()]);
+        assert_eq!(trailers, vec![("grpc-status".to_string(), "0".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn text_unary_call() {
+        let url = bridge(DEFAULT_MAX_MESSAGE_BYTES).await;
+        let body = STANDARD.encode(frame(0, b"\x0a\x02hi")).into_bytes();
+        let response = call(&url, GRPC_WEB_TEXT_PROTO, body, None).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], GRPC_WEB_TEXT_PROTO);
+        let decoded = STANDARD.decode(response.bytes().await.unwrap()).unwrap();
+        let (messages, trailers) = read(&decoded);
+        assert_eq!(messages, vec![b"\x0a\x02hi".to_vec()]);
+        assert_eq!(trailers, vec![("grpc-status".to_string(), "0".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn errors_are_trailers_only() {
+        let url = bridge(DEFAULT_MAX_MESSAGE_BYTES).await;
+        let response = call(&url, GRPC_WEB_PROTO, frame(0, b"missing"), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (messages, trailers) = read(&response.bytes().await.unwrap());
+        assert!(messages.is_empty());
+        assert_eq!(
+            trailers,
+            vec![
+                ("grpc-status".to_string(), "5".to_string()),
+                ("grpc-message".to_string(), "caf%C3%A9 100%25".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn message_size_is_checked_after_decoding() {
+        let url = bridge(16).await;
+        assert_eq!(status(&url, frame(0, &[1; 16]), None).await, "0");
+        assert_eq!(status(&url, frame(0, &[1; 17]), None).await, "8");
+        //Declares more than the limit without sending it
+        assert_eq!(
+            status(&url, frame(0, &[1; 17])[..8].to_vec(), None).await,
+            "8"
+        );
+        let text = STANDARD.encode(frame(0, &[1; 16])).into_bytes();
+        let response = call(&url, GRPC_WEB_TEXT_PROTO, text, None).await;
+        let (messages, _) = read(&STANDARD.decode(response.bytes().await.unwrap()).unwrap());
+        assert_eq!(messages, vec![vec![1; 16]]);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_get_grpc_status_codes() {
+        let url = bridge(DEFAULT_MAX_MESSAGE_BYTES).await;
+        assert_eq!(status(&url, vec![0, 0, 0], None).await, "13");
+        assert_eq!(
+            status(&url, frame(0, b"hello")[..7].to_vec(), None).await,
+            "13"
+        );
+        let mut two = frame(0, b"one");
+        two.extend(frame(0, b"two"));
+        assert_eq!(status(&url, two, None).await, "13");
+        assert_eq!(
+            status(&url, frame(FRAME_COMPRESSED, b"x"), None).await,
+            "13"
+        );
+        assert_eq!(
+            status(&url, frame(FRAME_COMPRESSED, b"x"), Some("gzip")).await,
+            "12"
+        );
+        let response = call(&url, GRPC_WEB_TEXT_PROTO, b"not base64!".to_vec(), None).await;
+        let (_, trailers) = read(&STANDARD.decode(response.bytes().await.unwrap()).unwrap());
+        assert_eq!(trailers[0], ("grpc-status".to_string(), "13".to_string()));
+        let response = call(&url, "application/json", b"{}".to_vec(), None).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -1,6 +1,20 @@
+pub mod baggage;
+pub mod breaker;
 pub mod client;
 pub mod endpoints;
+pub mod files;
 pub mod filters;
+pub mod flags;
+#[cfg(feature = "grpc_web")]
+pub mod grpc_web;
+pub mod loader;
+pub mod logging;
+pub mod pagination;
+pub mod resolver;
+#[cfg(feature = "log_shipper")]
+pub mod shipper;
+#[cfg(test)]
+mod test_server;
 pub mod wrappers;
 
 pub extern crate portfu_core as pfcore;
@@ -8,6 +22,7 @@ pub extern crate portfu_macros as macros;
 
 pub mod prelude {
     pub extern crate async_trait;
+    pub extern crate futures_util;
     pub extern crate http;
     pub extern crate http_body_util;
     pub extern crate hyper;
@@ -19,18 +34,65 @@ pub mod prelude {
     pub type Service = ::pfcore::service::Service;
     pub type Server = ::pfcore::server::Server;
     pub type ServerBuilder = ::pfcore::server::ServerBuilder;
+    pub type ShutdownReason = ::pfcore::shutdown::ShutdownReason;
     pub type SslConfig = ::pfcore::server::SslConfig;
+    pub type HttpsRedirect = ::pfcore::redirect::HttpsRedirect;
     pub type ServiceResponse = ::pfcore::ServiceResponse;
     pub type ServiceGroup = ::pfcore::service::ServiceGroup;
     pub type ServiceRegistry = ::pfcore::ServiceRegistry;
     pub type ServiceData = ::pfcore::ServiceData;
-    pub type Path = ::pfcore::Path;
+    pub type Path<T = String> = ::pfcore::Path<T>;
     pub type Body<T> = ::pfcore::Body<T>;
+    pub type Query<T> = ::pfcore::Query<T>;
+    pub type DynamicFiles = crate::files::DynamicFiles;
+    pub type AssetManifest = crate::files::AssetManifest;
     pub type State<T> = ::pfcore::State<T>;
+    pub type HttpError = ::pfcore::errors::HttpError;
+    pub type HeaderPolicy = ::pfcore::headers::HeaderPolicy;
+    pub type BlockingPool = ::pfcore::blocking::BlockingPool;
+    pub type LogControl = crate::logging::LogControl;
+    pub type Settings = ::pfcore::settings::Settings;
+    pub type FeatureFlags = crate::flags::FeatureFlags;
+    pub type Flags = crate::flags::Flags;
+    pub type PageRequest = crate::pagination::PageRequest;
+    pub type Paginated<T> = crate::pagination::Paginated<T>;
+    pub type TlsInfo = ::pfcore::tls::TlsInfo;
+    pub type Hedging = crate::client::Hedging;
+    pub type Breaker = crate::breaker::Breaker;
+    pub type Breakers = crate::breaker::Breakers;
+    pub type Baggage = crate::baggage::Baggage;
+    pub type Loader<K, T> = crate::loader::Loader<K, T>;
     pub type WebSocket = ::pfcore::sockets::WebSocket;
     pub type WebsocketConnection = ::pfcore::sockets::WebsocketConnection;
     pub type WebsocketMsgStream = tokio_tungstenite::WebSocketStream<
         hyper_util::rt::tokio::TokioIo<hyper::upgrade::Upgraded>,
     >;
     pub type Peers = ::pfcore::sockets::Peers;
+    pub type SecretString = ::pfcore::secrets::SecretString;
+    pub type SecretBytes = ::pfcore::secrets::SecretBytes;
+    pub type PeerEvent = ::pfcore::sockets::PeerEvent;
+    pub type DisconnectReason = ::pfcore::sockets::DisconnectReason;
+    pub type FloodPolicy = ::pfcore::sockets::FloodPolicy;
+    pub type FloodAction = ::pfcore::sockets::FloodAction;
+    pub type WebSocketOptions = ::pfcore::sockets::WebSocketOptions;
+    pub type ClientWebSocket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+    pub type ClientConnection = ::pfcore::sockets::ClientConnection;
+    pub type ClientState = ::pfcore::sockets::ClientState;
+    pub type ReconnectPolicy = ::pfcore::sockets::ReconnectPolicy;
+    pub type SystemClock = ::pfcore::clock::SystemClock;
+    pub type ManualClock = ::pfcore::clock::ManualClock;
+    pub type Profile = ::pfcore::profile::Profile;
+    pub type NdJson<S> = ::pfcore::responders::NdJson<S>;
+    pub type Sse<S> = ::pfcore::responders::Sse<S>;
+    pub type SseEvent<T> = ::pfcore::responders::SseEvent<T>;
+    pub type ResponseBuilder = ::pfcore::responders::ResponseBuilder;
+    pub type UpgradeConfig = ::pfcore::upgrade::UpgradeConfig;
+    pub type ShutdownSignal = ::pfcore::task::ShutdownSignal;
+    pub type DuplicateKeys = ::pfcore::duplicates::DuplicateKeys;
+    pub type Maintenance = ::pfcore::static_response::Maintenance;
+    pub type StaticResponse = ::pfcore::static_response::StaticResponse;
+    pub type FileDataStore = ::pfcore::data_store::FileDataStore;
+    pub type MemoryDataStore = ::pfcore::data_store::MemoryDataStore;
 }
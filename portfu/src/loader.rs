@@ -0,0 +1,146 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Fetches many keys in one call, keys missing from the result are treated as not found
+#[async_trait]
+pub trait BatchLoad<K, T> {
+    async fn load_many(&self, keys: Vec<K>) -> Result<HashMap<K, T>, Error>;
+}
+
+type Waiter<T> = oneshot::Sender<Result<Option<T>, (ErrorKind, String)>>;
+
+struct LoaderState<K, T> {
+    cache: HashMap<K, Option<T>>,
+    pending: HashMap<K, Vec<Waiter<T>>>,
+    scheduled: bool,
+}
+
+struct LoaderInner<K, T> {
+    batch: Arc<dyn BatchLoad<K, T> + Send + Sync>,
+    delay: Duration,
+    state: Mutex<LoaderState<K, T>>,
+}
+impl<K, T> LoaderInner<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    async fn dispatch(&self) {
+        let pending = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.scheduled = false;
+            std::mem::take(&mut state.pending)
+        };
+        if pending.is_empty() {
+            return;
+        }
+        let keys = pending.keys().cloned().collect();
+        match self.batch.load_many(keys).await {
+            Ok(mut found) => {
+                let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+                for (key, waiters) in pending {
+                    let value = found.remove(&key);
+                    state.cache.insert(key, value.clone());
+                    for waiter in waiters {
+                        let _ = waiter.send(Ok(value.clone()));
+                    }
+                }
+            }
+            Err(e) => {
+                //Failures are not cached so a later load tries again
+                for waiter in pending.into_values().flatten() {
+                    let _ = waiter.send(Err((e.kind(), e.to_string())));
+                }
+            }
+        }
+    }
+}
+
+/// Batches and caches loads for the lifetime of the Loader, create one per request.
+/// Loads issued within `delay` of the first pending one are sent as a single load_many call,
+/// concurrent loads of the same key share one entry in that call.
+pub struct Loader<K, T> {
+    inner: Arc<LoaderInner<K, T>>,
+}
+impl<K, T> Clone for Loader<K, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+impl<K, T> Loader<K, T>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    pub fn new(batch: Arc<dyn BatchLoad<K, T> + Send + Sync>) -> Self {
+        Self::with_delay(batch, Duration::from_millis(1))
+    }
+    pub fn with_delay(batch: Arc<dyn BatchLoad<K, T> + Send + Sync>, delay: Duration) -> Self {
+        Self {
+            inner: Arc::new(LoaderInner {
+                batch,
+                delay,
+                state: Mutex::new(LoaderState {
+                    cache: HashMap::new(),
+                    pending: HashMap::new(),
+                    scheduled: false,
+                }),
+            }),
+        }
+    }
+    pub async fn load(&self, key: K) -> Result<Option<T>, Error> {
+        let receiver = {
+            let mut state = self.inner.state.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(cached) = state.cache.get(&key) {
+                return Ok(cached.clone());
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.pending.entry(key).or_default().push(sender);
+            if !state.scheduled {
+                state.scheduled = true;
+                let inner = self.inner.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(inner.delay).await;
+                    inner.dispatch().await;
+                });
+            }
+            receiver
+        };
+        match receiver.await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err((kind, message))) => Err(Error::new(kind, message)),
+            Err(_) => Err(Error::new(
+                ErrorKind::Interrupted,
+                "Loader dropped before the batch finished",
+            )),
+        }
+    }
+    pub async fn load_many(&self, keys: Vec<K>) -> Result<HashMap<K, T>, Error> {
+        let loads = keys.into_iter().map(|key| async move {
+            let value = self.load(key.clone()).await?;
+            Ok::<_, Error>(value.map(|v| (key, v)))
+        });
+        let loaded = futures_util::future::try_join_all(loads).await?;
+        Ok(loaded.into_iter().flatten().collect())
+    }
+    /// Sends the pending loads now instead of waiting for the delay
+    pub async fn dispatch(&self) {
+        self.inner.dispatch().await;
+    }
+    /// Forgets a cached value, for example after it was written
+    pub fn clear(&self, key: &K) {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .cache
+            .remove(key);
+    }
+}
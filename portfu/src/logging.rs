@@ -0,0 +1,262 @@
+use crate::baggage::Baggage;
+#[cfg(feature = "log_shipper")]
+use crate::shipper::{LogShipper, ShippedRecord, ShipperStats, SHIPPER_TARGET};
+use arc_swap::ArcSwap;
+#[cfg(feature = "log_shipper")]
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use pfcore::reload::{ReloadFn, ReloadReport};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Key used for the default level when listing or updating filters
+pub const DEFAULT_FILTER: &str = "*";
+
+#[derive(Debug, Clone)]
+struct LogFilters {
+    default: LevelFilter,
+    modules: HashMap<String, LevelFilter>,
+}
+impl LogFilters {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let mut best: Option<(&str, LevelFilter)> = None;
+        for (module, level) in self.modules.iter() {
+            let matches = target == module
+                || (target.starts_with(module.as_str())
+                    && target[module.len()..].starts_with("::"));
+            if matches && best.map(|(m, _)| module.len() > m.len()).unwrap_or(true) {
+                best = Some((module, *level));
+            }
+        }
+        best.map(|(_, level)| level).unwrap_or(self.default)
+    }
+    fn max_level(&self) -> LevelFilter {
+        self.modules.values().copied().fold(self.default, Ord::max)
+    }
+}
+
+/// Runtime adjustable per module log levels.
+/// Install it in place of the application logger, the wrapped logger should be configured to accept
+/// every level since filtering is done here. Clones share the same filter table.
+#[derive(Clone)]
+pub struct LogControl {
+    filters: Arc<ArcSwap<LogFilters>>,
+    #[cfg(feature = "log_shipper")]
+    shipper: Arc<ArcSwapOption<LogShipper>>,
+    /// Held by update so concurrent writers neither lose changes nor set a stale max level
+    writer: Arc<Mutex<()>>,
+}
+impl LogControl {
+    pub fn new(default: LevelFilter) -> Self {
+        Self {
+            filters: Arc::new(ArcSwap::from_pointee(LogFilters {
+                default,
+                modules: HashMap::new(),
+            })),
+            #[cfg(feature = "log_shipper")]
+            shipper: Arc::new(ArcSwapOption::empty()),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+    /// Also sends every record that passes the filters to `shipper`
+    #[cfg(feature = "log_shipper")]
+    pub fn ship_to(&self, shipper: LogShipper) {
+        self.shipper.store(Some(Arc::new(shipper)));
+    }
+    /// Counters of the shipper set with ship_to
+    #[cfg(feature = "log_shipper")]
+    pub fn shipper_stats(&self) -> Option<ShipperStats> {
+        self.shipper.load().as_ref().map(|shipper| shipper.stats())
+    }
+    /// Sets the global logger to `logger` filtered by the returned LogControl
+    pub fn install(
+        logger: Box<dyn Log>,
+        default: LevelFilter,
+    ) -> Result<LogControl, SetLoggerError> {
+        let control = LogControl::new(default);
+        log::set_boxed_logger(Box::new(ControlledLogger {
+            inner: logger,
+            control: control.clone(),
+        }))?;
+        log::set_max_level(default);
+        Ok(control)
+    }
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.filters.load().level_for(target)
+    }
+    /// Current filters as strings, the default level is listed under DEFAULT_FILTER
+    pub fn filters(&self) -> HashMap<String, String> {
+        let filters = self.filters.load();
+        let mut listed: HashMap<String, String> = filters
+            .modules
+            .iter()
+            .map(|(module, level)| (module.clone(), level.to_string().to_lowercase()))
+            .collect();
+        listed.insert(
+            DEFAULT_FILTER.to_string(),
+            filters.default.to_string().to_lowercase(),
+        );
+        listed
+    }
+    /// Applies updates atomically, a None level removes the module override.
+    /// Nothing is changed when any level fails to parse, the invalid entries are returned instead.
+    pub fn update(&self, updates: HashMap<String, Option<String>>) -> Result<(), Vec<String>> {
+        let mut parsed = Vec::with_capacity(updates.len());
+        let mut invalid = vec![];
+        for (module, level) in updates {
+            match level.map(|l| LevelFilter::from_str(&l)).transpose() {
+                Ok(level) => parsed.push((module, level)),
+                Err(_) => invalid.push(module),
+            }
+        }
+        if !invalid.is_empty() {
+            return Err(invalid);
+        }
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut filters = self.filters.load().as_ref().clone();
+        for (module, level) in parsed {
+            match (module.as_str(), level) {
+                (DEFAULT_FILTER, Some(level)) => filters.default = level,
+                (DEFAULT_FILTER, None) => {}
+                (_, Some(level)) => {
+                    filters.modules.insert(module, level);
+                }
+                (_, None) => {
+                    filters.modules.remove(&module);
+                }
+            }
+        }
+        log::set_max_level(filters.max_level());
+        self.filters.store(Arc::new(filters));
+        Ok(())
+    }
+    /// ReloadFn applying the JSON object of module levels at `path`, as accepted by update
+    pub fn reloader<P: AsRef<Path>>(&self, path: P) -> Arc<dyn ReloadFn + Send + Sync> {
+        Arc::new(LogFile {
+            control: self.clone(),
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+struct LogFile {
+    control: LogControl,
+    path: PathBuf,
+}
+#[async_trait]
+impl ReloadFn for LogFile {
+    fn name(&self) -> &str {
+        "log_levels"
+    }
+    async fn reload(&self) -> Result<ReloadReport, Error> {
+        let document = tokio::fs::read(&self.path).await?;
+        let updates: HashMap<String, Option<String>> = serde_json::from_slice(&document)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid log levels: {e}")))?;
+        let mut changed: Vec<String> = updates
+            .keys()
+            .map(|module| format!("log level {module}"))
+            .collect();
+        changed.sort();
+        self.control.update(updates).map_err(|invalid| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid levels for {}", invalid.join(", ")),
+            )
+        })?;
+        Ok(ReloadReport {
+            applied: changed,
+            ..Default::default()
+        })
+    }
+}
+
+struct ControlledLogger {
+    inner: Box<dyn Log>,
+    control: LogControl,
+}
+impl Log for ControlledLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.control.level_for(metadata.target())
+    }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            #[cfg(feature = "log_shipper")]
+            if let Some(shipper) = self.control.shipper.load().as_ref() {
+                //The collector client logs through here too, shipping those would feed back on itself
+                let target = record.target();
+                if ![SHIPPER_TARGET, "reqwest", "hyper", "h2", "rustls"]
+                    .iter()
+                    .any(|t| target.starts_with(t))
+                {
+                    let baggage = Baggage::current()
+                        .filter(|b| !b.is_empty())
+                        .map(|b| b.to_string());
+                    shipper.ship(ShippedRecord::from_record(record, baggage));
+                }
+            }
+            match Baggage::current().filter(|b| !b.is_empty()) {
+                Some(baggage) => self.inner.log(
+                    &Record::builder()
+                        .args(format_args!("{} baggage={baggage}", record.args()))
+                        .metadata(record.metadata().clone())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                ),
+                None => self.inner.log(record),
+            }
+        }
+    }
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_updates_are_all_applied() {
+        let control = LogControl::new(LevelFilter::Warn);
+        let writers: Vec<_> = (0..16)
+            .map(|i| {
+                let control = control.clone();
+                std::thread::spawn(move || {
+                    for round in 0..50 {
+                        control
+                            .update(HashMap::from([(
+                                format!("module_{i}_{round}"),
+                                Some("debug".to_string()),
+                            )]))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(control.filters().len(), 16 * 50 + 1);
+        assert_eq!(control.level_for("module_3_7::inner"), LevelFilter::Debug);
+        assert_eq!(control.level_for("other"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn invalid_levels_change_nothing() {
+        let control = LogControl::new(LevelFilter::Info);
+        let invalid = control
+            .update(HashMap::from([
+                ("a".to_string(), Some("debug".to_string())),
+                ("b".to_string(), Some("loud".to_string())),
+            ]))
+            .unwrap_err();
+        assert_eq!(invalid, vec!["b".to_string()]);
+        assert_eq!(control.filters().len(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http::header::LINK;
+use http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use pfcore::service::ServiceRequest;
+use pfcore::{FromRequestRef, PathVars};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+pub const MAX_PAGE_SIZE: u64 = 1000;
+pub static X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+/// Position after the last item of a page, encoded as an opaque string for clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub last_key: String,
+    pub sort: String,
+}
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+    pub fn decode(value: &str) -> Result<Self, Error> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid cursor: {e}")))?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid cursor: {e}")))
+    }
+}
+
+/// Page selection read from the `page`, `page_size` and `cursor` query parameters.
+/// Pages start at 1, a cursor selects cursor pagination instead of page numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageRequest {
+    pub page: u64,
+    pub page_size: u64,
+    pub cursor: Option<Cursor>,
+}
+impl Default for PageRequest {
+    fn default() -> Self {
+        Self {
+            page: 1,
+            page_size: DEFAULT_PAGE_SIZE,
+            cursor: None,
+        }
+    }
+}
+impl PageRequest {
+    pub fn from_uri(uri: &Uri) -> Result<Self, Error> {
+        let mut request = PageRequest::default();
+        for (key, value) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "page" => {
+                    request.page = value.parse::<u64>().map_err(|e| {
+                        Error::new(ErrorKind::InvalidInput, format!("Invalid page: {e}"))
+                    })?;
+                }
+                "page_size" => {
+                    request.page_size = value.parse::<u64>().map_err(|e| {
+                        Error::new(ErrorKind::InvalidInput, format!("Invalid page_size: {e}"))
+                    })?;
+                }
+                "cursor" if !value.is_empty() => request.cursor = Some(Cursor::decode(&value)?),
+                _ => {}
+            }
+        }
+        request.page = request.page.max(1);
+        request.page_size = request.page_size.clamp(1, MAX_PAGE_SIZE);
+        if (request.page - 1).checked_mul(request.page_size).is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Invalid page: {} is past the last possible page",
+                    request.page
+                ),
+            ));
+        }
+        Ok(request)
+    }
+    /// Number of items to skip for page based pagination
+    pub fn offset(&self) -> u64 {
+        self.page.saturating_sub(1).saturating_mul(self.page_size)
+    }
+}
+impl PathVars for PageRequest {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+#[async_trait]
+impl<'a> FromRequestRef<'a> for PageRequest {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        PageRequest::from_uri(request.request.uri())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub page: u64,
+    pub page_size: u64,
+    pub total: u64,
+    pub total_pages: u64,
+    /// Set when more items follow under cursor pagination
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub next_cursor: Option<String>,
+}
+impl<T> Paginated<T> {
+    pub fn new(items: Vec<T>, request: &PageRequest, total: u64) -> Self {
+        Self {
+            items,
+            page: request.page,
+            page_size: request.page_size,
+            total,
+            total_pages: total.div_ceil(request.page_size),
+            next_cursor: None,
+        }
+    }
+    /// Takes one page out of all `items`
+    pub fn from_all(items: Vec<T>, request: &PageRequest) -> Self {
+        let total = items.len() as u64;
+        let page = items
+            .into_iter()
+            .skip(usize::try_from(request.offset()).unwrap_or(usize::MAX))
+            .take(request.page_size as usize)
+            .collect();
+        Self::new(page, request, total)
+    }
+    pub fn next_cursor(self, cursor: Option<Cursor>) -> Self {
+        let mut s = self;
+        s.next_cursor = cursor.map(|c| c.encode());
+        s
+    }
+    /// Adds X-Total-Count and RFC 8288 Link headers for first, prev, next and last built from `uri`,
+    /// every other query parameter of `uri` is kept so filters carry over between pages.
+    pub fn apply_headers(&self, uri: &Uri, headers: &mut HeaderMap) {
+        headers.insert(X_TOTAL_COUNT.clone(), HeaderValue::from(self.total));
+        let mut links = vec![];
+        if let Some(cursor) = &self.next_cursor {
+            links.push((page_url(uri, &[("cursor", cursor.clone())]), "next"));
+        } else {
+            let last = self.total_pages.max(1);
+            let page = |page: u64| page_url(uri, &[("page", page.to_string())]);
+            links.push((page(1), "first"));
+            if self.page > 1 {
+                links.push((page((self.page - 1).min(last)), "prev"));
+            }
+            if self.page < last {
+                links.push((page(self.page + 1), "next"));
+            }
+            links.push((page(last), "last"));
+        }
+        let link = links
+            .into_iter()
+            .map(|(url, rel)| format!("<{url}>; rel=\"{rel}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(value) = HeaderValue::from_str(&link) {
+            headers.insert(LINK, value);
+        }
+    }
+}
+
+fn page_url(uri: &Uri, replacements: &[(&str, String)]) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    for (key, value) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        if key != "page" && key != "cursor" {
+            query.append_pair(&key, &value);
+        }
+    }
+    for (key, value) in replacements {
+        query.append_pair(key, value);
+    }
+    format!("{}?{}", uri.path(), query.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(query: &str) -> Result<PageRequest, Error> {
+        PageRequest::from_uri(&format!("/items?{query}").parse::<Uri>().unwrap())
+    }
+
+    #[test]
+    fn out_of_range_pages_are_bad_requests() {
+        let e = page(&format!("page={}&page_size=1000", u64::MAX)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            pfcore::errors::status_for(e.kind()),
+            http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            page(&format!("page={}&page_size=1", u64::MAX))
+                .unwrap()
+                .offset(),
+            u64::MAX - 1
+        );
+    }
+
+    #[test]
+    fn pages_are_clamped_and_sliced() {
+        let request = page("page=0&page_size=5000").unwrap();
+        assert_eq!((request.page, request.page_size), (1, MAX_PAGE_SIZE));
+        let request = page("page=3&page_size=2").unwrap();
+        assert_eq!(request.offset(), 4);
+        let paginated = Paginated::from_all((0..7).collect(), &request);
+        assert_eq!(paginated.items, vec![4, 5]);
+        assert_eq!(paginated.total_pages, 4);
+    }
+}
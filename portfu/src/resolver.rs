@@ -0,0 +1,274 @@
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::select;
+
+pub static DEFAULT_RESOLVER: Lazy<Resolver> = Lazy::new(Resolver::default);
+
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    /// How long a successful lookup is reused
+    pub ttl: Duration,
+    /// How long a failed lookup is remembered before retrying
+    pub negative_ttl: Duration,
+    /// How long past its ttl an entry may still be used when a refresh fails
+    pub max_stale: Duration,
+    /// Delay before racing the next address, RFC 8305 recommends 250ms
+    pub attempt_delay: Duration,
+    pub connect_timeout: Duration,
+}
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(30),
+            negative_ttl: Duration::from_secs(2),
+            max_stale: Duration::from_secs(300),
+            attempt_delay: Duration::from_millis(250),
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResolverStats {
+    pub lookups: u64,
+    pub failures: u64,
+    pub stale_hits: u64,
+    pub average_lookup: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    fetched: Instant,
+}
+
+/// Caching resolver with happy eyeballs connection racing for outbound requests.
+/// Lookups go through the system resolver, which does not expose record ttls, so entries use the configured ttl.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    config: ResolverConfig,
+    overrides: RwLock<HashMap<String, Vec<IpAddr>>>,
+    cache: RwLock<HashMap<String, CacheEntry>>,
+    failed: RwLock<HashMap<String, Instant>>,
+    lookups: AtomicU64,
+    failures: AtomicU64,
+    stale_hits: AtomicU64,
+    total_lookup_micros: AtomicU64,
+}
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+    /// Always resolve `host` to `addrs`, for split horizon setups
+    pub fn set_override<S: AsRef<str>>(&self, host: S, addrs: Vec<IpAddr>) {
+        if let Ok(mut overrides) = self.overrides.write() {
+            overrides.insert(host.as_ref().to_ascii_lowercase(), addrs);
+        }
+    }
+    pub fn remove_override(&self, host: &str) {
+        if let Ok(mut overrides) = self.overrides.write() {
+            overrides.remove(&host.to_ascii_lowercase());
+        }
+    }
+    pub fn stats(&self) -> ResolverStats {
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        ResolverStats {
+            lookups,
+            failures: self.failures.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            average_lookup: Duration::from_micros(
+                self.total_lookup_micros
+                    .load(Ordering::Relaxed)
+                    .checked_div(lookups)
+                    .unwrap_or(0),
+            ),
+        }
+    }
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+            return Ok(vec![ip]);
+        }
+        let host = host.to_ascii_lowercase();
+        if let Some(addrs) = self
+            .overrides
+            .read()
+            .ok()
+            .and_then(|o| o.get(&host).cloned())
+        {
+            return Ok(addrs);
+        }
+        let cached = self.cache.read().ok().and_then(|c| c.get(&host).cloned());
+        if let Some(entry) = &cached {
+            if entry.fetched.elapsed() < self.config.ttl {
+                return Ok(entry.addrs.clone());
+            }
+        }
+        let recently_failed = self
+            .failed
+            .read()
+            .ok()
+            .and_then(|f| f.get(&host).copied())
+            .map(|at| at.elapsed() < self.config.negative_ttl)
+            .unwrap_or_default();
+        let result = if recently_failed {
+            Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Recent lookup for {host} failed"),
+            ))
+        } else {
+            self.lookup(&host).await
+        };
+        match result {
+            Ok(addrs) => Ok(addrs),
+            Err(e) => match cached {
+                Some(entry)
+                    if entry.fetched.elapsed() < self.config.ttl + self.config.max_stale =>
+                {
+                    warn!("Using stale addresses for {host} after lookup failure: {e:?}");
+                    self.stale_hits.fetch_add(1, Ordering::Relaxed);
+                    Ok(entry.addrs)
+                }
+                _ => Err(e),
+            },
+        }
+    }
+    async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let start = Instant::now();
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let result = lookup_host((host, 0)).await.map(|addrs| {
+            let mut ips: Vec<IpAddr> = vec![];
+            for addr in addrs {
+                if !ips.contains(&addr.ip()) {
+                    ips.push(addr.ip());
+                }
+            }
+            ips
+        });
+        self.total_lookup_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        match result {
+            Ok(addrs) if !addrs.is_empty() => {
+                if let Ok(mut cache) = self.cache.write() {
+                    cache.insert(
+                        host.to_string(),
+                        CacheEntry {
+                            addrs: addrs.clone(),
+                            fetched: Instant::now(),
+                        },
+                    );
+                }
+                if let Ok(mut failed) = self.failed.write() {
+                    failed.remove(host);
+                }
+                Ok(addrs)
+            }
+            other => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                if let Ok(mut failed) = self.failed.write() {
+                    failed.insert(host.to_string(), Instant::now());
+                }
+                match other {
+                    Err(e) => Err(e),
+                    Ok(_) => Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("No addresses found for {host}"),
+                    )),
+                }
+            }
+        }
+    }
+    /// Resolves `host` and races connections across the results, alternating address families
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let addrs = self.resolve(host).await?;
+        let addrs = interleave_families(addrs)
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        connect_happy_eyeballs(
+            addrs,
+            self.config.attempt_delay,
+            self.config.connect_timeout,
+        )
+        .await
+    }
+}
+
+fn interleave_families(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let prefer_v6 = addrs.first().map(IpAddr::is_ipv6).unwrap_or_default();
+    let (mut first, mut second): (VecDeque<IpAddr>, VecDeque<IpAddr>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == prefer_v6);
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => break,
+            (a, b) => {
+                ordered.extend(a);
+                ordered.extend(b);
+            }
+        }
+    }
+    ordered
+}
+
+async fn connect_one(addr: SocketAddr, timeout: Duration) -> Result<TcpStream, Error> {
+    match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("Connection to {addr} timed out"),
+        )),
+    }
+}
+
+/// Starts a connection attempt per address, each `attempt_delay` after the previous or as soon as one fails,
+/// returning the first to succeed.
+pub async fn connect_happy_eyeballs(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+    timeout: Duration,
+) -> Result<TcpStream, Error> {
+    let mut pending: VecDeque<SocketAddr> = addrs.into();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.pop_front() {
+                Some(addr) => attempts.push(connect_one(addr, timeout)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        Error::new(ErrorKind::NotFound, "No addresses to connect to")
+                    }))
+                }
+            }
+        }
+        select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Connection attempt failed: {e:?}");
+                    last_error = Some(e);
+                    if let Some(addr) = pending.pop_front() {
+                        attempts.push(connect_one(addr, timeout));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(attempt_delay), if !pending.is_empty() => {
+                if let Some(addr) = pending.pop_front() {
+                    attempts.push(connect_one(addr, timeout));
+                }
+            }
+        }
+    }
+}
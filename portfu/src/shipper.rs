@@ -0,0 +1,696 @@
+use async_trait::async_trait;
+use http::Extensions;
+use log::{info, warn, Record};
+use pfcore::task::{ShutdownSignal, TaskFn};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+use uuid::Uuid;
+
+const SPILL_EXTENSION: &str = "logbatch";
+/// Target of the shipper's own records, those are never shipped so a failing collector
+/// does not fill the queue with reports about itself
+pub const SHIPPER_TARGET: &str = module_path!();
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShippedRecord {
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baggage: Option<String>,
+}
+impl ShippedRecord {
+    pub fn from_record(record: &Record, baggage: Option<String>) -> Self {
+        Self {
+            timestamp: unix_millis(),
+            level: record.level().to_string().to_lowercase(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            baggage,
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShipFormat {
+    /// One JSON record per line
+    Ndjson,
+    /// Loki push API, every batch is sent as one stream with these labels
+    Loki(HashMap<String, String>),
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShipperStats {
+    pub queued: u64,
+    pub shipped_batches: u64,
+    pub shipped_records: u64,
+    pub dropped: u64,
+    pub spilled_batches: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    shipped_batches: AtomicU64,
+    shipped_records: AtomicU64,
+    dropped: AtomicU64,
+    spilled_batches: AtomicU64,
+}
+
+struct ShipperInner {
+    endpoint: String,
+    format: ShipFormat,
+    batch_size: usize,
+    flush_interval: Duration,
+    spill_dir: Option<PathBuf>,
+    spill_cap_bytes: u64,
+    retry_base: Duration,
+    retry_max: Duration,
+    shutdown_deadline: Duration,
+    client: reqwest::Client,
+    receiver: Mutex<mpsc::Receiver<ShippedRecord>>,
+    counters: Counters,
+    stop: watch::Sender<Option<Instant>>,
+    stopped: Notify,
+}
+
+/// Ships log records to an HTTP collector from a background task, register it with
+/// `ServerBuilder::task` and `LogControl::ship_to`. Records are queued without blocking the
+/// caller and dropped with a counter when the queue is full. Batches the collector cannot take
+/// are spilled to `spill_dir` and sent oldest first once it recovers, the oldest spilled batches
+/// are dropped when the directory passes `spill_cap_bytes`.
+#[derive(Clone)]
+pub struct LogShipper {
+    sender: mpsc::Sender<ShippedRecord>,
+    inner: Arc<ShipperInner>,
+}
+pub struct LogShipperBuilder {
+    endpoint: String,
+    format: ShipFormat,
+    batch_size: usize,
+    flush_interval: Duration,
+    queue_capacity: usize,
+    spill_dir: Option<PathBuf>,
+    spill_cap_bytes: u64,
+    retry_base: Duration,
+    retry_max: Duration,
+    shutdown_deadline: Duration,
+}
+impl LogShipperBuilder {
+    pub fn new<S: AsRef<str>>(endpoint: S) -> Self {
+        Self {
+            endpoint: endpoint.as_ref().to_string(),
+            format: ShipFormat::Ndjson,
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+            queue_capacity: 10_000,
+            spill_dir: None,
+            spill_cap_bytes: 64 * 1024 * 1024,
+            retry_base: Duration::from_millis(500),
+            retry_max: Duration::from_secs(30),
+            shutdown_deadline: Duration::from_secs(5),
+        }
+    }
+    pub fn format(self, format: ShipFormat) -> Self {
+        let mut s = self;
+        s.format = format;
+        s
+    }
+    pub fn batch_size(self, batch_size: usize) -> Self {
+        let mut s = self;
+        s.batch_size = batch_size.max(1);
+        s
+    }
+    pub fn flush_interval(self, flush_interval: Duration) -> Self {
+        let mut s = self;
+        s.flush_interval = flush_interval;
+        s
+    }
+    pub fn queue_capacity(self, queue_capacity: usize) -> Self {
+        let mut s = self;
+        s.queue_capacity = queue_capacity.max(1);
+        s
+    }
+    /// Directory for batches the collector could not take, without one they are dropped
+    pub fn spill_dir<P: Into<PathBuf>>(self, spill_dir: P) -> Self {
+        let mut s = self;
+        s.spill_dir = Some(spill_dir.into());
+        s
+    }
+    pub fn spill_cap_bytes(self, spill_cap_bytes: u64) -> Self {
+        let mut s = self;
+        s.spill_cap_bytes = spill_cap_bytes;
+        s
+    }
+    /// Backoff after a failed delivery, doubling from `base` up to `max`
+    pub fn retry(self, base: Duration, max: Duration) -> Self {
+        let mut s = self;
+        s.retry_base = base;
+        s.retry_max = max;
+        s
+    }
+    /// How long the flush on the server's ShutdownSignal may take before the rest is spilled
+    pub fn shutdown_deadline(self, shutdown_deadline: Duration) -> Self {
+        let mut s = self;
+        s.shutdown_deadline = shutdown_deadline;
+        s
+    }
+    pub fn build(self) -> LogShipper {
+        let (sender, receiver) = mpsc::channel(self.queue_capacity);
+        let (stop, _) = watch::channel(None);
+        LogShipper {
+            sender,
+            inner: Arc::new(ShipperInner {
+                endpoint: self.endpoint,
+                format: self.format,
+                batch_size: self.batch_size,
+                flush_interval: self.flush_interval,
+                spill_dir: self.spill_dir,
+                spill_cap_bytes: self.spill_cap_bytes,
+                retry_base: self.retry_base,
+                retry_max: self.retry_max,
+                shutdown_deadline: self.shutdown_deadline,
+                client: reqwest::Client::new(),
+                receiver: Mutex::new(receiver),
+                counters: Counters::default(),
+                stop,
+                stopped: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl LogShipper {
+    pub fn builder<S: AsRef<str>>(endpoint: S) -> LogShipperBuilder {
+        LogShipperBuilder::new(endpoint)
+    }
+    /// Queues `record`, never waits. Records logged by the shipper itself are skipped.
+    pub fn ship(&self, record: ShippedRecord) {
+        if record.target.starts_with(SHIPPER_TARGET) {
+            return;
+        }
+        if self.sender.try_send(record).is_err() {
+            self.inner.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub fn stats(&self) -> ShipperStats {
+        let counters = &self.inner.counters;
+        ShipperStats {
+            queued: (self.sender.max_capacity() - self.sender.capacity()) as u64,
+            shipped_batches: counters.shipped_batches.load(Ordering::Relaxed),
+            shipped_records: counters.shipped_records.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            spilled_batches: counters.spilled_batches.load(Ordering::Relaxed),
+        }
+    }
+    /// Asks the running task to send what is queued and returns once it did or `deadline` passed,
+    /// whatever is left is spilled to disk
+    pub async fn shutdown(&self, deadline: Duration) {
+        let stopped = self.inner.stopped.notified();
+        self.inner
+            .stop
+            .send_replace(Some(Instant::now() + deadline));
+        let _ = tokio::time::timeout(deadline + Duration::from_secs(1), stopped).await;
+    }
+}
+
+impl ShipperInner {
+    fn payload(&self, records: &[ShippedRecord]) -> Vec<u8> {
+        match &self.format {
+            ShipFormat::Ndjson => {
+                let mut payload = vec![];
+                for record in records {
+                    if serde_json::to_writer(&mut payload, record).is_ok() {
+                        payload.push(b'\n');
+                    }
+                }
+                payload
+            }
+            ShipFormat::Loki(labels) => {
+                let values: Vec<[String; 2]> = records
+                    .iter()
+                    .map(|record| {
+                        [
+                            (u128::from(record.timestamp) * 1_000_000).to_string(),
+                            serde_json::to_string(record).unwrap_or_default(),
+                        ]
+                    })
+                    .collect();
+                serde_json::to_vec(&json!({
+                    "streams": [{"stream": labels, "values": values}]
+                }))
+                .unwrap_or_default()
+            }
+        }
+    }
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            ShipFormat::Ndjson => "application/x-ndjson",
+            ShipFormat::Loki(_) => "application/json",
+        }
+    }
+    async fn deliver(&self, payload: Vec<u8>) -> Result<(), Error> {
+        self.deliver_within(payload, None).await
+    }
+    /// A request cut off by `timeout` may still have reached the collector, it is reported as failed
+    async fn deliver_within(
+        &self,
+        payload: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        let mut request = self
+            .client
+            .post(&self.endpoint)
+            .header(reqwest::header::CONTENT_TYPE, self.content_type())
+            .body(payload);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!(
+                "Collector answered {}",
+                response.status()
+            )))
+        }
+    }
+    /// Spilled batches oldest first with their size and record count
+    async fn spilled(&self) -> Vec<(PathBuf, u64, u64)> {
+        let dir = match &self.spill_dir {
+            Some(dir) => dir,
+            None => return vec![],
+        };
+        let mut batches = vec![];
+        if let Ok(mut entries) = tokio::fs::read_dir(dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if path.extension().and_then(|e| e.to_str()) != Some(SPILL_EXTENSION) {
+                    continue;
+                }
+                let records = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.split('-').nth(1))
+                    .and_then(|c| c.parse().ok())
+                    .unwrap_or_default();
+                let size = entry.metadata().await.map(|m| m.len()).unwrap_or_default();
+                batches.push((path, size, records));
+            }
+        }
+        batches.sort();
+        batches
+    }
+    async fn spill(&self, payload: Vec<u8>, records: u64) {
+        let dir = match &self.spill_dir {
+            Some(dir) => dir,
+            None => {
+                self.counters.dropped.fetch_add(records, Ordering::Relaxed);
+                return;
+            }
+        };
+        let mut spilled = self.spilled().await;
+        let mut total: u64 = spilled.iter().map(|(_, size, _)| size).sum();
+        let size = payload.len() as u64;
+        while total + size > self.spill_cap_bytes && !spilled.is_empty() {
+            let (path, old_size, old_records) = spilled.remove(0);
+            if tokio::fs::remove_file(&path).await.is_ok() {
+                total -= old_size;
+                self.counters
+                    .dropped
+                    .fetch_add(old_records, Ordering::Relaxed);
+            }
+        }
+        if total + size > self.spill_cap_bytes {
+            self.counters.dropped.fetch_add(records, Ordering::Relaxed);
+            return;
+        }
+        //Zero padded so the names sort oldest first
+        let name = format!("{:020}-{records}-{}", unix_millis(), Uuid::new_v4());
+        let path = dir.join(format!("{name}.{SPILL_EXTENSION}"));
+        let temp = dir.join(format!("{name}.tmp"));
+        let written = async {
+            tokio::fs::create_dir_all(dir).await?;
+            tokio::fs::write(&temp, &payload).await?;
+            tokio::fs::rename(&temp, &path).await
+        }
+        .await;
+        match written {
+            Ok(()) => {
+                self.counters
+                    .spilled_batches
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!("Failed to spill log batch: {e:?}");
+                let _ = tokio::fs::remove_file(&temp).await;
+                self.counters.dropped.fetch_add(records, Ordering::Relaxed);
+            }
+        }
+    }
+    /// Sends spilled batches until one fails, true once none are left
+    async fn drain_spilled(&self) -> bool {
+        for (path, _, records) in self.spilled().await {
+            let payload = match tokio::fs::read(&path).await {
+                Ok(payload) => payload,
+                Err(_) => continue,
+            };
+            if self.deliver(payload).await.is_err() {
+                return false;
+            }
+            let _ = tokio::fs::remove_file(&path).await;
+            self.counters
+                .shipped_batches
+                .fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .shipped_records
+                .fetch_add(records, Ordering::Relaxed);
+        }
+        true
+    }
+    async fn ship(&self, batch: Vec<ShippedRecord>) -> bool {
+        let records = batch.len() as u64;
+        let payload = self.payload(&batch);
+        if self.drain_spilled().await && self.deliver(payload.clone()).await.is_ok() {
+            self.counters
+                .shipped_batches
+                .fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .shipped_records
+                .fetch_add(records, Ordering::Relaxed);
+            return true;
+        }
+        self.spill(payload, records).await;
+        false
+    }
+}
+
+#[async_trait]
+impl TaskFn for LogShipper {
+    fn name(&self) -> &str {
+        "log_shipper"
+    }
+    async fn run(&self, state: Arc<Extensions>) -> Result<(), Error> {
+        let inner = &self.inner;
+        let shutdown = state.get::<ShutdownSignal>().cloned();
+        let mut receiver = inner.receiver.lock().await;
+        let mut stop = inner.stop.subscribe();
+        let mut failures: u32 = 0;
+        let mut retry_at: Option<Instant> = None;
+        loop {
+            let mut batch = Vec::with_capacity(inner.batch_size);
+            let flush_at = tokio::time::Instant::now() + inner.flush_interval;
+            while batch.len() < inner.batch_size {
+                tokio::select! {
+                    record = receiver.recv() => match record {
+                        Some(record) => batch.push(record),
+                        None => break,
+                    },
+                    _ = tokio::time::sleep_until(flush_at) => break,
+                    //Also returns when shutdown was asked before the task started
+                    _ = stop.wait_for(Option::is_some) => break,
+                    //The server shutting down flushes like a shutdown call
+                    _ = async {
+                        match &shutdown {
+                            Some(shutdown) => shutdown.wait().await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        inner.stop.send_modify(|stop| {
+                            stop.get_or_insert(Instant::now() + inner.shutdown_deadline);
+                        });
+                        break;
+                    }
+                }
+            }
+            let deadline = *stop.borrow();
+            if let Some(deadline) = deadline {
+                while let Ok(record) = receiver.try_recv() {
+                    batch.push(record);
+                }
+                //Spilled batches stay on disk for the next start, each chunk is either delivered or
+                //spilled once so nothing is cut off halfway and written twice
+                for chunk in batch.chunks(inner.batch_size) {
+                    let payload = inner.payload(chunk);
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    let records = chunk.len() as u64;
+                    if !remaining.is_zero()
+                        && inner
+                            .deliver_within(payload.clone(), Some(remaining))
+                            .await
+                            .is_ok()
+                    {
+                        inner
+                            .counters
+                            .shipped_batches
+                            .fetch_add(1, Ordering::Relaxed);
+                        inner
+                            .counters
+                            .shipped_records
+                            .fetch_add(records, Ordering::Relaxed);
+                    } else {
+                        inner.spill(payload, records).await;
+                    }
+                }
+                let stats = self.stats();
+                info!(
+                    "Log shipper stopped: {} records in {} batches shipped, {} dropped, {} batches spilled",
+                    stats.shipped_records, stats.shipped_batches, stats.dropped, stats.spilled_batches
+                );
+                inner.stopped.notify_waiters();
+                return Ok(());
+            }
+            //While backing off, batches go straight to disk instead of retrying the collector
+            if retry_at.map(|at| Instant::now() < at).unwrap_or_default() {
+                if !batch.is_empty() {
+                    inner.spill(inner.payload(&batch), batch.len() as u64).await;
+                }
+                continue;
+            }
+            let delivered = if batch.is_empty() {
+                inner.drain_spilled().await
+            } else {
+                inner.ship(batch).await
+            };
+            if delivered {
+                failures = 0;
+                retry_at = None;
+            } else {
+                let backoff = inner
+                    .retry_base
+                    .saturating_mul(2u32.saturating_pow(failures.min(16)))
+                    .min(inner.retry_max);
+                failures += 1;
+                retry_at = Some(Instant::now() + backoff);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::start;
+    use pfcore::server::ServerBuilder;
+    use pfcore::service::ServiceBuilder;
+    use pfcore::{FromBody, ServiceData, ServiceHandler};
+    use std::sync::atomic::AtomicBool;
+
+    /// Records every delivered body, answers 503 while `failing` is set
+    #[derive(Default)]
+    struct Collector {
+        bodies: std::sync::Mutex<Vec<String>>,
+        failing: AtomicBool,
+    }
+    impl Collector {
+        fn lines(&self) -> Vec<String> {
+            self.bodies
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|body| body.lines().map(str::to_string).collect::<Vec<_>>())
+                .collect()
+        }
+    }
+    #[async_trait]
+    impl ServiceHandler for Collector {
+        fn name(&self) -> &str {
+            "collector"
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            if self.failing.load(Ordering::SeqCst) {
+                *data.response.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+                return Ok(data);
+            }
+            match String::from_body(&mut data.request.request.body()).await {
+                Ok(body) => self.bodies.lock().unwrap().push(body),
+                Err(e) => return Err((data, e)),
+            }
+            Ok(data)
+        }
+    }
+
+    async fn collector() -> (Arc<Collector>, String) {
+        let collector = Arc::new(Collector::default());
+        let url = start(
+            ServerBuilder::default().register(
+                ServiceBuilder::new("/collect")
+                    .name("collect")
+                    .handler(collector.clone())
+                    .build(),
+            ),
+        )
+        .await;
+        (collector, format!("{url}/collect"))
+    }
+
+    fn record(target: &str, message: &str) -> ShippedRecord {
+        ShippedRecord {
+            timestamp: 0,
+            level: "info".to_string(),
+            target: target.to_string(),
+            message: message.to_string(),
+            baggage: None,
+        }
+    }
+
+    fn messages(lines: &[String]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                value["message"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ships_each_record_once() {
+        let (collector, endpoint) = collector().await;
+        let shipper = LogShipper::builder(endpoint)
+            .batch_size(2)
+            .flush_interval(Duration::from_millis(20))
+            .build();
+        let task = tokio::spawn({
+            let shipper = shipper.clone();
+            async move { shipper.run(Arc::new(Extensions::new())).await }
+        });
+        shipper.ship(record("app", "one"));
+        shipper.ship(record("app", "two"));
+        shipper.ship(record(SHIPPER_TARGET, "about itself"));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        shipper.ship(record("app", "three"));
+        shipper.shutdown(Duration::from_secs(5)).await;
+        task.await.unwrap().unwrap();
+        assert_eq!(messages(&collector.lines()), vec!["one", "two", "three"]);
+        let stats = shipper.stats();
+        assert_eq!(stats.shipped_records, 3);
+        assert_eq!(stats.dropped, 0);
+        assert_eq!(stats.spilled_batches, 0);
+    }
+
+    #[tokio::test]
+    async fn spilled_batches_are_sent_after_recovery() {
+        let (collector, endpoint) = collector().await;
+        let spill_dir = std::env::temp_dir().join(format!("portfu-shipper-{}", Uuid::new_v4()));
+        collector.failing.store(true, Ordering::SeqCst);
+        let shipper = LogShipper::builder(endpoint)
+            .flush_interval(Duration::from_millis(20))
+            .spill_dir(&spill_dir)
+            .retry(Duration::from_millis(10), Duration::from_millis(10))
+            .build();
+        let task = tokio::spawn({
+            let shipper = shipper.clone();
+            async move { shipper.run(Arc::new(Extensions::new())).await }
+        });
+        shipper.ship(record("app", "while down"));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(collector.lines().is_empty());
+        assert_eq!(shipper.stats().spilled_batches, 1);
+        collector.failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        shipper.ship(record("app", "after"));
+        shipper.shutdown(Duration::from_secs(5)).await;
+        task.await.unwrap().unwrap();
+        assert_eq!(messages(&collector.lines()), vec!["while down", "after"]);
+        assert_eq!(shipper.stats().shipped_records, 2);
+        let _ = std::fs::remove_dir_all(spill_dir);
+    }
+
+    #[tokio::test]
+    async fn shutdown_spills_what_the_collector_refuses() {
+        let (collector, endpoint) = collector().await;
+        let spill_dir = std::env::temp_dir().join(format!("portfu-shipper-{}", Uuid::new_v4()));
+        collector.failing.store(true, Ordering::SeqCst);
+        let shipper = LogShipper::builder(endpoint)
+            .flush_interval(Duration::from_secs(60))
+            .spill_dir(&spill_dir)
+            .build();
+        let task = tokio::spawn({
+            let shipper = shipper.clone();
+            async move { shipper.run(Arc::new(Extensions::new())).await }
+        });
+        shipper.ship(record("app", "last words"));
+        shipper.shutdown(Duration::from_secs(5)).await;
+        task.await.unwrap().unwrap();
+        assert!(collector.lines().is_empty());
+        let stats = shipper.stats();
+        assert_eq!((stats.shipped_records, stats.spilled_batches), (0, 1));
+        let _ = std::fs::remove_dir_all(spill_dir);
+    }
+
+    #[tokio::test]
+    async fn server_shutdown_flushes_without_waiting_for_the_timeout() {
+        let (collector, endpoint) = collector().await;
+        let shipper = LogShipper::builder(endpoint)
+            .flush_interval(Duration::from_secs(60))
+            .build();
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let server = ServerBuilder::default()
+            .host(address.ip().to_string())
+            .port(address.port())
+            .task(pfcore::task::Task::new(
+                "log_shipper",
+                Arc::new(shipper.clone()),
+            ))
+            .build();
+        let run = server.run.clone();
+        let serving = tokio::spawn(server.run());
+        shipper.ship(record("app", "before shutdown"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(collector.lines().is_empty());
+
+        let stopping = Instant::now();
+        run.store(false, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(10), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        //The default shutdown_timeout is 30s, the shipper returns as soon as it flushed
+        assert!(stopping.elapsed() < Duration::from_secs(5));
+        assert_eq!(messages(&collector.lines()), vec!["before shutdown"]);
+        assert_eq!(shipper.stats().shipped_records, 1);
+    }
+}
@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use pfcore::server::ServerBuilder;
+use pfcore::{IntoStreamBody, ServiceData, ServiceHandler};
+use std::io::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Runs the server on a free local port until the test ends and returns its base url
+pub(crate) async fn start(builder: ServerBuilder) -> String {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = builder
+        .host(address.ip().to_string())
+        .port(address.port())
+        .build();
+    tokio::spawn(server.run());
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    format!("http://{address}")
+}
+
+/// Answers with its body after `delay`, counting every call
+#[derive(Default)]
+pub(crate) struct Counting {
+    pub calls: AtomicUsize,
+    pub delay: Duration,
+    pub body: &'static str,
+}
+impl Counting {
+    pub fn new(body: &'static str, delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            calls: AtomicUsize::new(0),
+            delay,
+            body,
+        })
+    }
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+#[async_trait]
+impl ServiceHandler for Counting {
+    fn name(&self) -> &str {
+        "counting"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(self.delay).await;
+        *data.response.body_mut() = self.body.stream_body();
+        Ok(data)
+    }
+}
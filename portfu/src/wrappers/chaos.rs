@@ -0,0 +1,263 @@
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use http::header::CONTENT_LENGTH;
+use http::StatusCode;
+use http_body_util::BodyExt;
+use hyper::body::Bytes;
+use log::warn;
+use once_cell::sync::Lazy;
+use pfcore::clock::clock;
+use pfcore::profile::{profile, Profile, FORCE_DEV_ONLY_ENV};
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::{IntoStreamBody, ServiceData};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Faults are only injected when this environment variable is set to `1` or `true`, never in the prod profile
+pub const CHAOS_ENV: &str = "PORTFU_CHAOS";
+
+static CHAOS_REQUESTED: Lazy<bool> = Lazy::new(|| {
+    std::env::var(CHAOS_ENV)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or_default()
+});
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+fn allowed(requested: bool, profile: &Profile) -> bool {
+    if requested && !profile.allows_dev_only() {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Chaos is disabled in the {profile} profile, set {FORCE_DEV_ONLY_ENV} to allow it"
+            );
+        }
+        return false;
+    }
+    requested
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Fault {
+    /// Delays the request by `millis` plus up to `jitter_millis`
+    Latency { millis: u64, jitter_millis: u64 },
+    /// Responds with `status` without running the service
+    Status { status: u16 },
+    /// Cuts the response body down to `bytes`
+    Truncate { bytes: usize },
+    /// Never responds
+    Blackhole,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChaosRule {
+    /// Requests whose path starts with this prefix are affected
+    pub path_prefix: String,
+    /// Chance between 0 and 1 that a matching request is affected
+    pub probability: f64,
+    pub fault: Fault,
+    /// The rule expires after affecting this many requests
+    pub max_requests: Option<u64>,
+    /// The rule expires this long after it was set
+    pub max_seconds: Option<u64>,
+}
+
+struct ActiveRule {
+    rule: ChaosRule,
+    hits: AtomicU64,
+    expires_at: Option<Instant>,
+}
+impl ActiveRule {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.map(|e| now < e).unwrap_or(true)
+            && self
+                .rule
+                .max_requests
+                .map(|max| self.hits.load(Ordering::Relaxed) < max)
+                .unwrap_or(true)
+    }
+    /// Claims one use of the request budget, false when it is already spent
+    fn claim(&self) -> bool {
+        match self.rule.max_requests {
+            Some(max) => self
+                .hits
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |hits| {
+                    (hits < max).then_some(hits + 1)
+                })
+                .is_ok(),
+            None => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+}
+
+/// Runtime controlled fault rules, clones share the same rules.
+/// Does nothing unless CHAOS_ENV is set, regardless of the rules configured.
+#[derive(Clone, Default)]
+pub struct ChaosControl {
+    rules: Arc<ArcSwap<Vec<Arc<ActiveRule>>>>,
+}
+impl ChaosControl {
+    /// True when CHAOS_ENV is set and `profile` allows dev only components
+    pub fn is_enabled(&self, profile: &Profile) -> bool {
+        allowed(*CHAOS_REQUESTED, profile)
+    }
+    /// Replaces all rules, budgets start over
+    pub fn set_rules(&self, rules: Vec<ChaosRule>) {
+        self.set_rules_at(rules, Instant::now())
+    }
+    /// set_rules with max_seconds counted from `now`
+    pub fn set_rules_at(&self, rules: Vec<ChaosRule>, now: Instant) {
+        self.rules.store(Arc::new(
+            rules
+                .into_iter()
+                .map(|rule| {
+                    Arc::new(ActiveRule {
+                        expires_at: rule.max_seconds.map(|s| now + Duration::from_secs(s)),
+                        hits: AtomicU64::new(0),
+                        rule,
+                    })
+                })
+                .collect(),
+        ));
+    }
+    /// Rules that have not expired yet
+    pub fn rules(&self) -> Vec<ChaosRule> {
+        self.rules_at(Instant::now())
+    }
+    /// Rules that have not expired by `now`
+    pub fn rules_at(&self, now: Instant) -> Vec<ChaosRule> {
+        self.rules
+            .load()
+            .iter()
+            .filter(|r| r.is_live(now))
+            .map(|r| r.rule.clone())
+            .collect()
+    }
+    pub fn clear(&self) {
+        self.rules.store(Arc::new(vec![]));
+    }
+    fn select(&self, path: &str, now: Instant, profile: &Profile) -> Option<Fault> {
+        if !self.is_enabled(profile) {
+            return None;
+        }
+        self.rules
+            .load()
+            .iter()
+            .filter(|r| r.is_live(now) && path.starts_with(&r.rule.path_prefix))
+            .find(|r| random_unit() < r.rule.probability && r.claim())
+            .map(|r| r.rule.fault.clone())
+    }
+}
+
+#[derive(Clone)]
+struct TruncateResponse(usize);
+
+/// Injects the faults configured in its ChaosControl
+pub struct ChaosWrapper {
+    pub control: ChaosControl,
+}
+#[async_trait]
+impl WrapperFn for ChaosWrapper {
+    fn name(&self) -> &str {
+        "ChaosWrapper"
+    }
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        let path = data.request.request.uri().path().to_string();
+        let (now, profile) = (clock(&data.request).now(), profile(&data.request));
+        match self.control.select(&path, now, profile) {
+            None => WrapperResult::Continue,
+            Some(Fault::Latency {
+                millis,
+                jitter_millis,
+            }) => {
+                let jitter = (random_unit() * jitter_millis as f64) as u64;
+                warn!("Chaos: delaying {path} by {}ms", millis + jitter);
+                tokio::time::sleep(Duration::from_millis(millis + jitter)).await;
+                WrapperResult::Continue
+            }
+            Some(Fault::Status { status }) => {
+                warn!("Chaos: responding to {path} with {status}");
+                *data.response.status_mut() =
+                    StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                *data.response.body_mut() = Bytes::from_static(b"Injected Fault").stream_body();
+                WrapperResult::Return
+            }
+            Some(Fault::Truncate { bytes }) => {
+                data.request.insert(TruncateResponse(bytes));
+                WrapperResult::Continue
+            }
+            Some(Fault::Blackhole) => {
+                warn!("Chaos: blackholing {path}");
+                std::future::pending::<()>().await;
+                WrapperResult::Return
+            }
+        }
+    }
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        if let Some(TruncateResponse(bytes)) = data.request.get::<TruncateResponse>().cloned() {
+            let body = std::mem::replace(data.response.body_mut(), Bytes::new().stream_body());
+            let mut collected = match body.collect().await {
+                Ok(collected) => collected.to_bytes(),
+                Err(_) => Bytes::new(),
+            };
+            collected.truncate(bytes);
+            warn!(
+                "Chaos: truncating {} to {bytes} bytes",
+                data.request.request.uri().path()
+            );
+            data.response.headers_mut().remove(CONTENT_LENGTH);
+            *data.response.body_mut() = collected.stream_body();
+        }
+        WrapperResult::Continue
+    }
+}
+
+fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pfcore::clock::{Clock, ManualClock};
+
+    #[test]
+    fn rules_expire_on_the_given_clock() {
+        let clock = ManualClock::default();
+        let control = ChaosControl::default();
+        let rule = ChaosRule {
+            path_prefix: "/".to_string(),
+            probability: 1.0,
+            fault: Fault::Blackhole,
+            max_requests: None,
+            max_seconds: Some(10),
+        };
+        control.set_rules_at(vec![rule.clone()], clock.now());
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(control.rules_at(clock.now()), vec![rule]);
+        clock.advance(Duration::from_secs(1));
+        assert!(control.rules_at(clock.now()).is_empty());
+    }
+
+    #[test]
+    fn the_server_profile_decides() {
+        assert!(allowed(true, &Profile::Dev));
+        assert!(!allowed(false, &Profile::Dev));
+        if !Profile::Prod.allows_dev_only() {
+            assert!(!allowed(true, &Profile::Prod));
+        }
+    }
+}
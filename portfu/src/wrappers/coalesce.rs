@@ -0,0 +1,291 @@
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use http::header::{ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, COOKIE, HOST, SET_COOKIE};
+use http::{HeaderMap, Method, StatusCode};
+use http_body::Frame;
+use http_body_util::{BodyStream, StreamBody};
+use hyper::body::Bytes;
+use log::debug;
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::{BoxedBody, IntoStreamBody, ServiceBody, ServiceData};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+#[derive(Clone)]
+enum Outcome {
+    Response(Arc<SharedResponse>),
+    FallThrough,
+}
+
+struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+type InFlight = Arc<Mutex<HashMap<String, watch::Receiver<Option<Outcome>>>>>;
+
+/// Held by the request doing the work, waiters fall through if it is dropped without a response
+struct Leader {
+    key: String,
+    sender: watch::Sender<Option<Outcome>>,
+    in_flight: InFlight,
+}
+impl Leader {
+    fn finish(&self, outcome: Outcome) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        //A newer leader may have taken the key once this one finished
+        if in_flight
+            .get(&self.key)
+            .map(|receiver| receiver.same_channel(&self.sender.subscribe()))
+            .unwrap_or_default()
+        {
+            in_flight.remove(&self.key);
+        }
+        drop(in_flight);
+        self.sender.send_if_modified(|current| {
+            if current.is_none() {
+                *current = Some(outcome);
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.finish(Outcome::FallThrough);
+    }
+}
+
+#[derive(Clone)]
+struct CoalesceLeader(Arc<Leader>);
+
+/// Runs identical concurrent GET and HEAD requests once and shares the response with every waiter.
+/// Responses that set cookies, are not successful or have bodies over max_body_bytes are not shared,
+/// waiters for those run the service themselves.
+pub struct Coalesce {
+    in_flight: InFlight,
+    max_body_bytes: usize,
+}
+impl Default for Coalesce {
+    fn default() -> Self {
+        Self {
+            in_flight: Default::default(),
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+impl Coalesce {
+    pub fn max_body_bytes(self, max_body_bytes: usize) -> Self {
+        let mut s = self;
+        s.max_body_bytes = max_body_bytes;
+        s
+    }
+}
+
+fn coalesce_key(data: &ServiceData) -> Option<String> {
+    let request = &data.request.request;
+    let method = request.method();
+    if method != Method::GET && method != Method::HEAD {
+        return None;
+    }
+    let headers = request.headers()?;
+    let mut hasher = Sha256::new();
+    //Length prefixed so no two different requests hash the same parts
+    let mut part = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_be_bytes());
+        hasher.update(bytes);
+    };
+    part(method.as_str().as_bytes());
+    part(request.uri().to_string().as_bytes());
+    for header in [HOST, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, COOKIE] {
+        for value in headers.get_all(&header) {
+            part(header.as_str().as_bytes());
+            part(value.as_bytes());
+        }
+    }
+    Some(hex::encode(hasher.finalize()))
+}
+
+#[async_trait]
+impl WrapperFn for Coalesce {
+    fn name(&self) -> &str {
+        "Coalesce"
+    }
+    /// Keys on Authorization and Cookie, without them callers would share responses
+    fn infrastructure(&self) -> bool {
+        true
+    }
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        let key = match coalesce_key(data) {
+            Some(key) => key,
+            None => return WrapperResult::Continue,
+        };
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+            match in_flight.get(&key) {
+                Some(receiver) => Some(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    in_flight.insert(key.clone(), receiver);
+                    data.request.insert(CoalesceLeader(Arc::new(Leader {
+                        key,
+                        sender,
+                        in_flight: self.in_flight.clone(),
+                    })));
+                    None
+                }
+            }
+        };
+        let mut receiver = match waiting {
+            Some(receiver) => receiver,
+            None => return WrapperResult::Continue,
+        };
+        let outcome = match receiver.wait_for(Option::is_some).await {
+            Ok(outcome) => outcome.clone(),
+            Err(_) => None,
+        };
+        match outcome {
+            Some(Outcome::Response(shared)) => {
+                *data.response.status_mut() = shared.status;
+                *data.response.headers_mut() = shared.headers.clone();
+                *data.response.body_mut() = shared.body.clone().stream_body();
+                WrapperResult::Return
+            }
+            _ => {
+                debug!("Coalesced request could not be shared, running it directly");
+                WrapperResult::Continue
+            }
+        }
+    }
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        let leader = match data.request.get::<CoalesceLeader>().cloned() {
+            Some(CoalesceLeader(leader)) => leader,
+            None => return WrapperResult::Continue,
+        };
+        if !data.response.status().is_success() || data.response.headers().contains_key(SET_COOKIE)
+        {
+            leader.finish(Outcome::FallThrough);
+            return WrapperResult::Continue;
+        }
+        let body = std::mem::replace(data.response.body_mut(), Bytes::new().stream_body());
+        match buffer_body(body, self.max_body_bytes).await {
+            Ok(bytes) => {
+                leader.finish(Outcome::Response(Arc::new(SharedResponse {
+                    status: data.response.status(),
+                    headers: data.response.headers().clone(),
+                    body: bytes.clone(),
+                })));
+                *data.response.body_mut() = bytes.stream_body();
+            }
+            Err(body) => {
+                leader.finish(Outcome::FallThrough);
+                *data.response.body_mut() = body;
+            }
+        }
+        WrapperResult::Continue
+    }
+}
+
+/// Reads `body` into memory if it fits in `limit`, otherwise returns an equivalent body
+/// made from what was read so far and the unread remainder.
+pub(crate) async fn buffer_body(body: ServiceBody, limit: usize) -> Result<Bytes, ServiceBody> {
+    let mut remaining = BodyStream::new(body);
+    let mut frames: Vec<Frame<Bytes>> = vec![];
+    let mut size = 0;
+    while let Some(frame) = remaining.next().await {
+        match frame {
+            Ok(frame) => {
+                size += frame.data_ref().map(Bytes::len).unwrap_or_default();
+                frames.push(frame);
+                if size > limit {
+                    let replay = stream::iter(frames.into_iter().map(Ok)).chain(remaining);
+                    let boxed: Pin<BoxedBody> = Box::pin(StreamBody::new(replay));
+                    return Err(StreamBody::new(BodyStream::new(boxed)));
+                }
+            }
+            Err(e) => {
+                let failed = stream::iter(frames.into_iter().map(Ok)).chain(stream::iter([Err(e)]));
+                let boxed: Pin<BoxedBody> = Box::pin(StreamBody::new(failed));
+                return Err(StreamBody::new(BodyStream::new(boxed)));
+            }
+        }
+    }
+    let mut buffer = Vec::with_capacity(size);
+    for frame in frames {
+        if let Ok(data) = frame.into_data() {
+            buffer.extend_from_slice(&data);
+        }
+    }
+    Ok(Bytes::from(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{start, Counting};
+    use pfcore::headers::HeaderPolicy;
+    use pfcore::server::ServerBuilder;
+    use pfcore::service::ServiceBuilder;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn hosts_are_not_coalesced_together() {
+        let handler = Counting::new("shared", Duration::from_millis(200));
+        let url = start(
+            ServerBuilder::default().register(
+                ServiceBuilder::new("/shared")
+                    .name("shared")
+                    .wrap(Arc::new(Coalesce::default()))
+                    .handler(handler.clone())
+                    .build(),
+            ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let get = |host: &'static str| {
+            client
+                .get(format!("{url}/shared"))
+                .header(HOST, host)
+                .send()
+        };
+        let (a, b) = tokio::join!(get("a.example"), get("a.example"));
+        assert_eq!(a.unwrap().text().await.unwrap(), "shared");
+        assert_eq!(b.unwrap().text().await.unwrap(), "shared");
+        assert_eq!(handler.calls(), 1);
+        let (a, b) = tokio::join!(get("a.example"), get("b.example"));
+        assert!(a.unwrap().status().is_success() && b.unwrap().status().is_success());
+        assert_eq!(handler.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn credentials_split_requests_under_the_sensitive_policy() {
+        let handler = Counting::new("private", Duration::from_millis(200));
+        let url = start(
+            ServerBuilder::default().register(
+                ServiceBuilder::new("/private")
+                    .name("private")
+                    .header_policy(HeaderPolicy::sensitive())
+                    .wrap(Arc::new(Coalesce::default()))
+                    .handler(handler.clone())
+                    .build(),
+            ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let get = |token: &'static str| {
+            client
+                .get(format!("{url}/private"))
+                .header(AUTHORIZATION, token)
+                .send()
+        };
+        let (a, b) = tokio::join!(get("Bearer a"), get("Bearer b"));
+        assert!(a.unwrap().status().is_success() && b.unwrap().status().is_success());
+        assert_eq!(handler.calls(), 2);
+    }
+}
@@ -0,0 +1,577 @@
+use crate::wrappers::sessions::Session;
+use async_trait::async_trait;
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use cookie::Cookie;
+use http::{header, Extensions, HeaderValue, StatusCode};
+use log::{error, warn};
+use pfcore::clock::clock;
+use pfcore::errors::HttpError;
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::ServiceData;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub static COOKIE_SESSION_NAME: &str = "portfu_session";
+/// Browsers drop cookies over 4KiB
+pub const DEFAULT_MAX_COOKIE_BYTES: usize = 4096;
+
+type SaveFn = fn(&Extensions) -> Option<Result<Value, serde_json::Error>>;
+type LoadFn = fn(Value, &mut Extensions) -> Result<(), serde_json::Error>;
+
+/// A type stored in the cookie under `name`
+#[derive(Clone)]
+struct Registered {
+    name: String,
+    save: SaveFn,
+    load: LoadFn,
+}
+
+fn save<T: Serialize + Send + Sync + 'static>(
+    extensions: &Extensions,
+) -> Option<Result<Value, serde_json::Error>> {
+    extensions.get::<T>().map(serde_json::to_value)
+}
+
+fn load<T: DeserializeOwned + Clone + Send + Sync + 'static>(
+    value: Value,
+    extensions: &mut Extensions,
+) -> Result<(), serde_json::Error> {
+    extensions.insert(serde_json::from_value::<T>(value)?);
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    issued: u64,
+    values: Map<String, Value>,
+}
+
+/// The values and issue time the session was opened with
+#[derive(Clone)]
+struct Opened(Option<(SystemTime, Map<String, Value>)>);
+
+/// What check_session_size needs to size the cookie outside the wrapper
+#[derive(Clone)]
+struct Layout {
+    registered: Arc<Vec<Registered>>,
+    max_cookie_bytes: usize,
+}
+
+/// Keeps the session in an AES-256-GCM encrypted cookie instead of server memory.
+/// Handlers get the same `Arc<Session>` as with SessionWrapper, only registered types are kept
+/// and changes are made through `Arc::get_mut` on the request's session.
+/// The first key encrypts, every key is tried when decrypting so old keys can be rotated out.
+/// Sessions expire `session_duration` after the last request, the cookie is re-issued once it is
+/// older than `refresh_after` and removed when every value is gone.
+pub struct CookieSessionWrapper {
+    keys: Vec<LessSafeKey>,
+    registered: Arc<Vec<Registered>>,
+    pub session_duration: Duration,
+    pub refresh_after: Duration,
+    pub max_cookie_bytes: usize,
+    random: SystemRandom,
+}
+impl CookieSessionWrapper {
+    pub fn new(keys: Vec<[u8; 32]>) -> Result<Self, Error> {
+        if keys.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "CookieSessionWrapper needs at least one key",
+            ));
+        }
+        let keys = keys
+            .iter()
+            .map(|key| {
+                UnboundKey::new(&AES_256_GCM, key)
+                    .map(LessSafeKey::new)
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid session key"))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Self {
+            keys,
+            registered: Arc::new(vec![]),
+            session_duration: Duration::from_secs(60 * 30), //30 minutes
+            refresh_after: Duration::from_secs(60),
+            max_cookie_bytes: DEFAULT_MAX_COOKIE_BYTES,
+            random: SystemRandom::new(),
+        })
+    }
+    /// Keys as url safe base64 without padding, the first encrypts
+    pub fn from_base64<S: AsRef<str>>(keys: &[S]) -> Result<Self, Error> {
+        let keys = keys
+            .iter()
+            .map(|key| {
+                URL_SAFE_NO_PAD
+                    .decode(key.as_ref())
+                    .ok()
+                    .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                    .ok_or(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Session keys must be 32 bytes of base64",
+                    ))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Self::new(keys)
+    }
+    /// Stores `T` from the session data in the cookie under `name`
+    pub fn register<T, S>(self, name: S) -> Self
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+        S: AsRef<str>,
+    {
+        let mut s = self;
+        Arc::make_mut(&mut s.registered).push(Registered {
+            name: name.as_ref().to_string(),
+            save: save::<T>,
+            load: load::<T>,
+        });
+        s
+    }
+    pub fn session_duration(self, session_duration: Duration) -> Self {
+        let mut s = self;
+        s.session_duration = session_duration;
+        s
+    }
+    /// How old the cookie gets before a request re-issues it to slide the expiry
+    pub fn refresh_after(self, refresh_after: Duration) -> Self {
+        let mut s = self;
+        s.refresh_after = refresh_after;
+        s
+    }
+    pub fn max_cookie_bytes(self, max_cookie_bytes: usize) -> Self {
+        let mut s = self;
+        s.max_cookie_bytes = max_cookie_bytes;
+        s
+    }
+    fn encrypt(&self, payload: &Payload) -> Result<String, Error> {
+        let mut in_out = serde_json::to_vec(payload).map_err(Error::other)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        self.random
+            .fill(&mut nonce)
+            .map_err(|_| Error::other("Failed to generate a nonce"))?;
+        self.keys[0]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(COOKIE_SESSION_NAME.as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| Error::other("Failed to encrypt session"))?;
+        Ok(URL_SAFE_NO_PAD.encode([nonce.as_slice(), &in_out].concat()))
+    }
+    /// None for tampered cookies and cookies sealed with an unknown key
+    fn decrypt(&self, value: &str) -> Option<Payload> {
+        let sealed = URL_SAFE_NO_PAD.decode(value).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.keys.iter().find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut in_out = ciphertext.to_vec();
+            let plaintext = key
+                .open_in_place(
+                    nonce,
+                    Aad::from(COOKIE_SESSION_NAME.as_bytes()),
+                    &mut in_out,
+                )
+                .ok()?;
+            serde_json::from_slice(plaintext).ok()
+        })
+    }
+    fn open(
+        &self,
+        data: &ServiceData,
+        now: SystemTime,
+    ) -> Option<(SystemTime, Map<String, Value>)> {
+        let cookie = get_cookie_session_from_request(data)?;
+        let payload = match self.decrypt(cookie.value()) {
+            Some(payload) => payload,
+            None => {
+                warn!("Rejected a session cookie that failed to decrypt");
+                return None;
+            }
+        };
+        let issued = UNIX_EPOCH + Duration::from_secs(payload.issued);
+        match now.duration_since(issued) {
+            Ok(age) if age >= self.session_duration => None,
+            _ => Some((issued, payload.values)),
+        }
+    }
+}
+fn values(
+    registered: &[Registered],
+    extensions: &Extensions,
+) -> Result<Map<String, Value>, serde_json::Error> {
+    let mut values = Map::new();
+    for registered in registered.iter() {
+        if let Some(value) = (registered.save)(extensions) {
+            values.insert(registered.name.clone(), value?);
+        }
+    }
+    Ok(values)
+}
+fn session_cookie(value: String) -> Cookie<'static> {
+    Cookie::build((COOKIE_SESSION_NAME, value))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(cookie::SameSite::Lax)
+        .build()
+}
+/// The Set-Cookie length for a payload, sealing adds the nonce and tag before base64
+fn cookie_len(payload: &Payload) -> Result<usize, serde_json::Error> {
+    let sealed = NONCE_LEN + serde_json::to_vec(payload)?.len() + AES_256_GCM.tag_len();
+    Ok(session_cookie(String::new()).to_string().len() + (sealed * 4).div_ceil(3))
+}
+fn unix_secs(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+/// Fails when the request's session no longer fits in the cookie, handlers call it before
+/// committing side effects since the wrapper can only answer 500 once they ran
+pub fn check_session_size(data: &ServiceData) -> Result<(), Error> {
+    let (Some(layout), Some(session)) = (
+        data.request.get::<Layout>(),
+        data.request.get::<Arc<Session>>(),
+    ) else {
+        return Ok(());
+    };
+    let payload = Payload {
+        issued: unix_secs(clock(&data.request).now_utc()),
+        values: values(&layout.registered, &session.data).map_err(Error::other)?,
+    };
+    let len = cookie_len(&payload).map_err(Error::other)?;
+    if len > layout.max_cookie_bytes {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Session cookie would be {len} bytes, over the {} byte limit",
+                layout.max_cookie_bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+pub fn get_cookie_session_from_request(data: &ServiceData) -> Option<Cookie<'_>> {
+    data.request
+        .request
+        .headers()?
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|cookie| cookie.name() == COOKIE_SESSION_NAME)
+}
+#[async_trait]
+impl WrapperFn for CookieSessionWrapper {
+    fn name(&self) -> &str {
+        "CookieSessionWrapper"
+    }
+    /// Reads the session cookie, which HeaderPolicy::sensitive hides
+    fn infrastructure(&self) -> bool {
+        true
+    }
+
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        let clock = clock(&data.request);
+        let now = clock.now_utc();
+        let opened = self.open(data, now);
+        let mut extensions = Extensions::new();
+        for (name, value) in opened.iter().flat_map(|(_, values)| values) {
+            if let Some(registered) = self.registered.iter().find(|r| &r.name == name) {
+                if let Err(e) = (registered.load)(value.clone(), &mut extensions) {
+                    warn!("Dropped session value {name}: {e:?}");
+                }
+            }
+        }
+        let session = Arc::new(Session::new(extensions, now));
+        data.request.insert(session);
+        data.request.insert(Opened(opened));
+        data.request.insert(Layout {
+            registered: self.registered.clone(),
+            max_cookie_bytes: self.max_cookie_bytes,
+        });
+        WrapperResult::Continue
+    }
+
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        let opened = data.request.remove::<Opened>().and_then(|o| o.0);
+        data.request.remove::<Layout>();
+        let session = match data.request.remove::<Arc<Session>>() {
+            Some(session) => session,
+            None => return WrapperResult::Continue,
+        };
+        let values = match values(&self.registered, &session.data) {
+            Ok(values) => values,
+            Err(e) => {
+                error!("Failed to serialize session: {e:?}");
+                return WrapperResult::Continue;
+            }
+        };
+        let now = clock(&data.request).now_utc();
+        let cookie = match opened {
+            None if values.is_empty() => return WrapperResult::Continue,
+            Some(_) if values.is_empty() => {
+                let mut cookie = session_cookie(String::new());
+                cookie.make_removal();
+                cookie
+            }
+            Some((issued, opened))
+                if opened == values
+                    && now.duration_since(issued).unwrap_or_default() < self.refresh_after =>
+            {
+                return WrapperResult::Continue
+            }
+            _ => {
+                let payload = Payload {
+                    issued: unix_secs(now),
+                    values,
+                };
+                match cookie_len(&payload) {
+                    Ok(len) if len > self.max_cookie_bytes => {
+                        error!(
+                            "Session cookie is {len} bytes, over the {} byte limit",
+                            self.max_cookie_bytes
+                        );
+                        HttpError::new(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!(
+                                "Session data exceeds the {} byte cookie limit",
+                                self.max_cookie_bytes
+                            ),
+                        )
+                        .write_response(&mut data.response);
+                        return WrapperResult::Return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Failed to serialize session: {e:?}");
+                        return WrapperResult::Continue;
+                    }
+                }
+                match self.encrypt(&payload) {
+                    Ok(sealed) => session_cookie(sealed),
+                    Err(e) => {
+                        error!("{e:?}");
+                        return WrapperResult::Continue;
+                    }
+                }
+            }
+        };
+        if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+            data.response
+                .headers_mut()
+                .append(header::SET_COOKIE, value);
+        }
+        WrapperResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::start;
+    use pfcore::clock::ManualClock;
+    use pfcore::headers::HeaderPolicy;
+    use pfcore::server::ServerBuilder;
+    use pfcore::service::ServiceBuilder;
+    use pfcore::{IntoStreamBody, ServiceHandler};
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct User(String);
+
+    /// `?user=` logs in, `?logout` logs out, answers with the session's user
+    struct Login;
+    #[async_trait]
+    impl ServiceHandler for Login {
+        fn name(&self) -> &str {
+            "login"
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            let query = data
+                .request
+                .request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .to_string();
+            let session = data.request.get_mut::<Arc<Session>>().unwrap();
+            let session = Arc::get_mut(session).unwrap();
+            if let Some(user) = query.strip_prefix("user=") {
+                session.data.insert(User(user.to_string()));
+            } else if query == "logout" {
+                session.data.remove::<User>();
+            }
+            if check_session_size(&data).is_err() {
+                let session = data.request.get_mut::<Arc<Session>>().unwrap();
+                Arc::get_mut(session).unwrap().data.remove::<User>();
+                *data.response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                return Ok(data);
+            }
+            let user = data
+                .request
+                .get::<Arc<Session>>()
+                .and_then(|session| session.data.get::<User>())
+                .map_or("anonymous".to_string(), |user| user.0.clone());
+            *data.response.body_mut() = user.stream_body();
+            Ok(data)
+        }
+    }
+
+    fn key(byte: u8) -> [u8; 32] {
+        [byte; 32]
+    }
+
+    fn wrapper(keys: Vec<[u8; 32]>) -> CookieSessionWrapper {
+        CookieSessionWrapper::new(keys)
+            .unwrap()
+            .register::<User, _>("user")
+    }
+
+    async fn serve(wrapper: CookieSessionWrapper, clock: Arc<ManualClock>) -> String {
+        start(
+            ServerBuilder::default().clock(clock).register(
+                ServiceBuilder::new("/login")
+                    .name("login")
+                    .header_policy(HeaderPolicy::sensitive())
+                    .wrap(Arc::new(wrapper))
+                    .handler(Arc::new(Login))
+                    .build(),
+            ),
+        )
+        .await
+    }
+
+    /// The body and the Set-Cookie header without its attributes
+    async fn get(url: &str, query: &str, cookie: Option<&str>) -> (String, Option<String>) {
+        let mut request = reqwest::Client::new().get(format!("{url}/login?{query}"));
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        let response = request.send().await.unwrap();
+        let set_cookie = response
+            .headers()
+            .get(header::SET_COOKIE)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.text().await.unwrap(), set_cookie)
+    }
+
+    fn value(set_cookie: &str) -> String {
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn sessions_survive_the_sensitive_policy_and_reject_tampering() {
+        let url = serve(wrapper(vec![key(1)]), Arc::default()).await;
+        let (_, cookie) = get(&url, "user=ada", None).await;
+        let cookie = value(&cookie.unwrap());
+        assert_eq!(
+            get(&url, "", Some(&cookie)).await,
+            ("ada".to_string(), None)
+        );
+
+        let mut tampered = cookie.into_bytes();
+        let middle = tampered.len() / 2;
+        tampered[middle] = if tampered[middle] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert_eq!(get(&url, "", Some(&tampered)).await.0, "anonymous");
+
+        let foreign = wrapper(vec![key(2)])
+            .encrypt(&Payload {
+                issued: unix_secs(SystemTime::now()),
+                values: Map::from_iter([("user".to_string(), Value::from("eve"))]),
+            })
+            .unwrap();
+        let foreign = format!("{COOKIE_SESSION_NAME}={foreign}");
+        assert_eq!(get(&url, "", Some(&foreign)).await.0, "anonymous");
+    }
+
+    #[test]
+    fn rotated_keys_open_old_cookies_and_seal_with_the_new_key() {
+        let old = wrapper(vec![key(1)]);
+        let rotated = wrapper(vec![key(2), key(1)]);
+        let payload = Payload {
+            issued: 1,
+            values: Map::from_iter([("user".to_string(), Value::from("ada"))]),
+        };
+        let sealed = old.encrypt(&payload).unwrap();
+        assert_eq!(rotated.decrypt(&sealed).unwrap().values, payload.values);
+
+        let resealed = rotated.encrypt(&payload).unwrap();
+        assert!(old.decrypt(&resealed).is_none());
+        assert!(wrapper(vec![key(2)]).decrypt(&resealed).is_some());
+    }
+
+    #[tokio::test]
+    async fn expiry_slides_with_each_request() {
+        let clock = Arc::new(ManualClock::default());
+        let url = serve(
+            wrapper(vec![key(1)])
+                .session_duration(Duration::from_secs(600))
+                .refresh_after(Duration::from_secs(60)),
+            clock.clone(),
+        )
+        .await;
+        let (_, first) = get(&url, "user=ada", None).await;
+        let first = value(&first.unwrap());
+        //Within refresh_after the cookie is left alone
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(get(&url, "", Some(&first)).await, ("ada".to_string(), None));
+
+        clock.advance(Duration::from_secs(270));
+        let (user, second) = get(&url, "", Some(&first)).await;
+        assert_eq!(user, "ada");
+        let second = value(&second.unwrap());
+
+        //Past the first cookie's expiry, the refreshed one is still live
+        clock.advance(Duration::from_secs(400));
+        assert_eq!(get(&url, "", Some(&first)).await.0, "anonymous");
+        assert_eq!(get(&url, "", Some(&second)).await.0, "ada");
+    }
+
+    #[tokio::test]
+    async fn logout_removes_the_cookie() {
+        let url = serve(wrapper(vec![key(1)]), Arc::default()).await;
+        let (_, cookie) = get(&url, "user=ada", None).await;
+        let cookie = value(&cookie.unwrap());
+        let (user, removal) = get(&url, "logout", Some(&cookie)).await;
+        assert_eq!(user, "anonymous");
+        let removal = Cookie::parse(removal.unwrap()).unwrap();
+        assert_eq!(removal.name(), COOKIE_SESSION_NAME);
+        assert_eq!(removal.value(), "");
+        assert_eq!(removal.max_age(), Some(cookie::time::Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn handlers_can_refuse_sessions_over_the_cookie_limit() {
+        let url = serve(wrapper(vec![key(1)]).max_cookie_bytes(1024), Arc::default()).await;
+        let response = reqwest::get(format!("{url}/login?user={}", "a".repeat(2000)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response.headers().get(header::SET_COOKIE).is_none());
+        let (user, cookie) = get(&url, &format!("user={}", "a".repeat(500)), None).await;
+        assert_eq!(user, "a".repeat(500));
+        assert!(cookie.unwrap().len() <= 1024);
+    }
+
+    #[test]
+    fn cookie_len_matches_the_sealed_cookie() {
+        let wrapper = wrapper(vec![key(1)]);
+        for len in [0, 1, 2, 3, 100, 1000] {
+            let payload = Payload {
+                issued: 1_700_000_000,
+                values: Map::from_iter([("user".to_string(), Value::from("a".repeat(len)))]),
+            };
+            let sealed = session_cookie(wrapper.encrypt(&payload).unwrap()).to_string();
+            assert_eq!(cookie_len(&payload).unwrap(), sealed.len());
+        }
+    }
+}
@@ -0,0 +1,572 @@
+use crate::wrappers::coalesce::buffer_body;
+use crate::wrappers::sessions::{get_named_cookie, SessionConfig};
+use async_trait::async_trait;
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, HeaderName, Method, StatusCode};
+use hyper::body::Bytes;
+use log::{error, warn};
+use pfcore::clock::clock;
+use pfcore::tls::TlsInfo;
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::{IntoStreamBody, ServiceData};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+const WAIT_POLL: Duration = Duration::from_millis(50);
+
+/// The response recorded for a key, `request` is the method and uri it was recorded for
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub request: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+pub enum Claim {
+    /// The caller now owns the key and must complete or release it
+    Started,
+    InFlight,
+    Completed(StoredResponse),
+}
+
+/// Records idempotent responses, implement for a shared store to replay across instances.
+/// `now` is the time on the request's clock, stores that expire records themselves can ignore it.
+#[async_trait]
+pub trait IdempotencyStore {
+    /// Claims `key` unless it is in flight or completed, an unfinished claim expires after `ttl`
+    async fn claim(&self, key: &str, ttl: Duration, now: Instant) -> Result<Claim, Error>;
+    async fn complete(
+        &self,
+        key: &str,
+        response: StoredResponse,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<(), Error>;
+    /// Drops a claim without a response so the key can be used again
+    async fn release(&self, key: &str) -> Result<(), Error>;
+}
+
+enum MemoryEntry {
+    InFlight,
+    Completed(StoredResponse),
+}
+
+/// Keeps records in process memory, expired records are dropped as new keys are claimed
+#[derive(Default)]
+pub struct MemoryIdempotencyStore {
+    entries: Mutex<HashMap<String, (MemoryEntry, Instant)>>,
+}
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn claim(&self, key: &str, ttl: Duration, now: Instant) -> Result<Claim, Error> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match entries.get(key) {
+            Some((MemoryEntry::InFlight, expires)) if *expires > now => Ok(Claim::InFlight),
+            Some((MemoryEntry::Completed(response), expires)) if *expires > now => {
+                Ok(Claim::Completed(response.clone()))
+            }
+            _ => {
+                entries.retain(|_, (_, expires)| *expires > now);
+                entries.insert(key.to_string(), (MemoryEntry::InFlight, now + ttl));
+                Ok(Claim::Started)
+            }
+        }
+    }
+    async fn complete(
+        &self,
+        key: &str,
+        response: StoredResponse,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                key.to_string(),
+                (MemoryEntry::Completed(response), now + ttl),
+            );
+        Ok(())
+    }
+    async fn release(&self, key: &str) -> Result<(), Error> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InFlightPolicy {
+    /// Answers a retry of an unfinished request with 409
+    Conflict,
+    /// Waits up to the duration for the first request to finish and replays its response
+    Wait(Duration),
+}
+
+/// Held by the request that claimed a key, releases the key if the request never completes
+struct ClaimGuard {
+    key: String,
+    request: String,
+    store: Arc<dyn IdempotencyStore + Send + Sync>,
+    done: AtomicBool,
+}
+impl ClaimGuard {
+    /// True for the first call only
+    fn finish(&self) -> bool {
+        !self.done.swap(true, Ordering::AcqRel)
+    }
+}
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        if self.finish() {
+            let store = self.store.clone();
+            let key = std::mem::take(&mut self.key);
+            tokio::spawn(async move {
+                if let Err(e) = store.release(&key).await {
+                    error!("Failed to release idempotency key: {e:?}");
+                }
+            });
+        }
+    }
+}
+
+#[derive(Clone)]
+struct IdempotencyClaim(Arc<ClaimGuard>);
+
+pub type IdempotencyScope = Arc<dyn Fn(&ServiceData) -> String + Send + Sync>;
+
+/// Replays the recorded response when a request is retried with the same `Idempotency-Key`.
+/// Keys are scoped per principal, by default the Authorization header, session cookie and
+/// client certificate, so one user can never replay another user's response. Callers with none
+/// of those are answered with 400 unless a scope is set.
+/// Pass the SessionWrapper's config to session_config when its cookie is not the default name.
+/// Server errors are not recorded so the request can be retried, and neither are bodies over
+/// max_body_bytes, those release the key and log a warning.
+pub struct Idempotency {
+    store: Arc<dyn IdempotencyStore + Send + Sync>,
+    methods: Vec<Method>,
+    ttl: Duration,
+    in_flight_ttl: Duration,
+    in_flight: InFlightPolicy,
+    max_body_bytes: usize,
+    required: bool,
+    /// Replaces the default scope entirely
+    scope: Option<IdempotencyScope>,
+    session_cookie: String,
+}
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self {
+            store: Arc::new(MemoryIdempotencyStore::default()),
+            methods: vec![Method::POST],
+            ttl: Duration::from_secs(24 * 60 * 60),
+            in_flight_ttl: Duration::from_secs(60),
+            in_flight: InFlightPolicy::Conflict,
+            max_body_bytes: 1024 * 1024,
+            required: false,
+            scope: None,
+            session_cookie: SessionConfig::default().cookie_name,
+        }
+    }
+}
+impl Idempotency {
+    pub fn store(self, store: Arc<dyn IdempotencyStore + Send + Sync>) -> Self {
+        let mut s = self;
+        s.store = store;
+        s
+    }
+    pub fn methods(self, methods: Vec<Method>) -> Self {
+        let mut s = self;
+        s.methods = methods;
+        s
+    }
+    /// How long completed responses are replayed
+    pub fn ttl(self, ttl: Duration) -> Self {
+        let mut s = self;
+        s.ttl = ttl;
+        s
+    }
+    /// How long a claim lasts when the request holding it never finishes
+    pub fn in_flight_ttl(self, in_flight_ttl: Duration) -> Self {
+        let mut s = self;
+        s.in_flight_ttl = in_flight_ttl;
+        s
+    }
+    pub fn in_flight(self, in_flight: InFlightPolicy) -> Self {
+        let mut s = self;
+        s.in_flight = in_flight;
+        s
+    }
+    pub fn max_body_bytes(self, max_body_bytes: usize) -> Self {
+        let mut s = self;
+        s.max_body_bytes = max_body_bytes;
+        s
+    }
+    /// Rejects requests without a key with 400
+    pub fn required(self, required: bool) -> Self {
+        let mut s = self;
+        s.required = required;
+        s
+    }
+    pub fn scope(self, scope: IdempotencyScope) -> Self {
+        let mut s = self;
+        s.scope = Some(scope);
+        s
+    }
+    /// The default scope reads the session cookie named in this config
+    pub fn session_config(self, config: &SessionConfig) -> Self {
+        let mut s = self;
+        s.session_cookie = config.cookie_name.clone();
+        s
+    }
+    /// None when the default scope finds no principal
+    fn scope_of(&self, data: &ServiceData) -> Option<String> {
+        match &self.scope {
+            Some(scope) => Some(scope(data)),
+            None => default_scope(data, &self.session_cookie),
+        }
+    }
+}
+
+fn default_scope(data: &ServiceData, session_cookie: &str) -> Option<String> {
+    let mut hasher = Sha256::new();
+    let mut found = false;
+    //Length prefixed so no two sets of credentials hash the same bytes
+    let mut part = |kind: &[u8], value: &[u8]| {
+        for bytes in [kind, value] {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+        found = true;
+    };
+    if let Some(headers) = data.request.request.headers() {
+        for value in headers.get_all(AUTHORIZATION) {
+            part(b"authorization", value.as_bytes());
+        }
+    }
+    if let Some(cookie) = get_named_cookie(data, session_cookie) {
+        part(
+            b"cookie",
+            format!("{session_cookie}={}", cookie.value()).as_bytes(),
+        );
+    }
+    if let Some(peer) = data
+        .request
+        .get::<Arc<TlsInfo>>()
+        .and_then(|tls| tls.peer_id())
+    {
+        part(b"peer", peer.as_bytes());
+    }
+    found.then(|| hex::encode(hasher.finalize()))
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_IDEMPOTENCY_KEY_LEN
+        && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn respond(data: &mut ServiceData, status: StatusCode, message: &'static str) -> WrapperResult {
+    *data.response.status_mut() = status;
+    *data.response.body_mut() = message.stream_body();
+    WrapperResult::Return
+}
+
+fn replay(data: &mut ServiceData, request: &str, stored: StoredResponse) -> WrapperResult {
+    if stored.request != request {
+        return respond(
+            data,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency-Key was already used for a different request",
+        );
+    }
+    *data.response.status_mut() = stored.status;
+    *data.response.headers_mut() = stored.headers;
+    *data.response.body_mut() = stored.body.stream_body();
+    WrapperResult::Return
+}
+
+#[async_trait]
+impl WrapperFn for Idempotency {
+    fn name(&self) -> &str {
+        "Idempotency"
+    }
+    fn infrastructure(&self) -> bool {
+        true
+    }
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        if !self.methods.contains(data.request.request.method()) {
+            return WrapperResult::Continue;
+        }
+        let key = data
+            .request
+            .request
+            .headers()
+            .and_then(|headers| headers.get(&IDEMPOTENCY_KEY))
+            .map(|value| value.to_str().map(str::to_string));
+        let key = match key {
+            Some(Ok(key)) if valid_key(&key) => key,
+            Some(_) => {
+                return respond(
+                    data,
+                    StatusCode::BAD_REQUEST,
+                    "Idempotency-Key must be 1 to 255 visible ASCII characters",
+                )
+            }
+            None if self.required => {
+                return respond(data, StatusCode::BAD_REQUEST, "Idempotency-Key is required")
+            }
+            None => return WrapperResult::Continue,
+        };
+        let key = match self.scope_of(data) {
+            Some(scope) => format!("{scope}:{key}"),
+            None => {
+                return respond(
+                    data,
+                    StatusCode::BAD_REQUEST,
+                    "Idempotency-Key needs an authenticated caller",
+                )
+            }
+        };
+        let request = format!(
+            "{} {}",
+            data.request.request.method(),
+            data.request.request.uri()
+        );
+        let clock = clock(&data.request);
+        let deadline = match self.in_flight {
+            InFlightPolicy::Wait(wait) => Some(clock.now() + wait),
+            InFlightPolicy::Conflict => None,
+        };
+        loop {
+            match self
+                .store
+                .claim(&key, self.in_flight_ttl, clock.now())
+                .await
+            {
+                Ok(Claim::Started) => {
+                    data.request.insert(IdempotencyClaim(Arc::new(ClaimGuard {
+                        key,
+                        request,
+                        store: self.store.clone(),
+                        done: AtomicBool::new(false),
+                    })));
+                    return WrapperResult::Continue;
+                }
+                Ok(Claim::Completed(stored)) => return replay(data, &request, stored),
+                Ok(Claim::InFlight) => match deadline {
+                    Some(deadline) if clock.now() < deadline => {
+                        tokio::time::sleep(WAIT_POLL).await;
+                    }
+                    _ => {
+                        return respond(
+                            data,
+                            StatusCode::CONFLICT,
+                            "A request with this Idempotency-Key is still in progress",
+                        )
+                    }
+                },
+                Err(e) => {
+                    error!("Idempotency store failed: {e:?}");
+                    return respond(
+                        data,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Idempotency store is unavailable",
+                    );
+                }
+            }
+        }
+    }
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        let guard = match data.request.get::<IdempotencyClaim>().cloned() {
+            Some(IdempotencyClaim(guard)) => guard,
+            None => return WrapperResult::Continue,
+        };
+        if !guard.finish() {
+            return WrapperResult::Continue;
+        }
+        let result = if data.response.status().is_server_error() {
+            self.store.release(&guard.key).await
+        } else {
+            let body = std::mem::replace(data.response.body_mut(), Bytes::new().stream_body());
+            match buffer_body(body, self.max_body_bytes).await {
+                Ok(bytes) => {
+                    let stored = StoredResponse {
+                        request: guard.request.clone(),
+                        status: data.response.status(),
+                        headers: data.response.headers().clone(),
+                        body: bytes.clone(),
+                    };
+                    *data.response.body_mut() = bytes.stream_body();
+                    let now = clock(&data.request).now();
+                    self.store.complete(&guard.key, stored, self.ttl, now).await
+                }
+                Err(body) => {
+                    warn!(
+                        "Response for {} is over {} bytes, it will not be replayed",
+                        guard.request, self.max_body_bytes
+                    );
+                    *data.response.body_mut() = body;
+                    self.store.release(&guard.key).await
+                }
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to record idempotent response: {e:?}");
+        }
+        WrapperResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{start, Counting};
+    use http::header::COOKIE;
+    use http::{HeaderValue, Request, Response};
+    use http_body_util::Full;
+    use pfcore::clock::ManualClock;
+    use pfcore::routes::Route;
+    use pfcore::server::ServerBuilder;
+    use pfcore::service::{IncomingRequest, ServiceBuilder, ServiceRequest};
+
+    fn with_header(name: HeaderName, value: &'static str) -> ServiceData {
+        let mut request = Request::new(Full::new(Bytes::new()));
+        request
+            .headers_mut()
+            .insert(name, HeaderValue::from_static(value));
+        ServiceData {
+            server: Arc::new(ServerBuilder::default().build()),
+            request: ServiceRequest {
+                request: IncomingRequest::Sized(request),
+                path: Arc::new(Route::new("/".to_string())),
+            },
+            response: Response::new("".stream_body()),
+        }
+    }
+
+    #[tokio::test]
+    async fn default_scope_reads_the_configured_session_cookie() {
+        let (first, second) = (
+            with_header(COOKIE, "sid=first"),
+            with_header(COOKIE, "sid=second"),
+        );
+        let default = Idempotency::default();
+        assert_eq!(default.scope_of(&first), None);
+
+        let config = SessionConfig {
+            cookie_name: "sid".to_string(),
+            ..Default::default()
+        };
+        let configured = Idempotency::default().session_config(&config);
+        assert!(configured.scope_of(&first).is_some());
+        assert_ne!(configured.scope_of(&first), configured.scope_of(&second));
+    }
+
+    #[test]
+    fn credentials_do_not_collide_across_sources() {
+        let config = SessionConfig {
+            cookie_name: "sid".to_string(),
+            ..Default::default()
+        };
+        let idempotency = Idempotency::default().session_config(&config);
+        let authorization = with_header(AUTHORIZATION, "sid=token");
+        let cookie = with_header(COOKIE, "sid=token");
+        assert_ne!(
+            idempotency.scope_of(&authorization),
+            idempotency.scope_of(&cookie)
+        );
+    }
+
+    async fn serve(
+        server: ServerBuilder,
+        idempotency: Idempotency,
+        handler: Arc<Counting>,
+    ) -> String {
+        start(
+            server.register(
+                ServiceBuilder::new("/orders")
+                    .name("orders")
+                    .wrap(Arc::new(idempotency))
+                    .handler(handler)
+                    .build(),
+            ),
+        )
+        .await
+    }
+
+    fn post(
+        client: &reqwest::Client,
+        url: &str,
+        authorization: Option<&'static str>,
+    ) -> reqwest::RequestBuilder {
+        let request = client
+            .post(format!("{url}/orders"))
+            .header(&IDEMPOTENCY_KEY, "order-1");
+        match authorization {
+            Some(authorization) => request.header(AUTHORIZATION, authorization),
+            None => request,
+        }
+    }
+
+    #[tokio::test]
+    async fn callers_without_a_principal_are_refused() {
+        let handler = Counting::new("created", Duration::ZERO);
+        let url = serve(
+            ServerBuilder::default(),
+            Idempotency::default(),
+            handler.clone(),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let response = post(&client, &url, None).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(handler.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicates_run_once() {
+        let handler = Counting::new("created", Duration::from_millis(200));
+        let idempotency =
+            Idempotency::default().in_flight(InFlightPolicy::Wait(Duration::from_secs(5)));
+        let url = serve(ServerBuilder::default(), idempotency, handler.clone()).await;
+        let client = reqwest::Client::new();
+        let (a, b) = tokio::join!(
+            post(&client, &url, Some("Bearer a")).send(),
+            post(&client, &url, Some("Bearer a")).send()
+        );
+        assert_eq!(a.unwrap().text().await.unwrap(), "created");
+        assert_eq!(b.unwrap().text().await.unwrap(), "created");
+        assert_eq!(handler.calls(), 1);
+        //Another principal with the same key is a different request
+        post(&client, &url, Some("Bearer b")).send().await.unwrap();
+        assert_eq!(handler.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_keys_execute_again() {
+        let clock = Arc::new(ManualClock::default());
+        let idempotency = Idempotency::default().ttl(Duration::from_secs(60));
+        let handler = Counting::new("created", Duration::ZERO);
+        let server = ServerBuilder::default().clock(clock.clone());
+        let url = serve(server, idempotency, handler.clone()).await;
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let response = post(&client, &url, Some("Bearer a")).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "created");
+        }
+        assert_eq!(handler.calls(), 1);
+        clock.advance(Duration::from_secs(61));
+        post(&client, &url, Some("Bearer a")).send().await.unwrap();
+        assert_eq!(handler.calls(), 2);
+    }
+}
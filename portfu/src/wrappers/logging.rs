@@ -0,0 +1,144 @@
+use async_trait::async_trait;
+use http::header::CONTENT_LENGTH;
+use hyper::body::Body;
+use log::info;
+use pfcore::clock::clock;
+use pfcore::service::MatchedService;
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::ServiceData;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Log target of the access log lines
+pub static ACCESS_LOG_TARGET: &str = "access";
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Common Log Format followed by the service name and the elapsed milliseconds
+    #[default]
+    Common,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone)]
+struct RequestStart(Instant);
+
+/// Logs one line per request under the `access` target, register it with ServerBuilder::wrap to cover every service.
+/// Requests answered by an earlier wrapper's `before` never reach `after` and are not logged.
+#[derive(Default)]
+pub struct LoggingWrapper {
+    pub format: LogFormat,
+    pub skip: Vec<String>,
+}
+impl LoggingWrapper {
+    pub fn new(format: LogFormat) -> Self {
+        Self {
+            format,
+            skip: vec![],
+        }
+    }
+    pub fn format(self, format: LogFormat) -> Self {
+        let mut s = self;
+        s.format = format;
+        s
+    }
+    /// Requests under `prefix` are not logged
+    pub fn skip<S: AsRef<str>>(self, prefix: S) -> Self {
+        let mut s = self;
+        s.skip.push(prefix.as_ref().to_string());
+        s
+    }
+    fn skipped(&self, path: &str) -> bool {
+        self.skip
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+    pub fn line(&self, data: &ServiceData, elapsed_ms: f64) -> String {
+        let request = &data.request.request;
+        let method = request.method().to_string();
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| request.uri().path().to_string());
+        let service = data
+            .request
+            .get::<MatchedService>()
+            .map(|s| s.0.as_str())
+            .unwrap_or_default();
+        let remote = data
+            .request
+            .get::<SocketAddr>()
+            .map(|address| data.get_best_guess_public_ip(address))
+            .unwrap_or_default()
+            .trim_matches('"')
+            .to_string();
+        let status = data.response.status().as_u16();
+        let size = response_size(data);
+        match self.format {
+            LogFormat::Common => {
+                let size = size.map(|s| s.to_string()).unwrap_or("-".to_string());
+                format!(
+                    "{} - - [{}] \"{method} {path} {:?}\" {status} {size} \"{service}\" {elapsed_ms:.3}ms",
+                    if remote.is_empty() { "-" } else { &remote },
+                    common_log_time(data),
+                    request.version(),
+                )
+            }
+            LogFormat::Json => json!({
+                "method": method,
+                "path": path,
+                "service": service,
+                "remote": remote,
+                "status": status,
+                "size": size,
+                "elapsed_ms": elapsed_ms,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// The Content-Length header if set, otherwise the exact size of the body when known
+fn response_size(data: &ServiceData) -> Option<u64> {
+    data.response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or_else(|| data.response.body().size_hint().exact())
+}
+
+/// `10/Oct/2000:13:55:36 +0000` from the request clock
+fn common_log_time(data: &ServiceData) -> String {
+    let date = httpdate::fmt_http_date(clock(&data.request).now_utc());
+    // Tue, 15 Nov 1994 08:12:31 GMT
+    match date.split(' ').collect::<Vec<_>>().as_slice() {
+        [_, day, month, year, time, _] => format!("{day}/{month}/{year}:{time} +0000"),
+        _ => date,
+    }
+}
+
+#[async_trait]
+impl WrapperFn for LoggingWrapper {
+    fn name(&self) -> &str {
+        "LoggingWrapper"
+    }
+
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        if !self.skipped(data.request.request.uri().path()) {
+            data.request.insert(RequestStart(Instant::now()));
+        }
+        WrapperResult::Continue
+    }
+
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        if let Some(RequestStart(start)) = data.request.remove::<RequestStart>() {
+            let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+            info!(target: ACCESS_LOG_TARGET, "{}", self.line(data, elapsed_ms));
+        }
+        WrapperResult::Continue
+    }
+}
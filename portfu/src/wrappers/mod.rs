@@ -1,2 +1,9 @@
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod coalesce;
+pub mod cookie_sessions;
+pub mod idempotency;
+pub mod logging;
+pub mod origin;
 pub mod rate_limits;
 pub mod sessions;
@@ -0,0 +1,42 @@
+use async_trait::async_trait;
+use http::StatusCode;
+use log::warn;
+use pfcore::sockets::OriginPolicy;
+use pfcore::wrappers::{WrapperFn, WrapperResult};
+use pfcore::{IntoStreamBody, ServiceData};
+
+/// Applies an OriginPolicy to websocket upgrades of services not generated by the websocket macro
+#[derive(Default)]
+pub struct OriginCheck {
+    pub policy: OriginPolicy,
+}
+impl OriginCheck {
+    pub fn new(policy: OriginPolicy) -> Self {
+        Self { policy }
+    }
+}
+#[async_trait]
+impl WrapperFn for OriginCheck {
+    fn name(&self) -> &str {
+        "OriginCheck"
+    }
+
+    async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        if !data.request.request.is_upgrade_request() {
+            return WrapperResult::Continue;
+        }
+        match data.request.request.check_origin(&self.policy) {
+            Ok(_) => WrapperResult::Continue,
+            Err(e) => {
+                warn!("Rejecting Websocket Upgrade: {e}");
+                *data.response.status_mut() = StatusCode::FORBIDDEN;
+                *data.response.body_mut() = e.message().stream_body();
+                WrapperResult::Return
+            }
+        }
+    }
+
+    async fn after(&self, _: &mut ServiceData) -> WrapperResult {
+        WrapperResult::Continue
+    }
+}
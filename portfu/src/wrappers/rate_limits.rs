@@ -3,16 +3,18 @@ use http::StatusCode;
 use http_body_util::{BodyExt, Full};
 use hyper::body::{Body, Bytes};
 use log::{debug, warn};
+use pfcore::clock::clock;
 use pfcore::service::{ConsumedBodyType, IncomingRequest};
+use pfcore::settings::Settings;
 use pfcore::wrappers::{WrapperFn, WrapperResult};
 use pfcore::{IntoStreamBody, ServiceData};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, VecDeque};
-use std::io::{Error, ErrorKind};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
-use tokio::time::Instant;
 
 pub struct RateLimit {
     pub requests_count: AtomicUsize,
@@ -20,6 +22,16 @@ pub struct RateLimit {
     pub request_size_limit_bytes: AtomicUsize,
 }
 
+impl RateLimit {
+    fn fields(&self) -> [(&'static str, &AtomicUsize); 3] {
+        [
+            ("requests_count", &self.requests_count),
+            ("count_seconds", &self.count_seconds),
+            ("request_size_limit_bytes", &self.request_size_limit_bytes),
+        ]
+    }
+}
+
 pub struct RecentRequests {
     requests: RwLock<HashMap<String, VecDeque<Instant>>>,
     _last_request: RwLock<Instant>,
@@ -33,19 +45,31 @@ impl RecentRequests {
         }
     }
     pub async fn add(&self, path: String) {
+        self.add_at(path, Instant::now()).await
+    }
+    pub async fn add_at(&self, path: String, now: Instant) {
         let mut write_lock = self.requests.write().await;
         match write_lock.entry(path) {
             Entry::Occupied(mut e) => {
-                e.get_mut().push_front(Instant::now());
+                e.get_mut().push_front(now);
                 e.get_mut().truncate(self.depth);
             }
             Entry::Vacant(e) => {
-                e.insert(VecDeque::from([Instant::now()]));
+                e.insert(VecDeque::from([now]));
             }
         }
     }
     pub async fn recent_requests(&self, path: Option<&str>, last_seconds: u64) -> usize {
-        let now = Instant::now();
+        self.recent_requests_at(path, last_seconds, Instant::now())
+            .await
+    }
+    /// Requests in the `last_seconds` before `now`
+    pub async fn recent_requests_at(
+        &self,
+        path: Option<&str>,
+        last_seconds: u64,
+        now: Instant,
+    ) -> usize {
         match path {
             None => self
                 .requests
@@ -80,6 +104,8 @@ pub struct RateLimiter {
     pub global_limits: Arc<RateLimit>,
     pub client_rates: Arc<ClientMap>,
     pub enabled: Arc<AtomicBool>,
+    settings: Option<(Settings, String)>,
+    settings_version: AtomicU64,
 }
 impl RateLimiter {
     pub fn new(
@@ -92,6 +118,31 @@ impl RateLimiter {
             global_limits,
             client_rates,
             enabled: Arc::new(AtomicBool::new(true)),
+            settings: None,
+            settings_version: AtomicU64::new(u64::MAX),
+        }
+    }
+    /// Reads the global limits from `<prefix>.requests_count`, `<prefix>.count_seconds`
+    /// and `<prefix>.request_size_limit_bytes` in `settings`, the current limits are the defaults
+    pub fn with_settings<S: AsRef<str>>(self, settings: Settings, prefix: S) -> Self {
+        let mut s = self;
+        let prefix = prefix.as_ref().to_string();
+        for (key, limit) in s.global_limits.fields() {
+            settings.define(format!("{prefix}.{key}"), limit.load(Ordering::Relaxed));
+        }
+        s.settings = Some((settings, prefix));
+        s
+    }
+    fn apply_settings(&self) {
+        if let Some((settings, prefix)) = &self.settings {
+            let version = settings.version();
+            if self.settings_version.swap(version, Ordering::Relaxed) != version {
+                for (key, limit) in self.global_limits.fields() {
+                    if let Some(value) = settings.get_u64(&format!("{prefix}.{key}")) {
+                        limit.store(value as usize, Ordering::Relaxed);
+                    }
+                }
+            }
         }
     }
 }
@@ -100,12 +151,18 @@ impl WrapperFn for RateLimiter {
     fn name(&self) -> &str {
         "RateLimiter"
     }
+    /// Tells clients apart by x-real-ip and x-forwarded-for, which a HeaderPolicy may hide
+    fn infrastructure(&self) -> bool {
+        true
+    }
     async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+        self.apply_settings();
         let address = data
             .request
             .get()
             .expect("Expected Connection To Have SockerAddr");
         let remote = data.get_best_guess_public_ip(address);
+        let now = clock(&data.request).now();
         let recent_requests = match self.client_rates.write().await.entry(remote.clone()) {
             Entry::Vacant(e) => {
                 let val = Arc::new(RecentRequests::new(
@@ -129,9 +186,10 @@ impl WrapperFn for RateLimiter {
                     * path_limits.count_seconds.load(Ordering::Relaxed),
                 path_limits.request_size_limit_bytes.load(Ordering::Relaxed) as u64,
                 recent_requests
-                    .recent_requests(
+                    .recent_requests_at(
                         Some(data.request.request.uri().path()),
                         path_limits.count_seconds.load(Ordering::Relaxed) as u64,
+                        now,
                     )
                     .await,
             )
@@ -143,9 +201,10 @@ impl WrapperFn for RateLimiter {
                     .request_size_limit_bytes
                     .load(Ordering::Relaxed) as u64,
                 recent_requests
-                    .recent_requests(
+                    .recent_requests_at(
                         None,
                         self.global_limits.count_seconds.load(Ordering::Relaxed) as u64,
+                        now,
                     )
                     .await,
             )
@@ -163,7 +222,7 @@ impl WrapperFn for RateLimiter {
         } else {
             debug!("Not Request Rate Limited: {recent_count} < {requests_per_second_limit}");
             recent_requests
-                .add(data.request.request.uri().path().to_string())
+                .add_at(data.request.request.uri().path().to_string(), now)
                 .await; //We only add requests we accept, so some still go through instead of overuse causing the client to always get blocked
             if size_limit > 0 {
                 debug!("Checking Size Limit: {size_limit}");
@@ -274,12 +333,7 @@ async fn handle_unsized(data: &mut ServiceData, limit: usize) -> Result<WrapperR
     let mut body = data.request.consume()?;
     let mut buffer = Vec::with_capacity(limit);
     while let Some(next) = body.frame().await {
-        let frame = next.map_err(|e| {
-            Error::new(
-                ErrorKind::Other,
-                format!("HTTP ERROR IN RATE_LIMITER: {e:?}"),
-            )
-        })?;
+        let frame = next.map_err(|e| Error::other(format!("HTTP ERROR IN RATE_LIMITER: {e:?}")))?;
         if let Some(chunk) = frame.data_ref() {
             if buffer.len() > limit || buffer.len() + chunk.len() > limit {
                 return Ok(create_error(
@@ -306,3 +360,55 @@ impl Default for RecentRequests {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{start, Counting};
+    use pfcore::headers::HeaderPolicy;
+    use pfcore::server::ServerBuilder;
+    use pfcore::service::ServiceBuilder;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn limits_clients_under_the_sensitive_policy() {
+        let limiter = RateLimiter::new(
+            Arc::default(),
+            Arc::default(),
+            Arc::new(RateLimit {
+                requests_count: AtomicUsize::new(1),
+                count_seconds: AtomicUsize::new(60),
+                request_size_limit_bytes: AtomicUsize::new(0),
+            }),
+        );
+        assert!(limiter.infrastructure());
+        let handler = Counting::new("ok", Duration::ZERO);
+        let url = start(
+            ServerBuilder::default().register(
+                ServiceBuilder::new("/limited")
+                    .name("limited")
+                    .header_policy(HeaderPolicy::sensitive())
+                    .wrap(Arc::new(limiter))
+                    .handler(handler.clone())
+                    .build(),
+            ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let status = |ip: &'static str| {
+            let request = client
+                .get(format!("{url}/limited"))
+                .header("x-real-ip", ip)
+                .header("authorization", "Bearer token")
+                .send();
+            async move { request.await.unwrap().status() }
+        };
+        let limit = 60;
+        for _ in 0..limit {
+            assert_eq!(status("10.0.0.1").await, StatusCode::OK);
+        }
+        assert_eq!(status("10.0.0.1").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status("10.0.0.2").await, StatusCode::OK);
+        assert_eq!(handler.calls(), limit + 1);
+    }
+}
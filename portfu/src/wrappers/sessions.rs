@@ -1,81 +1,390 @@
 use async_trait::async_trait;
 use cookie::Cookie;
+pub use cookie::SameSite;
 use dashmap::DashMap;
 use http::{header, Extensions, HeaderName, HeaderValue};
+use log::debug;
+use portfu_core::clock::{clock, SharedClock};
+use portfu_core::task::{ShutdownSignal, Task, TaskFn};
+use portfu_core::tls::TlsInfo;
 use portfu_core::wrappers::{WrapperFn, WrapperResult};
 use portfu_core::ServiceData;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime};
+use tokio::select;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
 pub static SESSION_HEADER: &str = "session_id";
+/// `data` lives in process memory only, keep what other replicas or restarts must see in the
+/// JSON values set with set_value
 pub struct Session {
     pub data: Extensions,
-    pub last_update: RwLock<Instant>,
+    /// Wall clock time, stores can persist it and compare it across restarts
+    pub last_update: RwLock<SystemTime>,
+    /// Overrides the wrapper's session_duration for this session
+    pub ttl: RwLock<Option<Duration>>,
+    values: RwLock<Map<String, Value>>,
+}
+impl Session {
+    pub fn new(data: Extensions, now: SystemTime) -> Self {
+        Self {
+            data,
+            last_update: RwLock::new(now),
+            ttl: RwLock::new(None),
+            values: RwLock::default(),
+        }
+    }
+    /// A session read back from a store, with empty data
+    pub fn from_record(record: SessionRecord) -> Self {
+        Self {
+            data: Extensions::new(),
+            last_update: RwLock::new(record.last_update),
+            ttl: RwLock::new(record.ttl),
+            values: RwLock::new(record.values),
+        }
+    }
+    /// What a store has to persist to restore the session elsewhere
+    pub async fn record(&self) -> SessionRecord {
+        SessionRecord {
+            values: self.values.read().await.clone(),
+            last_update: *self.last_update.read().await,
+            ttl: *self.ttl.read().await,
+        }
+    }
+    pub async fn set_value<T: Serialize>(&self, key: &str, value: &T) -> Result<(), Error> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{e}")))?;
+        self.values.write().await.insert(key.to_string(), value);
+        Ok(())
+    }
+    /// None when `key` is unset or holds something other than a `T`
+    pub async fn value<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.values.read().await.get(key).cloned()?;
+        serde_json::from_value(value).ok()
+    }
+    pub async fn remove_value(&self, key: &str) {
+        self.values.write().await.remove(key);
+    }
+    pub async fn set_ttl(&self, ttl: Duration) {
+        *self.ttl.write().await = Some(ttl);
+    }
+    /// Idle for at least its own ttl, or `default_ttl` when it has none
+    pub async fn is_expired(&self, now: SystemTime, default_ttl: Duration) -> bool {
+        let ttl = self.ttl.read().await.unwrap_or(default_ttl);
+        idle_for(now, *self.last_update.read().await) >= ttl
+    }
+    /// is_expired without waiting, None while the session is being updated
+    pub fn try_is_expired(&self, now: SystemTime, default_ttl: Duration) -> Option<bool> {
+        let ttl = self.ttl.try_read().ok()?.unwrap_or(default_ttl);
+        Some(idle_for(now, *self.last_update.try_read().ok()?) >= ttl)
+    }
+}
+
+/// The persisted part of a Session, for stores that keep sessions outside the process
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub values: Map<String, Value>,
+    pub last_update: SystemTime,
+    pub ttl: Option<Duration>,
+}
+
+/// Zero when the clock went back past `last_update`
+fn idle_for(now: SystemTime, last_update: SystemTime) -> Duration {
+    now.duration_since(last_update).unwrap_or_default()
+}
+
+/// Where SessionWrapper keeps sessions, keyed by the server side session id.
+/// Stores shared between processes persist Session::record and load with Session::from_record,
+/// the wrapper saves the session again after every request so handler changes reach the store.
+#[async_trait]
+pub trait SessionStore {
+    async fn load(&self, id: &str) -> Option<Arc<Session>>;
+    async fn save(&self, id: &str, session: Arc<Session>);
+    async fn delete(&self, id: &str);
+    /// Removes every session expired at `now`, returns how many were removed
+    async fn purge_expired(&self, now: SystemTime, default_ttl: Duration) -> usize;
+}
+
+/// Sessions in process memory, lost on restart
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: DashMap<String, Arc<Session>>,
+}
+impl MemorySessionStore {
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.get(id).map(|v| v.value().clone())
+    }
+    async fn save(&self, id: &str, session: Arc<Session>) {
+        self.sessions.insert(id.to_string(), session);
+    }
+    async fn delete(&self, id: &str) {
+        self.sessions.remove(id);
+    }
+    /// Sessions being updated while the sweep runs are kept until the next one
+    async fn purge_expired(&self, now: SystemTime, default_ttl: Duration) -> usize {
+        let mut purged = 0;
+        self.sessions.retain(|_, session| {
+            let expired = session.try_is_expired(now, default_ttl) == Some(true);
+            purged += usize::from(expired);
+            !expired
+        });
+        purged
+    }
+}
+
+/// Attributes of the session cookie
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub cookie_name: String,
+    pub domain: Option<String>,
+    pub path: String,
+    /// SameSite::None always sends Secure, browsers reject it otherwise
+    pub same_site: SameSite,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Without one the cookie ends with the browser session, with one it is re-issued on renewal
+    pub max_age: Option<Duration>,
+    /// Leaves out Secure on plaintext requests to localhost so local development works over http
+    pub insecure_localhost: bool,
+}
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: SESSION_HEADER.to_string(),
+            domain: None,
+            path: "/".to_string(),
+            same_site: SameSite::Lax,
+            secure: true,
+            http_only: true,
+            max_age: None,
+            insecure_localhost: false,
+        }
+    }
+}
+impl SessionConfig {
+    pub fn cookie_name<S: AsRef<str>>(self, cookie_name: S) -> Self {
+        let mut s = self;
+        s.cookie_name = cookie_name.as_ref().to_string();
+        s
+    }
+    pub fn domain<S: AsRef<str>>(self, domain: S) -> Self {
+        let mut s = self;
+        s.domain = Some(domain.as_ref().to_string());
+        s
+    }
+    pub fn path<S: AsRef<str>>(self, path: S) -> Self {
+        let mut s = self;
+        s.path = path.as_ref().to_string();
+        s
+    }
+    pub fn same_site(self, same_site: SameSite) -> Self {
+        let mut s = self;
+        s.same_site = same_site;
+        s
+    }
+    pub fn secure(self, secure: bool) -> Self {
+        let mut s = self;
+        s.secure = secure;
+        s
+    }
+    pub fn http_only(self, http_only: bool) -> Self {
+        let mut s = self;
+        s.http_only = http_only;
+        s
+    }
+    pub fn max_age(self, max_age: Duration) -> Self {
+        let mut s = self;
+        s.max_age = Some(max_age);
+        s
+    }
+    pub fn insecure_localhost(self, insecure_localhost: bool) -> Self {
+        let mut s = self;
+        s.insecure_localhost = insecure_localhost;
+        s
+    }
+    /// The session cookie for `value`, `plaintext_localhost` when the request came to localhost without TLS
+    pub fn cookie(&self, value: String, plaintext_localhost: bool) -> Cookie<'static> {
+        let secure = self.same_site == SameSite::None
+            || (self.secure && !(self.insecure_localhost && plaintext_localhost));
+        let mut cookie = Cookie::build((self.cookie_name.clone(), value))
+            .path(self.path.clone())
+            .secure(secure)
+            .http_only(self.http_only)
+            .same_site(self.same_site);
+        if let Some(domain) = &self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if let Some(max_age) = self.max_age {
+            cookie = cookie.max_age(cookie::time::Duration::seconds(
+                max_age.as_secs().min(i64::MAX as u64) as i64,
+            ));
+        }
+        cookie.build()
+    }
 }
 
 pub struct SessionWrapper {
-    pub sessions: Arc<DashMap<String, Arc<Session>>>,
+    pub sessions: Arc<dyn SessionStore + Send + Sync>,
     pub session_duration: Duration,
+    pub config: SessionConfig,
 }
 impl Default for SessionWrapper {
     fn default() -> Self {
-        Self {
-            sessions: Arc::new(Default::default()),
-            session_duration: Duration::from_secs(60 * 30), //30 minutes
-        }
+        Self::new(SessionConfig::default())
     }
 }
 
 impl SessionWrapper {
-    async fn create_session_cookie(&self, data: &ServiceData) -> (Cookie, Arc<Session>) {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            sessions: Arc::new(MemorySessionStore::default()),
+            session_duration: Duration::from_secs(60 * 30), //30 minutes
+            config,
+        }
+    }
+    pub fn store(self, sessions: Arc<dyn SessionStore + Send + Sync>) -> Self {
+        let mut s = self;
+        s.sessions = sessions;
+        s
+    }
+    pub fn session_duration(self, session_duration: Duration) -> Self {
+        let mut s = self;
+        s.session_duration = session_duration;
+        s
+    }
+    /// A task purging expired sessions from the store every `interval`
+    pub fn sweeper(&self, interval: Duration) -> Task {
+        Task::new(
+            "SessionSweeper",
+            Arc::new(SessionSweeper {
+                sessions: self.sessions.clone(),
+                session_duration: self.session_duration,
+                interval,
+            }),
+        )
+    }
+    fn server_session_id(data: &ServiceData, client_session_id: &str) -> String {
         let address: &SocketAddr = data.request.get().unwrap();
         let salt = data.get_best_guess_public_ip(address);
-        let client_session_id = Uuid::new_v4();
         let mut hasher = Sha256::new();
         hasher.update([client_session_id.as_bytes(), salt.as_bytes()].concat());
-        let server_session_id = hex::encode(hasher.finalize().as_slice());
-        let cookie = Cookie::build((SESSION_HEADER, client_session_id.to_string()))
-            .path("/")
-            .secure(true)
-            .http_only(true)
-            .same_site(cookie::SameSite::Lax)
-            .build();
-        let session = Arc::new(Session {
-            data: Extensions::new(),
-            last_update: RwLock::new(Instant::now()),
-        });
-        self.sessions.insert(server_session_id, session.clone());
-        (cookie, session)
+        hex::encode(hasher.finalize().as_slice())
+    }
+    fn session_cookie(&self, data: &ServiceData, client_session_id: String) -> Cookie<'static> {
+        let plaintext_localhost =
+            data.request.get::<Arc<TlsInfo>>().is_none() && is_localhost(data);
+        self.config.cookie(client_session_id, plaintext_localhost)
+    }
+    async fn create_session_cookie(
+        &self,
+        data: &ServiceData,
+    ) -> (Cookie<'static>, String, Arc<Session>) {
+        let client_session_id = Uuid::new_v4().to_string();
+        let server_session_id = Self::server_session_id(data, &client_session_id);
+        let cookie = self.session_cookie(data, client_session_id);
+        let session = Arc::new(Session::new(
+            Extensions::new(),
+            clock(&data.request).now_utc(),
+        ));
+        self.sessions
+            .save(&server_session_id, session.clone())
+            .await;
+        (cookie, server_session_id, session)
     }
     pub async fn get_session(&self, data: &ServiceData) -> Option<Arc<Session>> {
-        let address: &SocketAddr = data.request.get().unwrap();
-        let session_cookie = get_session_cookie_from_request(data)?;
-        let salt = data.get_best_guess_public_ip(address);
-        let mut hasher = Sha256::new();
-        hasher.update([session_cookie.value().as_bytes(), salt.as_bytes()].concat());
-        let server_session_id = hex::encode(hasher.finalize().as_slice());
-        if let Some(session) = self
-            .sessions
-            .get(&server_session_id)
-            .map(|v| v.value().clone())
-        {
-            let last_update = *session.last_update.read().await;
-            if Instant::now().duration_since(last_update) >= self.session_duration {
-                None
-            } else {
-                *session.last_update.write().await = Instant::now();
-                Some(session)
-            }
-        } else {
+        let session_cookie = get_named_cookie(data, &self.config.cookie_name)?;
+        let server_session_id = Self::server_session_id(data, session_cookie.value());
+        let session = self.sessions.load(&server_session_id).await?;
+        let now = clock(&data.request).now_utc();
+        if session.is_expired(now, self.session_duration).await {
+            self.sessions.delete(&server_session_id).await;
             None
+        } else {
+            *session.last_update.write().await = now;
+            self.sessions
+                .save(&server_session_id, session.clone())
+                .await;
+            Some(session)
+        }
+    }
+}
+
+/// The store key of the request's session, for saving it again after the handler
+#[derive(Clone)]
+struct ServerSessionId(String);
+
+fn is_localhost(data: &ServiceData) -> bool {
+    let host = data
+        .request
+        .request
+        .headers()
+        .and_then(|headers| headers.get(header::HOST))
+        .and_then(|host| host.to_str().ok())
+        .or(data.request.request.uri().host())
+        .unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+struct SessionSweeper {
+    sessions: Arc<dyn SessionStore + Send + Sync>,
+    session_duration: Duration,
+    interval: Duration,
+}
+#[async_trait]
+impl TaskFn for SessionSweeper {
+    fn name(&self) -> &str {
+        "SessionSweeper"
+    }
+    async fn run(&self, state: Arc<Extensions>) -> Result<(), Error> {
+        let shutdown = state.get::<ShutdownSignal>().cloned();
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            match &shutdown {
+                Some(shutdown) => select! {
+                    _ = shutdown.wait() => return Ok(()),
+                    _ = interval.tick() => {}
+                },
+                None => {
+                    interval.tick().await;
+                }
+            }
+            let now = state
+                .get::<SharedClock>()
+                .map_or_else(SystemTime::now, |clock| clock.now_utc());
+            let purged = self
+                .sessions
+                .purge_expired(now, self.session_duration)
+                .await;
+            if purged > 0 {
+                debug!("Purged {purged} expired sessions");
+            }
         }
     }
 }
-pub fn get_session_cookie_from_request(data: &ServiceData) -> Option<Cookie> {
+pub fn get_session_cookie_from_request(data: &ServiceData) -> Option<Cookie<'_>> {
+    get_named_cookie(data, SESSION_HEADER)
+}
+pub fn get_named_cookie<'a>(data: &'a ServiceData, name: &str) -> Option<Cookie<'a>> {
     let mut session_cookie = None;
     if let Some(headers) = data.request.request.headers() {
         'outer: for value in headers.get_all(header::COOKIE) {
@@ -83,7 +392,7 @@ pub fn get_session_cookie_from_request(data: &ServiceData) -> Option<Cookie> {
                 Ok(val) => {
                     let mut split_cookies = Cookie::split_parse(val);
                     while let Some(Ok(cookie)) = split_cookies.next() {
-                        if cookie.name() == SESSION_HEADER {
+                        if cookie.name() == name {
                             session_cookie = Some(cookie);
                             break 'outer;
                         }
@@ -95,50 +404,300 @@ pub fn get_session_cookie_from_request(data: &ServiceData) -> Option<Cookie> {
     }
     session_cookie
 }
+fn set_session_cookie(data: &mut ServiceData, cookie: &Cookie<'static>) {
+    if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+        if let Some(headers) = data.request.request.headers_mut() {
+            headers.insert(HeaderName::from_static(SESSION_HEADER), value.clone());
+        }
+        data.response
+            .headers_mut()
+            .insert(header::SET_COOKIE, value);
+    }
+}
 #[async_trait]
 impl WrapperFn for SessionWrapper {
     fn name(&self) -> &str {
         "SessionWrapper"
     }
+    /// Reads the session cookie, which HeaderPolicy::sensitive hides
+    fn infrastructure(&self) -> bool {
+        true
+    }
 
     async fn before(&self, data: &mut ServiceData) -> WrapperResult {
-        let session = match get_session_cookie_from_request(data) {
-            None => {
-                let (cookie, session) = self.create_session_cookie(data).await;
-                if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
-                    if let Some(headers) = data.request.request.headers_mut() {
-                        headers.insert(HeaderName::from_static(SESSION_HEADER), value.clone());
-                    }
-                    data.response
-                        .headers_mut()
-                        .insert(header::SET_COOKIE, value);
+        let (server_session_id, session) = match self.get_session(data).await {
+            Some(session) => {
+                let value = get_named_cookie(data, &self.config.cookie_name)
+                    .map(|cookie| cookie.value().to_string())
+                    .unwrap_or_default();
+                let server_session_id = Self::server_session_id(data, &value);
+                if self.config.max_age.is_some() {
+                    let cookie = self.session_cookie(data, value);
+                    set_session_cookie(data, &cookie);
                 }
-                session
+                (server_session_id, session)
             }
-            Some(_) => {
-                if let Some(sessiopn) = self.get_session(data).await {
-                    sessiopn
-                } else {
-                    let (cookie, session) = self.create_session_cookie(data).await;
-                    if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
-                        if let Some(headers) = data.request.request.headers_mut() {
-                            headers.insert(HeaderName::from_static(SESSION_HEADER), value.clone());
-                        }
-                        data.response
-                            .headers_mut()
-                            .insert(header::SET_COOKIE, value);
-                    }
-                    session
-                }
+            None => {
+                let (cookie, server_session_id, session) = self.create_session_cookie(data).await;
+                set_session_cookie(data, &cookie);
+                (server_session_id, session)
             }
         };
         if let Some(ext) = data.request.request.extensions_mut() {
             ext.insert(session);
+            ext.insert(ServerSessionId(server_session_id));
         }
         WrapperResult::Continue
     }
 
-    async fn after(&self, _: &mut ServiceData) -> WrapperResult {
+    async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+        //Stores holding records rather than the Arc only see what the handler set once saved again
+        let session = data.request.get::<Arc<Session>>().cloned();
+        let id = data.request.get::<ServerSessionId>().map(|id| id.0.clone());
+        if let (Some(session), Some(id)) = (session, id) {
+            self.sessions.save(&id, session).await;
+        }
         WrapperResult::Continue
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{start, Counting};
+    use portfu_core::headers::HeaderPolicy;
+    use portfu_core::server::ServerBuilder;
+    use portfu_core::service::ServiceBuilder;
+
+    #[tokio::test]
+    async fn sessions_survive_the_sensitive_policy() {
+        let store = Arc::new(MemorySessionStore::default());
+        let url = start(
+            ServerBuilder::default().register(
+                ServiceBuilder::new("/session")
+                    .name("session")
+                    .header_policy(HeaderPolicy::sensitive())
+                    .wrap(Arc::new(SessionWrapper::default().store(store.clone())))
+                    .handler(Counting::new("ok", Duration::ZERO))
+                    .build(),
+            ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let response = client.get(format!("{url}/session")).send().await.unwrap();
+        let cookie = response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+        response.text().await.unwrap();
+        assert_eq!(store.len(), 1);
+        for _ in 0..2 {
+            let response = client
+                .get(format!("{url}/session"))
+                .header(header::COOKIE, &cookie)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.text().await.unwrap(), "ok");
+        }
+        assert_eq!(store.len(), 1);
+    }
+
+    /// Keeps only serialized records, like a store shared between replicas
+    #[derive(Default)]
+    struct JsonSessionStore {
+        records: std::sync::Mutex<std::collections::HashMap<String, String>>,
+    }
+    #[async_trait]
+    impl SessionStore for JsonSessionStore {
+        async fn load(&self, id: &str) -> Option<Arc<Session>> {
+            let record = self.records.lock().unwrap().get(id).cloned()?;
+            let record = serde_json::from_str(&record).unwrap();
+            Some(Arc::new(Session::from_record(record)))
+        }
+        async fn save(&self, id: &str, session: Arc<Session>) {
+            let record = serde_json::to_string(&session.record().await).unwrap();
+            self.records.lock().unwrap().insert(id.to_string(), record);
+        }
+        async fn delete(&self, id: &str) {
+            self.records.lock().unwrap().remove(id);
+        }
+        async fn purge_expired(&self, _: SystemTime, _: Duration) -> usize {
+            0
+        }
+    }
+
+    /// Counts the session's visits in its persisted values
+    struct Visits;
+    #[async_trait]
+    impl portfu_core::ServiceHandler for Visits {
+        fn name(&self) -> &str {
+            "visits"
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            let session = data.request.get::<Arc<Session>>().cloned().unwrap();
+            let visits = session.value::<u32>("visits").await.unwrap_or_default() + 1;
+            session.set_value("visits", &visits).await.unwrap();
+            *data.response.body_mut() =
+                portfu_core::IntoStreamBody::stream_body(visits.to_string());
+            Ok(data)
+        }
+    }
+
+    #[tokio::test]
+    async fn serialized_sessions_are_shared_between_servers() {
+        let store = Arc::new(JsonSessionStore::default());
+        let mut urls = vec![];
+        for _ in 0..2 {
+            let wrapper = SessionWrapper::default().store(store.clone());
+            let service = ServiceBuilder::new("/visits")
+                .name("visits")
+                .wrap(Arc::new(wrapper))
+                .handler(Arc::new(Visits))
+                .build();
+            urls.push(start(ServerBuilder::default().register(service)).await);
+        }
+        //Each server sees a different client port, the session id is salted with the client address
+        let get = |url: &String, cookie: Option<&str>| {
+            let request = reqwest::Client::new()
+                .get(format!("{url}/visits"))
+                .header("x-real-ip", "10.0.0.1");
+            match cookie {
+                Some(cookie) => request.header(header::COOKIE, cookie),
+                None => request,
+            }
+            .send()
+        };
+        let response = get(&urls[0], None).await.unwrap();
+        let cookie = response.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+        assert_eq!(response.text().await.unwrap(), "1");
+        for (url, visits) in [(&urls[1], "2"), (&urls[0], "3"), (&urls[1], "4")] {
+            let response = get(url, Some(&cookie)).await.unwrap();
+            assert_eq!(response.text().await.unwrap(), visits);
+        }
+    }
+
+    #[tokio::test]
+    async fn records_round_trip_values_and_expiry() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let session = Session::new(Extensions::new(), now);
+        session.set_value("user", &"alice").await.unwrap();
+        session.set_value("roles", &vec!["admin"]).await.unwrap();
+        session.set_ttl(Duration::from_secs(5)).await;
+        let record: SessionRecord =
+            serde_json::from_str(&serde_json::to_string(&session.record().await).unwrap()).unwrap();
+        assert_eq!(record, session.record().await);
+
+        let restored = Session::from_record(record);
+        assert_eq!(
+            restored.value::<String>("user").await.as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            restored.value::<Vec<String>>("roles").await,
+            Some(vec!["admin".to_string()])
+        );
+        //A value of another type reads as unset
+        assert_eq!(restored.value::<u32>("user").await, None);
+        assert!(
+            !restored
+                .is_expired(now + Duration::from_secs(4), Duration::MAX)
+                .await
+        );
+        assert!(
+            restored
+                .is_expired(now + Duration::from_secs(6), Duration::MAX)
+                .await
+        );
+        restored.remove_value("user").await;
+        assert_eq!(restored.value::<String>("user").await, None);
+    }
+
+    #[tokio::test]
+    async fn purge_expired_removes_only_idle_sessions() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let ttl = Duration::from_secs(60);
+        let store = MemorySessionStore::default();
+        store
+            .save("idle", Arc::new(Session::new(Extensions::new(), start)))
+            .await;
+        let fresh = start + Duration::from_secs(50);
+        store
+            .save("fresh", Arc::new(Session::new(Extensions::new(), fresh)))
+            .await;
+        let long = Arc::new(Session::new(Extensions::new(), start));
+        long.set_ttl(Duration::from_secs(3600)).await;
+        store.save("long", long).await;
+        let future = Arc::new(Session::new(Extensions::new(), start + ttl * 10));
+        store.save("future", future).await;
+
+        assert_eq!(store.purge_expired(start + ttl, ttl).await, 1);
+        assert!(store.load("idle").await.is_none());
+        assert!(store.load("fresh").await.is_some());
+        assert!(store.load("long").await.is_some());
+        assert!(store.load("future").await.is_some());
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn default_cookie_is_secure_http_only_lax() {
+        let cookie = SessionConfig::default().cookie("id".to_string(), false);
+        assert_eq!(cookie.name(), SESSION_HEADER);
+        assert_eq!(cookie.value(), "id");
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.domain(), None);
+        assert_eq!(cookie.max_age(), None);
+    }
+
+    #[test]
+    fn cookie_attributes_follow_the_config() {
+        let config = SessionConfig::default()
+            .cookie_name("sid")
+            .domain("example.com")
+            .path("/app")
+            .same_site(SameSite::Strict)
+            .http_only(false)
+            .max_age(Duration::from_secs(3600));
+        let cookie = config.cookie("id".to_string(), false);
+        assert_eq!(cookie.name(), "sid");
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.path(), Some("/app"));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(cookie.http_only(), Some(false));
+        assert_eq!(
+            cookie.max_age(),
+            Some(cookie::time::Duration::seconds(3600))
+        );
+    }
+
+    #[test]
+    fn secure_follows_localhost_and_same_site_none() {
+        let local = SessionConfig::default().insecure_localhost(true);
+        assert_eq!(local.cookie("id".to_string(), true).secure(), Some(false));
+        assert_eq!(local.cookie("id".to_string(), false).secure(), Some(true));
+        let plain = SessionConfig::default().secure(false);
+        assert_eq!(plain.cookie("id".to_string(), false).secure(), Some(false));
+
+        let none = SessionConfig::default()
+            .secure(false)
+            .insecure_localhost(true)
+            .same_site(SameSite::None);
+        for plaintext_localhost in [true, false] {
+            let cookie = none.cookie("id".to_string(), plaintext_localhost);
+            assert_eq!(cookie.secure(), Some(true));
+            assert_eq!(cookie.same_site(), Some(SameSite::None));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use portfu::macros::get;
+use portfu::prelude::*;
+use portfu::wrappers::logging::{LogFormat, LoggingWrapper, ACCESS_LOG_TARGET};
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+#[get("/hello")]
+pub async fn hello() -> Result<String, Error> {
+    Ok("hello".to_string())
+}
+
+#[get("/healthz")]
+pub async fn healthz() -> Result<String, Error> {
+    Ok("ok".to_string())
+}
+
+/// Keeps every access log line
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<String>>>);
+impl Log for Captured {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == ACCESS_LOG_TARGET
+    }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+    fn flush(&self) {}
+}
+impl Captured {
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+/// Serves the endpoints behind `wrapper` on a free local port and returns its url
+async fn start(wrapper: LoggingWrapper) -> String {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = ServerBuilder::default()
+        .host(address.ip().to_string())
+        .port(address.port())
+        .wrap(Arc::new(wrapper.skip("/healthz")))
+        .register(hello)
+        .register(healthz)
+        .build();
+    tokio::spawn(server.run());
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    format!("http://{address}")
+}
+
+async fn get_each(url: &str) {
+    for path in ["/hello?name=a", "/healthz", "/missing"] {
+        reqwest::get(format!("{url}{path}")).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn requests_are_logged_with_their_fields() {
+    let captured = Captured::default();
+    log::set_boxed_logger(Box::new(captured.clone())).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let url = start(LoggingWrapper::new(LogFormat::Common)).await;
+    get_each(&url).await;
+    let lines = captured.take();
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    let line = &lines[0];
+    //The remote address is the peer's ip and port when no proxy header names the client
+    assert!(line.starts_with("127.0.0.1:"), "{line}");
+    //Streamed bodies have no known size
+    assert!(
+        line.contains(" - - [")
+            && line.contains("] \"GET /hello?name=a HTTP/1.1\" 200 - \"hello\" "),
+        "{line}"
+    );
+    assert!(line.ends_with("ms"), "{line}");
+
+    let url = start(LoggingWrapper::new(LogFormat::Json)).await;
+    get_each(&url).await;
+    let lines = captured.take();
+    assert_eq!(lines.len(), 1, "{lines:?}");
+    let line: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(line["method"], "GET");
+    assert_eq!(line["path"], "/hello?name=a");
+    assert_eq!(line["service"], "hello");
+    assert!(line["remote"].as_str().unwrap().starts_with("127.0.0.1:"));
+    assert_eq!(line["status"], 200);
+    assert_eq!(line["size"], serde_json::Value::Null);
+    assert!(line["elapsed_ms"].as_f64().unwrap() >= 0.0);
+}
@@ -0,0 +1,113 @@
+use futures_util::StreamExt;
+use portfu::macros::client_websocket;
+use portfu::prelude::tokio_tungstenite::accept_async;
+use portfu::prelude::*;
+use std::io::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Reads until the server goes away
+#[client_websocket(
+    "ws://127.0.0.1:1/",
+    reconnect = true,
+    max_retries = 2,
+    backoff_ms = 50
+)]
+pub async fn follower(websocket: ClientWebSocket) -> Result<(), Error> {
+    let mut websocket = websocket;
+    while let Some(Ok(_)) = websocket.next().await {}
+    Ok(())
+}
+
+/// Completes the websocket handshake of every connection then drops it, counting them
+async fn dropping_server() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/", listener.local_addr().unwrap());
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counted = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            if let Ok(websocket) = accept_async(stream).await {
+                counted.fetch_add(1, Ordering::SeqCst);
+                drop(websocket);
+            }
+        }
+    });
+    (url, accepted)
+}
+
+/// A server on a free local port running only `task`
+fn server(task: follower) -> Server {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    ServerBuilder::default()
+        .host(address.ip().to_string())
+        .port(address.port())
+        .task(task)
+        .build()
+}
+
+#[tokio::test]
+async fn dropped_connections_are_reopened_until_shutdown() {
+    let (url, accepted) = dropping_server().await;
+    let connection = ClientConnection::default().url(url);
+    let server = server(follower {
+        connection: connection.clone(),
+    });
+    let run = server.run.clone();
+    let serving = tokio::spawn(server.run());
+    for _ in 0..200 {
+        if accepted.load(Ordering::SeqCst) >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    //Every dropped connection that was established starts the retries over
+    assert!(accepted.load(Ordering::SeqCst) >= 3);
+
+    //The task stops on the server's shutdown instead of holding it for the shutdown timeout
+    run.store(false, Ordering::Relaxed);
+    tokio::time::timeout(Duration::from_secs(5), serving)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(connection.state(), ClientState::Closed);
+}
+
+#[tokio::test]
+async fn refused_connections_give_up_after_the_retries() {
+    //A port that was free a moment ago and has nothing listening now
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let connection = ClientConnection::default().url(format!("ws://{address}/"));
+    let mut states = connection.subscribe();
+    let server = server(follower {
+        connection: connection.clone(),
+    });
+    tokio::spawn(server.run());
+    let mut seen = vec![];
+    while let Ok(Ok(())) = tokio::time::timeout(Duration::from_secs(5), states.changed()).await {
+        let state = *states.borrow_and_update();
+        seen.push(state);
+        if state == ClientState::Closed {
+            break;
+        }
+    }
+    let retries: Vec<_> = seen
+        .iter()
+        .filter(|state| matches!(state, ClientState::Reconnecting { .. }))
+        .collect();
+    assert_eq!(
+        retries,
+        vec![
+            &ClientState::Reconnecting { attempt: 1 },
+            &ClientState::Reconnecting { attempt: 2 }
+        ]
+    );
+    assert_eq!(seen.last(), Some(&ClientState::Closed));
+}
@@ -0,0 +1,73 @@
+//! Compiles every fixture in tests/compile_fail as a binary of a scratch crate depending on portfu
+//! and checks it is rejected with the message in its first line, `// error: <message>`, as a
+//! compile error rather than a macro panic.
+use std::path::Path;
+use std::process::Command;
+
+const SCRATCH_MANIFEST: &str = r#"[package]
+name = "portfu_compile_fail"
+version = "0.0.0"
+edition = "2021"
+publish = false
+
+[dependencies]
+portfu = { path = "PORTFU" }
+
+[workspace]
+"#;
+
+#[test]
+fn macros_reject_invalid_input() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let workspace = manifest_dir.parent().unwrap();
+    let scratch = workspace.join("target").join("compile_fail");
+    let bins = scratch.join("src").join("bin");
+    std::fs::create_dir_all(&bins).unwrap();
+    std::fs::write(
+        scratch.join("Cargo.toml"),
+        SCRATCH_MANIFEST.replace("PORTFU", &manifest_dir.to_string_lossy()),
+    )
+    .unwrap();
+    //The same versions as the workspace so nothing has to be resolved again
+    std::fs::copy(workspace.join("Cargo.lock"), scratch.join("Cargo.lock")).unwrap();
+    let mut fixtures: Vec<_> = std::fs::read_dir(manifest_dir.join("tests").join("compile_fail"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    fixtures.sort();
+    for fixture in &fixtures {
+        std::fs::copy(fixture, bins.join(fixture.file_name().unwrap())).unwrap();
+    }
+    let mut failures = vec![];
+    for fixture in &fixtures {
+        let name = fixture.file_stem().unwrap().to_string_lossy().to_string();
+        let source = std::fs::read_to_string(fixture).unwrap();
+        let expected = source
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("// error: "))
+            .unwrap_or_else(|| panic!("{name} does not start with `// error: <message>`"));
+        let output = Command::new(env!("CARGO"))
+            .args([
+                "check",
+                "--quiet",
+                "--message-format",
+                "short",
+                "--bin",
+                &name,
+            ])
+            .current_dir(&scratch)
+            .env("CARGO_TARGET_DIR", scratch.join("target"))
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            failures.push(format!("{name} compiled"));
+        } else if stderr.contains("proc macro panicked") {
+            failures.push(format!("{name} panicked instead of failing:\n{stderr}"));
+        } else if !stderr.contains(expected) {
+            failures.push(format!("{name} did not fail with `{expected}`:\n{stderr}"));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
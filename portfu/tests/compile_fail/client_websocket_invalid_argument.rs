@@ -0,0 +1,12 @@
+// error: Invalid Input Type for Websocket Client String
+use portfu::macros::client_websocket;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[client_websocket("ws://127.0.0.1:1/")]
+pub async fn client(name: String) -> Result<(), Error> {
+    drop(name);
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+// error: invalid url
+use portfu::macros::client_websocket;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[client_websocket("not a url")]
+pub async fn client(websocket: ClientWebSocket) -> Result<(), Error> {
+    drop(websocket);
+    Ok(())
+}
+
+fn main() {}
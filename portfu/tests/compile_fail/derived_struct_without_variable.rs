@@ -0,0 +1,19 @@
+// error: Path variable `id` of "/users/{id}" is not read by any argument; add one named `id` or set allow_unused_path_vars = true
+use portfu::macros::{get, FromRequest};
+use portfu::prelude::*;
+use std::io::Error;
+use std::net::SocketAddr;
+
+#[derive(FromRequest)]
+pub struct UserRequest {
+    #[from_request(var = "name")]
+    value: Path,
+    address: SocketAddr,
+}
+
+#[get("/users/{id}")]
+pub async fn user(request: UserRequest) -> Result<String, Error> {
+    Ok(format!("{} from {}", request.value.inner(), request.address))
+}
+
+fn main() {}
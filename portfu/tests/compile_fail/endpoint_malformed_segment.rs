@@ -0,0 +1,11 @@
+// error: contains malformed dynamic segment
+use portfu::macros::get;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[get("/users/{id")]
+pub async fn user() -> Result<String, Error> {
+    Ok(String::new())
+}
+
+fn main() {}
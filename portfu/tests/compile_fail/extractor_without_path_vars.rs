@@ -0,0 +1,21 @@
+// error: `Unlisted` does not declare the path variables it reads
+use portfu::macros::get;
+use portfu::pfcore::service::ServiceRequest;
+use portfu::pfcore::FromRequest;
+use portfu::prelude::*;
+use std::io::Error;
+
+pub struct Unlisted;
+#[portfu::prelude::async_trait::async_trait]
+impl<'a> FromRequest<'a> for Unlisted {
+    async fn from_request(_: &'a mut ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        Ok(Unlisted)
+    }
+}
+
+#[get("/users/{id}")]
+pub async fn user(_unlisted: Unlisted) -> Result<String, Error> {
+    Ok(String::new())
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+// error: Attribute flood_close_after needs flood_action = "close"
+use portfu::macros::websocket;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[websocket("/ws", flood_action = "drop", flood_close_after = 3)]
+pub async fn flood(websocket: WebSocket) -> Result<(), Error> {
+    websocket.next_message().await.map(|_| ())
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+// error: `iid` does not match a path variable in "/users/{id}", available: id, did you mean `id`?
+use portfu::macros::get;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[get("/users/{id}", allow_unused_path_vars = true)]
+pub async fn user(iid: Path) -> Result<String, Error> {
+    Ok(iid.inner())
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+// error: failed to read static files from
+use portfu::macros::static_files;
+
+#[static_files("does_not_exist/")]
+pub struct missing;
+
+fn main() {}
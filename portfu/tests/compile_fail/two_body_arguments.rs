@@ -0,0 +1,11 @@
+// error: `first` and `second` both read the request body, only one Body argument is allowed
+use portfu::macros::post;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[post("/upload")]
+pub async fn upload(first: Body<String>, second: Body<Vec<u8>>) -> Result<String, Error> {
+    Ok(format!("{} {}", first.inner(), second.inner().len()))
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+// error: Path variables not consumed by the handler: `id`; add arguments with matching names or set allow_unused_path_vars = true
+use portfu::macros::get;
+use std::io::Error;
+
+#[get("/users/{id}")]
+pub async fn user() -> Result<String, Error> {
+    Ok(String::new())
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+// error: must be the final segment
+use portfu::macros::websocket;
+use portfu::prelude::*;
+use std::io::Error;
+
+#[websocket("/rooms/{rest..}/join")]
+pub async fn join(websocket: WebSocket, rest: Path) -> Result<(), Error> {
+    let _ = rest;
+    websocket.next_message().await.map(|_| ())
+}
+
+fn main() {}
@@ -0,0 +1,37 @@
+use portfu::macros::{get, FromRequest};
+use portfu::pfcore::PathVars;
+use portfu::prelude::*;
+use std::io::Error;
+use std::net::SocketAddr;
+
+#[derive(FromRequest)]
+pub struct Names {
+    #[from_request(var = "name")]
+    value: Path,
+    address: SocketAddr,
+}
+
+#[derive(FromRequest)]
+pub struct UserRequest {
+    id: Path<u64>,
+    #[from_request(flatten)]
+    names: Names,
+}
+
+//Both variables are read through the struct so no allow_unused_path_vars is needed
+#[get("/users/{id}/{name}")]
+pub async fn user(request: UserRequest) -> Result<String, Error> {
+    Ok(format!(
+        "{} {} from {}",
+        request.id.inner(),
+        request.names.value.inner(),
+        request.names.address
+    ))
+}
+
+#[test]
+fn derived_structs_declare_their_path_vars() {
+    assert_eq!(Names::PATH_VARS, ["name"]);
+    assert_eq!(UserRequest::PATH_VARS, ["id", "name"]);
+    let _service: Service = user.into();
+}
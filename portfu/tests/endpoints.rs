@@ -0,0 +1,184 @@
+use portfu::macros::{get, post};
+use portfu::prelude::http::header::LOCATION;
+use portfu::prelude::http::StatusCode;
+use portfu::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+#[derive(Deserialize)]
+pub struct Page {
+    pub page: u32,
+    pub size: Option<u32>,
+}
+
+#[get("/items")]
+pub async fn items(query: Query<Page>) -> Result<String, Error> {
+    let page = query.inner();
+    Ok(format!("page {} of {}", page.page, page.size.unwrap_or(10)))
+}
+
+#[get("/search")]
+pub async fn search(query: Option<Query<Page>>) -> Result<String, Error> {
+    Ok(match query {
+        Some(query) => format!("page {}", query.inner().page),
+        None => "first page".to_string(),
+    })
+}
+
+#[get("/users/{id}")]
+pub async fn get_user(id: Path<i64>) -> Result<String, Error> {
+    Ok(format!("user {}", id.inner()))
+}
+
+#[derive(Serialize)]
+pub struct User {
+    pub id: u32,
+    pub name: String,
+}
+
+#[post("/users")]
+pub async fn create_user() -> Result<ResponseBuilder, Error> {
+    let user = User {
+        id: 5,
+        name: "ada".to_string(),
+    };
+    Ok(ResponseBuilder::new(StatusCode::CREATED)
+        .header(LOCATION, "/users/5")
+        .json(&user))
+}
+
+//The body comes first, the extractors after it still see the request
+#[post("/orders/{id}")]
+pub async fn mixed(
+    body: Body<String>,
+    id: Path<u32>,
+    query: Query<Page>,
+    orders: State<AtomicUsize>,
+    address: SocketAddr,
+) -> Result<String, Error> {
+    let count = orders.as_ref().fetch_add(1, Ordering::Relaxed) + 1;
+    Ok(format!(
+        "{} {} page {} order {count} from {}",
+        body.inner(),
+        id.inner(),
+        query.inner().page,
+        address.ip()
+    ))
+}
+
+/// Serves the endpoints on a free local port and returns its url
+async fn start(builder: ServerBuilder) -> String {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = builder
+        .host(address.ip().to_string())
+        .port(address.port())
+        .build();
+    tokio::spawn(server.run());
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    format!("http://{address}")
+}
+
+async fn get_text(url: String) -> (StatusCode, String) {
+    let response = reqwest::get(url).await.unwrap();
+    (response.status(), response.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn queries_are_required_unless_optional() {
+    let url = start(ServerBuilder::default().register(items).register(search)).await;
+    assert_eq!(
+        get_text(format!("{url}/items?page=2&size=5")).await,
+        (StatusCode::OK, "page 2 of 5".to_string())
+    );
+
+    let (status, body) = get_text(format!("{url}/items")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Missing query string"), "{body}");
+
+    let (status, body) = get_text(format!("{url}/items?size=5")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("missing field `page`"), "{body}");
+
+    let (status, body) = get_text(format!("{url}/items?page=two")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Failed to parse query string"), "{body}");
+
+    assert_eq!(
+        get_text(format!("{url}/search")).await,
+        (StatusCode::OK, "first page".to_string())
+    );
+    assert_eq!(
+        get_text(format!("{url}/search?page=3")).await,
+        (StatusCode::OK, "page 3".to_string())
+    );
+    let (status, _) = get_text(format!("{url}/search?page=two")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn typed_path_variables_are_parsed() {
+    let url = start(ServerBuilder::default().register(get_user)).await;
+    assert_eq!(
+        get_text(format!("{url}/users/42")).await,
+        (StatusCode::OK, "user 42".to_string())
+    );
+    assert_eq!(
+        get_text(format!("{url}/users/%2D7")).await,
+        (StatusCode::OK, "user -7".to_string())
+    );
+    let (status, body) = get_text(format!("{url}/users/abc")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Path variable id must be a i64"), "{body}");
+}
+
+#[tokio::test]
+async fn handlers_set_status_and_headers_through_response_builder() {
+    let url = start(ServerBuilder::default().register(create_user)).await;
+    let response = reqwest::Client::new()
+        .post(format!("{url}/users"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()[LOCATION], "/users/5");
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"id\":5,\"name\":\"ada\"}"
+    );
+}
+
+#[tokio::test]
+async fn shared_extractors_do_not_depend_on_argument_order() {
+    let url = start(
+        ServerBuilder::default()
+            .shared_state(AtomicUsize::new(0))
+            .register(mixed),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    for count in 1..=2 {
+        let response = client
+            .post(format!("{url}/orders/9?page=4"))
+            .body("widgets")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            format!("widgets 9 page 4 order {count} from 127.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use portfu::macros::{get, post};
+use portfu::pfcore::errors::{error_status, ErrorHandler};
+use portfu::pfcore::{IntoStreamBody, Json};
+use portfu::prelude::async_trait::async_trait;
+use portfu::prelude::http::{HeaderValue, StatusCode};
+use portfu::prelude::*;
+use serde::Deserialize;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+#[get("/fail")]
+pub async fn fail() -> Result<String, Error> {
+    Err(Error::other("the disk is on fire"))
+}
+
+#[post("/upload")]
+pub async fn upload(body: Body<String>) -> Result<String, Error> {
+    Ok(body.inner())
+}
+
+#[get("/kind/{kind}")]
+pub async fn fail_with(kind: Path) -> Result<String, Error> {
+    let kind = match kind.inner().as_str() {
+        "not_found" => ErrorKind::NotFound,
+        "invalid_input" => ErrorKind::InvalidInput,
+        "invalid_data" => ErrorKind::InvalidData,
+        "permission_denied" => ErrorKind::PermissionDenied,
+        "timed_out" => ErrorKind::TimedOut,
+        _ => ErrorKind::BrokenPipe,
+    };
+    Err(Error::new(kind, "failed"))
+}
+
+#[derive(Deserialize)]
+pub struct Order {
+    pub quantity: u32,
+}
+
+#[post("/orders")]
+pub async fn place_order(order: Body<Json<Order>>) -> Result<String, Error> {
+    Ok(order.inner().inner().quantity.to_string())
+}
+
+/// Answers every error as text, marking the response so the test can tell it ran
+struct Plain;
+#[async_trait]
+impl ErrorHandler for Plain {
+    async fn render(&self, error: Error, data: &mut ServiceData) {
+        *data.response.status_mut() = error_status(&error);
+        data.response
+            .headers_mut()
+            .insert("x-error-handler", HeaderValue::from_static("plain"));
+        *data.response.body_mut() = format!("handled: {error}").stream_body();
+    }
+}
+
+/// Serves the endpoints on a free local port and returns its url
+async fn start(builder: ServerBuilder) -> String {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = builder
+        .host(address.ip().to_string())
+        .port(address.port())
+        .build();
+    tokio::spawn(server.run());
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    format!("http://{address}")
+}
+
+#[tokio::test]
+async fn the_error_handler_shapes_failed_endpoints() {
+    let url = start(
+        ServerBuilder::default()
+            .error_handler(Arc::new(Plain))
+            .max_body_size(4)
+            .register(fail)
+            .register(upload),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let response = client.get(format!("{url}/fail")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()["x-error-handler"], "plain");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "handled: the disk is on fire"
+    );
+
+    //A declared length over the limit is refused before the handler and still rendered by it
+    let response = client
+        .post(format!("{url}/upload"))
+        .body("too long")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.headers()["x-error-handler"], "plain");
+    assert!(response.text().await.unwrap().starts_with("handled: "));
+
+    let response = client
+        .post(format!("{url}/upload"))
+        .body("ok")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("x-error-handler").is_none());
+    assert_eq!(response.text().await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn endpoint_errors_answer_the_status_of_their_kind() {
+    let url = start(
+        ServerBuilder::default()
+            .register(fail_with)
+            .register(place_order),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    for (kind, status) in [
+        ("not_found", StatusCode::NOT_FOUND),
+        ("invalid_input", StatusCode::BAD_REQUEST),
+        ("invalid_data", StatusCode::BAD_REQUEST),
+        ("permission_denied", StatusCode::FORBIDDEN),
+        ("timed_out", StatusCode::REQUEST_TIMEOUT),
+        ("broken_pipe", StatusCode::INTERNAL_SERVER_ERROR),
+    ] {
+        let response = client
+            .get(format!("{url}/kind/{kind}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{kind}");
+    }
+
+    let response = client
+        .post(format!("{url}/orders"))
+        .header("content-type", "application/json")
+        .body(r#"{"quantity": "many"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let envelope: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(envelope["status"], 400);
+    assert_eq!(envelope["details"]["line"], 1);
+
+    let response = client
+        .post(format!("{url}/orders"))
+        .header("content-type", "application/json")
+        .body(r#"{"quantity": 3}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "3");
+}
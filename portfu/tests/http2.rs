@@ -0,0 +1,91 @@
+use portfu::macros::get;
+use portfu::prelude::*;
+use std::io::Error;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+const CA: &str = include_str!("../../portfu_core/test_data/ca.pem");
+const CERT: &str = include_str!("../../portfu_core/test_data/localhost.pem");
+const KEY: &str = include_str!("../../portfu_core/test_data/localhost.key");
+
+#[get("/hello")]
+pub async fn hello() -> Result<String, Error> {
+    Ok("hello".to_string())
+}
+
+/// Serves `/hello` over TLS on a free local port and returns its https url
+async fn start(http2: bool) -> String {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = ServerBuilder::default()
+        .host(address.ip().to_string())
+        .port(address.port())
+        .ssl_config(Some(SslConfig {
+            domain: "localhost".to_string(),
+            key: KEY.into(),
+            certs: CERT.to_string(),
+            root_certs: CA.to_string(),
+        }))
+        .http2(http2)
+        .register(hello)
+        .build();
+    tokio::spawn(server.run());
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    format!("https://localhost:{}", address.port())
+}
+
+fn client(h2_only: bool) -> reqwest::Client {
+    let builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_pem(CA.as_bytes()).unwrap())
+        .resolve("localhost", "127.0.0.1:0".parse().unwrap());
+    match h2_only {
+        true => builder.http2_prior_knowledge(),
+        false => builder.http1_only(),
+    }
+    .build()
+    .unwrap()
+}
+
+#[tokio::test]
+async fn h2_only_clients_are_served_over_tls() {
+    let url = start(true).await;
+    let response = client(true)
+        .get(format!("{url}/hello"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.version(), http::Version::HTTP_2);
+    assert_eq!(response.text().await.unwrap(), "hello");
+
+    //http/1.1 stays available on the same listener
+    let response = client(false)
+        .get(format!("{url}/hello"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.version(), http::Version::HTTP_11);
+    assert_eq!(response.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn h2_is_off_by_default() {
+    let url = start(false).await;
+    assert!(client(true)
+        .get(format!("{url}/hello"))
+        .send()
+        .await
+        .is_err());
+    let response = client(false)
+        .get(format!("{url}/hello"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "hello");
+}
@@ -0,0 +1,391 @@
+use futures_util::{SinkExt, StreamExt};
+use portfu::macros::websocket;
+use portfu::prelude::http::Response;
+use portfu::prelude::tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use portfu::prelude::tokio_tungstenite::tungstenite::Message;
+use portfu::prelude::tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use portfu::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::select;
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn echo(websocket: WebSocket) -> Result<(), Error> {
+    loop {
+        match websocket.next_message().await? {
+            Some(Message::Text(text)) => websocket.send(Message::Text(text)).await?,
+            Some(Message::Close(_)) => return Ok(()),
+            _ => tokio::time::sleep(Duration::from_millis(1)).await,
+        }
+    }
+}
+
+#[websocket(
+    "/kicks",
+    messages_per_sec = 5,
+    flood_action = "close",
+    flood_close_after = 3
+)]
+pub async fn kicks(websocket: WebSocket) -> Result<(), Error> {
+    echo(websocket).await
+}
+
+#[websocket("/drops", messages_per_sec = 5, flood_action = "drop")]
+pub async fn drops(websocket: WebSocket) -> Result<(), Error> {
+    echo(websocket).await
+}
+
+#[websocket("/small", max_message_size = "16")]
+pub async fn small(websocket: WebSocket) -> Result<(), Error> {
+    echo(websocket).await
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Move {
+    player: String,
+    x: u32,
+}
+
+/// Answers every move with the next one, and undecodable messages with their error
+#[websocket("/moves")]
+pub async fn moves(websocket: WebSocket) -> Result<(), Error> {
+    loop {
+        match websocket.next_json::<Move>().await {
+            Ok(Some(next)) => {
+                websocket
+                    .send_json(&Move {
+                        x: next.x + 1,
+                        ..next
+                    })
+                    .await?
+            }
+            Ok(None) => tokio::time::sleep(Duration::from_millis(1)).await,
+            Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                websocket.send(Message::Text(e.to_string())).await?
+            }
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// `join <room>` tags the connection, anything else goes to its room, or to everyone else in it
+/// when prefixed with `others `
+#[websocket("/chat")]
+pub async fn chat(websocket: WebSocket) -> Result<(), Error> {
+    loop {
+        match websocket.next_message().await? {
+            Some(Message::Text(text)) => {
+                if let Some(room) = text.strip_prefix("join ") {
+                    websocket.set_tag("room", room).await;
+                    websocket
+                        .send(Message::Text(format!("joined {room}")))
+                        .await?;
+                    continue;
+                }
+                let room = websocket.tag("room").await.unwrap_or_default();
+                match text.strip_prefix("others ") {
+                    Some(text) => {
+                        let message = Message::Text(text.to_string());
+                        websocket
+                            .broadcast_filtered(message, |uuid, tags| {
+                                uuid != websocket.uuid.as_ref() && tags.get("room") == Some(&room)
+                            })
+                            .await?
+                    }
+                    None => {
+                        websocket
+                            .broadcast_tagged(Message::Text(text), "room", &room)
+                            .await?
+                    }
+                }
+            }
+            Some(Message::Close(_)) => return Ok(()),
+            _ => tokio::time::sleep(Duration::from_millis(1)).await,
+        }
+    }
+}
+
+/// Serves the endpoints on one free local port and returns its address
+async fn start(peers: Peers) -> String {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = ServerBuilder::default()
+        .host(address.ip().to_string())
+        .port(address.port())
+        .register(kicks {
+            peers: peers.clone(),
+        })
+        .register(drops {
+            peers: peers.clone(),
+        })
+        .register(small {
+            peers: peers.clone(),
+        })
+        .register(moves {
+            peers: peers.clone(),
+        })
+        .register(chat { peers })
+        .build();
+    tokio::spawn(server.run());
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    address.to_string()
+}
+
+async fn connect(address: &str, path: &str) -> Client {
+    connect_async(format!("ws://{address}{path}"))
+        .await
+        .unwrap()
+        .0
+}
+
+/// Every message read until the connection ends or nothing arrives for `quiet`
+async fn read_all(client: &mut Client, quiet: Duration) -> Vec<Message> {
+    let mut messages = vec![];
+    while let Ok(Some(Ok(message))) = tokio::time::timeout(quiet, client.next()).await {
+        messages.push(message);
+    }
+    messages
+}
+
+fn texts(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .filter(|m| matches!(m, Message::Text(_)))
+        .count()
+}
+
+#[tokio::test]
+async fn flooding_client_is_closed_while_others_are_served() {
+    let peers = Peers::default();
+    let address = start(peers.clone()).await;
+    let mut compliant = connect(&address, "/kicks").await;
+    let mut flooding = connect(&address, "/kicks").await;
+    for index in 0..50 {
+        let _ = flooding.send(Message::Text(format!("flood {index}"))).await;
+    }
+    let received = read_all(&mut flooding, Duration::from_secs(2)).await;
+    let close = received.iter().find_map(|m| match m {
+        Message::Close(Some(frame)) => Some(frame.code),
+        _ => None,
+    });
+    assert_eq!(close, Some(CloseCode::Policy));
+    assert!(texts(&received) <= 5);
+    assert_eq!(peers.flood_stats().kicked, 1);
+
+    for index in 0..3 {
+        compliant
+            .send(Message::Text(format!("hello {index}")))
+            .await
+            .unwrap();
+        let reply = read_all(&mut compliant, Duration::from_millis(300)).await;
+        assert_eq!(reply, vec![Message::Text(format!("hello {index}"))]);
+    }
+}
+
+#[tokio::test]
+async fn dropped_messages_warn_once_per_burst() {
+    let peers = Peers::default();
+    let address = start(peers.clone()).await;
+    let mut client = connect(&address, "/drops").await;
+    for index in 0..20 {
+        client
+            .send(Message::Text(format!("burst {index}")))
+            .await
+            .unwrap();
+    }
+    let received = read_all(&mut client, Duration::from_millis(500)).await;
+    let pings = received
+        .iter()
+        .filter(|m| matches!(m, Message::Ping(_)))
+        .count();
+    assert_eq!(pings, 1);
+    assert_eq!(texts(&received), 5);
+    //The pong answering the warning counts against the limit as well
+    assert!(peers.flood_stats().dropped >= 15);
+
+    //The connection stays open once the bucket refills
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    client
+        .send(Message::Text("after".to_string()))
+        .await
+        .unwrap();
+    let reply = read_all(&mut client, Duration::from_millis(300)).await;
+    assert_eq!(reply, vec![Message::Text("after".to_string())]);
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_websockets_and_closes_them() {
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let server = ServerBuilder::default()
+        .host(address.ip().to_string())
+        .port(address.port())
+        .register(drops {
+            peers: Peers::default(),
+        })
+        .build();
+    let run = server.run.clone();
+    let open = server
+        .shared_state
+        .get::<portfu::pfcore::server::OpenConnections>()
+        .cloned()
+        .unwrap();
+    let serving = tokio::spawn(server.run());
+    for _ in 0..100 {
+        if TcpStream::connect(address).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut client = connect(&address.to_string(), "/drops").await;
+    client
+        .send(Message::Text("hello".to_string()))
+        .await
+        .unwrap();
+    let reply = read_all(&mut client, Duration::from_millis(300)).await;
+    assert_eq!(reply, vec![Message::Text("hello".to_string())]);
+    //The http connection was handed to the websocket, which is still counted
+    assert_eq!(open.count(), 1);
+
+    run.store(false, std::sync::atomic::Ordering::Relaxed);
+    let received = read_all(&mut client, Duration::from_secs(2)).await;
+    let close = received.iter().find_map(|m| match m {
+        Message::Close(Some(frame)) => Some(frame.code),
+        _ => None,
+    });
+    assert_eq!(close, Some(CloseCode::Away));
+    tokio::time::timeout(Duration::from_secs(5), serving)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(open.count(), 0);
+}
+
+#[tokio::test]
+async fn oversized_messages_close_with_1009() {
+    let address = start(Peers::default()).await;
+    let mut client = connect(&address, "/small").await;
+    client
+        .send(Message::Text("within limit".to_string()))
+        .await
+        .unwrap();
+    let reply = read_all(&mut client, Duration::from_millis(300)).await;
+    assert_eq!(reply, vec![Message::Text("within limit".to_string())]);
+
+    client.send(Message::Text("x".repeat(100))).await.unwrap();
+    let received = read_all(&mut client, Duration::from_secs(2)).await;
+    let close = received.iter().find_map(|m| match m {
+        Message::Close(Some(frame)) => Some(frame.code),
+        _ => None,
+    });
+    assert_eq!(close, Some(CloseCode::Size));
+    assert_eq!(texts(&received), 0);
+}
+
+#[tokio::test]
+async fn json_messages_round_trip_and_bad_ones_are_described() {
+    let address = start(Peers::default()).await;
+    let mut client = connect(&address, "/moves").await;
+    let sent = Move {
+        player: "a".to_string(),
+        x: 1,
+    };
+    client
+        .send(Message::Text(serde_json::to_string(&sent).unwrap()))
+        .await
+        .unwrap();
+    let reply = read_all(&mut client, Duration::from_millis(300)).await;
+    let replies: Vec<Move> = reply
+        .iter()
+        .map(|m| serde_json::from_str(m.to_text().unwrap()).unwrap())
+        .collect();
+    let next = Move {
+        player: "a".to_string(),
+        x: 2,
+    };
+    assert_eq!(replies, vec![next]);
+
+    //Binary JSON decodes as well, control frames are skipped
+    client.send(Message::Ping(vec![1])).await.unwrap();
+    client
+        .send(Message::Binary(serde_json::to_vec(&sent).unwrap()))
+        .await
+        .unwrap();
+    let reply = read_all(&mut client, Duration::from_millis(300)).await;
+    assert_eq!(texts(&reply), 1);
+
+    client
+        .send(Message::Text("not json at all".to_string()))
+        .await
+        .unwrap();
+    let reply = read_all(&mut client, Duration::from_millis(300)).await;
+    match reply.as_slice() {
+        [Message::Text(error)] => {
+            assert!(error.starts_with("Invalid JSON message \"not json at all\""))
+        }
+        _ => panic!("expected the decode error, got {reply:?}"),
+    }
+}
+
+#[tokio::test]
+async fn room_broadcasts_reach_only_that_room() {
+    let address = start(Peers::default()).await;
+    let mut clients = vec![];
+    for room in ["a", "a", "b"] {
+        let mut client = connect(&address, "/chat").await;
+        client
+            .send(Message::Text(format!("join {room}")))
+            .await
+            .unwrap();
+        let reply = read_all(&mut client, Duration::from_millis(200)).await;
+        assert_eq!(reply, vec![Message::Text(format!("joined {room}"))]);
+        clients.push(client);
+    }
+
+    clients[0]
+        .send(Message::Text("hello a".to_string()))
+        .await
+        .unwrap();
+    let hello = vec![Message::Text("hello a".to_string())];
+    assert_eq!(
+        read_all(&mut clients[0], Duration::from_millis(300)).await,
+        hello
+    );
+    assert_eq!(
+        read_all(&mut clients[1], Duration::from_millis(300)).await,
+        hello
+    );
+    assert_eq!(
+        read_all(&mut clients[2], Duration::from_millis(300)).await,
+        vec![]
+    );
+
+    clients[1]
+        .send(Message::Text("others psst".to_string()))
+        .await
+        .unwrap();
+    let psst = vec![Message::Text("psst".to_string())];
+    assert_eq!(
+        read_all(&mut clients[0], Duration::from_millis(300)).await,
+        psst
+    );
+    assert_eq!(
+        read_all(&mut clients[1], Duration::from_millis(300)).await,
+        vec![]
+    );
+    assert_eq!(
+        read_all(&mut clients[2], Duration::from_millis(300)).await,
+        vec![]
+    );
+}
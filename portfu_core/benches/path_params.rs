@@ -0,0 +1,44 @@
+//! Compares capturing every path variable once with matching the route per variable,
+//! run with `cargo bench -p portfu_core --bench path_params`
+use portfu_core::routes::Route;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROUNDS: usize = 20_000;
+const NAMES: [&str; 3] = ["org", "repo", "path"];
+
+fn time<F: FnMut() -> usize>(mut lookup: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(lookup());
+    }
+    start.elapsed() / ROUNDS as u32
+}
+
+fn main() {
+    let route = Route::new("/orgs/{org}/repos/{repo}/files/{path..}".to_string());
+    for (label, path) in [
+        ("plain", "/orgs/acme/repos/portfu/files/src/lib.rs"),
+        (
+            "encoded",
+            "/orgs/acme%20inc/repos/port%2Dfu/files/src/caf%C3%A9.rs",
+        ),
+    ] {
+        let per_variable = time(|| {
+            NAMES
+                .iter()
+                .filter_map(|name| route.extract(path, name))
+                .map(|value| value.len())
+                .sum()
+        });
+        let captured = time(|| {
+            let params = route.params(path).unwrap_or_default();
+            NAMES
+                .iter()
+                .filter_map(|name| params.get(name))
+                .map(str::len)
+                .sum()
+        });
+        println!("{label}: extract per variable {per_variable:?}, params once {captured:?}");
+    }
+}
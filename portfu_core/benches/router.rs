@@ -0,0 +1,73 @@
+//! Compares Router::candidates with checking every route, run with `cargo bench -p portfu_core`
+use portfu_core::routes::{Route, Router};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROUTES: usize = 500;
+const ROUNDS: usize = 200;
+
+fn routes() -> Vec<Route> {
+    (0..ROUTES)
+        .map(|i| {
+            Route::new(match i % 5 {
+                0 => format!("/assets/file{i}.js"),
+                1 => format!("/docs/section{}/page{i}.html", i % 10),
+                2 => format!("/api/resource{i}/{{id}}"),
+                3 => format!("/api/resource{i}/{{id}}/items/{{item}}"),
+                _ => format!("/download{i}/{{path..}}"),
+            })
+        })
+        .collect()
+}
+
+fn paths() -> Vec<String> {
+    (0..ROUTES)
+        .map(|i| match i % 5 {
+            0 => format!("/assets/file{i}.js"),
+            1 => format!("/docs/section{}/page{i}.html", i % 10),
+            2 => format!("/api/resource{i}/42"),
+            3 => format!("/api/resource{i}/42/items/7"),
+            _ => format!("/download{i}/a/b/c.txt"),
+        })
+        .chain(["/missing".to_string(), "/api/unknown/1".to_string()])
+        .collect()
+}
+
+fn time<F: FnMut(&str) -> usize>(paths: &[String], mut lookup: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for path in paths {
+            black_box(lookup(path));
+        }
+    }
+    start.elapsed() / (ROUNDS * paths.len()) as u32
+}
+
+fn main() {
+    let routes = routes();
+    let paths = paths();
+    let mut router = Router::default();
+    for (i, route) in routes.iter().enumerate() {
+        router.insert(route, i);
+    }
+    let linear = |path: &str| {
+        routes
+            .iter()
+            .position(|r| r.matches(path))
+            .unwrap_or(usize::MAX)
+    };
+    let tree = |path: &str| {
+        router
+            .candidates(path)
+            .into_iter()
+            .copied()
+            .find(|i| routes[*i].matches(path))
+            .unwrap_or(usize::MAX)
+    };
+    for path in &paths {
+        assert_eq!(linear(path), tree(path), "{path}");
+    }
+    println!("{ROUTES} routes, {} paths", paths.len());
+    println!("linear scan: {:?} per lookup", time(&paths, linear));
+    println!("router:      {:?} per lookup", time(&paths, tree));
+}
@@ -0,0 +1,136 @@
+use log::error;
+use serde::Serialize;
+use std::io::Error;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Runs blocking work on tokio's blocking threads with at most `size` jobs in flight,
+/// so bursts of expensive work queue here instead of exhausting the blocking thread pool.
+/// The server registers one as shared state, extract it with `State<BlockingPool>`.
+#[derive(Debug)]
+pub struct BlockingPool {
+    size: usize,
+    permits: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    running: Arc<AtomicUsize>,
+    completed: Arc<AtomicU64>,
+    total_wait_micros: AtomicU64,
+}
+impl Default for BlockingPool {
+    fn default() -> Self {
+        Self::new(default_pool_size())
+    }
+}
+impl BlockingPool {
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            size,
+            permits: Arc::new(Semaphore::new(size)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            running: Arc::new(AtomicUsize::new(0)),
+            completed: Arc::new(AtomicU64::new(0)),
+            total_wait_micros: AtomicU64::new(0),
+        }
+    }
+    pub async fn run<F, T>(&self, func: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued_at = Instant::now();
+        let queued = Gauge::increment(self.queued.clone());
+        let permit = self.permits.clone().acquire_owned().await;
+        drop(queued);
+        let permit = permit.map_err(|e| Error::other(format!("Blocking Pool Closed: {e:?}")))?;
+        self.total_wait_micros
+            .fetch_add(queued_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+        //Counted on the blocking thread so a cancelled caller or a panic cannot leave it raised
+        let running = Gauge::increment(self.running.clone());
+        let completed = self.completed.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = func();
+            completed.fetch_add(1, Ordering::Relaxed);
+            drop(running);
+            drop(permit);
+            result
+        })
+        .await
+        .map_err(|e| {
+            error!("Blocking task failed: {e:?}");
+            Error::other(format!("Blocking task failed: {e:?}"))
+        })
+    }
+    pub fn stats(&self) -> BlockingPoolStats {
+        let completed = self.completed.load(Ordering::Relaxed);
+        let total_wait = self.total_wait_micros.load(Ordering::Relaxed);
+        BlockingPoolStats {
+            size: self.size,
+            queued: self.queued.load(Ordering::Relaxed),
+            running: self.running.load(Ordering::Relaxed),
+            completed,
+            average_wait: Duration::from_micros(total_wait.checked_div(completed).unwrap_or(0)),
+        }
+    }
+}
+
+/// Decrements the counter when dropped
+struct Gauge(Arc<AtomicUsize>);
+impl Gauge {
+    fn increment(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockingPoolStats {
+    pub size: usize,
+    pub queued: usize,
+    pub running: usize,
+    pub completed: u64,
+    pub average_wait: Duration,
+}
+
+pub fn default_pool_size() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[tokio::test]
+    async fn cancelled_and_failed_jobs_release_their_counts() {
+        let pool = BlockingPool::new(1);
+        let (release, wait) = mpsc::channel::<()>();
+        let busy = pool.run(move || wait.recv().unwrap());
+        tokio::pin!(busy);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut busy)
+            .await
+            .is_err());
+        assert_eq!(pool.stats().running, 1);
+        //Waits for the only permit and is cancelled while queued
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), pool.run(|| ()))
+                .await
+                .is_err()
+        );
+        assert_eq!(pool.stats().queued, 0);
+        release.send(()).unwrap();
+        busy.await.unwrap();
+        assert!(pool.run(|| panic!("job failed")).await.is_err());
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running, stats.completed), (0, 0, 1));
+    }
+}
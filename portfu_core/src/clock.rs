@@ -0,0 +1,64 @@
+use crate::service::ServiceRequest;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Where time dependent components read the time, swap in a ManualClock to step through expiry without sleeping
+pub trait Clock: Send + Sync {
+    /// Monotonic time for measuring durations and TTLs
+    fn now(&self) -> Instant;
+    /// Wall clock time for timestamps shared outside the process
+    fn now_utc(&self) -> SystemTime;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn now_utc(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when advanced
+pub struct ManualClock {
+    start: Instant,
+    start_utc: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            start_utc: SystemTime::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+}
+impl ManualClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+    fn now_utc(&self) -> SystemTime {
+        self.start_utc + self.elapsed()
+    }
+}
+
+/// The clock set with ServerBuilder::clock, SystemClock when none was set
+pub fn clock(request: &ServiceRequest) -> &dyn Clock {
+    match request.get::<SharedClock>() {
+        Some(clock) => clock.as_ref(),
+        None => &SystemClock,
+    }
+}
@@ -0,0 +1,89 @@
+use serde_json::{Map, Value};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where runtime changes made through the admin endpoints persist across restarts
+pub trait DataStore: Send + Sync {
+    fn load(&self, key: &str) -> Result<Option<Value>, Error>;
+    fn store(&self, key: &str, value: Value) -> Result<(), Error>;
+}
+
+pub type SharedDataStore = Arc<dyn DataStore>;
+
+#[derive(Default)]
+pub struct MemoryDataStore {
+    values: Mutex<Map<String, Value>>,
+}
+impl DataStore for MemoryDataStore {
+    fn load(&self, key: &str) -> Result<Option<Value>, Error> {
+        let values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(values.get(key).cloned())
+    }
+    fn store(&self, key: &str, value: Value) -> Result<(), Error> {
+        let mut values = self.values.lock().unwrap_or_else(|e| e.into_inner());
+        values.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Keeps every key in one JSON object, rewritten through a temp file so a crash never leaves half a document
+pub struct FileDataStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+impl FileDataStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+    fn read(&self) -> Result<Map<String, Value>, Error> {
+        match std::fs::read(&self.path) {
+            Ok(document) => serde_json::from_slice(&document).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid data store {}: {e}", self.path.display()),
+                )
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Map::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+impl DataStore for FileDataStore {
+    fn load(&self, key: &str) -> Result<Option<Value>, Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(self.read()?.remove(key))
+    }
+    fn store(&self, key: &str, value: Value) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut values = self.read()?;
+        values.insert(key.to_string(), value);
+        let document = serde_json::to_vec_pretty(&values).map_err(Error::other)?;
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, document)?;
+        std::fs::rename(&temp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("pf_store_{}.json", uuid::Uuid::new_v4()));
+        let store = FileDataStore::new(&path);
+        assert_eq!(store.load("a").unwrap(), None);
+        store.store("a", Value::Bool(true)).unwrap();
+        store.store("b", Value::from(2)).unwrap();
+        store.store("a", Value::Bool(false)).unwrap();
+
+        let reopened = FileDataStore::new(&path);
+        assert_eq!(reopened.load("a").unwrap(), Some(Value::Bool(false)));
+        assert_eq!(reopened.load("b").unwrap(), Some(Value::from(2)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
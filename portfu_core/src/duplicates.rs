@@ -0,0 +1,354 @@
+use crate::errors::HttpError;
+use crate::service::ServiceRequest;
+use http::StatusCode;
+use serde::de::value::{Error as ValueError, MapDeserializer, SeqDeserializer};
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, Error as DeError, IntoDeserializer, MapAccess, SeqAccess,
+    Visitor,
+};
+use serde::{forward_to_deserialize_any, Deserializer};
+use serde_json::map::Entry;
+use serde_json::{Map, Number, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Formatter;
+use std::io::Error;
+
+/// How repeated keys in query strings and JSON objects are resolved.
+/// Without a policy each extractor keeps its parser's own behavior.
+/// Set for the server with ServerBuilder::duplicate_keys or per endpoint with `duplicates = "reject"`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    First,
+    Last,
+    /// 400 naming the repeated key
+    Reject,
+    /// Keeps every value, repeated JSON keys become an array
+    Collect,
+}
+
+/// The policy for this request, an endpoint's own policy wins over the server's
+pub fn duplicate_keys(request: &ServiceRequest) -> Option<DuplicateKeys> {
+    request.get::<DuplicateKeys>().copied()
+}
+
+fn rejected(key: &str) -> String {
+    format!("Duplicate key {key}")
+}
+
+/// Resolves repeated `key=value` pairs before they reach the query or form parser.
+/// Collect keeps every pair, see from_collected_pairs.
+pub fn resolve_pairs(
+    pairs: Vec<(String, String)>,
+    policy: DuplicateKeys,
+) -> Result<Vec<(String, String)>, Error> {
+    if policy == DuplicateKeys::Collect {
+        return Ok(pairs);
+    }
+    let mut positions: HashMap<String, usize> = HashMap::with_capacity(pairs.len());
+    let mut resolved: Vec<(String, String)> = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        match positions.get(&key) {
+            None => {
+                positions.insert(key.clone(), resolved.len());
+                resolved.push((key, value));
+            }
+            Some(position) => match policy {
+                DuplicateKeys::First | DuplicateKeys::Collect => {}
+                DuplicateKeys::Last => resolved[*position].1 = value,
+                DuplicateKeys::Reject => {
+                    return Err(HttpError::new(StatusCode::BAD_REQUEST, rejected(&key)).into());
+                }
+            },
+        }
+    }
+    Ok(resolved)
+}
+
+/// Deserializes pairs keeping every value of a repeated key, for sequence fields like `Vec<u32>`.
+/// Values are parsed into the field's type the way serde_urlencoded does.
+pub fn from_collected_pairs<T: DeserializeOwned>(
+    pairs: Vec<(String, String)>,
+) -> Result<T, serde::de::value::Error> {
+    let mut positions: HashMap<String, usize> = HashMap::with_capacity(pairs.len());
+    let mut collected: Vec<(String, Vec<String>)> = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        match positions.get(&key) {
+            Some(position) => collected[*position].1.push(value),
+            None => {
+                positions.insert(key.clone(), collected.len());
+                collected.push((key, vec![value]));
+            }
+        }
+    }
+    T::deserialize(MapDeserializer::new(
+        collected
+            .into_iter()
+            .map(|(key, values)| (key, Values(values))),
+    ))
+}
+
+/// Every value of one key, a sequence when the field asks for one
+struct Values(Vec<String>);
+impl<'de> IntoDeserializer<'de, ValueError> for Values {
+    type Deserializer = Self;
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+macro_rules! single_value {
+    ($($method:ident)*) => {
+        $(fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+            match <[String; 1]>::try_from(self.0) {
+                Ok([value]) => Part(value).$method(visitor),
+                Err(values) => Values(values).deserialize_seq(visitor),
+            }
+        })*
+    };
+}
+impl<'de> Deserializer<'de> for Values {
+    type Error = ValueError;
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        visitor.visit_seq(SeqDeserializer::new(self.0.into_iter().map(Part)))
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        match <[String; 1]>::try_from(self.0) {
+            Ok([value]) => Part(value).deserialize_enum(name, variants, visitor),
+            Err(values) => Values(values).deserialize_seq(visitor),
+        }
+    }
+    single_value! {
+        deserialize_any deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_i64 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64
+    }
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// One value, parsed when the field is not a string
+struct Part(String);
+impl<'de> IntoDeserializer<'de, ValueError> for Part {
+    type Deserializer = Self;
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+macro_rules! parse_part {
+    ($($method:ident => $visit:ident,)*) => {
+        $(fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+            match self.0.parse() {
+                Ok(value) => visitor.$visit(value),
+                Err(_) => self.deserialize_any(visitor),
+            }
+        })*
+    };
+}
+impl<'de> Deserializer<'de> for Part {
+    type Error = ValueError;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        visitor.visit_string(self.0)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+    parse_part! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Parses `body` applying `policy` to the keys of every object
+pub fn resolve_json(body: &[u8], policy: DuplicateKeys) -> Result<Value, serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value = Resolve(policy).deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+#[derive(Copy, Clone)]
+struct Resolve(DuplicateKeys);
+impl<'de> DeserializeSeed<'de> for Resolve {
+    type Value = Value;
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+impl<'de> Visitor<'de> for Resolve {
+    type Value = Value;
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+    fn visit_bool<E: DeError>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+    fn visit_i64<E: DeError>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+    fn visit_u64<E: DeError>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+    fn visit_f64<E: DeError>(self, v: f64) -> Result<Value, E> {
+        Ok(Number::from_f64(v).map_or(Value::Null, Value::Number))
+    }
+    fn visit_str<E: DeError>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_string()))
+    }
+    fn visit_string<E: DeError>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+    fn visit_unit<E: DeError>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = vec![];
+        while let Some(value) = seq.next_element_seed(self)? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Value, A::Error> {
+        let mut map = Map::new();
+        let mut collected = HashSet::new();
+        while let Some(key) = access.next_key::<String>()? {
+            let value = access.next_value_seed(self)?;
+            match map.entry(key) {
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                }
+                Entry::Occupied(mut entry) => match self.0 {
+                    DuplicateKeys::First => {}
+                    DuplicateKeys::Last => {
+                        entry.insert(value);
+                    }
+                    DuplicateKeys::Reject => return Err(A::Error::custom(rejected(entry.key()))),
+                    DuplicateKeys::Collect => {
+                        if collected.insert(entry.key().clone()) {
+                            let first = entry.get_mut().take();
+                            entry.insert(Value::Array(vec![first, value]));
+                        } else if let Value::Array(values) = entry.get_mut() {
+                            values.push(value);
+                        }
+                    }
+                },
+            }
+        }
+        Ok(Value::Object(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::BodyType;
+    use crate::{FromBody, Json};
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+    use serde::Deserialize;
+
+    fn pairs(query: &str) -> Vec<(String, String)> {
+        form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    #[test]
+    fn pairs_follow_the_policy() {
+        let query = pairs("a=1&b=2&a=3");
+        let first = resolve_pairs(query.clone(), DuplicateKeys::First).unwrap();
+        assert_eq!(first, pairs("a=1&b=2"));
+        let last = resolve_pairs(query.clone(), DuplicateKeys::Last).unwrap();
+        assert_eq!(last, pairs("a=3&b=2"));
+        assert!(resolve_pairs(query, DuplicateKeys::Reject).is_err());
+    }
+
+    #[test]
+    fn many_distinct_keys_resolve() {
+        let query: Vec<(String, String)> = (0..100_000)
+            .map(|i| (format!("key{i}"), i.to_string()))
+            .collect();
+        let resolved = resolve_pairs(query.clone(), DuplicateKeys::Reject).unwrap();
+        assert_eq!(resolved, query);
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        id: Vec<u32>,
+        name: String,
+        exact: Option<bool>,
+        tag: Vec<String>,
+    }
+
+    #[test]
+    fn collected_pairs_fill_sequences() {
+        let search: Search =
+            from_collected_pairs(pairs("id=1&name=x&id=2&exact=true&tag=a")).unwrap();
+        assert_eq!(
+            search,
+            Search {
+                id: vec![1, 2],
+                name: "x".to_string(),
+                exact: Some(true),
+                tag: vec!["a".to_string()],
+            }
+        );
+        assert!(from_collected_pairs::<Search>(pairs("id=x&name=x&tag=a")).is_err());
+        assert!(from_collected_pairs::<Search>(pairs("id=1&name=x&name=y&tag=a")).is_err());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Count {
+        #[allow(dead_code)]
+        count: u32,
+    }
+
+    #[tokio::test]
+    async fn json_type_errors_keep_their_position() {
+        let body = "{\n  \"count\": 1,\n  \"count\": \"many\"\n}";
+        let mut full = Full::new(Bytes::from(body));
+        let error = Json::<Count>::from_body_with(
+            &mut BodyType::Sized(&mut full),
+            Some(DuplicateKeys::Last),
+        )
+        .await
+        .err()
+        .unwrap();
+        let details = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<HttpError>())
+            .and_then(|e| e.details.clone())
+            .unwrap();
+        assert_eq!(details["line"], 3);
+        assert!(details["column"].as_u64().unwrap() > 0);
+    }
+}
@@ -1,5 +1,60 @@
+use crate::service::ConsumedBodyType;
+use crate::{IntoStreamBody, ServiceBody};
+use http_body_util::BodyExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
 pub enum EditResult {
     NotEditable,
     Success(Vec<u8>),
     Failed(String),
+    /// The new value was rejected, reported to clients as 422
+    Invalid(String),
+    /// The expected hash is stale, carries the content_hash of the current value, reported as 412
+    Conflict(String),
+}
+
+/// A previous value kept before an edit. Versions are listed newest first, their names sort oldest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct EditVersion {
+    pub version: String,
+    pub size: u64,
+}
+
+/// Streamed counterpart of EditResult for current values
+pub enum EditStream {
+    NotEditable,
+    /// `hash` is the content_hash of the full value and serves as its version
+    Success {
+        body: ServiceBody,
+        hash: String,
+    },
+    Failed(String),
+    Invalid(String),
+}
+impl From<EditResult> for EditStream {
+    fn from(value: EditResult) -> Self {
+        match value {
+            EditResult::NotEditable => EditStream::NotEditable,
+            EditResult::Success(value) => EditStream::Success {
+                hash: content_hash(&value),
+                body: value.stream_body(),
+            },
+            EditResult::Failed(e) => EditStream::Failed(e),
+            EditResult::Invalid(e) => EditStream::Invalid(e),
+            EditResult::Conflict(hash) => {
+                EditStream::Failed(format!("Value has been updated, current version is {hash}"))
+            }
+        }
+    }
+}
+
+/// Hex sha256 used to version editable values
+pub fn content_hash(value: &[u8]) -> String {
+    hex::encode(Sha256::digest(value))
+}
+
+/// Reads a whole request body, used to bridge streamed updates to the buffered API
+pub async fn collect_body(body: ConsumedBodyType) -> Result<Vec<u8>, String> {
+    body.collect().await.map(|b| b.to_bytes().to_vec())
 }
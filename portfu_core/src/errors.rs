@@ -0,0 +1,318 @@
+use crate::{IntoStreamBody, ServiceData, ServiceResponse};
+use async_trait::async_trait;
+use http::header::CONTENT_TYPE;
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use log::{debug, error};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+
+/// Bytes of a body kept around the position a structured extractor failed at, unless the
+/// server sets ExcerptBytes
+pub const DEFAULT_EXCERPT_BYTES: usize = 256;
+
+/// The excerpt size for this request, set from ServerConfig::excerpt_bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ExcerptBytes(pub usize);
+
+tokio::task_local! {
+    static EXCERPT_LIMIT: usize;
+}
+
+/// Runs `parse` with ParseContext excerpts capped at `limit` bytes
+pub async fn with_excerpt_bytes<F: Future>(limit: usize, parse: F) -> F::Output {
+    EXCERPT_LIMIT.scope(limit, parse).await
+}
+
+/// An error that knows the HTTP response it should produce.
+/// Carried inside an io::Error so it can pass through extractors and handlers unchanged,
+/// use `HttpError::apply` to turn it back into a response.
+#[derive(Debug, Clone)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub message: String,
+    pub expected: Vec<String>,
+    pub headers: HeaderMap,
+    /// Extra structured context, sent as `details` in the envelope
+    pub details: Option<serde_json::Value>,
+}
+impl HttpError {
+    pub fn new<S: Into<String>>(status: StatusCode, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            expected: vec![],
+            headers: HeaderMap::new(),
+            details: None,
+        }
+    }
+    pub fn details<T: Serialize>(self, details: &T) -> Self {
+        let mut s = self;
+        s.details = serde_json::to_value(details).ok();
+        s
+    }
+    pub fn expected<S: Into<String>>(self, expected: Vec<S>) -> Self {
+        let mut s = self;
+        s.expected = expected.into_iter().map(Into::into).collect();
+        s
+    }
+    pub fn header(self, name: HeaderName, value: HeaderValue) -> Self {
+        let mut s = self;
+        s.headers.append(name, value);
+        s
+    }
+    pub fn find(error: &Error) -> Option<&HttpError> {
+        error.get_ref().and_then(|e| e.downcast_ref::<HttpError>())
+    }
+    /// Writes the status, headers and JSON envelope of the HttpError inside `error` to the response.
+    /// Returns false, leaving the response untouched, when `error` does not carry an HttpError.
+    pub fn apply(error: &Error, response: &mut ServiceResponse) -> bool {
+        match Self::find(error) {
+            Some(http_error) => {
+                http_error.write_response(response);
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn write_response(&self, response: &mut ServiceResponse) {
+        *response.status_mut() = self.status;
+        for (name, value) in self.headers.iter() {
+            response.headers_mut().append(name, value.clone());
+        }
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let envelope = ErrorEnvelope {
+            status: self.status.as_u16(),
+            error: self.status.canonical_reason().unwrap_or_default(),
+            message: &self.message,
+            expected: &self.expected,
+            details: self.details.as_ref(),
+        };
+        *response.body_mut() = serde_json::to_vec(&envelope)
+            .unwrap_or_default()
+            .stream_body();
+    }
+}
+impl Display for HttpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+impl std::error::Error for HttpError {}
+impl From<HttpError> for Error {
+    fn from(value: HttpError) -> Self {
+        let kind = if value.status.is_server_error() {
+            ErrorKind::Other
+        } else {
+            ErrorKind::InvalidInput
+        };
+        Error::new(kind, value)
+    }
+}
+
+/// Turns errors returned by handlers into responses, registered with ServerBuilder::error_handler.
+/// Without one endpoints write the Debug of the error and failed services answer 500.
+#[async_trait]
+pub trait ErrorHandler {
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+    async fn render(&self, error: Error, data: &mut ServiceData);
+}
+impl Debug for dyn ErrorHandler + Send + Sync + 'static {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The status a handler error answers with when it does not carry an HttpError
+pub fn status_for(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorKind::TimedOut => StatusCode::REQUEST_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Like status_for, except NotFound is a 500 since extractors return it for missing State
+pub fn extraction_status(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::INTERNAL_SERVER_ERROR,
+        kind => status_for(kind),
+    }
+}
+
+/// The status of the HttpError inside `error`, otherwise the status for its kind
+pub fn error_status(error: &Error) -> StatusCode {
+    HttpError::find(error)
+        .map(|e| e.status)
+        .unwrap_or_else(|| status_for(error.kind()))
+}
+
+/// Renders `error` with the server's ErrorHandler, handing it back when none is registered
+pub async fn render_error(error: Error, data: &mut ServiceData) -> Result<(), Error> {
+    match data.server.registry.load().error_handler.clone() {
+        Some(handler) => {
+            if error_status(&error).is_server_error() {
+                error!(
+                    "Service Error when Handling {} - {error:?}",
+                    data.request.request.uri()
+                );
+            } else {
+                debug!(
+                    "Client Error when Handling {} - {error:?}",
+                    data.request.request.uri()
+                );
+            }
+            handler.render(error, data).await;
+            Ok(())
+        }
+        None => Err(error),
+    }
+}
+
+/// Answers `{"error": "...", "status": 500}` with the status for the error's kind,
+/// errors carrying an HttpError keep their own envelope
+#[derive(Debug, Default, Copy, Clone)]
+pub struct JsonErrorHandler;
+#[async_trait]
+impl ErrorHandler for JsonErrorHandler {
+    fn name(&self) -> &str {
+        "JsonErrorHandler"
+    }
+    async fn render(&self, error: Error, data: &mut ServiceData) {
+        if HttpError::apply(&error, &mut data.response) {
+            return;
+        }
+        let status = status_for(error.kind());
+        *data.response.status_mut() = status;
+        data.response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        *data.response.body_mut() = json!({
+            "error": error.to_string(),
+            "status": status.as_u16(),
+        })
+        .to_string()
+        .stream_body();
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    status: u16,
+    error: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    expected: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a serde_json::Value>,
+}
+
+/// Where a body failed to parse, only the excerpt is copied so the body itself can be dropped
+#[derive(Debug, Clone, Serialize)]
+pub struct ParseContext {
+    pub line: usize,
+    pub column: usize,
+    /// Up to ExcerptBytes around the error with control characters escaped
+    pub excerpt: String,
+    pub body_size: usize,
+}
+impl ParseContext {
+    /// `line` and `column` are 1 based, as reported by serde_json
+    pub fn new(body: &[u8], line: usize, column: usize) -> Self {
+        let line_start = body
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .nth(line.saturating_sub(2))
+            .map(|(i, _)| i + 1)
+            .filter(|_| line > 1)
+            .unwrap_or_default();
+        let offset = (line_start + column.saturating_sub(1)).min(body.len());
+        Self {
+            line,
+            column,
+            excerpt: excerpt(
+                body,
+                offset,
+                EXCERPT_LIMIT
+                    .try_with(|limit| *limit)
+                    .unwrap_or(DEFAULT_EXCERPT_BYTES),
+            ),
+            body_size: body.len(),
+        }
+    }
+}
+
+/// Up to `limit` bytes of `value` centred on `offset`, cut on UTF-8 boundaries
+pub fn excerpt(value: &[u8], offset: usize, limit: usize) -> String {
+    let is_continuation = |b: u8| b & 0b1100_0000 == 0b1000_0000;
+    let mut start = offset.saturating_sub(limit / 2).min(value.len());
+    let mut end = (start + limit).min(value.len());
+    while start < end && is_continuation(value[start]) {
+        start += 1;
+    }
+    while end > start && end < value.len() && is_continuation(value[end]) {
+        end -= 1;
+    }
+    let mut escaped = String::with_capacity(end - start);
+    for c in String::from_utf8_lossy(&value[start..end]).chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_for_maps_error_kinds() {
+        assert_eq!(status_for(ErrorKind::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(status_for(ErrorKind::InvalidInput), StatusCode::BAD_REQUEST);
+        assert_eq!(status_for(ErrorKind::InvalidData), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status_for(ErrorKind::PermissionDenied),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status_for(ErrorKind::TimedOut), StatusCode::REQUEST_TIMEOUT);
+        for kind in [
+            ErrorKind::Other,
+            ErrorKind::BrokenPipe,
+            ErrorKind::Unsupported,
+        ] {
+            assert_eq!(status_for(kind), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    #[test]
+    fn extraction_status_treats_not_found_as_a_server_error() {
+        assert_eq!(
+            extraction_status(ErrorKind::NotFound),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            extraction_status(ErrorKind::InvalidData),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn error_status_prefers_the_http_error() {
+        let teapot: Error = HttpError::new(StatusCode::IM_A_TEAPOT, "short and stout").into();
+        assert_eq!(error_status(&teapot), StatusCode::IM_A_TEAPOT);
+        let plain = Error::new(ErrorKind::PermissionDenied, "no");
+        assert_eq!(error_status(&plain), StatusCode::FORBIDDEN);
+    }
+}
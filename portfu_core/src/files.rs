@@ -1,21 +1,138 @@
-use crate::editable::EditResult;
-use crate::{IntoStreamBody, ServiceBody, ServiceData, ServiceHandler};
-use futures_util::TryStreamExt;
-use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
-use http::{HeaderValue, StatusCode};
-use http_body::Frame;
-use http_body_util::{BodyStream, StreamBody};
+use crate::blocking::BlockingPool;
+use crate::editable::{EditResult, EditStream, EditVersion};
+use crate::filters::FilterFn;
+use crate::headers::HeaderPolicy;
+use crate::service::{ConsumedBodyType, Service, ServiceGroup};
+use crate::settings::Setting;
+use crate::task::{ShutdownSignal, TaskFn};
+use crate::wrappers::WrapperFn;
+use crate::{IntoStreamBody, LiveRegistry, ReaderBody, ServiceBody, ServiceData, ServiceHandler};
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+    RANGE, VARY,
+};
+use http::{Extensions, HeaderMap, HeaderValue, StatusCode};
+use http_body_util::BodyExt;
 use hyper::body::Bytes;
 use mime_guess::from_path;
+use once_cell::sync::Lazy;
+use percent_encoding::{
+    percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS, NON_ALPHANUMERIC,
+};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::io::Error;
-use std::path::Path;
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::RwLock;
-use tokio_util::codec::BytesCodec;
+use uuid::Uuid;
+
+/// One lock per edited file, held from the hash check to the rename so concurrent edits of a
+/// file are applied one at a time even through different loaders
+static EDIT_LOCKS: Lazy<std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(Default::default);
+
+/// Limits on edits made through the editor, checked before anything is written
+#[derive(Debug, Clone)]
+pub struct EditPolicy {
+    pub max_size: u64,
+    /// Extensions that may be edited, empty allows any extension that is not denied
+    pub allowed_extensions: Vec<String>,
+    pub denied_extensions: Vec<String>,
+    pub allow_dotfiles: bool,
+    /// Where previous contents are kept before each write, None keeps no backups
+    pub versions_dir: Option<PathBuf>,
+    /// Backups kept per file, the oldest are removed first
+    pub retention: usize,
+}
+impl Default for EditPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            allowed_extensions: vec![],
+            denied_extensions: [
+                "exe", "dll", "so", "dylib", "bin", "com", "msi", "bat", "cmd", "sh", "ps1",
+            ]
+            .iter()
+            .map(|e| e.to_string())
+            .collect(),
+            allow_dotfiles: false,
+            versions_dir: None,
+            retention: 10,
+        }
+    }
+}
+impl EditPolicy {
+    pub fn max_size(self, max_size: u64) -> Self {
+        let mut s = self;
+        s.max_size = max_size;
+        s
+    }
+    pub fn allow_extension<S: AsRef<str>>(self, extension: S) -> Self {
+        let mut s = self;
+        s.allowed_extensions
+            .push(extension.as_ref().trim_start_matches('.').to_lowercase());
+        s
+    }
+    pub fn deny_extension<S: AsRef<str>>(self, extension: S) -> Self {
+        let mut s = self;
+        s.denied_extensions
+            .push(extension.as_ref().trim_start_matches('.').to_lowercase());
+        s
+    }
+    pub fn allow_dotfiles(self, allow_dotfiles: bool) -> Self {
+        let mut s = self;
+        s.allow_dotfiles = allow_dotfiles;
+        s
+    }
+    pub fn versions_dir<P: Into<PathBuf>>(self, versions_dir: P, retention: usize) -> Self {
+        let mut s = self;
+        s.versions_dir = Some(versions_dir.into());
+        s.retention = retention.max(1);
+        s
+    }
+    /// Whether the file at `path` may be edited at all
+    pub fn check_target(&self, path: &str) -> Result<(), String> {
+        let path = Path::new(path);
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if !self.allow_dotfiles && file_name.starts_with('.') {
+            return Err(format!("Editing dotfiles is not allowed: {file_name}"));
+        }
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if self.denied_extensions.contains(&extension)
+            || (!self.allowed_extensions.is_empty()
+                && !self.allowed_extensions.contains(&extension))
+        {
+            return Err(format!("Editing .{extension} files is not allowed"));
+        }
+        Ok(())
+    }
+    fn check_size(&self, size: u64) -> Result<(), String> {
+        if size > self.max_size {
+            Err(format!(
+                "Edit is larger than the {} byte limit",
+                self.max_size
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
 
 pub struct FileLoader {
     pub name: String,
@@ -24,7 +141,341 @@ pub struct FileLoader {
     pub editable: bool,
     pub cache_threshold: u64,
     pub cache_status: AtomicBool,
-    pub cached_value: Arc<RwLock<Vec<u8>>>,
+    /// The cached bytes with their ETag and Last-Modified, behind one lock so a reload never
+    /// pairs new validators with old bytes
+    pub cached_value: Arc<RwLock<(Vec<u8>, Validators)>>,
+    pub encoded_variants: Vec<EncodedVariant>,
+    /// Overrides cache_threshold when set
+    pub threshold_setting: Option<Setting<u64>>,
+    pub edit_policy: Arc<EditPolicy>,
+    pub download: FileDownloadOptions,
+}
+impl FileLoader {
+    /// Serves the file as a download suggesting `filename`
+    pub fn as_attachment<S: AsRef<str>>(self, filename: S) -> Self {
+        let mut s = self;
+        s.download = FileDownloadOptions::attachment(filename);
+        s
+    }
+    /// Named after the file and a hash of its path, so files with the same name in different
+    /// directories keep their versions apart
+    fn versions_path(&self) -> Option<PathBuf> {
+        let versions_dir = self.edit_policy.versions_dir.as_ref()?;
+        let file_name = Path::new(&self.path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let hash = hex::encode(Sha256::digest(self.path.as_bytes()));
+        Some(versions_dir.join(format!("{file_name}-{}", &hash[..16])))
+    }
+    async fn lock_edits(&self) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = EDIT_LOCKS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(self.path.clone())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+    /// Copies the current file into the versions directory and prunes past the retention count
+    async fn backup(&self) -> Result<(), Error> {
+        let versions_path = match self.versions_path() {
+            Some(versions_path) => versions_path,
+            None => return Ok(()),
+        };
+        if tokio::fs::metadata(&self.path).await.is_err() {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(&versions_path).await?;
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        //Zero padded so versions sort oldest first
+        let version = format!("{nanos:020}-{}", &Uuid::new_v4().simple().to_string()[..8]);
+        tokio::fs::copy(&self.path, versions_path.join(version)).await?;
+        let versions = self.versions().await.map_err(Error::other)?;
+        for stale in versions.iter().skip(self.edit_policy.retention) {
+            tokio::fs::remove_file(versions_path.join(&stale.version)).await?;
+        }
+        Ok(())
+    }
+    /// Backs up the current file then moves `temp_path` over it, readers never see a partial file
+    async fn replace_with(&self, temp_path: &str) -> Result<(), String> {
+        self.backup()
+            .await
+            .map_err(|e| format!("Failed to back up {}: {e:?}", self.path))?;
+        tokio::fs::rename(temp_path, &self.path)
+            .await
+            .map_err(|e| format!("{e:?}"))?;
+        self.cache_status.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+    fn temp_path(&self) -> String {
+        format!("{}.{}.tmp", self.path, Uuid::new_v4())
+    }
+    fn cache_threshold(&self) -> u64 {
+        match &self.threshold_setting {
+            Some(setting) => setting.get(),
+            None => self.cache_threshold,
+        }
+    }
+    /// Drops the cached contents, the next request reads the file again
+    pub fn invalidate(&self) {
+        self.cache_status.store(false, Ordering::Relaxed);
+    }
+    async fn serve_encoded(
+        &self,
+        mut data: ServiceData,
+        variant: &EncodedVariant,
+    ) -> Result<ServiceData, (ServiceData, Error)> {
+        let metadata = match tokio::fs::metadata(&variant.path).await {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(server_error(data, e)),
+        };
+        if let Ok(val) = HeaderValue::from_str(&self.mime) {
+            data.response.headers_mut().insert(CONTENT_TYPE, val);
+        }
+        if Validators::from_metadata(&metadata)
+            .with_encoding(variant.encoding)
+            .apply(&mut data)
+        {
+            return Ok(data);
+        }
+        match stream_from_disk(&variant.path).await {
+            Ok(stream) => {
+                data.response.headers_mut().insert(
+                    CONTENT_ENCODING,
+                    HeaderValue::from_static(variant.encoding.as_str()),
+                );
+                if let Some(disposition) = self.download.header_value() {
+                    data.response
+                        .headers_mut()
+                        .insert(CONTENT_DISPOSITION, disposition);
+                }
+                data.response
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(metadata.len()));
+                *data.response.body_mut() = stream;
+                Ok(data)
+            }
+            Err(e) => Ok(server_error(data, e)),
+        }
+    }
+}
+
+impl FileLoader {
+    async fn load_cache(&self) -> Result<(), Error> {
+        let bytes = load_from_disk(&self.path).await?;
+        let last_modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        let validators = Validators {
+            etag: content_etag(&bytes),
+            last_modified,
+        };
+        *self.cached_value.write().await = (bytes, validators);
+        self.cache_status.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    /// Streams files over the cache threshold, reading only the requested window for ranges
+    async fn serve_from_disk(&self, mut data: ServiceData, metadata: Metadata) -> ServiceData {
+        if Validators::from_metadata(&metadata).apply(&mut data) {
+            return data;
+        }
+        let size = metadata.len();
+        let range = requested_range(&data, size);
+        let body = match range {
+            ByteRange::Full => stream_from_disk(&self.path).await,
+            ByteRange::Partial(start, end) => {
+                stream_range_from_disk(&self.path, start, end - start + 1).await
+            }
+            ByteRange::Unsatisfiable => Ok(Bytes::new().stream_body()),
+        };
+        match body {
+            Ok(body) => {
+                write_range(&mut data, range, size);
+                *data.response.body_mut() = body;
+                data
+            }
+            Err(e) => server_error(data, e),
+        }
+    }
+}
+
+/// ETag and Last-Modified of a file, used to answer conditional requests with 304
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+impl Validators {
+    /// A weak ETag from size and modification time, for files that are not read into memory
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+        let modified = last_modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self {
+            etag: format!("W/\"{:x}-{:x}\"", metadata.len(), modified.as_nanos()),
+            last_modified,
+        }
+    }
+    /// The validators of a content-coded copy, its ETag is suffixed with the coding so caches keep them apart
+    pub fn with_encoding(self, encoding: ContentEncoding) -> Self {
+        let mut s = self;
+        s.etag = match s.etag.strip_suffix('"') {
+            Some(tag) => format!("{tag}-{}\"", encoding.as_str()),
+            None => format!("{}-{}", s.etag, encoding.as_str()),
+        };
+        s
+    }
+    /// Whether the client's copy is current, If-None-Match wins over If-Modified-Since
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if headers.contains_key(IF_NONE_MATCH) {
+            let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            let etag = opaque(&self.etag);
+            return headers
+                .get_all(IF_NONE_MATCH)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .any(|tag| tag.trim() == "*" || opaque(tag) == etag);
+        }
+        match (
+            self.last_modified,
+            headers
+                .get(IF_MODIFIED_SINCE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok()),
+        ) {
+            //Http dates have second precision
+            (Some(modified), Some(since)) => modified
+                .duration_since(UNIX_EPOCH)
+                .map(|m| UNIX_EPOCH + Duration::from_secs(m.as_secs()) <= since)
+                .unwrap_or_default(),
+            _ => false,
+        }
+    }
+    /// Adds ETag and Last-Modified, returns true once a 304 has been written
+    pub fn apply(&self, data: &mut ServiceData) -> bool {
+        let headers = data.response.headers_mut();
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(ETAG, etag);
+        }
+        if let Some(modified) = self.last_modified {
+            if let Ok(modified) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+                headers.insert(LAST_MODIFIED, modified);
+            }
+        }
+        let not_modified = data
+            .request
+            .request
+            .headers()
+            .is_some_and(|headers| self.not_modified(headers));
+        if not_modified {
+            *data.response.status_mut() = StatusCode::NOT_MODIFIED;
+            data.response.headers_mut().remove(CONTENT_TYPE);
+            *data.response.body_mut() = Bytes::new().stream_body();
+        }
+        not_modified
+    }
+}
+
+/// A strong ETag from the sha256 of `bytes`
+pub fn content_etag(bytes: &[u8]) -> String {
+    format!("\"{}\"", &hex::encode(Sha256::digest(bytes))[..32])
+}
+
+fn server_error(mut data: ServiceData, e: Error) -> ServiceData {
+    let err = format!("{e:?}");
+    let bytes: Bytes = err.into();
+    *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    *data.response.body_mut() = bytes.stream_body();
+    data
+}
+
+/// A single `Range: bytes=` request resolved against the length of the body
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No range, multiple ranges or a malformed header, the whole body is sent
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+impl ByteRange {
+    pub fn parse(header: &str, len: u64) -> Self {
+        let spec = match header.trim().strip_prefix("bytes=") {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return ByteRange::Full,
+        };
+        let (first, last) = match spec.split_once('-') {
+            Some(parts) => parts,
+            None => return ByteRange::Full,
+        };
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            return match last.parse::<u64>() {
+                Ok(0) => ByteRange::Unsatisfiable,
+                Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+                Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+                Err(_) => ByteRange::Full,
+            };
+        }
+        let first = match first.parse::<u64>() {
+            Ok(first) => first,
+            Err(_) => return ByteRange::Full,
+        };
+        let last = if last.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            match last.parse::<u64>() {
+                Ok(last) if last >= first => last.min(len.saturating_sub(1)),
+                _ => return ByteRange::Full,
+            }
+        };
+        if first >= len {
+            ByteRange::Unsatisfiable
+        } else {
+            ByteRange::Partial(first, last)
+        }
+    }
+}
+
+fn requested_range(data: &ServiceData, len: u64) -> ByteRange {
+    data.request
+        .request
+        .headers()
+        .and_then(|headers| headers.get(RANGE))
+        .and_then(|value| value.to_str().ok())
+        .map_or(ByteRange::Full, |value| ByteRange::parse(value, len))
+}
+
+/// Sets the status, Content-Range and Content-Length for `range` out of `total` bytes
+fn write_range(data: &mut ServiceData, range: ByteRange, total: u64) {
+    let (status, content_range, len) = match range {
+        ByteRange::Full => (StatusCode::OK, None, total),
+        ByteRange::Partial(start, end) => (
+            StatusCode::PARTIAL_CONTENT,
+            Some(format!("bytes {start}-{end}/{total}")),
+            end - start + 1,
+        ),
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            Some(format!("bytes */{total}")),
+            0,
+        ),
+    };
+    if status != StatusCode::OK {
+        *data.response.status_mut() = status;
+    }
+    let headers = data.response.headers_mut();
+    if let Some(content_range) = content_range.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        headers.insert(CONTENT_RANGE, content_range);
+    }
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
 }
 
 #[async_trait::async_trait]
@@ -32,85 +483,73 @@ impl ServiceHandler for FileLoader {
     fn name(&self) -> &str {
         &self.name
     }
-    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+    async fn warm_up(&self) -> Result<(), Error> {
         if self.cache_status.load(Ordering::Relaxed) {
-            if let Ok(val) = HeaderValue::from_str(&self.mime) {
-                data.response.headers_mut().insert(CONTENT_TYPE, val);
+            return Ok(());
+        }
+        let size = tokio::fs::metadata(&self.path).await?.len();
+        if size < self.cache_threshold() {
+            self.load_cache().await?;
+        }
+        Ok(())
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        if !self.encoded_variants.is_empty() {
+            data.response
+                .headers_mut()
+                .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+            if let Some(variant) = data
+                .request
+                .request
+                .headers()
+                .and_then(|headers| select_encoded_variant(headers, &self.encoded_variants))
+            {
+                return self.serve_encoded(data, variant).await;
             }
-            let cached = self.cached_value.read().await.clone();
+        }
+        data.response
+            .headers_mut()
+            .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(val) = HeaderValue::from_str(&self.mime) {
+            data.response.headers_mut().insert(CONTENT_TYPE, val);
+        }
+        if let Some(disposition) = self.download.header_value() {
             data.response
                 .headers_mut()
-                .insert(CONTENT_LENGTH, HeaderValue::from(cached.len()));
-            *data.response.body_mut() = cached.stream_body();
-            Ok(data)
-        } else {
-            let mut stream = true;
-            match File::open(&self.path).await {
-                Ok(f) => {
-                    if let Ok(metadata) = f.metadata().await {
-                        let size = metadata.len();
-                        data.response
-                            .headers_mut()
-                            .insert(CONTENT_LENGTH, HeaderValue::from(size));
-                        if size < self.cache_threshold {
-                            match load_from_disk(&self.path).await {
-                                Ok(bytes) => {
-                                    *self.cached_value.write().await = bytes;
-                                    self.cache_status.store(true, Ordering::Relaxed);
-                                    stream = false;
-                                }
-                                Err(e) => {
-                                    let err = format!("{e:?}");
-                                    let bytes: Bytes = err.into();
-                                    *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                                    *data.response.body_mut() = bytes.stream_body();
-                                    return Ok(data);
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    let err = format!("{e:?}");
-                    let bytes: Bytes = err.into();
-                    *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                    *data.response.body_mut() = bytes.stream_body();
-                    return Ok(data);
-                }
+                .insert(CONTENT_DISPOSITION, disposition);
+        }
+        if !self.cache_status.load(Ordering::Relaxed) {
+            let metadata = match tokio::fs::metadata(&self.path).await {
+                Ok(metadata) => metadata,
+                Err(e) => return Ok(server_error(data, e)),
+            };
+            if metadata.len() >= self.cache_threshold() {
+                return Ok(self.serve_from_disk(data, metadata).await);
             }
-            if stream {
-                match stream_from_disk(&self.path).await {
-                    Ok(stream) => {
-                        if let Ok(val) = HeaderValue::from_str(&self.mime) {
-                            data.response.headers_mut().insert(CONTENT_TYPE, val);
-                        }
-                        *data.response.body_mut() = stream;
-                        Ok(data)
-                    }
-                    Err(e) => {
-                        let err = format!("{e:?}");
-                        let bytes: Bytes = err.into();
-                        *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                        *data.response.body_mut() = bytes.stream_body();
-                        return Ok(data);
-                    }
-                }
-            } else {
-                if let Ok(val) = HeaderValue::from_str(&self.mime) {
-                    data.response.headers_mut().insert(CONTENT_TYPE, val);
-                }
-                let cached = self.cached_value.read().await.clone();
-                data.response
-                    .headers_mut()
-                    .insert(CONTENT_LENGTH, HeaderValue::from(cached.len()));
-                *data.response.body_mut() = cached.stream_body();
-                Ok(data)
+            if let Err(e) = self.load_cache().await {
+                return Ok(server_error(data, e));
             }
         }
+        let guard = self.cached_value.read().await;
+        let (cached, validators) = &*guard;
+        if validators.apply(&mut data) {
+            return Ok(data);
+        }
+        let size = cached.len() as u64;
+        let range = requested_range(&data, size);
+        let body = match range {
+            ByteRange::Full => cached.clone(),
+            ByteRange::Partial(start, end) => cached[start as usize..=end as usize].to_vec(),
+            ByteRange::Unsatisfiable => vec![],
+        };
+        drop(guard);
+        write_range(&mut data, range, size);
+        *data.response.body_mut() = body.stream_body();
+        Ok(data)
     }
 
     fn is_editable(&self) -> bool {
-        true
+        self.editable
     }
 
     async fn current_value(&self) -> EditResult {
@@ -123,7 +562,65 @@ impl ServiceHandler for FileLoader {
         }
     }
 
+    async fn current_value_stream(&self) -> EditStream {
+        if self.cache_status.load(Ordering::Relaxed) {
+            return EditResult::Success(self.cached_value.read().await.0.clone()).into();
+        }
+        let hash = match hash_from_disk(&self.path).await {
+            Ok(hash) => hash,
+            Err(e) => return EditStream::Failed(format!("{e:?}")),
+        };
+        match stream_from_disk(&self.path).await {
+            Ok(body) => EditStream::Success { body, hash },
+            Err(e) => EditStream::Failed(format!("{e:?}")),
+        }
+    }
+
+    async fn update_value_stream(
+        &self,
+        new_value: ConsumedBodyType,
+        expected_hash: Option<String>,
+    ) -> EditResult {
+        if let Err(e) = self.edit_policy.check_target(&self.path) {
+            return EditResult::Invalid(e);
+        }
+        //Written next to the target first so the swap is a rename and readers never see a partial file
+        let temp_path = self.temp_path();
+        let result = async {
+            let hash = write_to_disk(&temp_path, new_value, self.edit_policy.max_size).await?;
+            let _edit = self.lock_edits().await;
+            if let Some(expected_hash) = expected_hash {
+                let current_hash = hash_from_disk(&self.path)
+                    .await
+                    .map_err(|e| EditResult::Failed(format!("{e:?}")))?;
+                if current_hash != expected_hash {
+                    return Err(EditResult::Conflict(current_hash));
+                }
+            }
+            self.replace_with(&temp_path)
+                .await
+                .map_err(EditResult::Failed)?;
+            Ok(hash)
+        }
+        .await;
+        match result {
+            Ok(hash) => EditResult::Success(hash.into_bytes()),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                e
+            }
+        }
+    }
+
     async fn update_value(&self, new_value: Vec<u8>, current_value: Option<Vec<u8>>) -> EditResult {
+        if let Err(e) = self
+            .edit_policy
+            .check_target(&self.path)
+            .and_then(|_| self.edit_policy.check_size(new_value.len() as u64))
+        {
+            return EditResult::Invalid(e);
+        }
+        let _edit = self.lock_edits().await;
         if let Some(to_match) = current_value {
             match load_from_disk(&self.path).await {
                 Ok(disk_value) => {
@@ -139,20 +636,201 @@ impl ServiceHandler for FileLoader {
                 }
             }
         }
-        match OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&self.path)
-            .await
-        {
-            Ok(mut file) => match file.write_all(&new_value).await {
-                Ok(_) => EditResult::Success(new_value),
+        let temp_path = self.temp_path();
+        let result = async {
+            let mut file = File::create(&temp_path)
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            file.write_all(&new_value)
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            file.sync_all().await.map_err(|e| format!("{e:?}"))?;
+            self.replace_with(&temp_path).await
+        }
+        .await;
+        match result {
+            Ok(()) => EditResult::Success(new_value),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                EditResult::Failed(e)
+            }
+        }
+    }
+
+    async fn versions(&self) -> Result<Vec<EditVersion>, String> {
+        let versions_path = match self.versions_path() {
+            Some(versions_path) => versions_path,
+            None => return Ok(vec![]),
+        };
+        let mut entries = match tokio::fs::read_dir(&versions_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(format!("{e:?}")),
+        };
+        let mut versions = vec![];
+        while let Some(entry) = entries.next_entry().await.map_err(|e| format!("{e:?}"))? {
+            if let Some(version) = entry.file_name().to_str() {
+                let size = entry.metadata().await.map(|m| m.len()).unwrap_or_default();
+                versions.push(EditVersion {
+                    version: version.to_string(),
+                    size,
+                });
+            }
+        }
+        versions.sort_by(|a, b| b.version.cmp(&a.version));
+        Ok(versions)
+    }
+
+    async fn restore_version(&self, version: &str) -> EditResult {
+        if !self.editable {
+            return EditResult::NotEditable;
+        }
+        //Only names listed in the versions directory are accepted, never a caller supplied path
+        let versions = match self.versions().await {
+            Ok(versions) => versions,
+            Err(e) => return EditResult::Failed(e),
+        };
+        let (versions_path, version) = match (
+            self.versions_path(),
+            versions.into_iter().find(|v| v.version == version),
+        ) {
+            (Some(versions_path), Some(version)) => (versions_path, version),
+            _ => return EditResult::Invalid(format!("Unknown version {version}")),
+        };
+        let _edit = self.lock_edits().await;
+        let temp_path = self.temp_path();
+        let result = async {
+            tokio::fs::copy(versions_path.join(&version.version), &temp_path)
+                .await
+                .map_err(|e| format!("{e:?}"))?;
+            self.replace_with(&temp_path).await
+        }
+        .await;
+        match result {
+            Ok(()) => match load_from_disk(&self.path).await {
+                Ok(bytes) => EditResult::Success(bytes),
                 Err(e) => EditResult::Failed(format!("{e:?}")),
             },
-            Err(e) => EditResult::Failed(format!("{e:?}")),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                EditResult::Failed(e)
+            }
+        }
+    }
+}
+
+async fn hash_from_disk(path: &str) -> Result<String, Error> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buffer).await? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
         }
     }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Writes `body` to `path`, returning the content_hash of what was written.
+/// Bodies over `max_size` are Invalid, other failures are Failed.
+async fn write_to_disk(
+    path: &str,
+    mut body: ConsumedBodyType,
+    max_size: u64,
+) -> Result<String, EditResult> {
+    let failed = |e: Error| EditResult::Failed(format!("{e:?}"));
+    let mut file = File::create(path).await.map_err(failed)?;
+    let mut hasher = Sha256::new();
+    let mut written: u64 = 0;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame.map_err(EditResult::Failed)?.into_data() {
+            written += data.len() as u64;
+            if written > max_size {
+                return Err(EditResult::Invalid(format!(
+                    "Edit is larger than the {max_size} byte limit"
+                )));
+            }
+            hasher.update(&data);
+            file.write_all(&data).await.map_err(failed)?;
+        }
+    }
+    file.sync_all().await.map_err(failed)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// How a FileLoader presents its file, inline unless an attachment filename is set
+#[derive(Debug, Clone, Default)]
+pub struct FileDownloadOptions {
+    pub attachment: Option<String>,
+}
+impl FileDownloadOptions {
+    pub fn attachment<S: AsRef<str>>(filename: S) -> Self {
+        Self {
+            attachment: Some(filename.as_ref().to_string()),
+        }
+    }
+    pub fn header_value(&self) -> Option<HeaderValue> {
+        self.attachment.as_deref().and_then(content_disposition)
+    }
+}
+
+/// `attachment; filename="..."`, adding an RFC 5987 `filename*` when the name is not plain ASCII
+pub fn content_disposition(filename: &str) -> Option<HeaderValue> {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let value = if fallback == filename {
+        format!("attachment; filename=\"{fallback}\"")
+    } else {
+        format!(
+            "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+            utf8_percent_encode(filename, RFC5987)
+        )
+    };
+    HeaderValue::from_str(&value).ok()
+}
+
+/// Everything but RFC 5987 attr-char
+const RFC5987: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Streams the file at `path` as a download suggesting `filename`
+pub async fn serve_download(path: &str, filename: &str, mut data: ServiceData) -> ServiceData {
+    let size = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return server_error(data, e),
+    };
+    match stream_from_disk(path).await {
+        Ok(body) => {
+            let headers = data.response.headers_mut();
+            if let Ok(val) = HeaderValue::from_str(&get_mime_type(path)) {
+                headers.insert(CONTENT_TYPE, val);
+            }
+            if let Some(disposition) = content_disposition(filename) {
+                headers.insert(CONTENT_DISPOSITION, disposition);
+            }
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+            *data.response.body_mut() = body;
+            data
+        }
+        Err(e) => server_error(data, e),
+    }
 }
 
 async fn load_from_disk(path: &str) -> Result<Vec<u8>, Error> {
@@ -161,24 +839,60 @@ async fn load_from_disk(path: &str) -> Result<Vec<u8>, Error> {
 
 async fn stream_from_disk(path: &str) -> Result<ServiceBody, Error> {
     let file = File::open(path).await?;
-    let buffer = tokio_util::codec::FramedRead::new(file, BytesCodec::new())
-        .map_ok(|b| Frame::data(Bytes::from(b.to_vec())))
-        .map_err(|_| "Failed to Convert File to Stream");
-    let stream = StreamBody::new(buffer);
-    Ok(StreamBody::new(BodyStream::new(Box::pin(stream))))
+    Ok(file.stream_body())
+}
+
+/// Streams `len` bytes of the file at `path` starting at `start`
+async fn stream_range_from_disk(path: &str, start: u64, len: u64) -> Result<ServiceBody, Error> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(ReaderBody(file.take(len)).stream_body())
 }
 
+/// content_etag of every embedded file, keyed by where its bytes live so each is hashed once
+static STATIC_ETAGS: Lazy<std::sync::RwLock<HashMap<(usize, usize), String>>> =
+    Lazy::new(Default::default);
+
 pub struct StaticFile {
     pub name: &'static str,
     pub mime: String,
     pub file_contents: &'static [u8],
 }
+impl StaticFile {
+    /// content_etag of file_contents, hashed on first use
+    pub fn etag(&self) -> String {
+        let key = (
+            self.file_contents.as_ptr() as usize,
+            self.file_contents.len(),
+        );
+        if let Some(etag) = STATIC_ETAGS
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+        {
+            return etag.clone();
+        }
+        let etag = content_etag(self.file_contents);
+        STATIC_ETAGS
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, etag.clone());
+        etag
+    }
+}
 #[async_trait::async_trait]
 impl ServiceHandler for StaticFile {
     fn name(&self) -> &str {
         self.name
     }
     async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let validators = Validators {
+            etag: self.etag(),
+            last_modified: None,
+        };
+        if validators.apply(&mut data) {
+            return Ok(data);
+        }
         let bytes: hyper::body::Bytes = self.file_contents.into();
         if let Ok(val) = HeaderValue::from_str(&self.mime) {
             data.response.headers_mut().insert(CONTENT_TYPE, val);
@@ -188,6 +902,427 @@ impl ServiceHandler for StaticFile {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+impl ContentEncoding {
+    /// Encodings in the order they are preferred when a client accepts several
+    pub const PREFERRED: [ContentEncoding; 2] = [ContentEncoding::Brotli, ContentEncoding::Gzip];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gz",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EncodedVariant {
+    pub encoding: ContentEncoding,
+    pub path: String,
+}
+
+/// Looks for pre-compressed siblings (`file.br`, `file.gz`) of the file at `path`.
+/// This is meant to be called once at registration so requests never stat the disk for them.
+pub fn find_encoded_variants(path: &str) -> Vec<EncodedVariant> {
+    ContentEncoding::PREFERRED
+        .iter()
+        .filter_map(|encoding| {
+            let sibling = format!("{path}.{}", encoding.extension());
+            if Path::new(&sibling).is_file() {
+                Some(EncodedVariant {
+                    encoding: *encoding,
+                    path: sibling,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Removes `file.br` and `file.gz` entries whose uncompressed file is also in `files`,
+/// they are served through that file's FileLoader instead of at their own path
+pub fn drop_encoded_variants(files: &mut HashMap<String, String>) {
+    let variants: Vec<String> = files
+        .keys()
+        .filter(|name| {
+            ContentEncoding::PREFERRED.iter().any(|encoding| {
+                name.strip_suffix(encoding.extension())
+                    .and_then(|name| name.strip_suffix('.'))
+                    .is_some_and(|original| files.contains_key(original))
+            })
+        })
+        .cloned()
+        .collect();
+    for name in variants {
+        files.remove(&name);
+    }
+}
+
+/// The q-value the Accept-Encoding header gives `encoding`, an explicit entry wins over `*`, 0 when not accepted
+pub fn encoding_quality(headers: &HeaderMap, encoding: ContentEncoding) -> f32 {
+    let mut wildcard = 0.0;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        for entry in value.to_str().unwrap_or_default().split(',') {
+            let mut parts = entry.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|p| p.split_once('='))
+                .filter(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .filter_map(|(_, q)| q.trim().parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            if name.eq_ignore_ascii_case(encoding.as_str()) {
+                return quality;
+            } else if name == "*" {
+                wildcard = quality;
+            }
+        }
+    }
+    wildcard
+}
+
+/// Checks the Accept-Encoding header for the given encoding, honoring `q=0` and `*`
+pub fn accepts_encoding(headers: &HeaderMap, encoding: ContentEncoding) -> bool {
+    encoding_quality(headers, encoding) > 0.0
+}
+
+/// The accepted variant with the highest q-value, ties go to the earlier variant
+pub fn select_encoded_variant<'a>(
+    headers: &HeaderMap,
+    variants: &'a [EncodedVariant],
+) -> Option<&'a EncodedVariant> {
+    let mut selected: Option<(&EncodedVariant, f32)> = None;
+    for variant in variants {
+        let quality = encoding_quality(headers, variant.encoding);
+        if quality > 0.0 && selected.is_none_or(|(_, best)| quality > best) {
+            selected = Some((variant, quality));
+        }
+    }
+    selected.map(|(variant, _)| variant)
+}
+
+/// Renders an HTML listing of the directory a request path names under `root`.
+/// Paths with `..` segments or resolving outside `root` through links are not found.
+pub struct DirectoryListing {
+    root: PathBuf,
+    mount: String,
+}
+impl DirectoryListing {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            mount: "/".to_string(),
+        })
+    }
+    /// Request path prefix the root is served under, defaults to `/`
+    pub fn mount<S: AsRef<str>>(self, mount: S) -> Self {
+        let mut s = self;
+        s.mount = format!("/{}/", mount.as_ref().trim_matches('/')).replace("//", "/");
+        s
+    }
+    /// The directory under root that `request_path` names
+    pub fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let relative = request_path
+            .strip_prefix(self.mount.as_str())
+            .or_else(|| (request_path == self.mount.trim_end_matches('/')).then_some(""))?;
+        let decoded = percent_decode_str(relative).decode_utf8().ok()?;
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                segment if segment.contains('\\') => return None,
+                segment => path.push(segment),
+            }
+        }
+        let path = path.canonicalize().ok()?;
+        (path.starts_with(&self.root) && path.is_dir()).then_some(path)
+    }
+    pub async fn render(&self, request_path: &str, directory: &Path) -> Result<String, Error> {
+        let mut entries = vec![];
+        let mut read_dir = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            entries.push((metadata.is_dir(), name, metadata));
+        }
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let base = format!("{}/", request_path.trim_end_matches('/'));
+        let title = escape_html(&base);
+        let mut html = format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\
+            <body><h1>Index of {title}</h1><table><tr><th>Name</th><th>Size</th><th>Modified</th></tr>"
+        );
+        if directory != self.root {
+            html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>");
+        }
+        for (is_dir, name, metadata) in entries {
+            let suffix = if is_dir { "/" } else { "" };
+            let size = if is_dir {
+                String::new()
+            } else {
+                metadata.len().to_string()
+            };
+            let modified = metadata
+                .modified()
+                .map(httpdate::fmt_http_date)
+                .unwrap_or_default();
+            //The request path is only escaped, names are percent-encoded so they stay one segment
+            let href = format!("{base}{}{suffix}", utf8_percent_encode(&name, PATH_SEGMENT));
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}\">{}{suffix}</a></td><td>{size}</td><td>{modified}</td></tr>",
+                escape_html(&href),
+                escape_html(&name),
+            ));
+        }
+        html.push_str("</table></body></html>");
+        Ok(html)
+    }
+}
+#[async_trait::async_trait]
+impl ServiceHandler for DirectoryListing {
+    fn name(&self) -> &str {
+        "DirectoryListing"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let request_path = data.request.request.uri().path().to_string();
+        let directory = match self.resolve(&request_path) {
+            Some(directory) => directory,
+            None => {
+                *data.response.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(data);
+            }
+        };
+        match self.render(&request_path, &directory).await {
+            Ok(html) => {
+                data.response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                *data.response.body_mut() = html.stream_body();
+                Ok(data)
+            }
+            Err(e) => Ok(server_error(data, e)),
+        }
+    }
+}
+
+/// Redirects a directory path without its trailing slash so relative links resolve inside it
+pub struct TrailingSlashRedirect;
+#[async_trait::async_trait]
+impl ServiceHandler for TrailingSlashRedirect {
+    fn name(&self) -> &str {
+        "TrailingSlashRedirect"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let uri = data.request.request.uri();
+        let location = match uri.query() {
+            Some(query) => format!("{}/?{query}", uri.path()),
+            None => format!("{}/", uri.path()),
+        };
+        if let Ok(location) = HeaderValue::from_str(&location) {
+            *data.response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+            data.response.headers_mut().insert(LOCATION, location);
+        }
+        Ok(data)
+    }
+}
+
+type ServiceFn = Box<dyn Fn(&str, &str) -> Service + Send + Sync>;
+
+/// What the groups a DirectoryWatcherTask joined apply to their services
+#[derive(Default)]
+struct Inherited {
+    filters: Vec<Arc<dyn FilterFn + Sync + Send>>,
+    wrappers: Vec<Arc<dyn WrapperFn + Sync + Send>>,
+    header_policy: Option<Arc<HeaderPolicy>>,
+}
+
+/// Rescans `root` every `interval`, registering a service in the server's LiveRegistry for each file
+/// added under it and removing the service of each file removed, including files present at startup.
+/// A rename is a removal and an addition. Added services get the filters, wrappers and header
+/// policy of every group the task is added to, like the services registered at startup.
+pub struct DirectoryWatcherTask {
+    root: PathBuf,
+    interval: Duration,
+    /// Request path to file path and the uuid of its service
+    served: std::sync::Mutex<HashMap<String, (String, Uuid)>>,
+    service: ServiceFn,
+    inherited: std::sync::Mutex<Inherited>,
+}
+impl DirectoryWatcherTask {
+    /// `served` are the services registered at startup by request path, with their file path and uuid.
+    /// `service` builds the service for a request path and file path.
+    pub fn new<P: AsRef<Path>>(
+        root: P,
+        served: HashMap<String, (String, Uuid)>,
+        service: ServiceFn,
+    ) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            interval: Duration::from_secs(2),
+            served: std::sync::Mutex::new(served),
+            service,
+            inherited: std::sync::Mutex::default(),
+        }
+    }
+    pub fn interval(self, interval: Duration) -> Self {
+        let mut s = self;
+        s.interval = interval;
+        s
+    }
+    /// Walks root on the server's BlockingPool then updates the LiveRegistry in `state` to match
+    pub async fn rescan(&self, state: &Extensions) -> Result<(), Error> {
+        let registry = state.get::<LiveRegistry>().ok_or(Error::new(
+            ErrorKind::NotFound,
+            "DirectoryWatcherTask needs the server's LiveRegistry",
+        ))?;
+        let root = self.root.clone();
+        let walk = move || {
+            let mut found = HashMap::new();
+            read_directory(&root, &root, &mut found).map(|_| found)
+        };
+        let found = match state.get::<Arc<BlockingPool>>() {
+            Some(pool) => pool.run(walk).await??,
+            None => tokio::task::spawn_blocking(walk)
+                .await
+                .map_err(Error::other)??,
+        };
+        let mut removed = vec![];
+        let mut added = vec![];
+        {
+            let inherited = self.inherited.lock().unwrap_or_else(|e| e.into_inner());
+            let mut served = self.served.lock().unwrap_or_else(|e| e.into_inner());
+            served.retain(|name, (path, uuid)| {
+                let keep = found.get(name) == Some(path);
+                if !keep {
+                    log::info!("Stopped serving removed file {name}");
+                    removed.push(*uuid);
+                }
+                keep
+            });
+            for (name, path) in found {
+                if let Entry::Vacant(entry) = served.entry(name) {
+                    log::info!("Serving new file {}", entry.key());
+                    let mut service = (self.service)(entry.key(), &path);
+                    service.filters.extend(inherited.filters.clone());
+                    service.wrappers.extend(inherited.wrappers.clone());
+                    if service.header_policy.is_none() {
+                        service.header_policy.clone_from(&inherited.header_policy);
+                    }
+                    let service = Arc::new(service);
+                    added.push(service.clone());
+                    entry.insert((path, service.uuid));
+                }
+            }
+        }
+        if !removed.is_empty() || !added.is_empty() {
+            registry.update(|registry| {
+                for uuid in &removed {
+                    registry.remove(uuid);
+                }
+                for service in &added {
+                    registry.register_shared(service.clone());
+                }
+            });
+        }
+        Ok(())
+    }
+}
+#[async_trait::async_trait]
+impl TaskFn for DirectoryWatcherTask {
+    fn name(&self) -> &str {
+        "DirectoryWatcherTask"
+    }
+    fn joined_group(&self, group: &ServiceGroup) {
+        let mut inherited = self.inherited.lock().unwrap_or_else(|e| e.into_inner());
+        inherited.filters.extend(group.filters.clone());
+        inherited.wrappers.extend(group.wrappers.clone());
+        if inherited.header_policy.is_none() {
+            inherited.header_policy.clone_from(&group.header_policy);
+        }
+    }
+    async fn run(&self, state: Arc<Extensions>) -> Result<(), Error> {
+        let shutdown = state.get::<ShutdownSignal>().cloned();
+        loop {
+            match &shutdown {
+                Some(shutdown) => tokio::select! {
+                    _ = shutdown.wait() => return Ok(()),
+                    _ = tokio::time::sleep(self.interval) => {}
+                },
+                None => tokio::time::sleep(self.interval).await,
+            }
+            if let Err(e) = self.rescan(&state).await {
+                log::error!("Failed to rescan {:?}: {e:?}", self.root);
+            }
+        }
+    }
+}
+
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Request paths of `root` and every directory below it, each with a trailing slash
+pub fn read_directories(root: &Path) -> Result<Vec<String>, Error> {
+    fn walk(root: &Path, directory: &Path, found: &mut Vec<String>) -> Result<(), Error> {
+        let relative = directory
+            .strip_prefix(root)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{e:?}")))?;
+        let mut request_path = String::from("/");
+        for component in relative.components() {
+            request_path.push_str(&component.as_os_str().to_string_lossy());
+            request_path.push('/');
+        }
+        found.push(request_path);
+        for entry in directory.read_dir()?.flatten() {
+            let path = entry.path();
+            //Links are not followed so a loop or a link out of root is never listed
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                walk(root, &path, found)?;
+            }
+        }
+        Ok(())
+    }
+    let root = root.canonicalize()?;
+    let mut found = vec![];
+    walk(&root, &root, &mut found)?;
+    Ok(found)
+}
+
 pub fn get_mime_type<P: AsRef<Path>>(path: P) -> String {
     from_path(path)
         .first_or_octet_stream() // Picks the first MIME type if multiple are guessed, or defaults to 'application/octet-stream'
@@ -232,3 +1367,723 @@ pub fn read_file(
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editable::content_hash;
+    use http_body_util::Full;
+
+    fn accept_encoding(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn variants() -> Vec<EncodedVariant> {
+        ContentEncoding::PREFERRED
+            .iter()
+            .map(|encoding| EncodedVariant {
+                encoding: *encoding,
+                path: format!("index.html.{}", encoding.extension()),
+            })
+            .collect()
+    }
+
+    fn selected(value: &'static str) -> Option<ContentEncoding> {
+        select_encoded_variant(&accept_encoding(value), &variants()).map(|v| v.encoding)
+    }
+
+    #[test]
+    fn encoded_variant_follows_q_values() {
+        assert_eq!(selected("gzip, br"), Some(ContentEncoding::Brotli));
+        assert_eq!(selected("br;q=0, gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(
+            selected("br;q=0.5, gzip;q=0.8"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(selected("br;q=0, gzip;q=0"), None);
+        assert_eq!(selected("*;q=0.1, br;q=0"), Some(ContentEncoding::Gzip));
+        assert_eq!(selected("identity"), None);
+        assert_eq!(
+            select_encoded_variant(&HeaderMap::new(), &variants()).map(|v| v.encoding),
+            None
+        );
+    }
+
+    #[test]
+    fn q_parameter_is_case_insensitive() {
+        assert_eq!(selected("br;Q=0, gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(
+            selected("br; Q = 0.2, gzip;q=0.9"),
+            Some(ContentEncoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn encoded_siblings_are_not_served_on_their_own() {
+        let mut files: HashMap<String, String> = [
+            "/app.js",
+            "/app.js.br",
+            "/app.js.gz",
+            "/archive.gz",
+            "/style.css.br",
+        ]
+        .into_iter()
+        .map(|name| (name.to_string(), format!("root{name}")))
+        .collect();
+        drop_encoded_variants(&mut files);
+        let mut names: Vec<_> = files.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["/app.js", "/archive.gz", "/style.css.br"]);
+    }
+
+    fn editable_loader(path: &Path) -> FileLoader {
+        FileLoader {
+            name: "/edited".to_string(),
+            mime: "text/plain".to_string(),
+            path: path.to_string_lossy().to_string(),
+            editable: true,
+            cache_threshold: 0,
+            cache_status: AtomicBool::default(),
+            cached_value: Arc::default(),
+            encoded_variants: vec![],
+            threshold_setting: None,
+            edit_policy: Arc::default(),
+            download: FileDownloadOptions::default(),
+        }
+    }
+
+    fn ranged_data(range: Option<&'static str>) -> ServiceData {
+        let mut request = http::Request::new(Full::new(Bytes::new()));
+        if let Some(range) = range {
+            request
+                .headers_mut()
+                .insert(RANGE, HeaderValue::from_static(range));
+        }
+        ServiceData {
+            server: Arc::new(crate::server::ServerBuilder::default().build()),
+            request: crate::service::ServiceRequest {
+                request: crate::service::IncomingRequest::Sized(request),
+                path: Arc::new(crate::routes::Route::new("/".to_string())),
+            },
+            response: http::Response::new(Bytes::new().stream_body()),
+        }
+    }
+
+    /// Status, Content-Range and body of `range` served from a ten byte file
+    async fn fetch_range(
+        cache_threshold: u64,
+        range: Option<&'static str>,
+    ) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("pf_range_{}", Uuid::new_v4()));
+        std::fs::write(&path, "0123456789").unwrap();
+        let loader = FileLoader {
+            cache_threshold,
+            ..editable_loader(&path)
+        };
+        let data = match loader.handle(ranged_data(range)).await {
+            Ok(data) => data,
+            Err((_, e)) => panic!("file handler failed: {e:?}"),
+        };
+        std::fs::remove_file(&path).unwrap();
+        let (parts, body) = data.response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes().to_vec();
+        (parts.status, parts.headers, body)
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_from_cached_and_streamed_files() {
+        //Threshold 0 streams from disk, anything over the file size serves the cached copy
+        for cache_threshold in [0, 1024] {
+            let (status, headers, body) = fetch_range(cache_threshold, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(headers[ACCEPT_RANGES], "bytes");
+            assert!(!headers.contains_key(CONTENT_RANGE));
+            assert_eq!(body, b"0123456789");
+
+            let (status, headers, body) = fetch_range(cache_threshold, Some("bytes=2-5")).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(headers[CONTENT_RANGE], "bytes 2-5/10");
+            assert_eq!(headers[CONTENT_LENGTH], "4");
+            assert_eq!(body, b"2345");
+
+            let (status, headers, body) = fetch_range(cache_threshold, Some("bytes=7-")).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(headers[CONTENT_RANGE], "bytes 7-9/10");
+            assert_eq!(body, b"789");
+
+            let (status, headers, body) = fetch_range(cache_threshold, Some("bytes=-3")).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(headers[CONTENT_RANGE], "bytes 7-9/10");
+            assert_eq!(body, b"789");
+
+            let (status, headers, body) = fetch_range(cache_threshold, Some("bytes=10-")).await;
+            assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(headers[CONTENT_RANGE], "bytes */10");
+            assert_eq!(headers[CONTENT_LENGTH], "0");
+            assert!(body.is_empty());
+
+            //Multiple ranges fall back to the whole file
+            let (status, _, body) = fetch_range(cache_threshold, Some("bytes=0-1,4-5")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, b"0123456789");
+        }
+    }
+
+    async fn respond(
+        handler: &dyn ServiceHandler,
+        data: ServiceData,
+    ) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let data = match handler.handle(data).await {
+            Ok(data) => data,
+            Err((_, e)) => panic!("handler failed: {e:?}"),
+        };
+        let (parts, body) = data.response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes().to_vec();
+        (parts.status, parts.headers, body)
+    }
+
+    /// Serves once, then replays the ETag it was given and expects an empty 304
+    async fn replays_as_not_modified(handler: &dyn ServiceHandler, contents: &[u8]) {
+        let (status, headers, body) = respond(handler, ranged_data(None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, contents);
+        let etag = headers[ETAG].clone();
+
+        let mut replay = ranged_data(None);
+        replay
+            .request
+            .request
+            .headers_mut()
+            .unwrap()
+            .insert(IF_NONE_MATCH, etag.clone());
+        let (status, headers, body) = respond(handler, replay).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(headers[ETAG], etag);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn served_etags_are_answered_with_304() {
+        let path = std::env::temp_dir().join(format!("pf_etag_{}", Uuid::new_v4()));
+        std::fs::write(&path, "0123456789").unwrap();
+        for cache_threshold in [0, 1024] {
+            let loader = FileLoader {
+                cache_threshold,
+                ..editable_loader(&path)
+            };
+            replays_as_not_modified(&loader, b"0123456789").await;
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let embedded = StaticFile {
+            name: "/embedded.txt",
+            mime: "text/plain".to_string(),
+            file_contents: b"embedded",
+        };
+        replays_as_not_modified(&embedded, b"embedded").await;
+        assert_eq!(embedded.etag(), content_etag(b"embedded"));
+    }
+
+    /// Resident memory of the test process in kB
+    fn resident_kb() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    #[tokio::test]
+    async fn large_files_stream_in_chunks_without_buffering() {
+        const SIZE: u64 = 256 * 1024 * 1024;
+        let path = std::env::temp_dir().join(format!("pf_large_{}", Uuid::new_v4()));
+        //Sparse, so the test does not write a quarter gigabyte first
+        std::fs::File::create(&path).unwrap().set_len(SIZE).unwrap();
+        let loader = FileLoader {
+            cache_threshold: 1024 * 1024,
+            ..editable_loader(&path)
+        };
+        let data = match loader.handle(ranged_data(None)).await {
+            Ok(data) => data,
+            Err((_, e)) => panic!("file handler failed: {e:?}"),
+        };
+        assert_eq!(data.response.headers()[CONTENT_LENGTH], SIZE.to_string());
+        let mut body = data.response.into_body();
+        let before = resident_kb();
+        let mut peak = before;
+        let (mut chunks, mut received) = (0u64, 0u64);
+        while let Some(frame) = body.frame().await {
+            if let Ok(bytes) = frame.unwrap().into_data() {
+                chunks += 1;
+                received += bytes.len() as u64;
+            }
+            if chunks % 1024 == 0 {
+                peak = peak.max(resident_kb());
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(received, SIZE);
+        assert!(chunks > 1000, "{chunks} chunks");
+        assert!(!loader.cache_status.load(Ordering::Relaxed));
+        if let (Some(before), Some(peak)) = (before, peak) {
+            assert!(
+                peak.saturating_sub(before) < 64 * 1024,
+                "resident memory grew by {} kB",
+                peak - before
+            );
+        }
+
+        //Files under the threshold are still served from the cache
+        let path = std::env::temp_dir().join(format!("pf_small_{}", Uuid::new_v4()));
+        std::fs::write(&path, "small").unwrap();
+        let loader = FileLoader {
+            cache_threshold: 1024 * 1024,
+            ..editable_loader(&path)
+        };
+        let (status, _, body) = respond(&loader, ranged_data(None)).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"small");
+        assert!(loader.cache_status.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn stale_hash_conflicts_with_the_current_version() {
+        let path = std::env::temp_dir().join(format!("pf_cas_{}", Uuid::new_v4()));
+        std::fs::write(&path, "current").unwrap();
+        let loader = editable_loader(&path);
+        let body = |value: &'static str| ConsumedBodyType::Sized(Full::new(Bytes::from(value)));
+        let stale = content_hash(b"older");
+        match loader.update_value_stream(body("lost"), Some(stale)).await {
+            EditResult::Conflict(current) => assert_eq!(current, content_hash(b"current")),
+            _ => panic!("a stale hash must conflict"),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "current");
+        match loader
+            .update_value_stream(body("next"), Some(content_hash(b"current")))
+            .await
+        {
+            EditResult::Success(hash) => assert_eq!(hash, content_hash(b"next").into_bytes()),
+            _ => panic!("the current hash must update"),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "next");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn versioned_loader(root: &Path, file: &str, policy: EditPolicy) -> FileLoader {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "original").unwrap();
+        let mut loader = editable_loader(&path);
+        loader.name = format!("/{file}");
+        loader.edit_policy = Arc::new(policy.versions_dir(root.join("versions"), 2));
+        loader
+    }
+
+    fn sized(value: &'static str) -> ConsumedBodyType {
+        ConsumedBodyType::Sized(Full::new(Bytes::from(value)))
+    }
+
+    #[tokio::test]
+    async fn oversized_edits_are_rejected() {
+        let root = std::env::temp_dir().join(format!("pf_edit_{}", Uuid::new_v4()));
+        let loader = versioned_loader(&root, "page.txt", EditPolicy::default().max_size(4));
+        assert!(matches!(
+            loader.update_value_stream(sized("too long"), None).await,
+            EditResult::Invalid(_)
+        ));
+        assert!(matches!(
+            loader.update_value(b"too long".to_vec(), None).await,
+            EditResult::Invalid(_)
+        ));
+        assert_eq!(std::fs::read_to_string(&loader.path).unwrap(), "original");
+        assert!(loader.versions().await.unwrap().is_empty());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn edits_are_backed_up_and_restored() {
+        let root = std::env::temp_dir().join(format!("pf_edit_{}", Uuid::new_v4()));
+        let loader = versioned_loader(&root, "page.txt", EditPolicy::default());
+        for value in ["first", "second", "third"] {
+            assert!(matches!(
+                loader.update_value_stream(sized(value), None).await,
+                EditResult::Success(_)
+            ));
+        }
+        //Newest first and pruned to the retention count
+        let versions = loader.versions().await.unwrap();
+        let kept: Vec<String> = versions
+            .iter()
+            .map(|v| {
+                std::fs::read_to_string(loader.versions_path().unwrap().join(&v.version)).unwrap()
+            })
+            .collect();
+        assert_eq!(kept, vec!["second", "first"]);
+        match loader.restore_version(&versions[1].version).await {
+            EditResult::Success(bytes) => assert_eq!(bytes, b"first"),
+            _ => panic!("a listed version must restore"),
+        }
+        assert_eq!(std::fs::read_to_string(&loader.path).unwrap(), "first");
+        assert!(matches!(
+            loader.restore_version("../page.txt").await,
+            EditResult::Invalid(_)
+        ));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn same_names_keep_separate_versions() {
+        let root = std::env::temp_dir().join(format!("pf_edit_{}", Uuid::new_v4()));
+        let nested = versioned_loader(&root, "a/b/page.txt", EditPolicy::default());
+        let flat = versioned_loader(&root, "a_b/page.txt", EditPolicy::default());
+        nested.update_value_stream(sized("nested"), None).await;
+        flat.update_value_stream(sized("flat"), None).await;
+        assert_ne!(nested.versions_path(), flat.versions_path());
+        assert_eq!(nested.versions().await.unwrap().len(), 1);
+        assert_eq!(flat.versions().await.unwrap().len(), 1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_edits_with_one_hash_apply_once() {
+        let root = std::env::temp_dir().join(format!("pf_edit_{}", Uuid::new_v4()));
+        let loader = versioned_loader(&root, "page.txt", EditPolicy::default());
+        let expected = content_hash(b"original");
+        let (first, second) = tokio::join!(
+            loader.update_value_stream(sized("first"), Some(expected.clone())),
+            loader.update_value_stream(sized("second"), Some(expected))
+        );
+        let results = [&first, &second];
+        let applied = results
+            .iter()
+            .filter(|r| matches!(r, EditResult::Success(_)))
+            .count();
+        let conflicted = results
+            .iter()
+            .filter(|r| matches!(r, EditResult::Conflict(_)))
+            .count();
+        assert_eq!((applied, conflicted), (1, 1));
+        assert_eq!(loader.versions().await.unwrap().len(), 1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn byte_range_parses_bounded_open_and_suffix_ranges() {
+        assert_eq!(ByteRange::parse("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(
+            ByteRange::parse("bytes=90-", 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-10", 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-500", 100),
+            ByteRange::Partial(0, 99)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=50-500", 100),
+            ByteRange::Partial(50, 99)
+        );
+    }
+
+    #[test]
+    fn byte_range_out_of_bounds_and_malformed() {
+        assert_eq!(
+            ByteRange::parse("bytes=100-", 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=200-300", 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(ByteRange::parse("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-5", 0), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=9-0", 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse("items=0-1", 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=a-b", 100), ByteRange::Full);
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"abc\"".to_string(),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_000_000)),
+        }
+    }
+
+    fn headers(name: http::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn not_modified_compares_etags_weakly() {
+        let validators = validators();
+        assert!(validators.not_modified(&headers(IF_NONE_MATCH, "\"abc\"")));
+        assert!(validators.not_modified(&headers(IF_NONE_MATCH, "W/\"abc\"")));
+        assert!(validators.not_modified(&headers(IF_NONE_MATCH, "\"x\", \"abc\"")));
+        assert!(validators.not_modified(&headers(IF_NONE_MATCH, "*")));
+        assert!(!validators.not_modified(&headers(IF_NONE_MATCH, "\"other\"")));
+        assert!(!validators.not_modified(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn not_modified_since_and_etag_precedence() {
+        let validators = validators();
+        let at = |secs| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert!(validators.not_modified(&headers(IF_MODIFIED_SINCE, &at(1_000_000))));
+        assert!(validators.not_modified(&headers(IF_MODIFIED_SINCE, &at(2_000_000))));
+        assert!(!validators.not_modified(&headers(IF_MODIFIED_SINCE, &at(999_999))));
+
+        let mut both = headers(IF_NONE_MATCH, "\"other\"");
+        both.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_str(&at(2_000_000)).unwrap(),
+        );
+        assert!(!validators.not_modified(&both));
+    }
+
+    #[tokio::test]
+    async fn apply_answers_304_for_a_current_copy() {
+        let mut request = http::Request::new(http_body_util::Full::new(Bytes::new()));
+        request
+            .headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("\"abc\""));
+        let mut data = ServiceData {
+            server: Arc::new(crate::server::ServerBuilder::default().build()),
+            request: crate::service::ServiceRequest {
+                request: crate::service::IncomingRequest::Sized(request),
+                path: Arc::new(crate::routes::Route::new("/".to_string())),
+            },
+            response: http::Response::new("body".stream_body()),
+        };
+        data.response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+
+        assert!(validators().apply(&mut data));
+        assert_eq!(data.response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(data.response.headers()[ETAG], "\"abc\"");
+        assert!(data.response.headers().contains_key(LAST_MODIFIED));
+        assert!(!data.response.headers().contains_key(CONTENT_TYPE));
+    }
+
+    #[test]
+    fn etag_changes_after_an_edit() {
+        assert_ne!(content_etag(b"before"), content_etag(b"after"));
+        assert_eq!(content_etag(b"same"), content_etag(b"same"));
+
+        let path = std::env::temp_dir().join(format!("pf_etag_{}", Uuid::new_v4()));
+        std::fs::write(&path, "before").unwrap();
+        let before = Validators::from_metadata(&std::fs::metadata(&path).unwrap());
+        std::fs::write(&path, "after the edit").unwrap();
+        let after = Validators::from_metadata(&std::fs::metadata(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(before.etag, after.etag);
+        assert!(before.etag.starts_with("W/\""));
+    }
+
+    #[test]
+    fn encoded_variants_get_their_own_etag() {
+        let plain = Validators {
+            etag: "W/\"10-20\"".to_string(),
+            last_modified: None,
+        };
+        let brotli = plain.clone().with_encoding(ContentEncoding::Brotli);
+        let gzip = plain.clone().with_encoding(ContentEncoding::Gzip);
+        assert_eq!(brotli.etag, "W/\"10-20-br\"");
+        assert_eq!(gzip.etag, "W/\"10-20-gzip\"");
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("W/\"10-20\""));
+        assert!(plain.not_modified(&headers));
+        assert!(!brotli.not_modified(&headers));
+    }
+
+    #[test]
+    fn directory_listing_resolves_inside_its_root_only() {
+        let root = std::env::temp_dir().join(format!("pf_listing_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("docs/nested dir")).unwrap();
+        std::fs::write(root.join("docs/file.txt"), "file").unwrap();
+        let listing = DirectoryListing::new(&root).unwrap().mount("/files");
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(listing.resolve("/files"), Some(root.clone()));
+        assert_eq!(listing.resolve("/files/"), Some(root.clone()));
+        assert_eq!(listing.resolve("/files/docs"), Some(root.join("docs")));
+        assert_eq!(listing.resolve("/files/docs/"), Some(root.join("docs")));
+        assert_eq!(
+            listing.resolve("/files/docs/nested%20dir/"),
+            Some(root.join("docs/nested dir"))
+        );
+        assert_eq!(listing.resolve("/files/./docs"), Some(root.join("docs")));
+
+        assert_eq!(listing.resolve("/files/docs/../docs"), None);
+        assert_eq!(listing.resolve("/files/%2e%2e/"), None);
+        assert_eq!(listing.resolve("/files/docs%5c..%5c"), None);
+        assert_eq!(listing.resolve("/files/docs/file.txt"), None);
+        assert_eq!(listing.resolve("/files/missing/"), None);
+        assert_eq!(listing.resolve("/filesystem/"), None);
+        assert_eq!(listing.resolve("/other/docs"), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn disposition(filename: &str) -> String {
+        content_disposition(filename)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn content_disposition_encodes_utf8_names() {
+        assert_eq!(
+            disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\""
+        );
+        assert_eq!(
+            disposition("résumé 2024.pdf"),
+            "attachment; filename=\"r_sum_ 2024.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%202024.pdf"
+        );
+        assert_eq!(
+            disposition("日本.txt"),
+            "attachment; filename=\"__.txt\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.txt"
+        );
+        assert_eq!(
+            disposition("a\"b\\c.txt"),
+            "attachment; filename=\"a_b_c.txt\"; filename*=UTF-8''a%22b%5Cc.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_listing_escapes_names_and_links() {
+        let root = std::env::temp_dir().join(format!("pf_listing_{}", Uuid::new_v4()));
+        let crafted = "<img src=x onerror=alert(1)>\"'&#?.txt";
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(crafted), "file").unwrap();
+        let listing = DirectoryListing::new(&root).unwrap().mount("/files");
+        let html = listing
+            .render("/files/\"><script>", &listing.root)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(!html.contains("<img"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains(
+            "<a href=\"/files/&quot;&gt;&lt;script&gt;/%3Cimg%20src=x%20onerror=alert(1)%3E%22%27%26%23%3F.txt\">"
+        ));
+        assert!(html.contains(">&lt;img src=x onerror=alert(1)&gt;&quot;&#39;&amp;#?.txt</a>"));
+    }
+
+    fn file_service(name: &str, _path: &str) -> Service {
+        crate::service::ServiceBuilder::new(name)
+            .name(name)
+            .method(http::Method::GET)
+            .handler(Arc::new(TrailingSlashRedirect))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn rescan_registers_added_and_removes_deleted_files() {
+        let root = std::env::temp_dir().join(format!("pf_watch_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        std::fs::write(root.join("startup.txt"), "startup").unwrap();
+
+        let startup = Arc::new(file_service("/startup.txt", ""));
+        let mut served = HashMap::new();
+        served.insert(
+            "/startup.txt".to_string(),
+            (
+                root.join("startup.txt").to_string_lossy().to_string(),
+                startup.uuid,
+            ),
+        );
+        let mut services = crate::ServiceRegistry::default();
+        services.register_shared(startup);
+        let registry = LiveRegistry::new(services);
+        let mut state = Extensions::new();
+        state.insert(registry.clone());
+        let watcher = DirectoryWatcherTask::new(&root, served, Box::new(file_service));
+
+        watcher.rescan(&state).await.unwrap();
+        assert_eq!(registry.load().find_by_name("/startup.txt").len(), 1);
+
+        std::fs::write(root.join("added.txt"), "added").unwrap();
+        std::fs::remove_file(root.join("startup.txt")).unwrap();
+        watcher.rescan(&state).await.unwrap();
+        let live = registry.load();
+        assert_eq!(live.find_by_name("/added.txt").len(), 1);
+        assert_eq!(
+            live.allowed_methods("/added.txt"),
+            Some(vec![http::Method::GET])
+        );
+        assert!(live.find_by_name("/startup.txt").is_empty());
+        assert_eq!(live.allowed_methods("/startup.txt"), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Changes nothing, shows which services carry it
+    struct Passes;
+    #[async_trait::async_trait]
+    impl WrapperFn for Passes {
+        fn name(&self) -> &str {
+            "Passes"
+        }
+        async fn before(&self, _: &mut ServiceData) -> crate::wrappers::WrapperResult {
+            crate::wrappers::WrapperResult::Continue
+        }
+        async fn after(&self, _: &mut ServiceData) -> crate::wrappers::WrapperResult {
+            crate::wrappers::WrapperResult::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn added_files_get_what_their_groups_apply() {
+        let root = std::env::temp_dir().join(format!("pf_watch_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let registry = LiveRegistry::new(crate::ServiceRegistry::default());
+        let mut state = Extensions::new();
+        state.insert(registry.clone());
+        let watcher = Arc::new(DirectoryWatcherTask::new(
+            &root,
+            HashMap::new(),
+            Box::new(file_service),
+        ));
+        let auth = Arc::new(crate::filters::Filter {
+            name: "auth".to_string(),
+            mode: crate::filters::FilterMode::All,
+            filter_functions: vec![],
+        });
+        let _group = ServiceGroup::default()
+            .filter(auth)
+            .header_policy(HeaderPolicy::sensitive())
+            .sub_group(
+                ServiceGroup::default()
+                    .wrap(Arc::new(Passes))
+                    .task(crate::task::Task::new("watcher", watcher.clone())),
+            );
+
+        std::fs::write(root.join("added.txt"), "added").unwrap();
+        watcher.rescan(&state).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        let live = registry.load();
+        let added = live.find_by_name("/added.txt");
+        assert_eq!(added.len(), 1);
+        let filters: Vec<&str> = added[0].filters.iter().map(|f| f.name()).collect();
+        assert_eq!(filters, vec!["auth"]);
+        let wrappers: Vec<&str> = added[0].wrappers.iter().map(|w| w.name()).collect();
+        assert_eq!(wrappers, vec!["Passes"]);
+        assert_eq!(
+            added[0].header_policy.as_deref(),
+            Some(&HeaderPolicy::sensitive())
+        );
+    }
+}
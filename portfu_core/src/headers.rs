@@ -0,0 +1,48 @@
+use http::header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use http::{HeaderMap, HeaderName};
+
+/// Which request headers stay visible to handlers and non infrastructure wrappers.
+/// Server level wrappers and wrappers reporting `infrastructure` run before it is applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HeaderPolicy {
+    #[default]
+    Passthrough,
+    AllowList(Vec<HeaderName>),
+    DenyList(Vec<HeaderName>),
+}
+impl HeaderPolicy {
+    /// Hides credentials from handlers, read them in an infrastructure wrapper instead
+    pub fn sensitive() -> Self {
+        HeaderPolicy::DenyList(vec![AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION])
+    }
+    pub fn allows(&self, name: &HeaderName) -> bool {
+        match self {
+            HeaderPolicy::Passthrough => true,
+            HeaderPolicy::AllowList(names) => names.contains(name),
+            HeaderPolicy::DenyList(names) => !names.contains(name),
+        }
+    }
+    /// Removes the headers the policy hides and returns them
+    pub fn apply(&self, headers: &mut HeaderMap) -> HeaderMap {
+        let mut removed = HeaderMap::new();
+        if *self == HeaderPolicy::Passthrough {
+            return removed;
+        }
+        let hidden: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| !self.allows(name))
+            .cloned()
+            .collect();
+        for name in hidden {
+            for value in headers.get_all(&name).iter() {
+                removed.append(name.clone(), value.clone());
+            }
+            headers.remove(&name);
+        }
+        removed
+    }
+}
+
+/// Headers hidden by the HeaderPolicy, kept in the request extensions for logging
+#[derive(Debug, Clone, Default)]
+pub struct RemovedHeaders(pub HeaderMap);
@@ -1,31 +1,70 @@
+pub mod blocking;
+pub mod clock;
+pub mod data_store;
+pub mod duplicates;
 pub mod editable;
+pub mod errors;
 pub mod files;
 pub mod filters;
+pub mod headers;
+pub mod listener;
+pub mod plan;
+pub mod profile;
+pub mod redirect;
+pub mod reload;
+pub mod responders;
 pub mod routes;
+pub mod secrets;
 pub mod server;
 pub mod service;
+pub mod settings;
+pub mod shutdown;
 pub mod signal;
 pub mod sockets;
 mod ssl;
+pub mod static_response;
 pub mod task;
+pub mod tls;
+pub mod upgrade;
+pub mod uploads;
 pub mod wrappers;
 
-use crate::editable::EditResult;
+use crate::duplicates::{
+    duplicate_keys, from_collected_pairs, resolve_json, resolve_pairs, DuplicateKeys,
+};
+use crate::editable::{collect_body, content_hash, EditResult, EditStream, EditVersion};
+use crate::errors::{
+    with_excerpt_bytes, ErrorHandler, ExcerptBytes, HttpError, ParseContext, DEFAULT_EXCERPT_BYTES,
+};
+use crate::routes::{MatchedPathParams, Router};
 use crate::server::Server;
-use crate::service::{BodyType, IncomingRequest, Service, ServiceRequest};
+use crate::service::{BodyType, ConsumedBodyType, IncomingRequest, Service, ServiceRequest};
+use crate::task::Task;
+use crate::uploads::UploadTracker;
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use http::Response;
+use futures_util::TryStreamExt;
+use http::header::CONTENT_TYPE;
+use http::{HeaderName, HeaderValue, Method, Response, StatusCode};
+use http_body::Frame;
 use http_body_util::Full;
-use http_body_util::{BodyExt, BodyStream, StreamBody};
-use hyper::body::Bytes;
-use log::trace;
+use http_body_util::{BodyExt, BodyStream, LengthLimitError, StreamBody};
+use hyper::body::{Bytes, SizeHint};
+use log::{trace, warn};
 use once_cell::sync::Lazy;
-use serde::Deserialize;
-use std::fmt::{Debug, Formatter};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
 
 #[async_trait]
 pub trait ServiceHandler {
@@ -45,8 +84,52 @@ pub trait ServiceHandler {
         );
         EditResult::NotEditable
     }
+    /// Streams the current value, services holding large values should override this
+    async fn current_value_stream(&self) -> EditStream {
+        self.current_value().await.into()
+    }
+    /// Replaces the value from a streamed body when `expected_hash` matches the content_hash
+    /// of the current value, Success carries the content_hash of the new value and Conflict
+    /// the one of the current value when it does not match.
+    async fn update_value_stream(
+        &self,
+        new_value: ConsumedBodyType,
+        expected_hash: Option<String>,
+    ) -> EditResult {
+        let new_value = match collect_body(new_value).await {
+            Ok(new_value) => new_value,
+            Err(e) => return EditResult::Failed(e),
+        };
+        if let Some(expected_hash) = expected_hash {
+            match self.current_value().await {
+                EditResult::Success(current) => {
+                    let current_hash = content_hash(&current);
+                    if current_hash != expected_hash {
+                        return EditResult::Conflict(current_hash);
+                    }
+                }
+                other => return other,
+            }
+        }
+        match self.update_value(new_value, None).await {
+            EditResult::Success(value) => EditResult::Success(content_hash(&value).into_bytes()),
+            other => other,
+        }
+    }
+    /// Previous values kept for restore, newest first
+    async fn versions(&self) -> Result<Vec<EditVersion>, String> {
+        Ok(vec![])
+    }
+    /// Replaces the value with one listed by `versions`, Success carries the restored value
+    async fn restore_version(&self, _version: &str) -> EditResult {
+        EditResult::NotEditable
+    }
+    /// Called once at startup before connections are accepted, use it to pay first request costs up front
+    async fn warm_up(&self) -> Result<(), Error> {
+        Ok(())
+    }
 }
-impl Debug for (dyn ServiceHandler + Send + Sync + 'static) {
+impl Debug for dyn ServiceHandler + Send + Sync + 'static {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.name())
     }
@@ -87,6 +170,17 @@ impl ServiceHandler for (&'static str, &'static [u8]) {
         Ok(data)
     }
 }
+/// Extracts a printable message from the payload of a caught panic
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic payload".to_string()
+    }
+}
+
 pub type BoxedBody =
     Box<dyn hyper::body::Body<Data = Bytes, Error = IntoStreamError> + Send + Sync + 'static>;
 pub type ServiceBody = StreamBody<BodyStream<Pin<BoxedBody>>>;
@@ -120,7 +214,7 @@ impl IntoStreamBody for String {
     }
 }
 
-impl<'a> IntoStreamBody for &'a str {
+impl IntoStreamBody for &str {
     type Data = Bytes;
     type Error = IntoStreamError;
     fn stream_body(self) -> ServiceBody {
@@ -150,6 +244,27 @@ impl IntoStreamBody for Full<Bytes> {
     }
 }
 
+/// Streams an AsyncRead chunk by chunk, nothing beyond the current chunk is held in memory
+pub struct ReaderBody<R>(pub R);
+impl<R: AsyncRead + Send + Sync + 'static> IntoStreamBody for ReaderBody<R> {
+    type Data = Bytes;
+    type Error = IntoStreamError;
+    fn stream_body(self) -> ServiceBody {
+        let stream = ReaderStream::new(self.0)
+            .map_ok(Frame::data)
+            .map_err(|_| "Failed to Convert Reader to Stream");
+        StreamBody::new(BodyStream::new(Box::pin(StreamBody::new(stream))))
+    }
+}
+
+impl IntoStreamBody for tokio::fs::File {
+    type Data = Bytes;
+    type Error = IntoStreamError;
+    fn stream_body(self) -> ServiceBody {
+        ReaderBody(self).stream_body()
+    }
+}
+
 pub struct ServiceData {
     pub server: Arc<Server>,
     pub request: ServiceRequest,
@@ -175,16 +290,174 @@ pub trait ServiceRegister {
     fn register(self, service_registry: &mut ServiceRegistry);
 }
 
-pub static mut STATIC_REGISTRY: Lazy<ServiceRegistry> =
-    Lazy::new(|| ServiceRegistry { services: vec![] });
+pub static mut STATIC_REGISTRY: Lazy<ServiceRegistry> = Lazy::new(ServiceRegistry::default);
+
+/// Methods a path is served with, `any` is set when a matching service accepts every method
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RouteMethods {
+    #[serde(serialize_with = "serialize_methods")]
+    pub methods: Vec<Method>,
+    pub any: bool,
+}
+fn serialize_methods<S: serde::Serializer>(methods: &[Method], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(methods.iter().map(Method::as_str))
+}
 
+/// The registry of a running server, in its shared state so tasks can register and remove services
+/// while it serves. A request keeps the registry it was routed with.
 #[derive(Clone, Debug, Default)]
+pub struct LiveRegistry(Arc<ArcSwap<ServiceRegistry>>);
+impl LiveRegistry {
+    pub fn new(registry: ServiceRegistry) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(registry)))
+    }
+    pub fn load(&self) -> Arc<ServiceRegistry> {
+        self.0.load_full()
+    }
+    /// Applies `update` to a copy of the current registry and swaps the copy in
+    pub fn update<F: Fn(&mut ServiceRegistry)>(&self, update: F) {
+        self.0.rcu(|current| {
+            let mut next = ServiceRegistry::clone(current);
+            update(&mut next);
+            next
+        });
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct ServiceRegistry {
-    pub services: Vec<Arc<Service>>,
+    /// In registration order
+    services: Vec<Arc<Service>>,
+    /// Tasks of registered ServiceGroups, moved to the server on build
+    pub tasks: Vec<Arc<Task>>,
+    pub error_handler: Option<Arc<dyn ErrorHandler + Send + Sync>>,
+    /// Handles requests no registered service matches
+    pub not_found: Option<Arc<Service>>,
+    names: HashMap<String, Vec<Uuid>>,
+    uuids: HashMap<Uuid, Arc<Service>>,
+    router: Router<Arc<Service>>,
+    /// Results of methods_for for paths that are the literal of a static route, so it stays as
+    /// small as the registry
+    method_cache: RwLock<HashMap<String, RouteMethods>>,
+}
+/// A clone starts with an empty method cache, it is changed on its own
+impl Clone for ServiceRegistry {
+    fn clone(&self) -> Self {
+        Self {
+            services: self.services.clone(),
+            tasks: self.tasks.clone(),
+            error_handler: self.error_handler.clone(),
+            not_found: self.not_found.clone(),
+            names: self.names.clone(),
+            uuids: self.uuids.clone(),
+            router: self.router.clone(),
+            method_cache: Default::default(),
+        }
+    }
 }
 impl ServiceRegistry {
     pub fn register(&mut self, service: Service) {
-        self.services.push(Arc::new(service));
+        self.register_shared(Arc::new(service))
+    }
+    /// Registers a service that is already shared, for writers of a LiveRegistry that may retry
+    pub fn register_shared(&mut self, service: Arc<Service>) {
+        if !service.name.is_empty() {
+            let uuids = self.names.entry(service.name.clone()).or_default();
+            if !uuids.is_empty() {
+                warn!(
+                    "Duplicate service name {} registered, use the uuid to address it: {}",
+                    service.name, service.uuid
+                );
+            }
+            uuids.push(service.uuid);
+        }
+        self.uuids.insert(service.uuid, service.clone());
+        self.router.insert(&service.path, service.clone());
+        self.services.push(service);
+        self.method_cache = Default::default();
+    }
+    pub fn remove(&mut self, uuid: &Uuid) -> Option<Arc<Service>> {
+        let service = self.uuids.remove(uuid)?;
+        self.services.retain(|s| s.uuid != *uuid);
+        if let Some(uuids) = self.names.get_mut(&service.name) {
+            uuids.retain(|u| u != uuid);
+            if uuids.is_empty() {
+                self.names.remove(&service.name);
+            }
+        }
+        self.router.remove(&service.path, |s| s.uuid == *uuid);
+        self.method_cache = Default::default();
+        Some(service)
+    }
+    /// Services whose route could match `path` in registration order, the first one that handles
+    /// a request serves it
+    pub fn candidates(&self, path: &str) -> Vec<&Arc<Service>> {
+        self.router.candidates(path)
+    }
+    pub fn find_by_uuid(&self, uuid: &Uuid) -> Option<&Arc<Service>> {
+        self.uuids.get(uuid)
+    }
+    /// Every registered service in registration order, change them through register and remove
+    pub fn services(&self) -> &[Arc<Service>] {
+        &self.services
+    }
+    /// Methods accepted on `path` by services that declare their methods, None when no such service matches
+    pub fn allowed_methods(&self, path: &str) -> Option<Vec<Method>> {
+        self.methods_for(path)
+            .map(|route| route.methods)
+            .filter(|methods| !methods.is_empty())
+    }
+    /// Methods of every service whose route matches `path` in registration order, the same routes a
+    /// live request is matched against. Filters are not evaluated. None when no service matches.
+    pub fn methods_for(&self, path: &str) -> Option<RouteMethods> {
+        if let Some(cached) = self
+            .method_cache
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(path)
+        {
+            return Some(cached.clone());
+        }
+        let mut matched = false;
+        let mut cacheable = false;
+        let mut route = RouteMethods::default();
+        for service in self
+            .candidates(path)
+            .into_iter()
+            .filter(|s| s.path.matches(path))
+        {
+            matched = true;
+            cacheable |= service.path.static_prefix() == (path, true);
+            match &service.methods {
+                Some(methods) => {
+                    for method in methods {
+                        if !route.methods.contains(method) {
+                            route.methods.push(method.clone());
+                        }
+                    }
+                }
+                None => route.any = true,
+            }
+        }
+        route.methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        if cacheable {
+            self.method_cache
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(path.to_string(), route.clone());
+        }
+        matched.then_some(route)
+    }
+    pub fn find_by_name(&self, name: &str) -> Vec<&Arc<Service>> {
+        self.names
+            .get(name)
+            .map(|uuids| {
+                uuids
+                    .iter()
+                    .filter_map(|uuid| self.find_by_uuid(uuid))
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 }
 
@@ -199,6 +472,100 @@ where
     ) -> Result<Self, Error>;
 }
 
+/// Extractors that only read the request, they can be taken in any order around a body extractor.
+/// Every FromRequestRef is also a FromRequest.
+#[async_trait]
+pub trait FromRequestRef<'a>
+where
+    Self: Sized,
+{
+    async fn from_request_ref(
+        request: &'a ServiceRequest,
+        var_name: &'a str,
+    ) -> Result<Self, Error>;
+}
+#[async_trait]
+impl<'a, T: FromRequestRef<'a>> FromRequest<'a> for T {
+    async fn from_request(
+        request: &'a mut ServiceRequest,
+        var_name: &'a str,
+    ) -> Result<Self, Error> {
+        T::from_request_ref(request, var_name).await
+    }
+}
+
+/// Path variables an extractor reads, `#[derive(FromRequest)]` implements it so an endpoint taking
+/// its variables through a struct needs no allow_unused_path_vars. Only checked by the server
+/// macros when a path variable has no argument of its own.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` does not declare the path variables it reads",
+    note = "derive FromRequest, implement PathVars for `{Self}` or set allow_unused_path_vars = true"
+)]
+pub trait PathVars {
+    const PATH_VARS: &'static [&'static str];
+}
+/// Joins the PATH_VARS of a derived struct and its flattened fields
+#[doc(hidden)]
+pub const fn concat_path_vars<const N: usize>(
+    parts: &[&'static [&'static str]],
+) -> [&'static str; N] {
+    let mut joined = [""; N];
+    let (mut part, mut next) = (0, 0);
+    while part < parts.len() {
+        let mut i = 0;
+        while i < parts[part].len() {
+            joined[next] = parts[part][i];
+            next += 1;
+            i += 1;
+        }
+        part += 1;
+    }
+    joined
+}
+/// Whether one of `declared` lists `var`, used by the const assertions of the server macros
+#[doc(hidden)]
+pub const fn uses_path_var(declared: &[&'static [&'static str]], var: &str) -> bool {
+    let mut part = 0;
+    while part < declared.len() {
+        let mut i = 0;
+        while i < declared[part].len() {
+            let (a, b) = (declared[part][i].as_bytes(), var.as_bytes());
+            if a.len() == b.len() {
+                let mut j = 0;
+                while j < a.len() && a[j] == b[j] {
+                    j += 1;
+                }
+                if j == a.len() {
+                    return true;
+                }
+            }
+            i += 1;
+        }
+        part += 1;
+    }
+    false
+}
+macro_rules! no_path_vars {
+    ($($ty:ty),* $(,)?) => {
+        $(impl PathVars for $ty {
+            const PATH_VARS: &'static [&'static str] = &[];
+        })*
+    };
+}
+no_path_vars!(SocketAddr);
+impl<T: Send + Sync + 'static> PathVars for State<T> {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+impl<T: for<'a> Deserialize<'a>> PathVars for Query<T> {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+impl<T: for<'a> Deserialize<'a>> PathVars for Option<Query<T>> {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+impl<T: FromBody> PathVars for Body<T> {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+
 #[derive(Clone)]
 pub struct State<T: Send + Sync + 'static>(pub Arc<T>);
 impl<T: Send + Sync + 'static> State<T> {
@@ -212,12 +579,9 @@ impl<T: Send + Sync + 'static> AsRef<T> for State<T> {
     }
 }
 #[async_trait]
-impl<'a, T: Send + Sync + 'static> FromRequest<'a> for State<T> {
-    async fn from_request(request: &'a mut ServiceRequest, _: &'a str) -> Result<Self, Error> {
+impl<'a, T: Send + Sync + 'static> FromRequestRef<'a> for State<T> {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
         request
-            .request
-            .extensions()
-            .ok_or(Error::new(ErrorKind::NotFound, "Failed to find State"))?
             .get::<Arc<T>>()
             .cloned()
             .map(State)
@@ -226,8 +590,8 @@ impl<'a, T: Send + Sync + 'static> FromRequest<'a> for State<T> {
 }
 
 #[async_trait]
-impl<'a> FromRequest<'a> for SocketAddr {
-    async fn from_request(request: &'a mut ServiceRequest, _: &'a str) -> Result<Self, Error> {
+impl<'a> FromRequestRef<'a> for SocketAddr {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
         request
             .get()
             .copied()
@@ -236,40 +600,127 @@ impl<'a> FromRequest<'a> for SocketAddr {
 }
 
 #[async_trait]
-impl<'a> FromRequest<'a> for &'a IncomingRequest {
-    async fn from_request(
-        request: &'a mut ServiceRequest,
+impl<'a> FromRequestRef<'a> for &'a IncomingRequest {
+    async fn from_request_ref(
+        request: &'a ServiceRequest,
         _: &'a str,
     ) -> Result<&'a IncomingRequest, Error> {
         Ok(&request.request)
     }
 }
 
+/// A path variable parsed with FromStr, `Path` alone is the raw String
 #[derive(Clone)]
-pub struct Path(String);
-impl Path {
-    pub fn inner(self) -> String {
+pub struct Path<T = String>(T);
+impl<T> Path<T> {
+    pub fn inner(self) -> T {
         self.0
     }
 }
+impl<T> AsRef<T> for Path<T> {
+    fn as_ref(&self) -> &T {
+        &self.0
+    }
+}
 #[async_trait]
-impl<'a> FromRequest<'a> for Path {
-    async fn from_request(
-        request: &'a mut ServiceRequest,
+impl<'a, T> FromRequestRef<'a> for Path<T>
+where
+    T: FromStr + Send,
+    T::Err: Display,
+{
+    async fn from_request_ref(
+        request: &'a ServiceRequest,
         var_name: &'a str,
     ) -> Result<Self, Error> {
-        request
-            .path
-            .extract(request.request.uri().path(), var_name)
-            .map(Path)
-            .ok_or(Error::new(
-                ErrorKind::InvalidInput,
+        let matched = match request.get::<MatchedPathParams>() {
+            Some(params) => Cow::Borrowed(params),
+            None => Cow::Owned(
+                request
+                    .path
+                    .params(request.request.uri().path())
+                    .unwrap_or_default(),
+            ),
+        };
+        let value = matched.get(var_name).ok_or(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Failed to parse path variable {} in path {}",
+                var_name,
+                request.request.uri().path()
+            ),
+        ))?;
+        value.parse().map(Path).map_err(|e: T::Err| {
+            HttpError::new(
+                StatusCode::BAD_REQUEST,
                 format!(
-                    "Failed to parse path variable {} in path {}",
-                    var_name,
-                    request.request.uri().path()
+                    "Path variable {var_name} must be a {}: {e}",
+                    std::any::type_name::<T>()
                 ),
-            ))
+            )
+            .into()
+        })
+    }
+}
+
+pub struct Query<T: for<'a> Deserialize<'a>>(T);
+impl<T: for<'a> Deserialize<'a>> Query<T> {
+    pub fn inner(self) -> T {
+        self.0
+    }
+}
+impl<T: for<'a> Deserialize<'a>> AsRef<T> for Query<T> {
+    fn as_ref(&self) -> &T {
+        &self.0
+    }
+}
+fn parse_query<T: for<'a> Deserialize<'a>>(
+    request: &ServiceRequest,
+    query: &str,
+) -> Result<T, Error> {
+    let parsed = match duplicate_keys(request) {
+        Some(DuplicateKeys::Collect) => from_collected_pairs(
+            form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+        )
+        .map_err(|e| e.to_string()),
+        Some(policy) => {
+            let pairs = form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect();
+            let pairs = resolve_pairs(pairs, policy)?;
+            serde_urlencoded::to_string(pairs)
+                .map_err(|e| e.to_string())
+                .and_then(|query| serde_urlencoded::from_str(&query).map_err(|e| e.to_string()))
+        }
+        None => serde_urlencoded::from_str(query).map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|e| {
+        HttpError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Failed to parse query string: {e}"),
+        )
+        .into()
+    })
+}
+/// Fails with a 400 when the query string is missing or does not deserialize into `T`
+#[async_trait]
+impl<'a, T: for<'b> Deserialize<'b> + Send> FromRequestRef<'a> for Query<T> {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        match request.request.uri().query() {
+            Some(query) => parse_query(request, query).map(Query),
+            None => Err(HttpError::new(StatusCode::BAD_REQUEST, "Missing query string").into()),
+        }
+    }
+}
+/// None without a query string, a query that does not deserialize is still a 400
+#[async_trait]
+impl<'a, T: for<'b> Deserialize<'b> + Send> FromRequestRef<'a> for Option<Query<T>> {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        match request.request.uri().query() {
+            Some(query) => parse_query(request, query).map(|q| Some(Query(q))),
+            None => Ok(None),
+        }
     }
 }
 
@@ -290,10 +741,111 @@ impl<T: FromBody> AsMut<T> for Body<T> {
     }
 }
 #[async_trait]
-impl<'a, T: FromBody> FromRequest<'a> for Body<T> {
+impl<'a, T: FromBody + Send> FromRequest<'a> for Body<T> {
     async fn from_request(request: &'a mut ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        check_content_type::<T>(request)?;
+        if let Some(consumer) = request.consumed_by() {
+            return Err(HttpError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!(
+                    "Request body was already consumed by {consumer}, \
+                    buffer it with ServiceRequest::set_body to read it more than once"
+                ),
+            )
+            .into());
+        }
+        let duplicates = duplicate_keys(request);
+        if let Some(MaxBodySize(limit)) = request.get::<MaxBodySize>().copied() {
+            let bytes = body_to_bytes(&mut request.request.body(), Some(limit)).await?;
+            request.set_body(ConsumedBodyType::Sized(Full::new(bytes)))?;
+        }
+        let limit = request
+            .get::<ExcerptBytes>()
+            .map_or(DEFAULT_EXCERPT_BYTES, |limit| limit.0);
         let mut body = request.request.body();
-        T::from_body(&mut body).await.map(Body)
+        let extracted = with_excerpt_bytes(limit, T::from_body_with(&mut body, duplicates))
+            .await
+            .map(Body);
+        request.mark_consumed(std::any::type_name::<T>());
+        extracted
+    }
+}
+
+/// The body size limit for this request, larger bodies are rejected with a 413
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MaxBodySize(pub usize);
+
+/// How structured body extractors treat a request without a Content-Type header
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MissingContentType {
+    #[default]
+    AttemptParse,
+    Reject,
+}
+
+/// Rejects the request with a 415 when its Content-Type is not one of the media types accepted by `T`
+pub fn check_content_type<T: FromBody>(request: &ServiceRequest) -> Result<(), Error> {
+    let media_types = match T::media_types() {
+        Some(media_types) => media_types,
+        None => return Ok(()),
+    };
+    let content_type = request
+        .request
+        .headers()
+        .and_then(|h| h.get(CONTENT_TYPE))
+        .map(|v| v.to_str().unwrap_or_default());
+    let accepted = match content_type {
+        Some(content_type) => media_types
+            .iter()
+            .any(|pattern| media_type_matches(pattern, content_type)),
+        None => {
+            request
+                .get::<MissingContentType>()
+                .copied()
+                .unwrap_or_default()
+                == MissingContentType::AttemptParse
+        }
+    };
+    if accepted {
+        Ok(())
+    } else {
+        let mut error = HttpError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            match content_type {
+                Some(content_type) => format!("Unsupported Content-Type: {content_type}"),
+                None => "Missing Content-Type".to_string(),
+            },
+        )
+        .expected(media_types.to_vec());
+        if let Ok(value) = HeaderValue::from_str(&media_types.join(", ")) {
+            error = error.header(HeaderName::from_static("accept-post"), value);
+        }
+        Err(error.into())
+    }
+}
+
+/// Matches a media type against a pattern, patterns can use `*` for the subtype or `*+suffix` for structured syntax suffixes
+pub fn media_type_matches(pattern: &str, content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let (pattern_type, pattern_subtype) = pattern.split_once('/').unwrap_or((pattern, ""));
+    let (actual_type, actual_subtype) = match media_type.split_once('/') {
+        Some(v) => v,
+        None => return false,
+    };
+    if pattern_type != "*" && !pattern_type.eq_ignore_ascii_case(actual_type) {
+        return false;
+    }
+    if pattern_subtype == "*" {
+        true
+    } else if let Some(suffix) = pattern_subtype.strip_prefix('*') {
+        actual_subtype.ends_with(suffix)
+    } else {
+        pattern_subtype.eq_ignore_ascii_case(actual_subtype)
     }
 }
 
@@ -302,26 +854,43 @@ pub trait FromBody {
     async fn from_body(body: &mut BodyType) -> Result<Self, Error>
     where
         Self: Sized;
+    /// Parses with the request's DuplicateKeys policy, extractors without keys ignore it
+    async fn from_body_with(
+        body: &mut BodyType,
+        _duplicates: Option<DuplicateKeys>,
+    ) -> Result<Self, Error>
+    where
+        Self: Sized + Send,
+    {
+        Self::from_body(body).await
+    }
+    /// Media types this extractor can parse, None accepts any Content-Type
+    fn media_types() -> Option<&'static [&'static str]>
+    where
+        Self: Sized,
+    {
+        None
+    }
 }
 
 #[async_trait::async_trait]
 impl FromBody for Bytes {
     async fn from_body(body: &mut BodyType) -> Result<Self, Error> {
-        body_to_bytes(body).await
+        body_to_bytes(body, None).await
     }
 }
 
 #[async_trait::async_trait]
 impl FromBody for Vec<u8> {
     async fn from_body(body: &mut BodyType) -> Result<Self, Error> {
-        body_to_bytes(body).await.map(|b| b.to_vec())
+        body_to_bytes(body, None).await.map(|b| b.to_vec())
     }
 }
 
 #[async_trait::async_trait]
 impl FromBody for String {
     async fn from_body(body: &mut BodyType) -> Result<Self, Error> {
-        let bytes = body_to_bytes(body).await?;
+        let bytes = body_to_bytes(body, None).await?;
         Ok(String::from_utf8_lossy(bytes.as_ref()).to_string())
     }
 }
@@ -336,18 +905,41 @@ impl<T: for<'a> Deserialize<'a>> Json<T> {
 #[async_trait::async_trait]
 impl<T> FromBody for Json<T>
 where
-    T: for<'a> Deserialize<'a>,
+    T: for<'a> Deserialize<'a> + Send,
 {
     async fn from_body(body: &mut BodyType) -> Result<Self, Error> {
-        let bytes = body_to_bytes(body).await?;
-        serde_json::from_slice(bytes.as_ref())
-            .map_err(|e| {
-                Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Failed to parse body as JSON: {e:?}"),
-                )
-            })
-            .map(Json)
+        Self::from_body_with(body, None).await
+    }
+    async fn from_body_with(
+        body: &mut BodyType,
+        duplicates: Option<DuplicateKeys>,
+    ) -> Result<Self, Error> {
+        let bytes = body_to_bytes(body, None).await?;
+        match duplicates {
+            Some(policy) => {
+                resolve_json(bytes.as_ref(), policy).and_then(|value| serde_json::from_value(value))
+            }
+            None => serde_json::from_slice(bytes.as_ref()),
+        }
+        .map_err(|e: serde_json::Error| {
+            //from_value errors have no position, parsing the body as is finds where it went wrong
+            let (line, column) = match e.line() {
+                0 => serde_json::from_slice::<T>(bytes.as_ref())
+                    .err()
+                    .map_or((1, 1), |e| (e.line(), e.column())),
+                line => (line, e.column()),
+            };
+            HttpError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse body as JSON: {e}"),
+            )
+            .details(&ParseContext::new(bytes.as_ref(), line, column))
+            .into()
+        })
+        .map(Json)
+    }
+    fn media_types() -> Option<&'static [&'static str]> {
+        Some(&["application/json", "application/*+json"])
     }
 }
 
@@ -356,7 +948,7 @@ macro_rules! from_body {
         #[async_trait::async_trait]
         impl FromBody for $int {
             async fn from_body(body: &mut BodyType) -> Result<Self, Error> {
-                let bytes = body_to_bytes(body).await?;
+                let bytes = body_to_bytes(body, None).await?;
                 let as_str = String::from_utf8_lossy(bytes.as_ref());
                 as_str.parse().map_err(|e| {
                     Error::new(
@@ -380,7 +972,33 @@ from_body!(i32);
 from_body!(i64);
 from_body!(i128);
 
-async fn body_to_bytes(body: &mut BodyType<'_>) -> Result<Bytes, Error> {
+fn too_large(size: u64, limit: usize) -> Error {
+    HttpError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Payload Too large {size}, Limit is {limit}"),
+    )
+    .into()
+}
+
+fn length_limit_exceeded(limit: Option<usize>) -> Error {
+    let message = match limit {
+        Some(limit) => format!("Payload Too large, Limit is {limit}"),
+        None => "Payload Too large".to_string(),
+    };
+    HttpError::new(StatusCode::PAYLOAD_TOO_LARGE, message).into()
+}
+
+async fn body_to_bytes(body: &mut BodyType<'_>, limit: Option<usize>) -> Result<Bytes, Error> {
+    if let Some(limit) = limit {
+        let size_hint = match body {
+            BodyType::Sized(b) => hyper::body::Body::size_hint(*b),
+            BodyType::Stream(b) => hyper::body::Body::size_hint(*b),
+            BodyType::Empty => SizeHint::with_exact(0),
+        };
+        if size_hint.lower() > limit as u64 {
+            return Err(too_large(size_hint.lower(), limit));
+        }
+    }
     match body {
         BodyType::Sized(b) => b.collect().await.map(|v| v.to_bytes()).map_err(|e| {
             Error::new(
@@ -388,12 +1006,135 @@ async fn body_to_bytes(body: &mut BodyType<'_>) -> Result<Bytes, Error> {
                 format!("Failed to read body: {e:?}"),
             )
         }),
-        BodyType::Stream(b) => b.collect().await.map(|v| v.to_bytes()).map_err(|e| {
-            Error::new(
-                ErrorKind::InvalidInput,
-                format!("Failed to read body: {e:?}"),
-            )
-        }),
+        BodyType::Stream(b) => {
+            let size_hint = hyper::body::Body::size_hint(*b);
+            let mut tracker = UploadTracker::new(size_hint.exact());
+            let mut buffer = Vec::with_capacity(size_hint.lower().min(1 << 20) as usize);
+            loop {
+                let frame = tokio::select! {
+                    frame = b.frame() => frame,
+                    _ = tracker.cancelled() => {
+                        return Err(Error::new(ErrorKind::Interrupted, "Upload was cancelled"));
+                    }
+                    _ = tokio::time::sleep(tracker.register_in()), if !tracker.is_registered() => {
+                        tracker.register();
+                        continue;
+                    }
+                };
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    //The stream itself enforces MaxBodySize
+                    Some(Err(e)) if e.is::<LengthLimitError>() => {
+                        return Err(length_limit_exceeded(limit));
+                    }
+                    Some(Err(e)) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Failed to read body: {e:?}"),
+                        ));
+                    }
+                    None => break,
+                };
+                if let Some(data) = frame.data_ref() {
+                    if let Some(limit) = limit {
+                        if buffer.len() + data.len() > limit {
+                            return Err(too_large((buffer.len() + data.len()) as u64, limit));
+                        }
+                    }
+                    buffer.extend_from_slice(data);
+                    if !tracker.record(data.len()) {
+                        return Err(Error::new(ErrorKind::Interrupted, "Upload was cancelled"));
+                    }
+                }
+            }
+            tracker.complete();
+            Ok(Bytes::from(buffer))
+        }
         BodyType::Empty => Ok(Bytes::new()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ServiceBuilder;
+
+    fn service(path: &str, name: &str) -> Service {
+        ServiceBuilder::new(path).name(name).build()
+    }
+
+    #[test]
+    fn lookups_survive_removal() {
+        let mut registry = ServiceRegistry::default();
+        let (first, second, third) = (
+            service("/a", "shared"),
+            service("/b", "other"),
+            service("/c", "shared"),
+        );
+        let uuids = [first.uuid, second.uuid, third.uuid];
+        registry.register(first);
+        registry.register(second);
+        registry.register(third);
+
+        let removed = registry.remove(&uuids[0]).unwrap();
+        assert_eq!(removed.uuid, uuids[0]);
+        assert!(registry.remove(&uuids[0]).is_none());
+        assert!(registry.find_by_uuid(&uuids[0]).is_none());
+        assert_eq!(registry.find_by_uuid(&uuids[1]).unwrap().name, "other");
+        assert_eq!(registry.find_by_uuid(&uuids[2]).unwrap().uuid, uuids[2]);
+        let shared: Vec<Uuid> = registry
+            .find_by_name("shared")
+            .iter()
+            .map(|s| s.uuid)
+            .collect();
+        assert_eq!(shared, vec![uuids[2]]);
+        let order: Vec<Uuid> = registry.services().iter().map(|s| s.uuid).collect();
+        assert_eq!(order, vec![uuids[1], uuids[2]]);
+        assert!(registry.candidates("/a").is_empty());
+    }
+
+    fn cached(registry: &ServiceRegistry) -> Vec<String> {
+        let mut paths: Vec<String> = registry
+            .method_cache
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn only_static_paths_are_cached() {
+        let mut registry = ServiceRegistry::default();
+        registry.register(ServiceBuilder::new("/x/{id}").method(Method::GET).build());
+        registry.register(ServiceBuilder::new("/static").method(Method::GET).build());
+        registry.register(ServiceBuilder::new("/*").method(Method::PUT).build());
+        for id in 0..10 {
+            registry.methods_for(&format!("/x/{id}"));
+            registry.methods_for(&format!("/missing/{id}"));
+        }
+        let route = registry.methods_for("/static").unwrap();
+        assert_eq!(route.methods, vec![Method::GET, Method::PUT]);
+        assert_eq!(cached(&registry), vec!["/static"]);
+    }
+
+    #[test]
+    fn clones_cache_on_their_own() {
+        let mut registry = ServiceRegistry::default();
+        registry.register(ServiceBuilder::new("/static").method(Method::GET).build());
+        registry.methods_for("/static");
+        let mut clone = registry.clone();
+        assert!(cached(&clone).is_empty());
+        clone.register(ServiceBuilder::new("/static").method(Method::POST).build());
+        assert_eq!(
+            clone.methods_for("/static").unwrap().methods,
+            vec![Method::GET, Method::POST]
+        );
+        assert_eq!(
+            registry.methods_for("/static").unwrap().methods,
+            vec![Method::GET]
+        );
+    }
+}
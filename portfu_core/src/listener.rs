@@ -0,0 +1,135 @@
+use log::warn;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    pub reuse_port: bool,
+    pub reuse_addr: bool,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive_time: Option<Duration>,
+    pub tcp_keepalive_interval: Option<Duration>,
+    pub backlog: u32,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            reuse_port: false,
+            reuse_addr: true,
+            tcp_nodelay: false,
+            tcp_keepalive_time: None,
+            tcp_keepalive_interval: None,
+            backlog: 1024,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+/// Creates a listening socket with the options from `config` applied.
+/// Options the platform does not support are logged and skipped rather than failing the bind.
+pub fn bind_listener(address: SocketAddr, config: &SocketConfig) -> Result<TcpListener, Error> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if config.reuse_addr {
+        if let Err(e) = socket.set_reuse_address(true) {
+            warn!("Failed to set SO_REUSEADDR on {address}: {e:?}");
+        }
+    }
+    if config.reuse_port {
+        set_reuse_port(&socket, address);
+    }
+    //So 0.0.0.0 and [::] on the same port can both be bound, [::] would take v4 as well otherwise
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        if let Err(e) = socket.set_recv_buffer_size(size) {
+            warn!("Failed to set SO_RCVBUF on {address}: {e:?}");
+        }
+    }
+    if let Some(size) = config.send_buffer_size {
+        if let Err(e) = socket.set_send_buffer_size(size) {
+            warn!("Failed to set SO_SNDBUF on {address}: {e:?}");
+        }
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(config.backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket, address: SocketAddr) {
+    if let Err(e) = socket.set_reuse_port(true) {
+        warn!("Failed to set SO_REUSEPORT on {address}: {e:?}");
+    }
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_: &Socket, address: SocketAddr) {
+    warn!("SO_REUSEPORT is not supported on this platform, ignoring for {address}");
+}
+
+/// Applies the per connection options from `config` to an accepted stream
+pub fn configure_stream(stream: &TcpStream, config: &SocketConfig) {
+    if config.tcp_nodelay {
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY: {e:?}");
+        }
+    }
+    if let Some(time) = config.tcp_keepalive_time {
+        let mut keepalive = TcpKeepalive::new().with_time(time);
+        if let Some(interval) = config.tcp_keepalive_interval {
+            keepalive = with_keepalive_interval(keepalive, interval);
+        }
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            warn!("Failed to set TCP keepalive: {e:?}");
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "tvos",
+    target_os = "watchos",
+    target_os = "windows",
+))]
+fn with_keepalive_interval(keepalive: TcpKeepalive, interval: Duration) -> TcpKeepalive {
+    keepalive.with_interval(interval)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "tvos",
+    target_os = "watchos",
+    target_os = "windows",
+)))]
+fn with_keepalive_interval(keepalive: TcpKeepalive, _: Duration) -> TcpKeepalive {
+    warn!("TCP keepalive interval is not supported on this platform, ignoring");
+    keepalive
+}
@@ -0,0 +1,87 @@
+use http::Method;
+use serde::Serialize;
+
+/// How a filter would decide, only what is known without a live request is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedResult {
+    Allow,
+    Block,
+    /// Depends on the request, headers, peer or state
+    Runtime,
+}
+impl PlannedResult {
+    /// Filters named after a method, like the method filters in portfu, only pass that method
+    pub fn for_filter(name: &str, method: &Method) -> Self {
+        const METHODS: [&str; 9] = [
+            "GET", "POST", "PUT", "DELETE", "HEAD", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+        ];
+        if !METHODS.contains(&name) {
+            PlannedResult::Runtime
+        } else if name == method.as_str() {
+            PlannedResult::Allow
+        } else {
+            PlannedResult::Block
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedFilter {
+    pub name: String,
+    pub result: PlannedResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanStage {
+    Before,
+    Handler,
+    After,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanScope {
+    Server,
+    Service,
+}
+
+/// One call made while handling the request, in execution order
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanStep {
+    pub stage: PlanStage,
+    pub scope: PlanScope,
+    pub name: String,
+    /// Runs before request headers are hidden by the HeaderPolicy
+    pub infrastructure: bool,
+}
+
+/// A service whose route matches the path, services are tried in registration order
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedService {
+    pub name: String,
+    pub uuid: String,
+    pub method_allowed: bool,
+    /// Path params, empty for routes without named segments
+    pub params: Vec<(String, String)>,
+    pub filters: Vec<PlannedFilter>,
+    /// Roles reported by the server and service wrappers through WrapperFn::required_role
+    pub required_roles: Vec<String>,
+    /// True when this service handles the request regardless of runtime filters
+    pub definite: bool,
+    /// Wrapper and handler calls for this service, server wrappers included
+    pub steps: Vec<PlanStep>,
+}
+
+/// A dry run of routing a request, see Server::route_plan
+#[derive(Debug, Clone, Serialize)]
+pub struct RoutePlan {
+    pub path: String,
+    pub method: String,
+    pub server_filters: Vec<PlannedFilter>,
+    /// Matching services up to and including the first definite one
+    pub candidates: Vec<PlannedService>,
+    /// Type names of the server's shared state, sorted
+    pub state: Vec<String>,
+}
@@ -0,0 +1,72 @@
+use crate::service::ServiceRequest;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+/// Selects the profile, one of dev, staging, prod or any custom name. Unset is dev.
+pub const PROFILE_ENV: &str = "PORTFU_PROFILE";
+/// Set to `1` or `true` to allow dev only components in the prod profile
+pub const FORCE_DEV_ONLY_ENV: &str = "PORTFU_FORCE_DEV_ONLY";
+
+static PROFILE: Lazy<Profile> = Lazy::new(|| {
+    std::env::var(PROFILE_ENV)
+        .map(|name| Profile::parse(&name))
+        .unwrap_or_default()
+});
+
+/// The environment the process runs in, components use it to pick safe defaults
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub enum Profile {
+    #[default]
+    Dev,
+    Staging,
+    Prod,
+    Custom(String),
+}
+impl Profile {
+    pub fn parse(name: &str) -> Self {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "dev" | "development" => Profile::Dev,
+            "staging" => Profile::Staging,
+            "prod" | "production" => Profile::Prod,
+            other => Profile::Custom(other.to_string()),
+        }
+    }
+    /// The profile selected by PROFILE_ENV
+    pub fn current() -> &'static Profile {
+        &PROFILE
+    }
+    pub fn name(&self) -> &str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Staging => "staging",
+            Profile::Prod => "prod",
+            Profile::Custom(name) => name,
+        }
+    }
+    /// False in prod unless FORCE_DEV_ONLY_ENV is set
+    pub fn allows_dev_only(&self) -> bool {
+        *self != Profile::Prod
+            || std::env::var(FORCE_DEV_ONLY_ENV)
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or_default()
+    }
+}
+/// The profile in the server's shared state, Profile::current when none was set
+pub fn profile(request: &ServiceRequest) -> &Profile {
+    request
+        .get::<Arc<Profile>>()
+        .map_or(Profile::current(), |profile| profile.as_ref())
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+impl Serialize for Profile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
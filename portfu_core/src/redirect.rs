@@ -0,0 +1,81 @@
+use http::header::HOST;
+use http::{HeaderValue, Request, StatusCode};
+
+/// Path prefix of the ACME HTTP-01 challenge, certificate issuance has to reach it over plain HTTP
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Plaintext listener that sends every request to the https scheme, only used when TLS is configured
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    pub port: u16,
+    /// 301 or 308, 308 keeps the method and body
+    pub status: StatusCode,
+    /// Path prefixes served by the registered services instead of redirected
+    pub exempt_paths: Vec<String>,
+    /// Strict-Transport-Security value added to TLS responses, None leaves it off
+    pub hsts: Option<HeaderValue>,
+}
+impl Default for HttpsRedirect {
+    fn default() -> Self {
+        Self {
+            port: 80,
+            status: StatusCode::PERMANENT_REDIRECT,
+            exempt_paths: vec![ACME_CHALLENGE_PATH.to_string()],
+            hsts: Some(HeaderValue::from_static("max-age=31536000")),
+        }
+    }
+}
+impl HttpsRedirect {
+    pub fn port(self, port: u16) -> Self {
+        let mut s = self;
+        s.port = port;
+        s
+    }
+    pub fn status(self, status: StatusCode) -> Self {
+        let mut s = self;
+        s.status = status;
+        s
+    }
+    pub fn exempt<S: AsRef<str>>(self, path: S) -> Self {
+        let mut s = self;
+        s.exempt_paths.push(path.as_ref().to_string());
+        s
+    }
+    pub fn hsts(self, hsts: Option<HeaderValue>) -> Self {
+        let mut s = self;
+        s.hsts = hsts;
+        s
+    }
+    pub fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths
+            .iter()
+            .any(|p| path.starts_with(p.as_str()))
+    }
+    /// The https url for `request` keeping host, path and query, None without a usable host
+    pub fn location<B>(&self, request: &Request<B>, https_port: u16) -> Option<HeaderValue> {
+        let authority = request
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| request.uri().authority().map(|a| a.as_str()))?;
+        let host = match authority.rsplit_once(':') {
+            //Leaves bracketed IPv6 hosts without a port intact
+            Some((host, port)) if !port.contains(']') => host,
+            _ => authority,
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let location = if https_port == 443 {
+            format!("https://{host}{path}")
+        } else {
+            format!("https://{host}:{https_port}{path}")
+        };
+        HeaderValue::from_str(&location).ok()
+    }
+}
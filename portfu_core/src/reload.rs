@@ -0,0 +1,103 @@
+use async_trait::async_trait;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fmt::{Debug, Formatter};
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+
+/// Reload requests arriving within this window of each other are applied once
+pub const RELOAD_COALESCE: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadReport {
+    /// Changes that took effect immediately
+    pub applied: Vec<String>,
+    /// Changes that were read but only take effect after a restart
+    pub restart_required: Vec<String>,
+    /// Changes that were read but not applied, like settings a later source has overridden
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+}
+impl ReloadReport {
+    pub fn applied<S: AsRef<str>>(change: S) -> Self {
+        Self {
+            applied: vec![change.as_ref().to_string()],
+            ..Default::default()
+        }
+    }
+    fn merge(&mut self, other: ReloadReport) {
+        self.applied.extend(other.applied);
+        self.restart_required.extend(other.restart_required);
+        self.skipped.extend(other.skipped);
+        self.failed.extend(other.failed);
+    }
+}
+
+/// Re-reads one piece of configuration and applies what can change at runtime
+#[async_trait]
+pub trait ReloadFn {
+    fn name(&self) -> &str;
+    async fn reload(&self) -> Result<ReloadReport, Error>;
+}
+impl Debug for dyn ReloadFn + Send + Sync + 'static {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Runs the registered ReloadFns on SIGHUP or when requested
+#[derive(Debug, Default)]
+pub struct Reloader {
+    reloaders: Vec<Arc<dyn ReloadFn + Send + Sync>>,
+    requested: Notify,
+    running: Mutex<()>,
+}
+impl Reloader {
+    pub fn new(reloaders: Vec<Arc<dyn ReloadFn + Send + Sync>>) -> Self {
+        Self {
+            reloaders,
+            requested: Notify::new(),
+            running: Mutex::new(()),
+        }
+    }
+    /// Queues a reload, repeated requests while one is pending collapse into it
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+    /// Runs every ReloadFn now, one reload runs at a time
+    pub async fn reload(&self) -> ReloadReport {
+        let _running = self.running.lock().await;
+        let mut report = ReloadReport::default();
+        for reloader in self.reloaders.iter() {
+            match reloader.reload().await {
+                Ok(result) => report.merge(result),
+                Err(e) => {
+                    error!("Failed to reload {}: {e:?}", reloader.name());
+                    report.failed.push(format!("{}: {e}", reloader.name()));
+                }
+            }
+        }
+        info!(
+            "Reload finished, applied: [{}], restart required: [{}], skipped: [{}], failed: [{}]",
+            report.applied.join(", "),
+            report.restart_required.join(", "),
+            report.skipped.join(", "),
+            report.failed.join(", ")
+        );
+        report
+    }
+    /// Applies queued requests until the future is dropped
+    pub async fn run(&self) {
+        loop {
+            self.requested.notified().await;
+            tokio::time::sleep(RELOAD_COALESCE).await;
+            if self.reloaders.is_empty() {
+                warn!("Reload requested but no reload handlers are registered");
+                continue;
+            }
+            self.reload().await;
+        }
+    }
+}
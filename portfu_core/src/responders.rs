@@ -0,0 +1,343 @@
+use crate::{IntoStreamBody, ServiceResponse};
+use futures_util::stream::{unfold, Stream, StreamExt};
+use http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use http_body::Frame;
+use http_body_util::{BodyStream, StreamBody};
+use hyper::body::Bytes;
+use log::warn;
+use serde::Serialize;
+use std::fmt::Display;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// What an endpoint returns, written into the response by the endpoint macros
+pub trait Responder {
+    fn respond(self, response: &mut ServiceResponse);
+}
+impl<T: Into<Bytes>> Responder for T {
+    fn respond(self, response: &mut ServiceResponse) {
+        let bytes: Bytes = self.into();
+        *response.body_mut() = bytes.stream_body();
+    }
+}
+
+type FrameResult = Result<Frame<Bytes>, &'static str>;
+
+fn stream_response<F>(response: &mut ServiceResponse, content_type: &'static str, frames: F)
+where
+    F: Stream<Item = FrameResult> + Send + Sync + 'static,
+{
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    *response.body_mut() = StreamBody::new(BodyStream::new(Box::pin(StreamBody::new(frames))));
+}
+
+/// Streams each item as one line of JSON, an error ends the stream with a final `{"error": ...}` line.
+/// Items are only pulled from the source as the client reads, dropping the connection drops the source.
+pub struct NdJson<S>(S);
+impl<S> NdJson<S> {
+    pub fn new(stream: S) -> Self {
+        Self(stream)
+    }
+}
+impl<S, T, E> Responder for NdJson<S>
+where
+    S: Stream<Item = Result<T, E>> + Send + Sync + 'static,
+    T: Serialize,
+    E: Display,
+{
+    fn respond(self, response: &mut ServiceResponse) {
+        let frames = unfold(Some(Box::pin(self.0)), |stream| async move {
+            let mut stream = stream?;
+            let (mut line, stream) = match stream.next().await? {
+                Ok(item) => match serde_json::to_vec(&item) {
+                    Ok(line) => (line, Some(stream)),
+                    Err(e) => (ndjson_error(e), None),
+                },
+                Err(e) => (ndjson_error(e), None),
+            };
+            line.push(b'\n');
+            Some((Ok(Frame::data(Bytes::from(line))), stream))
+        });
+        stream_response(response, "application/x-ndjson", frames);
+    }
+}
+
+fn ndjson_error<E: Display>(error: E) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({ "error": error.to_string() })).unwrap_or_default()
+}
+
+/// An item with the optional SSE fields, plain Serialize items are sent as `data` only
+pub struct SseEvent<T> {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub data: T,
+}
+impl<T> SseEvent<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            event: None,
+            id: None,
+            data,
+        }
+    }
+    pub fn event<S: AsRef<str>>(self, event: S) -> Self {
+        let mut s = self;
+        s.event = Some(event.as_ref().to_string());
+        s
+    }
+    pub fn id<S: AsRef<str>>(self, id: S) -> Self {
+        let mut s = self;
+        s.id = Some(id.as_ref().to_string());
+        s
+    }
+}
+
+/// Converts a stream item into an SSE event
+pub trait IntoSseEvent {
+    type Data: Serialize;
+    fn into_sse_event(self) -> SseEvent<Self::Data>;
+}
+impl<T: Serialize> IntoSseEvent for T {
+    type Data = T;
+    fn into_sse_event(self) -> SseEvent<T> {
+        SseEvent::new(self)
+    }
+}
+impl<T: Serialize> IntoSseEvent for SseEvent<T> {
+    type Data = T;
+    fn into_sse_event(self) -> SseEvent<T> {
+        self
+    }
+}
+
+fn sse_frame<T: Serialize>(event: SseEvent<T>) -> String {
+    //Field values can not span lines, the JSON data is already on one
+    let field = |name: &str, value: &str| format!("{name}: {}\n", value.replace(['\r', '\n'], " "));
+    let mut frame = String::new();
+    if let Some(event_name) = &event.event {
+        frame.push_str(&field("event", event_name));
+    }
+    if let Some(id) = &event.id {
+        frame.push_str(&field("id", id));
+    }
+    match serde_json::to_string(&event.data) {
+        Ok(data) => frame.push_str(&field("data", &data)),
+        Err(e) => return sse_error(e),
+    }
+    frame.push('\n');
+    frame
+}
+
+fn sse_error<E: Display>(error: E) -> String {
+    format!(
+        "event: error\ndata: {}\n\n",
+        serde_json::to_string(&error.to_string()).unwrap_or_default()
+    )
+}
+
+/// Streams each item as a server sent event serialized as JSON, an error ends the stream with a final `error` event.
+/// With keep_alive set a comment is sent whenever the source is idle for that long so proxies keep the connection open.
+pub struct Sse<S> {
+    stream: S,
+    keep_alive: Option<Duration>,
+}
+impl<S> Sse<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            keep_alive: None,
+        }
+    }
+    pub fn keep_alive(self, keep_alive: Duration) -> Self {
+        let mut s = self;
+        s.keep_alive = Some(keep_alive);
+        s
+    }
+}
+impl<S, T, E> Responder for Sse<S>
+where
+    S: Stream<Item = Result<T, E>> + Send + Sync + 'static,
+    T: IntoSseEvent,
+    E: Display,
+{
+    fn respond(self, response: &mut ServiceResponse) {
+        let keep_alive = self.keep_alive;
+        let stream: Pin<Box<S>> = Box::pin(self.stream);
+        let frames = unfold(Some(stream), move |stream| async move {
+            let mut stream = stream?;
+            let next = match keep_alive {
+                Some(keep_alive) => tokio::select! {
+                    next = stream.next() => Some(next),
+                    _ = tokio::time::sleep(keep_alive) => None,
+                },
+                None => Some(stream.next().await),
+            };
+            let (frame, stream) = match next {
+                None => (": keep-alive\n\n".to_string(), Some(stream)),
+                Some(None) => return None,
+                Some(Some(Ok(item))) => (sse_frame(item.into_sse_event()), Some(stream)),
+                Some(Some(Err(e))) => (sse_error(e), None),
+            };
+            Some((Ok(Frame::data(Bytes::from(frame))), stream))
+        });
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        stream_response(response, "text/event-stream", frames);
+    }
+}
+
+/// A body with its own status and headers.
+/// Headers are appended to those wrappers already set, Content-Type replaces theirs.
+pub struct ResponseBuilder {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+impl ResponseBuilder {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+    /// Invalid header values are logged and skipped
+    pub fn header<V: TryInto<HeaderValue>>(self, name: HeaderName, value: V) -> Self {
+        let mut s = self;
+        match value.try_into() {
+            Ok(value) => {
+                s.headers.append(name, value);
+            }
+            Err(_) => warn!("Skipped invalid value for header {name}"),
+        }
+        s
+    }
+    pub fn body<B: Into<Bytes>>(self, body: B) -> Self {
+        let mut s = self;
+        s.body = body.into();
+        s
+    }
+    /// Serializes `value` as the body, a serialization failure becomes a 500
+    pub fn json<T: Serialize>(self, value: &T) -> Self {
+        let mut s = self;
+        match serde_json::to_vec(value) {
+            Ok(body) => {
+                s.headers
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                s.body = body.into();
+            }
+            Err(e) => {
+                s.status = StatusCode::INTERNAL_SERVER_ERROR;
+                s.body = format!("Failed to serialize response: {e}").into();
+            }
+        }
+        s
+    }
+}
+impl Responder for ResponseBuilder {
+    fn respond(self, response: &mut ServiceResponse) {
+        *response.status_mut() = self.status;
+        let mut name = None;
+        for (key, value) in self.headers {
+            if key.is_some() {
+                name = key;
+            }
+            match &name {
+                Some(name) if *name == CONTENT_TYPE => {
+                    response.headers_mut().insert(name, value);
+                }
+                Some(name) => {
+                    response.headers_mut().append(name, value);
+                }
+                None => {}
+            }
+        }
+        *response.body_mut() = self.body.stream_body();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream::poll_fn;
+    use http::Response;
+    use http_body_util::BodyExt;
+    use tokio::sync::mpsc;
+
+    type Item = Result<serde_json::Value, String>;
+    type Source = Box<dyn Stream<Item = Item> + Send + Sync + Unpin>;
+
+    /// A response streaming what is sent on the returned channel through `responder`
+    fn respond_to<R: Responder, F: FnOnce(Source) -> R>(
+        responder: F,
+    ) -> (mpsc::Sender<Item>, ServiceResponse) {
+        let (sender, mut receiver) = mpsc::channel(8);
+        let mut response = Response::new(Bytes::new().stream_body());
+        responder(Box::new(poll_fn(move |cx| receiver.poll_recv(cx)))).respond(&mut response);
+        (sender, response)
+    }
+
+    async fn next_frame(response: &mut ServiceResponse) -> Option<String> {
+        let frame = response.body_mut().frame().await?.unwrap();
+        Some(String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn ndjson_sends_a_line_per_item_and_ends_on_error() {
+        let (sender, mut response) = respond_to(NdJson::new);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/x-ndjson");
+        sender.send(Ok(serde_json::json!({"n": 1}))).await.unwrap();
+        assert_eq!(next_frame(&mut response).await.unwrap(), "{\"n\":1}\n");
+        sender.send(Ok(serde_json::json!(2))).await.unwrap();
+        sender.send(Err("source failed".to_string())).await.unwrap();
+        sender.send(Ok(serde_json::json!(3))).await.unwrap();
+        assert_eq!(next_frame(&mut response).await.unwrap(), "2\n");
+        assert_eq!(
+            next_frame(&mut response).await.unwrap(),
+            "{\"error\":\"source failed\"}\n"
+        );
+        assert_eq!(next_frame(&mut response).await, None);
+    }
+
+    #[tokio::test]
+    async fn sse_frames_events_and_keeps_idle_streams_alive() {
+        let (sender, mut response) = respond_to(|stream| {
+            Sse::new(stream.map(|item| {
+                item.map(|data| match data.as_u64() {
+                    Some(id) => SseEvent::new(data).event("tick").id(id.to_string()),
+                    None => SseEvent::new(data),
+                })
+            }))
+            .keep_alive(Duration::from_millis(20))
+        });
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-cache");
+        sender.send(Ok(serde_json::json!(7))).await.unwrap();
+        assert_eq!(
+            next_frame(&mut response).await.unwrap(),
+            "event: tick\nid: 7\ndata: 7\n\n"
+        );
+        assert_eq!(next_frame(&mut response).await.unwrap(), ": keep-alive\n\n");
+        sender.send(Ok(serde_json::json!("a\nb"))).await.unwrap();
+        assert_eq!(
+            next_frame(&mut response).await.unwrap(),
+            "data: \"a\\nb\"\n\n"
+        );
+        drop(sender);
+        assert_eq!(next_frame(&mut response).await, None);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_body_drops_the_source() {
+        let (sender, mut response) = respond_to(NdJson::new);
+        sender.send(Ok(serde_json::json!(1))).await.unwrap();
+        next_frame(&mut response).await.unwrap();
+        assert!(!sender.is_closed());
+        drop(response);
+        assert!(sender.is_closed());
+    }
+}
@@ -1,5 +1,7 @@
+use percent_encoding::percent_decode_str;
 use regex::{escape, Regex};
 use std::borrow::Cow;
+use std::collections::HashMap;
 
 const REGEX_FLAGS: &str = "(?s-m)";
 
@@ -17,6 +19,26 @@ pub struct PathData {
 pub enum PathSegment {
     Static(String),
     Variable(PathVariable),
+    /// `{name..}` or `{*name}`, captures the rest of the path including slashes, may be empty
+    Tail(PathVariable),
+}
+
+/// Path variables captured once per request, kept in the request extensions for Path extraction.
+/// Values are percent-decoded, invalid UTF-8 is replaced.
+#[derive(Debug, Clone, Default)]
+pub struct MatchedPathParams {
+    values: Vec<(String, String)>,
+}
+impl MatchedPathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
 }
 
 #[derive(Debug)]
@@ -31,8 +53,11 @@ impl Route {
         let mut segments = Vec::new();
         let mut has_tail = false;
         while let Some(idx) = to_parse.find('{') {
+            if has_tail {
+                panic!("{}", Self::tail_error(&input));
+            }
             let (prefix, rem) = to_parse.split_at(idx);
-            segments.push(PathSegment::Static(to_parse.to_string()));
+            segments.push(PathSegment::Static(prefix.to_string()));
             re.push_str(&escape(prefix));
             let (param_pattern, re_part, rem, tail) = Self::parse_param(rem);
             if tail {
@@ -45,10 +70,15 @@ impl Route {
         if to_parse.ends_with('*') {
             re.push_str(&escape(to_parse.strip_suffix('*').unwrap()));
             re.push_str(".*");
+        } else if has_tail && !to_parse.is_empty() {
+            panic!("{}", Self::tail_error(&input));
         } else if !has_tail && !to_parse.is_empty() {
             segments.push(PathSegment::Static(to_parse.to_string()));
             re.push_str(&escape(to_parse));
             re.push('$');
+        } else if !has_tail && !segments.is_empty() {
+            // ends on a single segment variable, which must not match deeper paths
+            re.push('$');
         }
         if segments.is_empty() {
             Self::Static(Cow::Owned(input), Regex::new(re.as_str()).unwrap())
@@ -56,6 +86,59 @@ impl Route {
             Self::Segmented(segments, Regex::new(re.as_str()).unwrap())
         }
     }
+    /// Route::new without the panic on malformed patterns, for callers that report the error
+    pub fn try_new(input: String) -> Result<Self, String> {
+        Self::validate(&input)?;
+        Ok(Self::new(input))
+    }
+    /// Checks the pattern without building the route, a tail variable must be the final segment
+    pub fn validate(input: &str) -> Result<(), String> {
+        let mut to_parse = input;
+        let mut has_tail = false;
+        while let Some(idx) = to_parse.find('{') {
+            if has_tail {
+                return Err(Self::tail_error(input));
+            }
+            let rem = &to_parse[idx..];
+            let close_idx = rem.find('}').ok_or(format!(
+                r#"pattern "{}" contains malformed dynamic segment"#,
+                input
+            ))?;
+            let (param, mut rem) = rem.split_at(close_idx + 1);
+            if let Some(stripped) = rem.strip_prefix('*') {
+                rem = stripped;
+                has_tail = true;
+            }
+            has_tail |= Self::tail_name(&param[1..param.len() - 1]).is_some();
+            to_parse = rem;
+        }
+        if has_tail && !to_parse.is_empty() {
+            return Err(Self::tail_error(input));
+        }
+        Ok(())
+    }
+    fn tail_error(input: &str) -> String {
+        format!(
+            r#"pattern "{}" has segments after its tail variable, {{name..}} must be the final segment"#,
+            input
+        )
+    }
+    /// The name of a `name..` or `*name` parameter
+    fn tail_name(param: &str) -> Option<&str> {
+        param.strip_suffix("..").or_else(|| param.strip_prefix('*'))
+    }
+    /// The literal text every matching path starts with, and whether only that exact path matches
+    pub fn static_prefix(&self) -> (&str, bool) {
+        match self {
+            // only built for `*` patterns and the empty pattern, both match anything after their text
+            Route::Static(input, _) => (input.strip_suffix('*').unwrap_or(input), false),
+            Route::Segmented(segments, _) => match segments.as_slice() {
+                [PathSegment::Static(path)] => (path.as_str(), true),
+                [PathSegment::Static(prefix), ..] => (prefix.as_str(), false),
+                _ => ("", false),
+            },
+        }
+    }
     pub fn matches(&self, path: &str) -> bool {
         match self {
             Route::Static(_, r) => r.is_match(path),
@@ -67,13 +150,33 @@ impl Route {
             Route::Static(_, _) => None,
             Route::Segmented(_, r) => {
                 if let Some(captures) = r.captures(path) {
-                    captures.name(name).map(|m| m.as_str().to_string())
+                    captures.name(name).map(|m| decode(m.as_str()))
                 } else {
                     None
                 }
             }
         }
     }
+    /// Every variable of the route matched against `path` in one pass
+    pub fn params(&self, path: &str) -> Option<MatchedPathParams> {
+        match self {
+            Route::Static(_, _) => None,
+            Route::Segmented(_, r) => {
+                let captures = r.captures(path)?;
+                Some(MatchedPathParams {
+                    values: r
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|name| {
+                            captures
+                                .name(name)
+                                .map(|m| (name.to_string(), decode(m.as_str())))
+                        })
+                        .collect(),
+                })
+            }
+        }
+    }
     fn parse_param(input: &str) -> (PathSegment, String, &str, bool) {
         const DEFAULT_PATTERN: &str = "[^/]+";
         const DEFAULT_PATTERN_TAIL: &str = ".*";
@@ -81,23 +184,487 @@ impl Route {
             .find('}')
             .unwrap_or_else(|| panic!(r#"pattern "{}" contains malformed dynamic segment"#, input));
         let (mut param, mut unprocessed) = input.split_at(close_idx + 1);
-        let tail = unprocessed == "*";
+        let mut tail = unprocessed == "*";
+        if tail {
+            unprocessed = &unprocessed[1..];
+        }
         // remove outer curly brackets
         param = &param[1..param.len() - 1];
-        let (name, pattern) = (
-            param,
-            if tail {
-                unprocessed = &unprocessed[1..];
-                DEFAULT_PATTERN_TAIL
-            } else {
-                DEFAULT_PATTERN
-            },
-        );
-
-        let segment = PathSegment::Variable(PathVariable {
+        let name = match Self::tail_name(param) {
+            Some(name) => {
+                tail = true;
+                name
+            }
+            None => param,
+        };
+        let variable = PathVariable {
             name: name.to_string(),
-        });
+        };
+        let (segment, pattern) = if tail {
+            (PathSegment::Tail(variable), DEFAULT_PATTERN_TAIL)
+        } else {
+            (PathSegment::Variable(variable), DEFAULT_PATTERN)
+        };
         let regex = format!(r"(?P<{}>{})", &name, &pattern);
         (segment, regex, unprocessed, tail)
     }
 }
+
+/// Narrows the routes a path can match before their regexes run.
+/// Values are kept in a tree keyed on the whole segments at the start of their route, a segment that is a single
+/// variable becomes a wildcard node. A lookup walks the segments of the path and returns the values on the way in insertion order.
+#[derive(Debug, Clone)]
+pub struct Router<T> {
+    root: RouterNode<T>,
+    inserted: usize,
+}
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Self {
+            root: RouterNode::default(),
+            inserted: 0,
+        }
+    }
+}
+impl<T> Router<T> {
+    pub fn insert(&mut self, route: &Route, value: T) {
+        let order = self.inserted;
+        self.inserted += 1;
+        let key = RouteKey::new(route);
+        let node = key
+            .segments
+            .iter()
+            .fold(&mut self.root, |node, segment| match segment {
+                Some(literal) => node.children.entry(literal.clone()).or_default(),
+                None => node.variable.get_or_insert_with(Default::default),
+            });
+        if key.exact {
+            node.exact.push((order, value));
+        } else {
+            node.prefixed.push((order, value));
+        }
+    }
+    /// Removes the values registered for `route` that `remove` returns true for
+    pub fn remove<F: Fn(&T) -> bool>(&mut self, route: &Route, remove: F) {
+        let key = RouteKey::new(route);
+        let mut node = &mut self.root;
+        for segment in &key.segments {
+            let next = match segment {
+                Some(literal) => node.children.get_mut(literal),
+                None => node.variable.as_deref_mut(),
+            };
+            node = match next {
+                Some(child) => child,
+                None => return,
+            };
+        }
+        if key.exact {
+            node.exact.retain(|(_, value)| !remove(value));
+        } else {
+            node.prefixed.retain(|(_, value)| !remove(value));
+        }
+    }
+    /// Every value whose route could match `path`, in insertion order
+    pub fn candidates(&self, path: &str) -> Vec<&T> {
+        let mut found = vec![];
+        match path.strip_prefix('/') {
+            Some(path) => {
+                let segments: Vec<&str> = path.split('/').collect();
+                self.root.collect(&segments, &mut found);
+            }
+            None => found.extend(self.root.prefixed.iter()),
+        }
+        found.sort_by_key(|(order, _)| *order);
+        found.into_iter().map(|(_, value)| value).collect()
+    }
+}
+
+/// Where a route is kept in a Router
+#[derive(Debug, PartialEq, Eq)]
+struct RouteKey {
+    /// The complete segments after the leading `/`, None for a segment that is a single variable
+    segments: Vec<Option<String>>,
+    /// Whether the route ends after `segments`, otherwise it can match paths with more segments
+    exact: bool,
+}
+impl RouteKey {
+    /// Routes not starting at `/` are kept at the root and checked for every path
+    fn new(route: &Route) -> Self {
+        // (literal text, variables in it) for every `/` separated segment of the route
+        let mut pieces = vec![(String::new(), 0usize)];
+        let exact = match route {
+            Route::Static(..) => {
+                Self::push_text(&mut pieces, route.static_prefix().0);
+                false
+            }
+            Route::Segmented(segments, regex) => {
+                for segment in segments {
+                    match segment {
+                        PathSegment::Static(text) => Self::push_text(&mut pieces, text),
+                        PathSegment::Variable(_) => {
+                            if let Some((_, variables)) = pieces.last_mut() {
+                                *variables += 1;
+                            }
+                        }
+                        PathSegment::Tail(_) => break,
+                    }
+                }
+                // `*` and tail routes leave off the closing anchor
+                regex.as_str().ends_with('$')
+            }
+        };
+        if !exact {
+            // the last segment is only the start of what the route matches
+            pieces.pop();
+        }
+        let mut key = Self {
+            segments: vec![],
+            exact: false,
+        };
+        let mut pieces = pieces.into_iter();
+        if pieces.next() != Some((String::new(), 0)) {
+            return key;
+        }
+        for piece in pieces {
+            match piece {
+                (literal, 0) => key.segments.push(Some(literal)),
+                (literal, 1) if literal.is_empty() => key.segments.push(None),
+                _ => return key,
+            }
+        }
+        key.exact = exact;
+        key
+    }
+    fn push_text(pieces: &mut Vec<(String, usize)>, text: &str) {
+        let mut parts = text.split('/');
+        if let (Some(first), Some((literal, _))) = (parts.next(), pieces.last_mut()) {
+            literal.push_str(first);
+        }
+        pieces.extend(parts.map(|part| (part.to_string(), 0)));
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RouterNode<T> {
+    children: HashMap<String, RouterNode<T>>,
+    /// Routes with a single variable as their next segment
+    variable: Option<Box<RouterNode<T>>>,
+    /// Routes that can match any path at or below this node
+    prefixed: Vec<(usize, T)>,
+    /// Routes matching exactly the path of this node
+    exact: Vec<(usize, T)>,
+}
+impl<T> Default for RouterNode<T> {
+    fn default() -> Self {
+        Self {
+            children: HashMap::new(),
+            variable: None,
+            prefixed: vec![],
+            exact: vec![],
+        }
+    }
+}
+impl<T> RouterNode<T> {
+    fn collect<'a>(&'a self, segments: &[&str], found: &mut Vec<&'a (usize, T)>) {
+        found.extend(self.prefixed.iter());
+        match segments.split_first() {
+            None => found.extend(self.exact.iter()),
+            Some((segment, rest)) => {
+                if let Some(child) = self.children.get(*segment) {
+                    child.collect(rest, found);
+                }
+                // a variable matches one non-empty segment
+                if let (false, Some(variable)) = (segment.is_empty(), &self.variable) {
+                    variable.collect(rest, found);
+                }
+            }
+        }
+    }
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES: &[&str] = &[
+        "/",
+        "/index.html",
+        "/static/app.js",
+        "/static/*",
+        "/static/css/*",
+        "/api/users",
+        "/api/users/",
+        "/api/users/{id}",
+        "/api/users/{id}/posts",
+        "/api/users/{id}/posts/{post}",
+        "/api/user{id}",
+        "/api/{version}/status",
+        "/files/{path..}",
+        "/files/{*rest}",
+        "/{page}",
+        "/{a}/{b}",
+        "*",
+        "",
+        "no-slash",
+        "/api*",
+    ];
+    const PATHS: &[&str] = &[
+        "",
+        "/",
+        "//",
+        "/index.html",
+        "/index.htm",
+        "/index.html/",
+        "/static/",
+        "/static/app.js",
+        "/static/css/site.css",
+        "/static",
+        "/api",
+        "/api/users",
+        "/api/users/",
+        "/api/users/7",
+        "/api/users/7/",
+        "/api/users/7/posts",
+        "/api/users/7/posts/9",
+        "/api/users//posts",
+        "/api/user7",
+        "/api/v1/status",
+        "/files",
+        "/files/",
+        "/files/a/b/c",
+        "/about",
+        "/a/b",
+        "/a/b/c",
+        "no-slash",
+        "relative/path",
+    ];
+
+    fn router(routes: &[Route], skip: &[usize]) -> Router<usize> {
+        let mut router = Router::default();
+        for (i, route) in routes.iter().enumerate() {
+            if !skip.contains(&i) {
+                router.insert(route, i);
+            }
+        }
+        router
+    }
+
+    fn linear(routes: &[Route], skip: &[usize], path: &str) -> Vec<usize> {
+        (0..routes.len())
+            .filter(|i| !skip.contains(i) && routes[*i].matches(path))
+            .collect()
+    }
+
+    fn matched(router: &Router<usize>, routes: &[Route], path: &str) -> Vec<usize> {
+        router
+            .candidates(path)
+            .into_iter()
+            .copied()
+            .filter(|i| routes[*i].matches(path))
+            .collect()
+    }
+
+    #[test]
+    fn static_prefix_of_a_plain_path_is_exact() {
+        assert_eq!(
+            Route::new("/index.html".to_string()).static_prefix(),
+            ("/index.html", true)
+        );
+        assert_eq!(
+            Route::new("/api/{id}".to_string()).static_prefix(),
+            ("/api/", false)
+        );
+        assert_eq!(
+            Route::new("/static/*".to_string()).static_prefix(),
+            ("/static/", false)
+        );
+        assert_eq!(Route::new(String::new()).static_prefix(), ("", false));
+    }
+
+    #[test]
+    fn routes_are_keyed_on_their_complete_segments() {
+        let key = |input: &str| RouteKey::new(&Route::new(input.to_string()));
+        let literal = |s: &str| Some(s.to_string());
+        assert_eq!(
+            key("/index.html"),
+            RouteKey {
+                segments: vec![literal("index.html")],
+                exact: true
+            }
+        );
+        assert_eq!(
+            key("/api/users/{id}/posts"),
+            RouteKey {
+                segments: vec![literal("api"), literal("users"), None, literal("posts")],
+                exact: true
+            }
+        );
+        assert_eq!(
+            key("/api/user{id}/posts"),
+            RouteKey {
+                segments: vec![literal("api")],
+                exact: false
+            }
+        );
+        assert_eq!(
+            key("/static/css/*"),
+            RouteKey {
+                segments: vec![literal("static"), literal("css")],
+                exact: false
+            }
+        );
+        assert_eq!(
+            key("/files/{path..}"),
+            RouteKey {
+                segments: vec![literal("files")],
+                exact: false
+            }
+        );
+        assert_eq!(
+            key("no-slash"),
+            RouteKey {
+                segments: vec![],
+                exact: false
+            }
+        );
+    }
+
+    #[test]
+    fn static_routes_are_not_shared_with_their_parent() {
+        let routes: Vec<Route> = ["/a.html", "/b.html", "/dir/c.html"]
+            .iter()
+            .map(|r| Route::new(r.to_string()))
+            .collect();
+        let router = router(&routes, &[]);
+        assert_eq!(router.candidates("/a.html"), vec![&0]);
+        assert_eq!(router.candidates("/dir/c.html"), vec![&2]);
+        assert!(router.candidates("/dir").is_empty());
+    }
+
+    #[test]
+    fn candidates_match_a_linear_scan() {
+        let routes: Vec<Route> = ROUTES.iter().map(|r| Route::new(r.to_string())).collect();
+        let router = router(&routes, &[]);
+        for path in PATHS {
+            assert_eq!(
+                matched(&router, &routes, path),
+                linear(&routes, &[], path),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn candidates_match_a_linear_scan_after_removal() {
+        let routes: Vec<Route> = ROUTES.iter().map(|r| Route::new(r.to_string())).collect();
+        let removed = [1, 3, 7, 12, 14, 16, 18];
+        let mut router = router(&routes, &[]);
+        for i in removed {
+            router.remove(&routes[i], |value| *value == i);
+        }
+        for path in PATHS {
+            assert_eq!(
+                matched(&router, &routes, path),
+                linear(&routes, &removed, path),
+                "{path}"
+            );
+        }
+        // re-inserted routes come after the ones that stayed
+        for i in removed {
+            router.insert(&routes[i], i);
+        }
+        for path in PATHS {
+            let mut expected = linear(&routes, &removed, path);
+            expected.extend(removed.iter().filter(|i| routes[**i].matches(path)));
+            assert_eq!(matched(&router, &routes, path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn removal_only_drops_the_selected_value() {
+        let route = Route::new("/shared".to_string());
+        let mut router = Router::default();
+        router.insert(&route, 1);
+        router.insert(&route, 2);
+        router.remove(&route, |value| *value == 1);
+        assert_eq!(router.candidates("/shared"), vec![&2]);
+        router.remove(&Route::new("/missing/route".to_string()), |_| true);
+        assert_eq!(router.candidates("/shared"), vec![&2]);
+    }
+
+    #[test]
+    fn params_are_percent_decoded() {
+        let route = Route::new("/files/{name}/{rest..}".to_string());
+        let params = route.params("/files/a%20b/x%2Fy/%E2%9C%93").unwrap();
+        assert_eq!(params.get("name"), Some("a b"));
+        assert_eq!(params.get("rest"), Some("x/y/\u{2713}"));
+        assert_eq!(
+            route.extract("/files/a%20b/c", "name").as_deref(),
+            Some("a b")
+        );
+        let invalid = route.params("/files/%FF/").unwrap();
+        assert_eq!(invalid.get("name"), Some("\u{FFFD}"));
+        assert_eq!(invalid.get("rest"), Some(""));
+    }
+
+    #[test]
+    fn tails_capture_the_rest_of_the_path() {
+        for pattern in ["/files/{path..}", "/files/{*path}"] {
+            let route = Route::new(pattern.to_string());
+            assert!(route.matches("/files/"));
+            assert_eq!(route.extract("/files/", "path").as_deref(), Some(""));
+            assert_eq!(
+                route.extract("/files/a/b/c.txt", "path").as_deref(),
+                Some("a/b/c.txt")
+            );
+            assert!(!route.matches("/files"));
+            assert!(!route.matches("/other/a"));
+        }
+    }
+
+    #[test]
+    fn encoded_slashes_stay_in_their_segment() {
+        let tail = Route::new("/files/{path..}".to_string());
+        assert_eq!(
+            tail.extract("/files/a%2Fb/c", "path").as_deref(),
+            Some("a/b/c")
+        );
+        //A single segment variable matches before decoding, so %2F does not split it
+        let single = Route::new("/users/{id}".to_string());
+        assert!(single.matches("/users/a%2Fb"));
+        assert_eq!(single.extract("/users/a%2Fb", "id").as_deref(), Some("a/b"));
+        assert!(!single.matches("/users/a/b"));
+    }
+
+    #[test]
+    fn static_segments_before_a_tail_still_match() {
+        let route = Route::new("/api/{version}/raw/{rest..}".to_string());
+        let params = route.params("/api/v2/raw/x/y").unwrap();
+        assert_eq!(params.get("version"), Some("v2"));
+        assert_eq!(params.get("rest"), Some("x/y"));
+        assert!(!route.matches("/api/v2/cooked/x"));
+        assert!(!route.matches("/api/v2/x/raw/y"));
+    }
+
+    #[test]
+    fn tails_must_be_the_final_segment() {
+        for pattern in [
+            "/files/{path..}/raw",
+            "/files/{*path}/{name}",
+            "/files/{path}*/raw",
+        ] {
+            let error = Route::validate(pattern).unwrap_err();
+            assert!(error.contains("must be the final segment"), "{error}");
+            assert!(Route::try_new(pattern.to_string()).is_err());
+        }
+        assert!(Route::validate("/users/{id")
+            .unwrap_err()
+            .contains("malformed dynamic segment"));
+        assert!(Route::validate("/files/{name}/{path..}").is_ok());
+        assert!(Route::try_new("/static/*".to_string()).is_ok());
+    }
+}
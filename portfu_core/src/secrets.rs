@@ -0,0 +1,156 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::env;
+use std::fmt::{Debug, Display, Formatter};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Values that can be held by a Secret.
+pub trait SecretValue {
+    fn from_env_value(value: String) -> Self;
+    fn wipe(&mut self);
+}
+impl SecretValue for String {
+    fn from_env_value(value: String) -> Self {
+        value
+    }
+    fn wipe(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+impl SecretValue for Vec<u8> {
+    fn from_env_value(value: String) -> Self {
+        value.into_bytes()
+    }
+    fn wipe(&mut self) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+
+/// Wraps a value that should never end up in logs, Debug and Display print "[REDACTED]".
+/// Deserializes from either a literal value or `{"env": "NAME"}` to read the value from the environment.
+/// With the `zeroize` feature enabled the value is wiped when dropped.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret<T: SecretValue>(T);
+pub type SecretString = Secret<String>;
+pub type SecretBytes = Secret<Vec<u8>>;
+
+impl<T: SecretValue> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+    pub fn from_env(name: &str) -> Option<Self> {
+        env::var(name).ok().map(|v| Self(T::from_env_value(v)))
+    }
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+impl<T: SecretValue> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.wipe();
+    }
+}
+impl<T: SecretValue> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+impl<T: SecretValue> Display for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+impl From<Vec<u8>> for SecretBytes {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+impl From<&[u8]> for SecretBytes {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+/// Serializes as "[REDACTED]", fields that must be written out opt in with
+/// `#[serde(serialize_with = "Secret::serialize_exposed")]`
+impl<T: SecretValue> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+impl<T: SecretValue + Serialize> Secret<T> {
+    pub fn serialize_exposed<S: Serializer>(
+        secret: &Self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        secret.0.serialize(serializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource<T> {
+    Env { env: String },
+    Literal(T),
+}
+impl<'de, T: SecretValue + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match SecretSource::<T>::deserialize(deserializer)? {
+            SecretSource::Env { env } => Self::from_env(&env).ok_or_else(|| {
+                serde::de::Error::custom(format!("Missing the {env} environment variable"))
+            }),
+            SecretSource::Literal(value) => Ok(Self(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Config {
+        redacted: SecretString,
+        #[serde(serialize_with = "Secret::serialize_exposed")]
+        exposed: SecretString,
+    }
+
+    #[test]
+    fn serialize_redacts_unless_exposed() {
+        let config = Config {
+            redacted: "hunter2".into(),
+            exposed: "visible".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&config).unwrap(),
+            r#"{"redacted":"[REDACTED]","exposed":"visible"}"#
+        );
+        assert_eq!(
+            format!("{:?} {}", config.redacted, config.redacted),
+            "[REDACTED] [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn deserializes_literals_and_env() {
+        std::env::set_var("PORTFU_SECRET_TEST", "from-env");
+        let literal: SecretString = serde_json::from_str(r#""literal""#).unwrap();
+        let env: SecretString = serde_json::from_str(r#"{"env":"PORTFU_SECRET_TEST"}"#).unwrap();
+        assert_eq!(literal.expose(), "literal");
+        assert_eq!(env.expose(), "from-env");
+        assert!(
+            serde_json::from_str::<SecretString>(r#"{"env":"PORTFU_SECRET_MISSING"}"#).is_err()
+        );
+    }
+}
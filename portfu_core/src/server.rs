@@ -1,33 +1,215 @@
+use crate::blocking::{default_pool_size, BlockingPool};
+use crate::clock::SharedClock;
+use crate::data_store::SharedDataStore;
+use crate::duplicates::DuplicateKeys;
+use crate::errors::{
+    render_error, status_for, ErrorHandler, ExcerptBytes, HttpError, DEFAULT_EXCERPT_BYTES,
+};
 use crate::filters::{Filter, FilterFn, FilterResult};
-use crate::service::{IncomingRequest, ServiceRequest};
-use crate::signal::await_termination;
+use crate::headers::HeaderPolicy;
+use crate::listener::{bind_listener, configure_stream, SocketConfig};
+use crate::plan::{
+    PlanScope, PlanStage, PlanStep, PlannedFilter, PlannedResult, PlannedService, RoutePlan,
+};
+use crate::profile::Profile;
+use crate::redirect::HttpsRedirect;
+use crate::reload::{ReloadFn, ReloadReport, Reloader};
+use crate::secrets::SecretString;
+use crate::service::{
+    IncomingRequest, MatchedService, Service, ServiceBuilder, ServiceRequest, SharedState,
+};
+use crate::settings::Settings;
+use crate::shutdown::{failed_at, ShutdownReason, ShutdownSummary, StartupStage};
+use crate::signal::{await_termination, listen_for_hangups};
+use crate::sockets::TrustedProxy;
 use crate::ssl::load_ssl_certs;
-use crate::task::{Task, TaskFn};
-use crate::wrappers::{WrapperFn, WrapperResult};
-use crate::{IntoStreamBody, ServiceData, ServiceRegister, ServiceRegistry, ServiceResponse};
-use http::{Extensions, Request, Response, StatusCode};
-use http_body_util::{BodyExt, BodyStream, Empty, StreamBody};
+use crate::static_response::{Maintenance, StaticResponse, StaticResponses};
+use crate::task::{order_tasks, DependencyWait, ShutdownSignal, Task, TaskFn};
+use crate::tls::TlsInfo;
+use crate::upgrade::{hand_over, UpgradeConfig};
+use crate::wrappers::{RequestScope, ScopeNext, WrapperFn, WrapperResult};
+use crate::{
+    panic_message, IntoStreamBody, LiveRegistry, MaxBodySize, MissingContentType, RouteMethods,
+    ServiceData, ServiceHandler, ServiceRegister, ServiceRegistry, ServiceResponse,
+};
+use futures_util::FutureExt;
+use http::header::{ALLOW, LOCATION, STRICT_TRANSPORT_SECURITY};
+use http::{Extensions, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, BodyStream, Empty, Limited, StreamBody};
 use hyper::body::Incoming;
+use hyper::rt::{Read, Write};
 use hyper::server::conn::http1::Builder;
+use hyper::server::conn::http2::Builder as Http2Builder;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use log::{error, info};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
 use std::io::{Error, ErrorKind};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::TcpListener;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
 use tokio::task::JoinSet;
 use tokio::{select, spawn};
 use tokio_rustls::TlsAcceptor;
 
+/// Connection builders, http2 is only offered to TLS clients negotiating h2 through ALPN
+struct Protocols {
+    http1: Builder,
+    http2: Option<Http2Builder<TokioExecutor>>,
+}
+
+/// Counts what shutdown waits for: open connections and the websockets upgraded from them.
+/// Found in the shared state, hold a ConnectionGuard for work that outlives its connection.
+#[derive(Debug, Clone, Default)]
+pub struct OpenConnections(Arc<AtomicUsize>);
+impl OpenConnections {
+    pub fn track(&self) -> ConnectionGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.0.clone())
+    }
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counted by OpenConnections until dropped
+#[derive(Debug)]
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How long a connection accepted just before draining gets to send its first request
+const FIRST_REQUEST_GRACE: Duration = Duration::from_millis(500);
+
+/// graceful_shutdown closes a connection that has not sent a request yet right away, so give it
+/// FIRST_REQUEST_GRACE to send one. Some when the connection ended meanwhile.
+async fn await_first_request<C: Future>(
+    started: &AtomicBool,
+    mut connection: Pin<&mut C>,
+) -> Option<C::Output> {
+    let deadline = Instant::now() + FIRST_REQUEST_GRACE;
+    while !started.load(Ordering::Relaxed) && Instant::now() < deadline {
+        select! {
+            result = connection.as_mut() => return Some(result),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+    }
+    None
+}
+
+/// Open connections, told to finish their current request once draining is set
+/// and closed mid-request once aborting is set
+#[derive(Clone)]
+struct Connections {
+    active: OpenConnections,
+    draining: watch::Receiver<bool>,
+    aborting: watch::Receiver<bool>,
+}
+impl Connections {
+    async fn serve<I, S, B>(
+        self,
+        protocols: &Protocols,
+        h2: bool,
+        io: I,
+        service: S,
+    ) -> Result<(), hyper::Error>
+    where
+        I: Read + Write + Unpin + Send + 'static,
+        S: hyper::service::Service<Request<Incoming>, Response = Response<B>> + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        B: hyper::body::Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut draining = self.draining;
+        let mut aborting = self.aborting;
+        let _guard = self.active.track();
+        let started = Arc::new(AtomicBool::new(false));
+        let service = {
+            let started = started.clone();
+            service_fn(move |request| {
+                started.store(true, Ordering::Relaxed);
+                service.call(request)
+            })
+        };
+        match protocols.http2.as_ref().filter(|_| h2) {
+            Some(http2) => {
+                let connection = http2.serve_connection(io, service);
+                tokio::pin!(connection);
+                select! {
+                    result = connection.as_mut() => result,
+                    _ = async { draining.wait_for(|draining| *draining).await.is_ok() } => {
+                        if let Some(result) = await_first_request(&started, connection.as_mut()).await {
+                            return result;
+                        }
+                        connection.as_mut().graceful_shutdown();
+                        select! {
+                            result = connection.as_mut() => result,
+                            _ = async { aborting.wait_for(|aborting| *aborting).await.is_ok() } => Ok(()),
+                        }
+                    }
+                }
+            }
+            None => {
+                let connection = protocols
+                    .http1
+                    .serve_connection(io, service)
+                    .with_upgrades();
+                tokio::pin!(connection);
+                select! {
+                    result = connection.as_mut() => result,
+                    _ = async { draining.wait_for(|draining| *draining).await.is_ok() } => {
+                        if let Some(result) = await_first_request(&started, connection.as_mut()).await {
+                            return result;
+                        }
+                        connection.as_mut().graceful_shutdown();
+                        select! {
+                            result = connection.as_mut() => result,
+                            _ = async { aborting.wait_for(|aborting| *aborting).await.is_ok() } => Ok(()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn bind_error(address: SocketAddr, e: Error) -> Error {
+    Error::new(e.kind(), format!("Failed to bind {address}: {e}"))
+}
+
+fn resolve(
+    resolved: Result<impl Iterator<Item = SocketAddr>, Error>,
+    address: &str,
+) -> Result<Vec<SocketAddr>, Error> {
+    let addrs: Vec<SocketAddr> = resolved
+        .map_err(|e| Error::new(e.kind(), format!("Failed to resolve {address}: {e}")))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{address} did not resolve to any address"),
+        ));
+    }
+    Ok(addrs)
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SslConfig {
     pub domain: String,
-    pub key: String,
+    pub key: SecretString,
     pub certs: String,
     pub root_certs: String,
 }
@@ -36,44 +218,156 @@ pub struct SslConfig {
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// `address:port` entries to listen on instead of host and port, resolved at startup; names
+    /// resolving to several addresses bind all of them
+    pub bind: Vec<String>,
     pub ssl_config: Option<SslConfig>,
+    /// Plaintext listener redirecting to https, ignored without ssl_config
+    pub https_redirect: Option<HttpsRedirect>,
     pub keep_alive: bool,
+    /// Offer h2 to TLS clients through ALPN, off by default since websockets need http/1.1
+    pub http2: bool,
+    pub http2_max_concurrent_streams: Option<u32>,
     pub half_close: bool,
     pub preserve_header_case: bool,
     pub max_buf_size: usize,
+    /// Largest request body Body extractors will read, services can override it
+    pub max_body_size: Option<usize>,
+    /// Bytes of a body quoted in the details of a parse error
+    pub excerpt_bytes: usize,
+    pub socket_config: SocketConfig,
+    pub worker_accept_loops: usize,
+    pub auto_options: bool,
+    pub blocking_threads: usize,
+    pub warm_up_parallelism: usize,
+    pub warm_up_timeout: Duration,
+    pub strict_warm_up: bool,
+    /// Default for services without a HeaderPolicy of their own
+    pub header_policy: HeaderPolicy,
+    /// Socket handover with the previous and next process, forces reuse_port
+    pub upgrade: Option<UpgradeConfig>,
+    /// How long open connections and background tasks get to finish once shutdown starts
+    pub shutdown_timeout: Duration,
+    /// Peers whose x-forwarded-host is believed when checking the Origin of websocket upgrades
+    pub trusted_proxies: Vec<IpAddr>,
 }
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             host: "localhost".to_string(),
             port: 8080,
+            bind: vec![],
             ssl_config: None,
+            https_redirect: None,
             keep_alive: true,
+            http2: false,
+            http2_max_concurrent_streams: None,
             half_close: true,
             preserve_header_case: true,
             max_buf_size: 1024 * 1024 * 2, //2 Mib
+            max_body_size: None,
+            excerpt_bytes: DEFAULT_EXCERPT_BYTES,
+            socket_config: SocketConfig::default(),
+            worker_accept_loops: 1,
+            auto_options: false,
+            blocking_threads: default_pool_size(),
+            warm_up_parallelism: 8,
+            warm_up_timeout: Duration::from_secs(30),
+            strict_warm_up: false,
+            header_policy: HeaderPolicy::Passthrough,
+            upgrade: None,
+            shutdown_timeout: Duration::from_secs(30),
+            trusted_proxies: vec![],
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Server {
-    pub registry: Arc<ServiceRegistry>,
+    pub registry: LiveRegistry,
     pub config: ServerConfig,
     pub run: Arc<AtomicBool>,
     pub shared_state: Arc<Extensions>,
     filters: Vec<Arc<dyn FilterFn + Sync + Send>>,
     tasks: Vec<Arc<Task>>,
     wrappers: Vec<Arc<dyn WrapperFn + Sync + Send>>,
+    scopes: Vec<Arc<dyn RequestScope + Sync + Send>>,
+    state_types: Vec<&'static str>,
+    reloader: Arc<Reloader>,
+    served: Arc<AtomicUsize>,
+    shutdown: watch::Sender<bool>,
+    open: OpenConnections,
 }
 impl Server {
     pub async fn run(self) -> Result<(), Error> {
+        self.serve().await.into_result()
+    }
+    /// Runs the server and reports why it stopped, use ShutdownReason::exit_code for the process exit code
+    pub async fn serve(self) -> ShutdownReason {
+        let started = Instant::now();
+        let served = self.served.clone();
+        match self.run_until_shutdown().await {
+            Ok(mut summary) => {
+                summary.uptime = started.elapsed();
+                summary.requests_served = served.load(Ordering::Relaxed);
+                summary.log();
+                ShutdownReason::clean(&summary)
+            }
+            Err(e) => {
+                let reason = ShutdownReason::from_error(e);
+                error!(
+                    "Server stopped with exit code {}: {reason:?}",
+                    reason.exit_code()
+                );
+                reason
+            }
+        }
+    }
+    async fn run_until_shutdown(self) -> Result<ShutdownSummary, Error> {
         let server = Arc::new(self);
-        let socket_addr = Self::get_socket_addr(&server.config)?;
-        let listener = TcpListener::bind(socket_addr).await?;
+        //Registered first so a SIGHUP during startup is queued instead of ending the process
+        let reloader = server.reloader.clone();
+        let hangups = spawn(async move {
+            if let Err(e) = listen_for_hangups(|| reloader.request()).await {
+                error!("Failed to listen for SIGHUP: {e:?}");
+            }
+        });
+        let reloader = server.reloader.clone();
+        let reloads = spawn(async move { reloader.run().await });
+        let task_order = order_tasks(&server.tasks).map_err(failed_at(StartupStage::Config))?;
+        let socket_addrs = Self::get_socket_addrs(&server.config)
+            .await
+            .map_err(failed_at(StartupStage::Bind))?;
+        let mut listeners = vec![];
+        let mut bound = vec![];
+        for (socket_addr, required) in socket_addrs {
+            match Self::bind_listeners(&server.config, socket_addr) {
+                Ok(socket_listeners) => {
+                    listeners.extend(socket_listeners);
+                    bound.push(socket_addr);
+                }
+                Err(e) if !required => warn!("{e}, listening without it"),
+                Err(e) => Err(e).map_err(failed_at(StartupStage::Bind))?,
+            }
+        }
+        let mut redirect_listeners = vec![];
+        if let (Some(_), Some(redirect)) =
+            (&server.config.ssl_config, &server.config.https_redirect)
+        {
+            for socket_addr in bound.iter() {
+                let mut redirect_addr = *socket_addr;
+                redirect_addr.set_port(redirect.port);
+                let listener = bind_listener(redirect_addr, &server.config.socket_config)
+                    .map_err(|e| bind_error(redirect_addr, e))
+                    .map_err(failed_at(StartupStage::Bind))?;
+                info!("Redirecting http on {redirect_addr} to https");
+                redirect_listeners.push(Arc::new(listener));
+            }
+        }
+        server.warm_up().await?;
         let tls_acceptor = Arc::new(match server.config.ssl_config.as_ref() {
             Some(_) => {
-                let certs = load_ssl_certs(&server.config)?;
+                let certs = load_ssl_certs(&server.config).map_err(failed_at(StartupStage::Tls))?;
                 Some(TlsAcceptor::from(certs))
             }
             None => None,
@@ -83,55 +377,264 @@ impl Server {
         http.keep_alive(server.config.keep_alive);
         http.preserve_header_case(server.config.preserve_header_case);
         http.max_buf_size(server.config.max_buf_size);
-        let http = Arc::new(http);
+        let http2 = server.config.http2.then(|| {
+            let mut http2 = Http2Builder::new(TokioExecutor::new());
+            http2.max_concurrent_streams(server.config.http2_max_concurrent_streams);
+            http2
+        });
+        let http = Arc::new(Protocols { http1: http, http2 });
         let server_run_handle = server.run.clone();
         spawn(async move {
             let _ = await_termination().await;
             server_run_handle.store(false, Ordering::Relaxed);
         });
+        let mut background_tasks = Self::spawn_tasks(&server, &task_order);
+        if let Some(upgrade) = &server.config.upgrade {
+            hand_over(upgrade, server.run.clone()).await?;
+        }
+        let (draining, draining_rx) = watch::channel(false);
+        let (aborting, aborting_rx) = watch::channel(false);
+        let connections = Connections {
+            active: server.open.clone(),
+            draining: draining_rx,
+            aborting: aborting_rx,
+        };
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            accept_loops.spawn(Self::accept_loop(
+                server.clone(),
+                listener,
+                tls_acceptor.clone(),
+                http.clone(),
+                false,
+                connections.clone(),
+            ));
+        }
+        for listener in redirect_listeners {
+            accept_loops.spawn(Self::accept_loop(
+                server.clone(),
+                listener,
+                Arc::new(None),
+                http.clone(),
+                true,
+                connections.clone(),
+            ));
+        }
+        let mut fatal = None;
+        while let Some(result) = accept_loops.join_next().await {
+            if let Err(e) = result {
+                error!("Accept loop failed: {e:?}");
+                server.run.store(false, Ordering::Relaxed);
+                fatal.get_or_insert(Error::other(e));
+            }
+        }
+        let _ = draining.send(true);
+        let _ = server.shutdown.send(true);
+        let timeout = match &server.config.upgrade {
+            Some(upgrade) => upgrade.drain_timeout,
+            None => server.config.shutdown_timeout,
+        };
+        let deadline = Instant::now() + timeout;
+        let open = connections.active.count();
+        Self::drain(&connections.active, timeout).await;
+        let aborted = connections.active.count();
+        if aborted > 0 {
+            //Dropping the connection closes it along with the requests it is still serving
+            let _ = aborting.send(true);
+            Self::await_aborted(&connections.active).await;
+        }
+        hangups.abort();
+        reloads.abort();
+        let mut summary = ShutdownSummary {
+            drained_connections: open.saturating_sub(aborted),
+            aborted_connections: aborted,
+            ..Default::default()
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let completed = &mut summary.tasks_completed;
+        let tasks_done = async {
+            while background_tasks.join_next().await.is_some() {
+                *completed += 1;
+            }
+        };
+        if tokio::time::timeout(remaining, tasks_done).await.is_err() {
+            info!(
+                "Aborting {} background tasks after the shutdown timeout",
+                background_tasks.len()
+            );
+        }
+        summary.tasks_aborted = background_tasks.len();
+        background_tasks.shutdown().await;
+        match fatal {
+            Some(e) => Err(e),
+            None => Ok(summary),
+        }
+    }
+
+    /// Spawns the background tasks, each waiting for its dependencies to start or complete first
+    fn spawn_tasks(server: &Arc<Self>, order: &[usize]) -> JoinSet<()> {
         let mut background_tasks = JoinSet::new();
-        for task in server.tasks.iter().cloned() {
+        let mut started = HashMap::new();
+        let mut completed = HashMap::new();
+        let mut signals = HashMap::new();
+        for task in server.tasks.iter() {
+            let (started_tx, started_rx) = watch::channel(false);
+            let (completed_tx, completed_rx) = watch::channel(false);
+            started.insert(task.name.clone(), started_rx);
+            completed.insert(task.name.clone(), completed_rx);
+            signals.insert(task.name.clone(), (started_tx, completed_tx));
+        }
+        for index in order {
+            let task = server.tasks[*index].clone();
             let state = server.shared_state.clone();
-            info!("Spawning Task {}", task.name());
+            let waits: Vec<(String, watch::Receiver<bool>)> = task
+                .after
+                .iter()
+                .map(|dependency| {
+                    let receivers = match dependency.wait {
+                        DependencyWait::Started => &started,
+                        DependencyWait::Completed => &completed,
+                    };
+                    (dependency.name.clone(), receivers[&dependency.name].clone())
+                })
+                .collect();
+            let (started_tx, completed_tx) = match signals.remove(&task.name) {
+                Some(signals) => signals,
+                None => continue,
+            };
             background_tasks.spawn(async move {
-                if let Err(e) = task.task_fn.run(state).await {
-                    error!("Error in background task: {e:?}");
+                for (name, mut wait) in waits {
+                    if wait.wait_for(|ready| *ready).await.is_err() {
+                        error!(
+                            "Not starting Task {}, dependency {name} failed",
+                            task.name()
+                        );
+                        return;
+                    }
+                }
+                info!("Spawning Task {}", task.name());
+                let _ = started_tx.send(true);
+                match task.task_fn.run(state).await {
+                    Ok(_) => {
+                        let _ = completed_tx.send(true);
+                    }
+                    Err(e) => {
+                        error!("Error in background task: {e:?}");
+                    }
                 }
             });
         }
+        background_tasks
+    }
+
+    /// Each task with the names of the tasks it waits for
+    pub fn task_graph(&self) -> Vec<(String, Vec<String>)> {
+        self.tasks
+            .iter()
+            .map(|task| {
+                (
+                    task.name.clone(),
+                    task.after.iter().map(|d| d.name.clone()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    /// Runs ServiceHandler::warm_up for every service, failures only stop startup when strict_warm_up is set
+    async fn warm_up(&self) -> Result<(), Error> {
+        let permits = Arc::new(Semaphore::new(self.config.warm_up_parallelism.max(1)));
+        let timeout = self.config.warm_up_timeout;
+        let mut warm_ups = JoinSet::new();
+        for service in self.registry.load().services().iter().cloned() {
+            let Some(handler) = service.handler.clone() else {
+                continue;
+            };
+            let permits = permits.clone();
+            warm_ups.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let start = Instant::now();
+                let result = match tokio::time::timeout(timeout, handler.warm_up()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("Warm up timed out after {timeout:?}"),
+                    )),
+                };
+                (service, start.elapsed(), result)
+            });
+        }
+        let mut failed = vec![];
+        while let Some(joined) = warm_ups.join_next().await {
+            match joined {
+                Ok((service, elapsed, Ok(_))) => {
+                    debug!("Warmed up {} in {elapsed:?}", service.name());
+                }
+                Ok((service, elapsed, Err(e))) => {
+                    error!(
+                        "Failed to warm up {} after {elapsed:?}: {e:?}",
+                        service.name()
+                    );
+                    failed.push(service.name().to_string());
+                }
+                Err(e) => {
+                    error!("Warm up task failed: {e:?}");
+                    failed.push(format!("{e:?}"));
+                }
+            }
+        }
+        if self.config.strict_warm_up && !failed.is_empty() {
+            Err(Error::other(format!(
+                "Failed to warm up services: {}",
+                failed.join(", ")
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn bind_listeners(
+        config: &ServerConfig,
+        socket_addr: SocketAddr,
+    ) -> Result<Vec<Arc<TcpListener>>, Error> {
+        let loops = config.worker_accept_loops.max(1);
+        let bind = || {
+            bind_listener(socket_addr, &config.socket_config)
+                .map(Arc::new)
+                .map_err(|e| bind_error(socket_addr, e))
+        };
+        info!("Listening on {socket_addr}");
+        if config.socket_config.reuse_port {
+            (0..loops).map(|_| bind()).collect()
+        } else {
+            let listener = bind()?;
+            Ok((0..loops).map(|_| listener.clone()).collect())
+        }
+    }
+
+    async fn accept_loop(
+        server: Arc<Self>,
+        listener: Arc<TcpListener>,
+        tls_acceptor: Arc<Option<TlsAcceptor>>,
+        http: Arc<Protocols>,
+        redirect: bool,
+        connections: Connections,
+    ) {
+        let accepted = |stream, address| {
+            Self::spawn_connection(
+                server.clone(),
+                stream,
+                address,
+                tls_acceptor.clone(),
+                http.clone(),
+                redirect,
+                connections.clone(),
+            )
+        };
         while server.run.load(Ordering::Relaxed) {
             select!(
                 res = listener.accept() => {
                     match res {
-                        Ok((stream, address)) => {
-                            let server = server.clone();
-                            let tls_acceptor = tls_acceptor.clone();
-                            let http = http.clone();
-                            spawn(async move {
-                                let service = service_fn(move |req| {
-                                    let server = server.clone();
-                                    Self::connection_handler(server, req, address)
-                                });
-                                if let Some(acceptor) = tls_acceptor.as_ref() {
-                                    match acceptor.accept(stream).await {
-                                        Ok(stream) => {
-                                            let connection = http.serve_connection(TokioIo::new(stream), service).with_upgrades();
-                                            if let Err(err) = connection.await {
-                                                error!("Error serving tls connection: {:?}", err);
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!("Error accepting tls connection: {:?}", e);
-                                        }
-                                    }
-                                } else {
-                                    let connection = http.serve_connection(TokioIo::new(stream), service).with_upgrades();
-                                    if let Err(err) = connection.await {
-                                        error!("Error serving connection: {:?}", err);
-                                    }
-                                };
-                            });
-                        }
+                        Ok((stream, address)) => accepted(stream, address),
                         Err(e) => {
                             error!("Error accepting connection: {:?}", e);
                         }
@@ -140,25 +643,289 @@ impl Server {
                 _ = tokio::time::sleep(Duration::from_millis(100)) => {}
             )
         }
-        background_tasks.shutdown().await;
-        Ok(())
+        //Connections already queued would be reset when the listener closes, so serve them too
+        let mut queued = 0;
+        while let Some(Ok((stream, address))) = listener.accept().now_or_never() {
+            accepted(stream, address);
+            queued += 1;
+        }
+        if queued > 0 {
+            info!("Accepted {queued} queued connections before closing the listener");
+        }
     }
 
-    fn get_socket_addr(config: &ServerConfig) -> Result<SocketAddr, Error> {
-        Ok(SocketAddr::from((
-            Ipv4Addr::from_str(if config.host == "localhost" {
-                "127.0.0.1"
+    fn spawn_connection(
+        server: Arc<Self>,
+        stream: TcpStream,
+        address: SocketAddr,
+        tls_acceptor: Arc<Option<TlsAcceptor>>,
+        http: Arc<Protocols>,
+        redirect: bool,
+        connections: Connections,
+    ) {
+        configure_stream(&stream, &server.config.socket_config);
+        spawn(async move {
+            if let Some(acceptor) = tls_acceptor.as_ref() {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                        let tls_info = Some(Arc::new(TlsInfo::from_connection(stream.get_ref().1)));
+                        let service = service_fn(move |req| {
+                            let server = server.clone();
+                            Self::tls_handler(server, req, address, tls_info.clone())
+                        });
+                        if let Err(err) = connections
+                            .serve(&http, h2, TokioIo::new(stream), service)
+                            .await
+                        {
+                            error!("Error serving tls connection: {:?}", err);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error accepting tls connection: {:?}", e);
+                    }
+                }
             } else {
-                &config.host
-            })
-            .map_err(|e| {
-                Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Failed to parse Host: {:?}", e),
-                )
-            })?,
-            config.port,
-        )))
+                let service = service_fn(move |req| {
+                    let server = server.clone();
+                    Self::plaintext_handler(server, req, address, redirect)
+                });
+                if let Err(err) = connections
+                    .serve(&http, false, TokioIo::new(stream), service)
+                    .await
+                {
+                    error!("Error serving connection: {:?}", err);
+                }
+            };
+        });
+    }
+
+    /// Waits for open connections to finish, giving up after `timeout`
+    async fn drain(active: &OpenConnections, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while active.count() > 0 {
+            if Instant::now() >= deadline {
+                info!("Drain timed out with {} connections open", active.count());
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        info!("Drained all connections");
+    }
+
+    /// Gives aborted connections a moment to drop, they report as still open when they do not
+    async fn await_aborted(active: &OpenConnections) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while active.count() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let open = active.count();
+        if open > 0 {
+            info!("{open} aborted connections still open");
+        }
+    }
+
+    /// Methods the registered services accept on `path`, see ServiceRegistry::methods_for
+    pub fn methods_for(&self, path: &str) -> Option<RouteMethods> {
+        self.registry.load().methods_for(path)
+    }
+    /// Dry runs routing `method` `path` without calling any filter, wrapper or handler.
+    /// Method checks and the method filters are decided here, other filters need the request
+    /// so they are reported as Runtime.
+    pub fn route_plan(&self, path: &str, method: &Method) -> RoutePlan {
+        let planned = |filters: &[Arc<dyn FilterFn + Sync + Send>]| -> Vec<PlannedFilter> {
+            filters
+                .iter()
+                .map(|f| PlannedFilter {
+                    name: f.name().to_string(),
+                    result: PlannedResult::for_filter(f.name(), method),
+                })
+                .collect()
+        };
+        let step = |stage, scope, wrapper: &Arc<dyn WrapperFn + Sync + Send>| PlanStep {
+            stage,
+            scope,
+            name: wrapper.name().to_string(),
+            infrastructure: wrapper.infrastructure(),
+        };
+        let mut candidates = vec![];
+        let registry = self.registry.load();
+        for service in registry.candidates(path) {
+            if !service.path.matches(path) {
+                continue;
+            }
+            //Static routes, like catch-alls and mounts, match without any params
+            let params = service
+                .path
+                .params(path)
+                .map(|params| {
+                    params
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let method_allowed = service.allows_method(method);
+            let mut steps = vec![];
+            steps.extend(
+                self.wrappers
+                    .iter()
+                    .map(|w| step(PlanStage::Before, PlanScope::Server, w)),
+            );
+            steps.extend(
+                service
+                    .wrappers
+                    .iter()
+                    .map(|w| step(PlanStage::Before, PlanScope::Service, w)),
+            );
+            if let Some(handler) = &service.handler {
+                steps.push(PlanStep {
+                    stage: PlanStage::Handler,
+                    scope: PlanScope::Service,
+                    name: handler.name().to_string(),
+                    infrastructure: false,
+                });
+            }
+            steps.extend(
+                service
+                    .wrappers
+                    .iter()
+                    .map(|w| step(PlanStage::After, PlanScope::Service, w)),
+            );
+            steps.extend(
+                self.wrappers
+                    .iter()
+                    .map(|w| step(PlanStage::After, PlanScope::Server, w)),
+            );
+            let filters = planned(&service.filters);
+            let definite =
+                method_allowed && filters.iter().all(|f| f.result == PlannedResult::Allow);
+            candidates.push(PlannedService {
+                name: service.name().to_string(),
+                uuid: service.uuid.to_string(),
+                method_allowed,
+                params,
+                filters,
+                required_roles: self
+                    .wrappers
+                    .iter()
+                    .chain(service.wrappers.iter())
+                    .filter_map(|w| w.required_role().map(str::to_string))
+                    .collect(),
+                definite,
+                steps,
+            });
+            if definite {
+                break;
+            }
+        }
+        RoutePlan {
+            path: path.to_string(),
+            method: method.to_string(),
+            server_filters: planned(&self.filters),
+            candidates,
+            state: {
+                let mut state: Vec<String> =
+                    self.state_types.iter().map(|t| t.to_string()).collect();
+                state.sort();
+                state
+            },
+        }
+    }
+    /// Queues a reload of every registered ReloadFn, the same as sending SIGHUP
+    pub fn request_reload(&self) {
+        self.reloader.request();
+    }
+    /// Runs every registered ReloadFn now and returns what was applied
+    pub async fn reload(&self) -> ReloadReport {
+        self.reloader.reload().await
+    }
+
+    /// Every `bind` entry, or `host` and `port` when there are none, with whether failing to bind
+    /// the address fails startup. IP literals are used as is and anything else is resolved,
+    /// localhost is both loopbacks and hosts without IPv6 skip `::1`.
+    async fn get_socket_addrs(config: &ServerConfig) -> Result<Vec<(SocketAddr, bool)>, Error> {
+        if config.bind.is_empty() {
+            let host = config.host.trim_start_matches('[').trim_end_matches(']');
+            return Ok(if host == "localhost" {
+                vec![
+                    (SocketAddr::from((Ipv4Addr::LOCALHOST, config.port)), true),
+                    (SocketAddr::from((Ipv6Addr::LOCALHOST, config.port)), false),
+                ]
+            } else if let Ok(ip) = IpAddr::from_str(host) {
+                vec![(SocketAddr::from((ip, config.port)), true)]
+            } else {
+                resolve(lookup_host((host, config.port)).await, &config.host)?
+                    .into_iter()
+                    .map(|addr| (addr, true))
+                    .collect()
+            });
+        }
+        let mut addrs = vec![];
+        for bind in config.bind.iter() {
+            let resolved = match SocketAddr::from_str(bind) {
+                Ok(addr) => vec![addr],
+                Err(_) => resolve(lookup_host(bind.as_str()).await, bind)?,
+            };
+            for addr in resolved {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        Ok(addrs.into_iter().map(|addr| (addr, true)).collect())
+    }
+
+    async fn tls_handler(
+        server: Arc<Self>,
+        request: Request<Incoming>,
+        address: SocketAddr,
+        tls_info: Option<Arc<TlsInfo>>,
+    ) -> Result<ServiceResponse, Error> {
+        let hsts = server
+            .config
+            .https_redirect
+            .as_ref()
+            .and_then(|r| r.hsts.clone());
+        let mut response = Self::connection_handler(server, request, address, tls_info).await?;
+        if let Some(hsts) = hsts {
+            response
+                .headers_mut()
+                .insert(STRICT_TRANSPORT_SECURITY, hsts);
+        }
+        Ok(response)
+    }
+
+    /// Serves plain connections, on the redirect listener everything but the exempt paths is redirected
+    async fn plaintext_handler(
+        server: Arc<Self>,
+        request: Request<Incoming>,
+        address: SocketAddr,
+        redirect: bool,
+    ) -> Result<ServiceResponse, Error> {
+        let https_redirect = match (redirect, server.config.https_redirect.as_ref()) {
+            (true, Some(https_redirect)) if !https_redirect.is_exempt(request.uri().path()) => {
+                https_redirect
+            }
+            _ => return Self::connection_handler(server, request, address, None).await,
+        };
+        let mut response: ServiceResponse = Response::new(StreamBody::new(BodyStream::new(
+            Box::pin(Empty::new().map_err(|_| "Failed to Map Empty to Service Body")),
+        )));
+        match https_redirect.location(&request, server.config.port) {
+            Some(location) => {
+                *response.status_mut() = https_redirect.status;
+                response.headers_mut().insert(LOCATION, location);
+            }
+            None => *response.status_mut() = StatusCode::BAD_REQUEST,
+        }
+        info!(
+            "{address} {} {} {}",
+            request.method(),
+            request.uri(),
+            response.status().as_u16()
+        );
+        Ok(response)
     }
 
     #[inline]
@@ -166,11 +933,29 @@ impl Server {
         server: Arc<Self>,
         mut request: Request<Incoming>,
         address: SocketAddr,
+        tls_info: Option<Arc<TlsInfo>>,
     ) -> Result<ServiceResponse, Error> {
+        server.served.fetch_add(1, Ordering::Relaxed);
         request.extensions_mut().insert(address);
+        if server
+            .config
+            .trusted_proxies
+            .contains(&address.ip().to_canonical())
+        {
+            request.extensions_mut().insert(TrustedProxy);
+        }
+        if let Some(tls_info) = tls_info {
+            request.extensions_mut().insert(tls_info);
+        }
         let mut response: ServiceResponse = Response::new(StreamBody::new(BodyStream::new(
             Box::pin(Empty::new().map_err(|_| "Failed to Map Empty to Service Body")),
         )));
+        if let Some(maintenance) = server.shared_state.get::<Arc<Maintenance>>() {
+            if maintenance.intercepts(request.uri().path()) {
+                maintenance.write_response(&mut response);
+                return Ok(response);
+            }
+        }
         let handle = if !server.filters.is_empty() {
             let mut handle = true;
             for f in server.filters.iter() {
@@ -187,66 +972,214 @@ impl Server {
             *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
             Ok(response)
         } else {
+            let registry = server.registry.load();
             let mut handler = None;
-            for service in server.registry.services.iter() {
-                if service.handles(&request).await {
+            let mut wrong_method = vec![];
+            for service in registry.candidates(request.uri().path()) {
+                if !service.path.matches(request.uri().path()) {
+                    continue;
+                }
+                if !service.allows_method(request.method()) {
+                    wrong_method.push(service);
+                } else if service.passes_filters(&request).await {
                     handler = Some(service.clone());
                     break;
                 }
             }
+            //Only services whose filters let the request through answer 405, other rejections are a 404
+            let mut allowed: Option<Vec<Method>> = None;
+            if handler.is_none() {
+                for service in wrong_method {
+                    if let Some(methods) = &service.methods {
+                        if service.passes_filters(&request).await {
+                            let allowed = allowed.get_or_insert_with(Vec::new);
+                            for method in methods {
+                                if !allowed.contains(method) {
+                                    allowed.push(method.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            if let (None, None, Some(not_found)) = (&handler, &allowed, &registry.not_found) {
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                handler = Some(not_found.clone());
+            }
             match handler {
                 Some(service) => {
                     request
                         .extensions_mut()
-                        .extend(server.shared_state.as_ref().clone());
-                    let mut service_data = ServiceData {
-                        server: server.clone(),
-                        request: ServiceRequest {
-                            request: IncomingRequest::Stream(request),
-                            path: service.path.clone(),
-                        },
-                        response,
+                        .insert(SharedState(server.shared_state.clone()));
+                    let path = request.uri().path().to_string();
+                    let handling: ScopeNext = {
+                        let (server, service) = (server.clone(), service.clone());
+                        Box::new(move |request| {
+                            Box::pin(Self::handle_service(server, service, request, response))
+                        })
                     };
-                    for func in server.wrappers.iter() {
-                        match func.before(&mut service_data).await {
-                            WrapperResult::Continue => {}
-                            WrapperResult::Return => {
-                                return Ok(service_data.response);
-                            }
+                    let handling = server.scopes.iter().rev().fold(handling, |next, scope| {
+                        let scope = scope.clone();
+                        Box::new(move |request| scope.scope(request, next))
+                    });
+                    match AssertUnwindSafe(handling(request)).catch_unwind().await {
+                        Ok(result) => result,
+                        //Only wrappers get here, handler panics are answered in handle_service
+                        Err(payload) => {
+                            error!(
+                                "Service {} panicked when handling {path} - {}",
+                                service.name(),
+                                panic_message(payload.as_ref())
+                            );
+                            let mut response: ServiceResponse =
+                                Response::new("Internal Server Error".stream_body());
+                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                            Ok(response)
                         }
                     }
-                    service_data =
-                        service
-                            .handle(service_data)
-                            .await
-                            .unwrap_or_else(|(mut sd, e)| {
-                                error!(
-                                    "Service Error when Handling {} - {e:?}",
-                                    sd.request.request.uri()
-                                );
-                                *sd.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                                *sd.response.body_mut() = format!("{:?}", e).stream_body();
-                                sd
-                            });
-                    for func in server.wrappers.iter() {
-                        match func.after(&mut service_data).await {
-                            WrapperResult::Continue => {}
-                            WrapperResult::Return => {
-                                return Ok(service_data.response);
+                }
+                None => {
+                    match allowed {
+                        Some(mut allowed) => {
+                            let is_options = request.method() == Method::OPTIONS;
+                            if server.config.auto_options && !allowed.contains(&Method::OPTIONS) {
+                                allowed.push(Method::OPTIONS);
+                            }
+                            let allow = allowed
+                                .iter()
+                                .map(Method::as_str)
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            if let Ok(allow) = HeaderValue::from_str(&allow) {
+                                response.headers_mut().insert(ALLOW, allow);
                             }
+                            *response.status_mut() = if is_options && server.config.auto_options {
+                                StatusCode::NO_CONTENT
+                            } else {
+                                StatusCode::METHOD_NOT_ALLOWED
+                            };
+                        }
+                        None => {
+                            *response.status_mut() = StatusCode::NOT_FOUND;
                         }
                     }
-                    Ok(service_data.response)
-                }
-                None => {
-                    *response.status_mut() = StatusCode::NOT_FOUND;
                     Ok(response)
                 }
             }
         }
     }
+
+    #[inline]
+    async fn handle_service(
+        server: Arc<Self>,
+        service: Arc<Service>,
+        request: Request<Incoming>,
+        response: ServiceResponse,
+    ) -> Result<ServiceResponse, Error> {
+        //Resolved before any wrapper runs so none of them can read past the limit
+        let max_body_size = service.max_body_size.or(server.config.max_body_size);
+        //Limited clamps the size hint, so a declared length over the limit is checked up front
+        let declared = hyper::body::Body::size_hint(request.body()).lower();
+        let request = request.map(|body| Limited::new(body, max_body_size.unwrap_or(usize::MAX)));
+        let mut service_data = ServiceData {
+            server: server.clone(),
+            request: ServiceRequest {
+                request: IncomingRequest::Stream(request),
+                path: service.path.clone(),
+            },
+            response,
+        };
+        service_data
+            .request
+            .insert(MatchedService(service.name.clone()));
+        if let Some(max_body_size) = max_body_size {
+            service_data.request.insert(MaxBodySize(max_body_size));
+            if declared > max_body_size as u64 {
+                let error = crate::too_large(declared, max_body_size);
+                if let Err(error) = render_error(error, &mut service_data).await {
+                    HttpError::apply(&error, &mut service_data.response);
+                }
+                return Ok(service_data.response);
+            }
+        }
+        service_data
+            .request
+            .insert(ExcerptBytes(server.config.excerpt_bytes));
+        for func in server.wrappers.iter() {
+            match func.before(&mut service_data).await {
+                WrapperResult::Continue => {}
+                WrapperResult::Return => {
+                    return Ok(service_data.response);
+                }
+            }
+        }
+        //Kept so a panicking handler's error still reaches the error handlers and after wrappers
+        let snapshot = request_snapshot(&service_data.request);
+        let handled = match AssertUnwindSafe(service.handle(service_data))
+            .catch_unwind()
+            .await
+        {
+            Ok(handled) => handled,
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                error!(
+                    "Service {} panicked when handling {} - {message}",
+                    service.name(),
+                    snapshot.request.uri()
+                );
+                let data = ServiceData {
+                    server: server.clone(),
+                    request: snapshot,
+                    response: Response::new("".stream_body()),
+                };
+                Err((data, Error::other(format!("Service panicked: {message}"))))
+            }
+        };
+        service_data = match handled {
+            Ok(sd) => sd,
+            Err((mut sd, e)) => {
+                if let Err(e) = render_error(e, &mut sd).await {
+                    error!(
+                        "Service Error when Handling {} - {e:?}",
+                        sd.request.request.uri()
+                    );
+                    *sd.response.status_mut() = status_for(e.kind());
+                    *sd.response.body_mut() = format!("{:?}", e).stream_body();
+                }
+                sd
+            }
+        };
+        for func in server.wrappers.iter() {
+            match func.after(&mut service_data).await {
+                WrapperResult::Continue => {}
+                WrapperResult::Return => {
+                    return Ok(service_data.response);
+                }
+            }
+        }
+        Ok(service_data.response)
+    }
+}
+
+/// The request without its body, for answering after the handler consumed the original
+fn request_snapshot(request: &ServiceRequest) -> ServiceRequest {
+    let (mut parts, _) = Request::new(()).into_parts();
+    parts.method = request.request.method().clone();
+    parts.uri = request.request.uri().clone();
+    parts.version = request.request.version();
+    if let Some(headers) = request.request.headers() {
+        parts.headers = headers.clone();
+    }
+    if let Some(extensions) = request.request.extensions() {
+        parts.extensions = extensions.clone();
+    }
+    ServiceRequest {
+        request: IncomingRequest::Consumed(parts),
+        path: request.path.clone(),
+    }
 }
 
+#[derive(Default)]
 pub struct ServerBuilder {
     services: ServiceRegistry,
     config: ServerConfig,
@@ -254,16 +1187,28 @@ pub struct ServerBuilder {
     filters: Vec<Arc<dyn FilterFn + Sync + Send>>,
     tasks: Vec<Arc<Task>>,
     wrappers: Vec<Arc<dyn WrapperFn + Sync + Send>>,
+    scopes: Vec<Arc<dyn RequestScope + Sync + Send>>,
+    reloaders: Vec<Arc<dyn ReloadFn + Sync + Send>>,
+    state_types: Vec<&'static str>,
 }
 impl ServerBuilder {
     pub fn from_config(config: ServerConfig) -> Self {
         Self {
-            services: ServiceRegistry { services: vec![] },
+            services: ServiceRegistry::default(),
             config,
             shared_state: Extensions::default(),
             filters: vec![],
             tasks: vec![],
             wrappers: vec![],
+            scopes: vec![],
+            reloaders: vec![],
+            state_types: vec![],
+        }
+    }
+    /// Inserts into the shared state, keeping the type name for Server::route_plan
+    fn insert_state<T: Clone + Send + Sync + 'static>(&mut self, value: T) {
+        if self.shared_state.insert(value).is_none() {
+            self.state_types.push(std::any::type_name::<T>());
         }
     }
     pub fn host(self, host: String) -> Self {
@@ -276,11 +1221,127 @@ impl ServerBuilder {
         s.config.port = port;
         s
     }
+    /// Listens on `address` instead of host and port, call once per address
+    pub fn bind<S: AsRef<str>>(self, address: S) -> Self {
+        let mut s = self;
+        s.config.bind.push(address.as_ref().to_string());
+        s
+    }
+    /// Believes x-forwarded-host from `proxy`, call once per proxy
+    pub fn trusted_proxy(self, proxy: IpAddr) -> Self {
+        let mut s = self;
+        s.config.trusted_proxies.push(proxy.to_canonical());
+        s
+    }
+    pub fn socket_config(self, socket_config: SocketConfig) -> Self {
+        let mut s = self;
+        s.config.socket_config = socket_config;
+        s
+    }
+    pub fn reuse_port(self, reuse_port: bool) -> Self {
+        let mut s = self;
+        s.config.socket_config.reuse_port = reuse_port;
+        s
+    }
+    pub fn shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        let mut s = self;
+        s.config.shutdown_timeout = shutdown_timeout;
+        s
+    }
+    pub fn upgrade(self, upgrade: Option<UpgradeConfig>) -> Self {
+        let mut s = self;
+        s.config.upgrade = upgrade;
+        s
+    }
+    pub fn tcp_nodelay(self, tcp_nodelay: bool) -> Self {
+        let mut s = self;
+        s.config.socket_config.tcp_nodelay = tcp_nodelay;
+        s
+    }
+    /// Default DuplicateKeys policy for endpoints without one of their own
+    pub fn duplicate_keys(self, duplicate_keys: DuplicateKeys) -> Self {
+        let mut s = self;
+        s.insert_state(duplicate_keys);
+        s
+    }
+    /// Checked before filters and routing, see Maintenance
+    pub fn maintenance(self, maintenance: Arc<Maintenance>) -> Self {
+        let mut s = self;
+        s.insert_state(maintenance);
+        s
+    }
+    /// Restores the maintenance and static response flags at build, the admin toggles persist to it
+    pub fn data_store(self, data_store: SharedDataStore) -> Self {
+        let mut s = self;
+        s.insert_state(data_store);
+        s
+    }
+    /// Registers `response` and makes it switchable through StaticResponses in shared state
+    pub fn static_response(self, response: StaticResponse) -> Self {
+        let mut s = self;
+        let mut responses = s
+            .shared_state
+            .remove::<Arc<StaticResponses>>()
+            .map(|r| r.as_ref().clone())
+            .unwrap_or_default();
+        responses.push(response.toggle());
+        s.insert_state(Arc::new(responses));
+        response.register(&mut s.services);
+        s
+    }
+    pub fn missing_content_type(self, missing_content_type: MissingContentType) -> Self {
+        let mut s = self;
+        s.insert_state(missing_content_type);
+        s
+    }
+    /// The clock time dependent wrappers read through `clock::clock`, SystemClock by default
+    pub fn clock(self, clock: SharedClock) -> Self {
+        let mut s = self;
+        s.insert_state(clock);
+        s
+    }
+    pub fn auto_options(self, auto_options: bool) -> Self {
+        let mut s = self;
+        s.config.auto_options = auto_options;
+        s
+    }
+    pub fn worker_accept_loops(self, worker_accept_loops: usize) -> Self {
+        let mut s = self;
+        s.config.worker_accept_loops = worker_accept_loops;
+        s
+    }
     pub fn ssl_config(self, ssl_config: Option<SslConfig>) -> Self {
         let mut s = self;
         s.config.ssl_config = ssl_config;
         s
     }
+    pub fn max_body_size(self, max_body_size: usize) -> Self {
+        let mut s = self;
+        s.config.max_body_size = Some(max_body_size);
+        s
+    }
+    /// Opt in to h2 for TLS clients, websocket endpoints are then unreachable for clients choosing h2
+    pub fn http2(self, http2: bool) -> Self {
+        let mut s = self;
+        s.config.http2 = http2;
+        s
+    }
+    pub fn http2_max_concurrent_streams(self, max_concurrent_streams: u32) -> Self {
+        let mut s = self;
+        s.config.http2_max_concurrent_streams = Some(max_concurrent_streams);
+        s
+    }
+    /// Bytes of a body quoted around the position a Json body failed to parse at
+    pub fn excerpt_bytes(self, excerpt_bytes: usize) -> Self {
+        let mut s = self;
+        s.config.excerpt_bytes = excerpt_bytes;
+        s
+    }
+    pub fn https_redirect(self, https_redirect: Option<HttpsRedirect>) -> Self {
+        let mut s = self;
+        s.config.https_redirect = https_redirect;
+        s
+    }
     pub fn register<T: ServiceRegister>(self, service: T) -> Self {
         let mut s = self;
         service.register(&mut s.services);
@@ -291,41 +1352,1025 @@ impl ServerBuilder {
         s.filters.push(Arc::new(filter));
         s
     }
+    /// Answers requests no service matches, the response starts out as a 404.
+    /// Paths served with other methods still get a 405.
+    pub fn not_found_handler(self, handler: Arc<dyn ServiceHandler + Sync + Send>) -> Self {
+        let mut s = self;
+        s.services.not_found = Some(Arc::new(
+            ServiceBuilder::new("/{path}*")
+                .name("not_found")
+                .handler(handler)
+                .build(),
+        ));
+        s
+    }
+    /// Renders the errors of every service, see ErrorHandler
+    pub fn error_handler(self, error_handler: Arc<dyn ErrorHandler + Sync + Send>) -> Self {
+        let mut s = self;
+        s.services.error_handler = Some(error_handler);
+        s
+    }
     pub fn wrap(self, wrapper: Arc<dyn WrapperFn + Sync + Send>) -> Self {
         let mut s = self;
         s.wrappers.push(wrapper);
         s
     }
+    /// Runs every request inside `scope`, the first one added is the outermost
+    pub fn request_scope(self, scope: Arc<dyn RequestScope + Sync + Send>) -> Self {
+        let mut s = self;
+        s.scopes.push(scope);
+        s
+    }
+    /// Runs `reloader` on SIGHUP and Server::reload
+    pub fn on_reload(self, reloader: Arc<dyn ReloadFn + Sync + Send>) -> Self {
+        let mut s = self;
+        s.reloaders.push(reloader);
+        s
+    }
     pub fn task<T: Into<Task>>(mut self, task: T) -> Self {
         self.tasks.push(Arc::new(task.into()));
         self
     }
     pub fn shared_state<T: Send + Sync + 'static>(self, shared_state: T) -> Self {
         let mut s = self;
-        s.shared_state.insert(Arc::new(shared_state));
+        s.insert_state(Arc::new(shared_state));
+        s
+    }
+    pub fn warm_up_parallelism(self, warm_up_parallelism: usize) -> Self {
+        let mut s = self;
+        s.config.warm_up_parallelism = warm_up_parallelism;
+        s
+    }
+    pub fn warm_up_timeout(self, warm_up_timeout: Duration) -> Self {
+        let mut s = self;
+        s.config.warm_up_timeout = warm_up_timeout;
+        s
+    }
+    pub fn strict_warm_up(self, strict_warm_up: bool) -> Self {
+        let mut s = self;
+        s.config.strict_warm_up = strict_warm_up;
+        s
+    }
+    pub fn header_policy(self, header_policy: HeaderPolicy) -> Self {
+        let mut s = self;
+        s.config.header_policy = header_policy;
+        s
+    }
+    pub fn blocking_threads(self, blocking_threads: usize) -> Self {
+        let mut s = self;
+        s.config.blocking_threads = blocking_threads;
         s
     }
     pub fn build(self) -> Server {
+        let mut s = self;
+        let mut config = std::mem::take(&mut s.config);
+        if config.upgrade.is_some() {
+            config.socket_config.reuse_port = true;
+        }
+        if s.shared_state.get::<Arc<BlockingPool>>().is_none() {
+            s.insert_state(Arc::new(BlockingPool::new(config.blocking_threads)));
+        }
+        if s.shared_state.get::<Arc<Profile>>().is_none() {
+            s.insert_state(Arc::new(Profile::current().clone()));
+        }
+        if let Some(settings) = s.shared_state.get::<Arc<Settings>>() {
+            settings.log_report();
+        }
+        if let Some(store) = s.shared_state.get::<SharedDataStore>() {
+            if let Some(maintenance) = s.shared_state.get::<Arc<Maintenance>>() {
+                if let Err(e) = maintenance.restore(store.as_ref()) {
+                    warn!("Failed to restore maintenance mode: {e:?}");
+                }
+            }
+            if let Some(responses) = s.shared_state.get::<Arc<StaticResponses>>() {
+                if let Err(e) = responses.restore(store.as_ref()) {
+                    warn!("Failed to restore static responses: {e:?}");
+                }
+            }
+        }
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        s.insert_state(ShutdownSignal(shutdown_rx));
+        let mut services = std::mem::take(&mut s.services);
+        let mut tasks = std::mem::take(&mut s.tasks);
+        tasks.append(&mut services.tasks);
+        let registry = LiveRegistry::new(services);
+        s.insert_state(registry.clone());
+        let open = OpenConnections::default();
+        s.insert_state(open.clone());
         Server {
-            registry: Arc::new(self.services),
-            config: self.config,
+            registry,
+            config,
             run: Arc::new(AtomicBool::new(true)),
-            shared_state: Arc::new(self.shared_state),
-            filters: self.filters,
-            tasks: self.tasks,
-            wrappers: self.wrappers,
+            shared_state: Arc::new(s.shared_state),
+            filters: s.filters,
+            tasks,
+            wrappers: s.wrappers,
+            scopes: s.scopes,
+            state_types: s.state_types,
+            reloader: Arc::new(Reloader::new(s.reloaders)),
+            open,
+            served: Arc::new(AtomicUsize::new(0)),
+            shutdown,
         }
     }
 }
-impl Default for ServerBuilder {
-    fn default() -> Self {
-        Self {
-            services: ServiceRegistry { services: vec![] },
-            config: ServerConfig::default(),
-            shared_state: Extensions::default(),
-            filters: vec![],
-            tasks: vec![],
-            wrappers: vec![],
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ServiceGroup;
+    use crate::FromRequest;
+
+    struct Reply(&'static str);
+    #[async_trait::async_trait]
+    impl ServiceHandler for Reply {
+        fn name(&self) -> &str {
+            self.0
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            *data.response.body_mut() = self.0.stream_body();
+            Ok(data)
+        }
+    }
+
+    /// Echoes the body read through the Body extractor
+    struct Echo;
+    #[async_trait::async_trait]
+    impl ServiceHandler for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            match crate::Body::<String>::from_request(&mut data.request, "").await {
+                Ok(body) => *data.response.body_mut() = body.inner().stream_body(),
+                Err(e) => {
+                    crate::errors::HttpError::apply(&e, &mut data.response);
+                }
+            }
+            Ok(data)
+        }
+    }
+
+    /// Reads the whole body before the service runs, answering 413 when the stream hits its limit
+    struct ReadBody;
+    #[async_trait::async_trait]
+    impl WrapperFn for ReadBody {
+        fn name(&self) -> &str {
+            "ReadBody"
+        }
+        async fn before(&self, data: &mut ServiceData) -> WrapperResult {
+            let body = match data.request.consume() {
+                Ok(body) => body,
+                Err(_) => return WrapperResult::Continue,
+            };
+            match body.collect().await {
+                Ok(bytes) => {
+                    let _ = data
+                        .request
+                        .set_body(crate::service::ConsumedBodyType::Sized(
+                            http_body_util::Full::new(bytes.to_bytes()),
+                        ));
+                    WrapperResult::Continue
+                }
+                Err(_) => {
+                    *data.response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                    data.response
+                        .headers_mut()
+                        .insert("x-rejected-by", HeaderValue::from_static("ReadBody"));
+                    WrapperResult::Return
+                }
+            }
+        }
+        async fn after(&self, _: &mut ServiceData) -> WrapperResult {
+            WrapperResult::Continue
+        }
+    }
+
+    fn chunked(body: &'static str) -> reqwest::Body {
+        reqwest::Body::wrap_stream(futures_util::stream::iter(
+            body.as_bytes()
+                .chunks(4)
+                .map(|chunk| Ok::<_, Error>(chunk.to_vec()))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    fn echo(max_body_size: usize) -> Service {
+        ServiceBuilder::new("/echo")
+            .name("echo")
+            .max_body_size(max_body_size)
+            .handler(Arc::new(Echo))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn streamed_body_is_limited() {
+        let url = start(ServerBuilder::default().register(echo(8))).await;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{url}/echo"))
+            .body(chunked("short"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "short");
+        let response = client
+            .post(format!("{url}/echo"))
+            .body(chunked("far longer than eight bytes"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn sized_body_is_refused_from_its_length() {
+        let url = start(ServerBuilder::default().register(echo(8))).await;
+        let client = reqwest::Client::new();
+        for body in ["1234567", "12345678"] {
+            let response = client
+                .post(format!("{url}/echo"))
+                .body(body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.text().await.unwrap(), body);
+        }
+        let response = client
+            .post(format!("{url}/echo"))
+            .body("123456789")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        //Refused from content-length before any of the body was read
+        let text = response.text().await.unwrap();
+        assert!(text.contains("Payload Too large 9, Limit is 8"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn body_limit_applies_to_server_wrappers() {
+        let url = start(
+            ServerBuilder::default()
+                .max_body_size(1024)
+                .wrap(Arc::new(ReadBody))
+                .register(echo(8)),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{url}/echo"))
+            .body(chunked("far longer than eight bytes"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()["x-rejected-by"], "ReadBody");
+        let response = client
+            .post(format!("{url}/echo"))
+            .body(chunked("short"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "short");
+    }
+
+    /// Parses a Json body, answering with the extraction error
+    struct ParsesJson;
+    #[async_trait::async_trait]
+    impl ServiceHandler for ParsesJson {
+        fn name(&self) -> &str {
+            "parses_json"
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            let parsed =
+                crate::Body::<crate::Json<serde_json::Value>>::from_request(&mut data.request, "")
+                    .await;
+            if let Err(e) = parsed {
+                crate::errors::HttpError::apply(&e, &mut data.response);
+            }
+            Ok(data)
+        }
+    }
+
+    /// Runs the server on a free local port and returns its base url
+    async fn start(builder: ServerBuilder) -> String {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let server = builder
+            .host(address.ip().to_string())
+            .port(address.port())
+            .build();
+        spawn(server.run());
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        format!("http://{address}")
+    }
+
+    struct Panics;
+    #[async_trait::async_trait]
+    impl ServiceHandler for Panics {
+        fn name(&self) -> &str {
+            "panics"
+        }
+        async fn handle(&self, _: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            panic!("handler bug")
+        }
+    }
+
+    struct Rendered;
+    #[async_trait::async_trait]
+    impl crate::errors::ErrorHandler for Rendered {
+        async fn render(&self, error: Error, data: &mut ServiceData) {
+            *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            *data.response.body_mut() = format!("rendered {error}").stream_body();
+        }
+    }
+
+    /// Marks every response that went through the after wrappers
+    struct MarkAfter;
+    #[async_trait::async_trait]
+    impl WrapperFn for MarkAfter {
+        fn name(&self) -> &str {
+            "MarkAfter"
+        }
+        async fn before(&self, _: &mut ServiceData) -> WrapperResult {
+            WrapperResult::Continue
+        }
+        async fn after(&self, data: &mut ServiceData) -> WrapperResult {
+            data.response
+                .headers_mut()
+                .insert("x-after", HeaderValue::from_static("MarkAfter"));
+            WrapperResult::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_panic_goes_through_error_handler_and_wrappers() {
+        let url = start(
+            ServerBuilder::default()
+                .error_handler(Arc::new(Rendered))
+                .wrap(Arc::new(MarkAfter))
+                .register(
+                    ServiceBuilder::new("/panics")
+                        .name("panics")
+                        .handler(Arc::new(Panics))
+                        .build(),
+                ),
+        )
+        .await;
+        let response = reqwest::get(format!("{url}/panics")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()["x-after"], "MarkAfter");
+        assert_eq!(
+            response.text().await.unwrap(),
+            "rendered Service panicked: handler bug"
+        );
+    }
+
+    struct Hangs;
+    #[async_trait::async_trait]
+    impl ServiceHandler for Hangs {
+        fn name(&self) -> &str {
+            "hangs"
+        }
+        async fn handle(&self, data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok(data)
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout_closes_open_connections() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let server = ServerBuilder::default()
+            .bind(address.to_string())
+            .shutdown_timeout(Duration::from_millis(200))
+            .register(
+                ServiceBuilder::new("/hangs")
+                    .name("hangs")
+                    .handler(Arc::new(Hangs))
+                    .build(),
+            )
+            .build();
+        let run = server.run.clone();
+        let serving = spawn(server.run_until_shutdown());
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let request = spawn(reqwest::get(format!("http://{address}/hangs")));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        run.store(false, Ordering::Relaxed);
+
+        let summary = serving.await.unwrap().unwrap();
+        assert_eq!(summary.aborted_connections, 1);
+        let closed = tokio::time::timeout(Duration::from_secs(2), request)
+            .await
+            .expect("the aborted connection was left open");
+        assert!(closed.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_survives_a_handler_panic() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+        let url = start(
+            ServerBuilder::default()
+                .register(
+                    ServiceBuilder::new("/panics")
+                        .name("panics")
+                        .handler(Arc::new(Panics))
+                        .build(),
+                )
+                .register(
+                    ServiceBuilder::new("/ok")
+                        .name("ok")
+                        .handler(Arc::new(Reply("ok")))
+                        .build(),
+                ),
+        )
+        .await;
+        let stream = tokio::net::TcpStream::connect(url.trim_start_matches("http://"))
+            .await
+            .unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut statuses = vec![];
+        for path in ["/panics", "/ok"] {
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream
+                .get_mut()
+                .write_all(request.as_bytes())
+                .await
+                .unwrap();
+            let mut status = String::new();
+            stream.read_line(&mut status).await.unwrap();
+            statuses.push(status.trim().to_string());
+            let mut length = 0;
+            let mut chunked = false;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let line = line.trim().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                chunked |= line == "transfer-encoding: chunked";
+            }
+            if chunked {
+                loop {
+                    let mut size = String::new();
+                    stream.read_line(&mut size).await.unwrap();
+                    let size = usize::from_str_radix(size.trim(), 16).unwrap();
+                    let mut chunk = vec![0; size + 2];
+                    stream.read_exact(&mut chunk).await.unwrap();
+                    if size == 0 {
+                        break;
+                    }
+                }
+            } else {
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+            }
+        }
+        assert_eq!(
+            statuses,
+            vec!["HTTP/1.1 500 Internal Server Error", "HTTP/1.1 200 OK"]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_method_is_405_with_allow() {
+        let url = start(
+            ServerBuilder::default()
+                .auto_options(true)
+                .not_found_handler(Arc::new(Reply("custom not found")))
+                .register(
+                    ServiceBuilder::new("/only-get")
+                        .name("only-get")
+                        .method(Method::GET)
+                        .handler(Arc::new(Reply("got")))
+                        .build(),
+                ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let response = client.get(format!("{url}/only-get")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "got");
+        let response = client.post(format!("{url}/only-get")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, OPTIONS");
+    }
+
+    /// Lets requests through that carry x-allowed
+    struct NeedsHeader;
+    #[async_trait::async_trait]
+    impl crate::filters::FilterFn for NeedsHeader {
+        fn name(&self) -> &str {
+            "NeedsHeader"
+        }
+        async fn filter(&self, request: &Request<Incoming>) -> FilterResult {
+            request.headers().contains_key("x-allowed").into()
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_rejections_are_not_405() {
+        let url = start(
+            ServerBuilder::default().register(
+                ServiceBuilder::new("/filtered")
+                    .name("filtered")
+                    .method(Method::GET)
+                    .filter(Arc::new(NeedsHeader))
+                    .handler(Arc::new(Reply("got")))
+                    .build(),
+            ),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let response = client.post(format!("{url}/filtered")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get(ALLOW).is_none());
+        let response = client.get(format!("{url}/filtered")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = client
+            .post(format!("{url}/filtered"))
+            .header("x-allowed", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET");
+    }
+
+    #[tokio::test]
+    async fn methods_for_matches_live_requests() {
+        let builder = ServerBuilder::default()
+            .register(
+                ServiceBuilder::new("/x/{id}")
+                    .name("get")
+                    .method(Method::GET)
+                    .handler(Arc::new(Reply("get")))
+                    .build(),
+            )
+            .register(
+                ServiceBuilder::new("/x/{id}")
+                    .name("post")
+                    .method(Method::POST)
+                    .handler(Arc::new(Reply("post")))
+                    .build(),
+            )
+            .register(
+                ServiceBuilder::new("/*")
+                    .name("put")
+                    .method(Method::PUT)
+                    .handler(Arc::new(Reply("put")))
+                    .build(),
+            );
+        let registry = builder.services.clone();
+        let url = start(builder).await;
+        let client = reqwest::Client::new();
+        let route = registry.methods_for("/x/1").unwrap();
+        assert_eq!(route.methods, vec![Method::GET, Method::POST, Method::PUT]);
+        assert!(!route.any);
+        for (method, body) in [("GET", "get"), ("POST", "post"), ("PUT", "put")] {
+            let response = client
+                .request(method.parse().unwrap(), format!("{url}/x/1"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.text().await.unwrap(), body);
+        }
+        let response = client.delete(format!("{url}/x/1")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, POST, PUT");
+
+        assert_eq!(
+            registry.methods_for("/y").unwrap().methods,
+            vec![Method::PUT]
+        );
+        let response = client.get(format!("{url}/y")).send().await.unwrap();
+        assert_eq!(response.headers()[ALLOW], "PUT");
+    }
+
+    /// The parse error details returned for `body`
+    async fn parse_details(url: &str, body: Vec<u8>) -> serde_json::Value {
+        let response = reqwest::Client::new()
+            .post(format!("{url}/json"))
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let envelope = response.bytes().await.unwrap();
+        assert!(
+            envelope.len() < 1024,
+            "error echoed {} bytes",
+            envelope.len()
+        );
+        serde_json::from_slice::<serde_json::Value>(&envelope).unwrap()["details"].clone()
+    }
+
+    fn parses_json() -> Service {
+        ServiceBuilder::new("/json")
+            .name("parses_json")
+            .handler(Arc::new(ParsesJson))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn large_invalid_bodies_keep_only_an_excerpt() {
+        let mut body = b"[".to_vec();
+        while body.len() < 10 * 1024 * 1024 - 2 {
+            body.extend_from_slice(b"1,");
+        }
+        body.extend_from_slice(b"x]");
+        let size = body.len();
+        let url = start(ServerBuilder::default().register(parses_json())).await;
+        let details = parse_details(&url, body.clone()).await;
+        assert_eq!(details["body_size"], size);
+        assert_eq!(details["line"], 1);
+        let excerpt = details["excerpt"].as_str().unwrap();
+        assert_eq!(excerpt.len(), DEFAULT_EXCERPT_BYTES / 2 + 2);
+        assert!(excerpt.ends_with("1,x]"));
+
+        let url = start(
+            ServerBuilder::default()
+                .excerpt_bytes(16)
+                .register(parses_json()),
+        )
+        .await;
+        let details = parse_details(&url, body).await;
+        assert_eq!(details["body_size"], size);
+        assert_eq!(details["excerpt"], "1,1,1,1,x]");
+    }
+
+    type CallLog = Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// Logs its before and after calls, optionally reporting a required role
+    struct Recorder {
+        name: &'static str,
+        role: Option<&'static str>,
+        log: CallLog,
+    }
+    #[async_trait::async_trait]
+    impl WrapperFn for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn required_role(&self) -> Option<&str> {
+            self.role
+        }
+        async fn before(&self, _: &mut ServiceData) -> WrapperResult {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            WrapperResult::Continue
+        }
+        async fn after(&self, _: &mut ServiceData) -> WrapperResult {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+            WrapperResult::Continue
+        }
+    }
+
+    struct RecordsHandler(CallLog);
+    #[async_trait::async_trait]
+    impl ServiceHandler for RecordsHandler {
+        fn name(&self) -> &str {
+            "users"
+        }
+        async fn handle(&self, data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            self.0.lock().unwrap().push("handler users".to_string());
+            Ok(data)
+        }
+    }
+
+    struct Marker;
+
+    fn recorder(name: &'static str, role: Option<&'static str>, log: &CallLog) -> Arc<Recorder> {
+        Arc::new(Recorder {
+            name,
+            role,
+            log: log.clone(),
+        })
+    }
+
+    fn planned_builder(log: &CallLog) -> ServerBuilder {
+        let users = ServiceBuilder::new("/admin/users/{id}")
+            .name("users")
+            .method(Method::GET)
+            .wrap(recorder("service", None, log))
+            .handler(Arc::new(RecordsHandler(log.clone())))
+            .build();
+        let admin = ServiceGroup::default()
+            .wrap(recorder("admin", Some("admin"), log))
+            .sub_group(
+                ServiceGroup::default()
+                    .wrap(recorder("nested", None, log))
+                    .service(users),
+            );
+        ServerBuilder::default()
+            .wrap(recorder("server", None, log))
+            .shared_state(Marker)
+            .register(admin)
+            .register(
+                ServiceBuilder::new("/static/*")
+                    .name("assets")
+                    .handler(Arc::new(Reply("asset")))
+                    .build(),
+            )
+    }
+
+    #[tokio::test]
+    async fn route_plan_follows_the_runtime_order() {
+        let log = CallLog::default();
+        let plan = planned_builder(&log)
+            .build()
+            .route_plan("/admin/users/42", &Method::GET);
+        let url = start(planned_builder(&log)).await;
+        let response = reqwest::get(format!("{url}/admin/users/42")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert_eq!(plan.candidates.len(), 1);
+        let users = &plan.candidates[0];
+        assert_eq!(users.name, "users");
+        assert!(users.definite);
+        assert_eq!(users.params, vec![("id".to_string(), "42".to_string())]);
+        assert_eq!(users.required_roles, vec!["admin".to_string()]);
+        let planned: Vec<String> = users
+            .steps
+            .iter()
+            .map(|step| {
+                let stage = match step.stage {
+                    PlanStage::Before => "before",
+                    PlanStage::Handler => "handler",
+                    PlanStage::After => "after",
+                };
+                format!("{stage} {}", step.name)
+            })
+            .collect();
+        assert_eq!(planned, *log.lock().unwrap());
+        assert!(plan
+            .state
+            .contains(&std::any::type_name::<Arc<Marker>>().to_string()));
+        assert!(plan
+            .state
+            .contains(&std::any::type_name::<LiveRegistry>().to_string()));
+    }
+
+    #[test]
+    fn route_plan_includes_static_routes() {
+        let log = CallLog::default();
+        let server = planned_builder(&log).build();
+        let plan = server.route_plan("/static/app.js", &Method::GET);
+        assert_eq!(plan.candidates.len(), 1);
+        assert_eq!(plan.candidates[0].name, "assets");
+        assert!(plan.candidates[0].params.is_empty());
+        assert!(plan.candidates[0].required_roles.is_empty());
+
+        let plan = server.route_plan("/admin/users/42", &Method::POST);
+        assert!(!plan.candidates[0].method_allowed);
+        assert!(!plan.candidates[0].definite);
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn taken_address_is_a_bind_error() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = taken.local_addr().unwrap();
+        let reason = ServerBuilder::default()
+            .host(address.ip().to_string())
+            .port(address.port())
+            .build()
+            .serve()
+            .await;
+        assert!(matches!(reason, ShutdownReason::BindError(_)), "{reason:?}");
+        assert_eq!(reason.exit_code(), crate::shutdown::EXIT_BIND);
+    }
+
+    #[tokio::test]
+    async fn stopped_server_reports_a_clean_shutdown() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let server = ServerBuilder::default()
+            .host(address.ip().to_string())
+            .port(address.port())
+            .register(
+                ServiceBuilder::new("/ok")
+                    .name("ok")
+                    .handler(Arc::new(Reply("ok")))
+                    .build(),
+            )
+            .build();
+        let (run, served) = (server.run.clone(), server.served.clone());
+        let serving = spawn(server.serve());
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for _ in 0..2 {
+            let response = reqwest::get(format!("http://{address}/ok")).await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "ok");
+        }
+        run.store(false, Ordering::Relaxed);
+        let reason = serving.await.unwrap();
+        assert!(matches!(reason, ShutdownReason::Ok { .. }), "{reason:?}");
+        assert_eq!(reason.exit_code(), crate::shutdown::EXIT_OK);
+        assert_eq!(served.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_path_uses_the_not_found_handler() {
+        let url = start(
+            ServerBuilder::default()
+                .not_found_handler(Arc::new(Reply("custom not found")))
+                .register(
+                    ServiceBuilder::new("/only-get")
+                        .name("only-get")
+                        .method(Method::GET)
+                        .handler(Arc::new(Reply("got")))
+                        .build(),
+                ),
+        )
+        .await;
+        let response = reqwest::get(format!("{url}/missing")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.text().await.unwrap(), "custom not found");
+    }
+
+    fn replies(path: &'static str) -> Service {
+        ServiceBuilder::new(path)
+            .name(path)
+            .handler(Arc::new(Reply(path)))
+            .build()
+    }
+
+    async fn get(url: &str, path: &str) -> (StatusCode, String) {
+        let response = reqwest::get(format!("{url}{path}")).await.unwrap();
+        (response.status(), response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn maintenance_spares_health_and_its_own_toggle() {
+        let maintenance = Arc::new(Maintenance::default());
+        let url = start(
+            ServerBuilder::default()
+                .maintenance(maintenance.clone())
+                .register(replies("/users"))
+                .register(replies("/healthz"))
+                .register(replies("/healthzAnything"))
+                .register(replies("/pf_admin/maintenance/disable")),
+        )
+        .await;
+        maintenance.set_enabled(true);
+        let response = reqwest::get(format!("{url}/users")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "300");
+        assert_eq!(response.text().await.unwrap(), "Down for maintenance");
+        assert_eq!(get(&url, "/healthz").await.0, StatusCode::OK);
+        assert_eq!(
+            get(&url, "/healthzAnything").await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            get(&url, "/pf_admin/maintenance/disable").await.0,
+            StatusCode::OK
+        );
+
+        maintenance.set_enabled(false);
+        assert_eq!(
+            get(&url, "/users").await,
+            (StatusCode::OK, "/users".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn toggles_are_restored_from_the_data_store() {
+        let store: SharedDataStore = Arc::new(crate::data_store::MemoryDataStore::default());
+        let gone = crate::static_response::StaticResponseConfig {
+            name: "gone".to_string(),
+            path: "/legacy".to_string(),
+            enabled: true,
+            response: crate::static_response::FixedResponse {
+                status: 410,
+                headers: Default::default(),
+                body: crate::static_response::StaticBody::Text("gone".to_string()),
+            },
+        };
+        let first = ServerBuilder::default()
+            .data_store(store.clone())
+            .maintenance(Arc::new(Maintenance::default()))
+            .static_response(StaticResponse::from_config(&gone).unwrap())
+            .build();
+        let maintenance = first.shared_state.get::<Arc<Maintenance>>().unwrap();
+        maintenance.set_enabled(true);
+        maintenance.persist(store.as_ref()).unwrap();
+        let responses = first.shared_state.get::<Arc<StaticResponses>>().unwrap();
+        responses.get("gone").unwrap().set_enabled(false);
+        responses.persist(store.as_ref()).unwrap();
+
+        //A restart with the same configuration picks up the runtime changes
+        let url = start(
+            ServerBuilder::default()
+                .data_store(store)
+                .maintenance(Arc::new(Maintenance::default().allow("/legacy")))
+                .static_response(StaticResponse::from_config(&gone).unwrap())
+                .register(replies("/legacy")),
+        )
+        .await;
+        assert_eq!(get(&url, "/users").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            get(&url, "/legacy").await,
+            (StatusCode::OK, "/legacy".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn bind_replaces_host_and_port() {
+        let defaults = ServerBuilder::default().build();
+        assert_eq!(
+            Server::get_socket_addrs(&defaults.config).await.unwrap(),
+            vec![
+                ("127.0.0.1:8080".parse().unwrap(), true),
+                ("[::1]:8080".parse().unwrap(), false),
+            ]
+        );
+        let bound = ServerBuilder::default()
+            .bind("127.0.0.1:0")
+            .bind("[::1]:0")
+            .bind("127.0.0.1:0")
+            .build();
+        assert_eq!(
+            Server::get_socket_addrs(&bound.config).await.unwrap(),
+            vec![
+                ("127.0.0.1:0".parse().unwrap(), true),
+                ("[::1]:0".parse().unwrap(), true),
+            ]
+        );
+    }
+
+    /// Serves `/hello` on every `bind` entry and waits until `ready` accepts connections
+    async fn serve_on(binds: &[String], ready: SocketAddr) {
+        let mut builder = ServerBuilder::default().register(replies("/hello"));
+        for bind in binds {
+            builder = builder.bind(bind);
+        }
+        spawn(builder.build().run());
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(ready).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_the_v6_loopback() {
+        let address = std::net::TcpListener::bind("[::1]:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        serve_on(&[address.to_string()], address).await;
+        assert_eq!(
+            get(&format!("http://{address}"), "/hello").await,
+            (StatusCode::OK, "/hello".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_v4_and_v6_on_the_same_port() {
+        let port = std::net::TcpListener::bind("0.0.0.0:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let v4 = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+        serve_on(&[format!("0.0.0.0:{port}"), format!("[::]:{port}")], v4).await;
+        for address in [v4, v6] {
+            assert_eq!(
+                get(&format!("http://{address}"), "/hello").await,
+                (StatusCode::OK, "/hello".to_string())
+            );
         }
     }
 }
@@ -1,22 +1,26 @@
 use crate::filters::{FilterFn, FilterResult};
-use crate::routes::Route;
+use crate::headers::{HeaderPolicy, RemovedHeaders};
+use crate::routes::{MatchedPathParams, Route};
+use crate::sockets::{OriginPolicy, TrustedProxy};
+use crate::task::Task;
 use crate::wrappers::{WrapperFn, WrapperResult};
 use crate::{ServiceData, ServiceHandler, ServiceRegister, ServiceRegistry};
 use futures_util::TryStreamExt;
 use http::request::Parts;
-use http::{Extensions, HeaderMap, HeaderValue, Method, Request, Response, Uri};
+use http::{Extensions, HeaderMap, HeaderValue, Method, Request, Response, Uri, Version};
 use http_body::Frame;
-use http_body_util::{BodyExt, BodyStream, Empty, Full, StreamBody};
+use http_body_util::{BodyExt, BodyStream, Empty, Full, Limited, StreamBody};
 use hyper::body::{Body, Bytes, Incoming, SizeHint};
 use hyper::upgrade::OnUpgrade;
 use once_cell::sync::Lazy;
+use std::collections::HashSet;
 use std::io::{Error, ErrorKind};
 use std::mem::replace;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio_tungstenite::tungstenite::error::ProtocolError;
 use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct ServiceBuilder {
@@ -25,6 +29,9 @@ pub struct ServiceBuilder {
     filters: Vec<Arc<dyn FilterFn + Sync + Send>>,
     wrappers: Vec<Arc<dyn WrapperFn + Sync + Send>>,
     handler: Option<Arc<dyn ServiceHandler + Send + Sync>>,
+    methods: Option<HashSet<Method>>,
+    header_policy: Option<Arc<HeaderPolicy>>,
+    max_body_size: Option<usize>,
 }
 impl ServiceBuilder {
     pub fn new(path: &str) -> Self {
@@ -34,6 +41,9 @@ impl ServiceBuilder {
             filters: vec![],
             wrappers: vec![],
             handler: None,
+            methods: None,
+            header_policy: None,
+            max_body_size: None,
         }
     }
     pub fn name<S: AsRef<str>>(self, path: S) -> Self {
@@ -56,13 +66,36 @@ impl ServiceBuilder {
         s.handler = Some(service_handler);
         s
     }
+    /// Restricts the service to the given method, checked before any filters run.
+    /// Services with methods set also contribute to the Allow header of 405 and OPTIONS responses.
+    pub fn method(self, method: Method) -> Self {
+        let mut s = self;
+        s.methods.get_or_insert_with(HashSet::new).insert(method);
+        s
+    }
+    /// Overrides the server HeaderPolicy for this service
+    pub fn header_policy(self, header_policy: HeaderPolicy) -> Self {
+        let mut s = self;
+        s.header_policy = Some(Arc::new(header_policy));
+        s
+    }
+    /// Overrides ServerConfig::max_body_size for this service
+    pub fn max_body_size(self, max_body_size: usize) -> Self {
+        let mut s = self;
+        s.max_body_size = Some(max_body_size);
+        s
+    }
     pub fn build(self) -> Service {
         Service {
             path: Arc::new(self.path),
             name: self.name.unwrap_or_default(),
+            uuid: Uuid::new_v4(),
             filters: self.filters,
             wrappers: self.wrappers,
             handler: self.handler,
+            methods: self.methods,
+            header_policy: self.header_policy,
+            max_body_size: self.max_body_size,
         }
     }
 }
@@ -72,12 +105,18 @@ pub struct ServiceGroup {
     pub services: Vec<Service>,
     pub filters: Vec<Arc<dyn FilterFn + Sync + Send>>,
     pub wrappers: Vec<Arc<dyn WrapperFn + Sync + Send>>,
+    pub header_policy: Option<Arc<HeaderPolicy>>,
+    /// Started with the server's own tasks
+    pub tasks: Vec<Task>,
 }
 impl ServiceRegister for ServiceGroup {
     fn register(self, service_registry: &mut ServiceRegistry) {
         for service in self.services {
             service.register(service_registry);
         }
+        service_registry
+            .tasks
+            .extend(self.tasks.into_iter().map(Arc::new));
     }
 }
 impl ServiceGroup {
@@ -85,6 +124,9 @@ impl ServiceGroup {
         let mut service = service.into();
         service.filters.extend(self.filters.clone());
         service.wrappers.extend(self.wrappers.clone());
+        if service.header_policy.is_none() {
+            service.header_policy.clone_from(&self.header_policy);
+        }
         self.services.push(service);
         self
     }
@@ -93,6 +135,15 @@ impl ServiceGroup {
         for service in group.services {
             self = self.service(service);
         }
+        for task in group.tasks {
+            self = self.task(task);
+        }
+        self
+    }
+    pub fn task<T: Into<Task>>(mut self, task: T) -> Self {
+        let task = task.into();
+        task.task_fn.joined_group(&self);
+        self.tasks.push(task);
         self
     }
     pub fn filter(mut self, filter: Arc<dyn FilterFn + Sync + Send>) -> Self {
@@ -103,37 +154,62 @@ impl ServiceGroup {
         self.wrappers.push(wrappers);
         self
     }
+    /// Applies to services added after this call that have no policy of their own
+    pub fn header_policy(mut self, header_policy: HeaderPolicy) -> Self {
+        self.header_policy = Some(Arc::new(header_policy));
+        self
+    }
 }
 
 #[derive(Debug)]
 pub struct Service {
     pub path: Arc<Route>,
     pub name: String,
+    pub uuid: Uuid,
     pub filters: Vec<Arc<dyn FilterFn + Sync + Send>>,
     pub wrappers: Vec<Arc<dyn WrapperFn + Sync + Send>>,
     pub handler: Option<Arc<dyn ServiceHandler + Send + Sync>>,
+    pub methods: Option<HashSet<Method>>,
+    pub header_policy: Option<Arc<HeaderPolicy>>,
+    pub max_body_size: Option<usize>,
 }
 impl Service {
     pub async fn handles(&self, req: &Request<Incoming>) -> bool {
-        if self.path.matches(req.uri().path()) {
-            for f in self.filters.iter() {
-                if f.filter(req).await != FilterResult::Allow {
-                    return false;
-                }
+        self.allows_method(req.method())
+            && self.path.matches(req.uri().path())
+            && self.passes_filters(req).await
+    }
+    /// Evaluates the filters only, the method and path are checked by handles
+    pub async fn passes_filters(&self, req: &Request<Incoming>) -> bool {
+        for f in self.filters.iter() {
+            if f.filter(req).await != FilterResult::Allow {
+                return false;
             }
-            true
-        } else {
-            false
         }
+        true
     }
     pub async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        let server = data.server.clone();
+        let header_policy = self
+            .header_policy
+            .as_deref()
+            .unwrap_or(&server.config.header_policy);
+        let mut headers_hidden = *header_policy == HeaderPolicy::Passthrough;
         for func in self.wrappers.iter() {
-            match func.before(&mut data).await {
-                WrapperResult::Continue => {}
-                WrapperResult::Return => {
-                    return Ok(data);
-                }
+            if !headers_hidden && !func.infrastructure() {
+                Self::hide_headers(header_policy, &mut data);
+                headers_hidden = true;
+            }
+            let result = func.before(&mut data).await;
+            if matches!(data.request.request, IncomingRequest::Consumed(_)) {
+                data.request.mark_consumed(func.name());
             }
+            if matches!(result, WrapperResult::Return) {
+                return Ok(data);
+            }
+        }
+        if !headers_hidden {
+            Self::hide_headers(header_policy, &mut data);
         }
         if let Some(handler) = self.handler.as_ref() {
             data = handler.handle(data).await?;
@@ -148,6 +224,21 @@ impl Service {
         }
         Ok(data)
     }
+    fn hide_headers(header_policy: &HeaderPolicy, data: &mut ServiceData) {
+        let removed = match data.request.request.headers_mut() {
+            Some(headers) => header_policy.apply(headers),
+            None => return,
+        };
+        if !removed.is_empty() {
+            data.request.insert(RemovedHeaders(removed));
+        }
+    }
+    pub fn allows_method(&self, method: &Method) -> bool {
+        self.methods
+            .as_ref()
+            .map(|methods| methods.contains(method))
+            .unwrap_or(true)
+    }
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
@@ -158,20 +249,23 @@ impl ServiceRegister for Service {
     }
 }
 
+/// A streamed request body, reading past the MaxBodySize of the request fails with http_body_util::LengthLimitError
+pub type RequestBody = Limited<Incoming>;
+
 pub enum IncomingRequest {
-    Stream(Request<Incoming>),
+    Stream(Request<RequestBody>),
     Sized(Request<Full<Bytes>>),
     Consumed(Parts),
     Empty,
 }
 pub enum BodyType<'a> {
-    Stream(&'a mut Incoming),
+    Stream(&'a mut RequestBody),
     Sized(&'a mut Full<Bytes>),
     Empty,
 }
 
 pub enum ConsumedBodyType {
-    Stream(Incoming),
+    Stream(RequestBody),
     Sized(Full<Bytes>),
     Empty,
 }
@@ -256,6 +350,14 @@ impl IncomingRequest {
             IncomingRequest::Empty => &DEFAULT_URI,
         }
     }
+    pub fn version(&self) -> Version {
+        match &self {
+            IncomingRequest::Sized(r) => r.version(),
+            IncomingRequest::Stream(r) => r.version(),
+            IncomingRequest::Consumed(r) => r.version,
+            IncomingRequest::Empty => Version::default(),
+        }
+    }
     pub fn headers(&self) -> Option<&HeaderMap<HeaderValue>> {
         match &self {
             IncomingRequest::Sized(r) => Some(r.headers()),
@@ -304,7 +406,7 @@ impl IncomingRequest {
             IncomingRequest::Empty => None,
         }
     }
-    pub fn body(&mut self) -> BodyType {
+    pub fn body(&mut self) -> BodyType<'_> {
         match self {
             IncomingRequest::Sized(r) => BodyType::Sized(r.body_mut()),
             IncomingRequest::Stream(r) => BodyType::Stream(r.body_mut()),
@@ -313,45 +415,184 @@ impl IncomingRequest {
         }
     }
     pub fn is_upgrade_request(&self) -> bool {
-        if let Some(headers) = self.headers() {
-            header_contains_value(headers, hyper::header::CONNECTION, "Upgrade")
-                && header_contains_value(headers, hyper::header::UPGRADE, "websocket")
-        } else {
-            false
+        self.headers().map(is_websocket_upgrade).unwrap_or_default()
+    }
+    pub fn validate_upgrade(&self) -> Result<String, UpgradeError> {
+        match self.headers() {
+            Some(headers) => validate_websocket_upgrade(headers),
+            None => Err(UpgradeError::RequestConsumed),
+        }
+    }
+    pub fn check_origin(&self, policy: &OriginPolicy) -> Result<(), UpgradeError> {
+        match self.headers() {
+            Some(headers)
+                if policy.allows(
+                    headers,
+                    self.extensions()
+                        .map(|e| e.get::<TrustedProxy>().is_some())
+                        .unwrap_or_default(),
+                ) =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(UpgradeError::OriginNotAllowed),
+            None => Err(UpgradeError::RequestConsumed),
         }
     }
-    pub fn upgrade(&mut self) -> Result<(Response<Full<Bytes>>, OnUpgrade), ProtocolError> {
-        if let Some(headers) = self.headers() {
-            let key = headers
-                .get("Sec-WebSocket-Key")
-                .ok_or(ProtocolError::MissingSecWebSocketKey)?;
-            if headers.get("Sec-WebSocket-Version").map(|v| v.as_bytes()) != Some(b"13") {
-                return Err(ProtocolError::MissingSecWebSocketVersionHeader);
+    pub fn upgrade(&mut self) -> Result<(Response<Full<Bytes>>, OnUpgrade), UpgradeError> {
+        self.upgrade_with_subprotocols(&[])
+            .map(|(response, on_upgrade, _)| (response, on_upgrade))
+    }
+    /// Upgrades the connection, echoing the first subprotocol requested by the client that is in `supported`.
+    /// Fails when the client only requests subprotocols this endpoint does not speak,
+    /// an endpoint without any supported subprotocols accepts every request without echoing one.
+    pub fn upgrade_with_subprotocols(
+        &mut self,
+        supported: &[&str],
+    ) -> Result<NegotiatedUpgrade, UpgradeError> {
+        let accept_key = self.validate_upgrade()?;
+        let protocol = match self.headers() {
+            Some(headers) => negotiate_subprotocol(headers, supported)?,
+            None => None,
+        };
+        let mut response = Response::builder()
+            .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
+            .header(hyper::header::CONNECTION, "upgrade")
+            .header(hyper::header::UPGRADE, "websocket")
+            .header("Sec-WebSocket-Accept", &accept_key);
+        if let Some(protocol) = &protocol {
+            response = response.header(hyper::header::SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        let response = response
+            .body(Full::<Bytes>::from("switching to websocket protocol"))
+            .expect("bug: failed to build response");
+        let on_upgrade = match self {
+            IncomingRequest::Stream(request) => hyper::upgrade::on(request),
+            IncomingRequest::Sized(request) => hyper::upgrade::on(request),
+            IncomingRequest::Consumed(parts) => hyper::upgrade::on(
+                Request::<Empty<()>>::from_parts(parts.clone(), Empty::default()),
+            ),
+            IncomingRequest::Empty => return Err(UpgradeError::RequestConsumed),
+        };
+        Ok((response, on_upgrade, protocol))
+    }
+}
+
+/// Upgrade response, pending connection and the agreed subprotocol
+pub type NegotiatedUpgrade = (Response<Full<Bytes>>, OnUpgrade, Option<String>);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpgradeError {
+    MissingConnectionUpgrade,
+    MissingUpgradeWebsocket,
+    UnsupportedVersion,
+    MissingKey,
+    InvalidKey,
+    OriginNotAllowed,
+    UnsupportedSubprotocol,
+    RequestConsumed,
+}
+impl UpgradeError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            UpgradeError::MissingConnectionUpgrade => {
+                "Websocket upgrade requires a Connection header containing \"Upgrade\""
             }
-            let response = Response::builder()
-                .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
-                .header(hyper::header::CONNECTION, "upgrade")
-                .header(hyper::header::UPGRADE, "websocket")
-                .header("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes()))
-                .body(Full::<Bytes>::from("switching to websocket protocol"))
-                .expect("bug: failed to build response");
-            match self {
-                IncomingRequest::Stream(request) => Ok((response, hyper::upgrade::on(request))),
-                IncomingRequest::Sized(request) => Ok((response, hyper::upgrade::on(request))),
-                IncomingRequest::Consumed(parts) => Ok((
-                    response,
-                    hyper::upgrade::on(Request::<Empty<()>>::from_parts(
-                        parts.clone(),
-                        Empty::default(),
-                    )),
-                )),
-                IncomingRequest::Empty => Err(ProtocolError::InvalidCloseSequence), //maye a different error? Should not ever happen
+            UpgradeError::MissingUpgradeWebsocket => {
+                "Websocket upgrade requires an Upgrade header containing \"websocket\""
             }
-        } else {
-            Err(ProtocolError::MissingSecWebSocketKey)
+            UpgradeError::UnsupportedVersion => {
+                "Websocket upgrade requires Sec-WebSocket-Version: 13"
+            }
+            UpgradeError::MissingKey => "Websocket upgrade requires a Sec-WebSocket-Key header",
+            UpgradeError::InvalidKey => {
+                "Sec-WebSocket-Key must be the base64 encoding of a 16 byte value"
+            }
+            UpgradeError::OriginNotAllowed => "Websocket upgrade is not allowed from this Origin",
+            UpgradeError::UnsupportedSubprotocol => {
+                "Websocket upgrade requested no subprotocol supported by this endpoint"
+            }
+            UpgradeError::RequestConsumed => "Websocket upgrade request was already consumed",
         }
     }
 }
+impl std::fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+impl std::error::Error for UpgradeError {}
+impl From<UpgradeError> for Error {
+    fn from(value: UpgradeError) -> Self {
+        Error::new(ErrorKind::InvalidInput, value)
+    }
+}
+
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    header_contains_value(headers, hyper::header::CONNECTION, "Upgrade")
+        && header_contains_value(headers, hyper::header::UPGRADE, "websocket")
+}
+
+/// Checks the handshake headers of a websocket upgrade, returning the Sec-WebSocket-Accept value.
+/// Header values are matched case insensitively as comma separated lists so proxies that merge
+/// or rewrite Connection/Upgrade values are still accepted.
+pub fn validate_websocket_upgrade(headers: &HeaderMap) -> Result<String, UpgradeError> {
+    if !header_contains_value(headers, hyper::header::CONNECTION, "Upgrade") {
+        return Err(UpgradeError::MissingConnectionUpgrade);
+    }
+    if !header_contains_value(headers, hyper::header::UPGRADE, "websocket") {
+        return Err(UpgradeError::MissingUpgradeWebsocket);
+    }
+    if !header_contains_value(headers, hyper::header::SEC_WEBSOCKET_VERSION, "13") {
+        return Err(UpgradeError::UnsupportedVersion);
+    }
+    let key = headers
+        .get(hyper::header::SEC_WEBSOCKET_KEY)
+        .ok_or(UpgradeError::MissingKey)?
+        .to_str()
+        .map_err(|_| UpgradeError::InvalidKey)?
+        .trim();
+    if !is_valid_websocket_key(key) {
+        return Err(UpgradeError::InvalidKey);
+    }
+    Ok(derive_accept_key(key.as_bytes()))
+}
+
+/// Picks the first subprotocol in the client's Sec-WebSocket-Protocol list that is in `supported`
+pub fn negotiate_subprotocol(
+    headers: &HeaderMap,
+    supported: &[&str],
+) -> Result<Option<String>, UpgradeError> {
+    if supported.is_empty() {
+        return Ok(None);
+    }
+    let mut requested = headers
+        .get_all(hyper::header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .peekable();
+    if requested.peek().is_none() {
+        return Ok(None);
+    }
+    requested
+        .find(|protocol| supported.contains(protocol))
+        .map(|protocol| Some(protocol.to_string()))
+        .ok_or(UpgradeError::UnsupportedSubprotocol)
+}
+
+fn is_valid_websocket_key(key: &str) -> bool {
+    //A 16 byte nonce is always 22 base64 characters followed by "=="
+    let bytes = key.as_bytes();
+    bytes.len() == 24
+        && bytes[22..] == *b"=="
+        && bytes[..22]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+        && b"AQgw".contains(&bytes[21])
+}
 
 fn header_contains_value(
     headers: &HeaderMap,
@@ -372,17 +613,31 @@ fn header_contains_value(
     false
 }
 
+/// Name of the service routing picked for the request, inserted before any wrapper runs
+#[derive(Clone, Debug)]
+pub struct MatchedService(pub String);
+
+/// The server's shared state, inserted into each request as one Arc instead of copying every entry.
+/// Values are shared with tasks, so state that changes at runtime uses interior mutability.
+#[derive(Clone)]
+pub struct SharedState(pub Arc<Extensions>);
+
+/// Who read the request body, a wrapper name or an extractor type
+#[derive(Debug, Clone)]
+pub struct BodyConsumedBy(pub String);
+
 pub struct ServiceRequest {
     pub request: IncomingRequest,
     pub path: Arc<Route>,
 }
 impl ServiceRequest {
+    /// Values inserted for this request win, then the server's shared state is looked through.
+    /// get_mut, insert and remove only reach the per-request values, a removed value falls back to
+    /// the shared one again and shared values change through interior mutability.
     pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
-        if let Some(ext) = self.request.extensions() {
-            ext.get()
-        } else {
-            None
-        }
+        let ext = self.request.extensions()?;
+        ext.get()
+            .or_else(|| ext.get::<SharedState>().and_then(|shared| shared.0.get()))
     }
     pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
         if let Some(ext) = self.request.extensions_mut() {
@@ -405,6 +660,36 @@ impl ServiceRequest {
             None
         }
     }
+    /// Matches the path variables once so Path extractors can share them
+    pub fn capture_path_params(&mut self) {
+        if self.get::<MatchedPathParams>().is_none() {
+            if let Some(params) = self.path.params(self.request.uri().path()) {
+                self.insert(params);
+            }
+        }
+    }
+    /// Who read the body, None while it can still be read
+    pub fn consumed_by(&self) -> Option<&str> {
+        match self
+            .request
+            .extensions()
+            .and_then(|ext| ext.get::<BodyConsumedBy>())
+        {
+            Some(consumer) => Some(consumer.0.as_str()),
+            None if matches!(self.request, IncomingRequest::Consumed(_)) => Some("unknown"),
+            None => None,
+        }
+    }
+    /// Records `consumer` as the reader of the body unless one is already recorded
+    pub fn mark_consumed<S: AsRef<str>>(&mut self, consumer: S) {
+        if self
+            .request
+            .extensions()
+            .is_some_and(|ext| ext.get::<BodyConsumedBy>().is_none())
+        {
+            self.insert(BodyConsumedBy(consumer.as_ref().to_string()));
+        }
+    }
     pub fn consume(&mut self) -> Result<ConsumedBodyType, Error> {
         match replace(&mut self.request, IncomingRequest::Empty) {
             IncomingRequest::Sized(r) => {
@@ -457,6 +742,149 @@ impl ServiceRequest {
                 );
             }
         }
+        self.remove::<BodyConsumedBy>();
         Ok(old_body)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    const KEY: (&str, &str) = ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+    const VERSION: (&str, &str) = ("sec-websocket-version", "13");
+
+    #[test]
+    fn upgrade_accepts_header_permutations() {
+        let accepted = [
+            handshake(&[
+                ("connection", "Upgrade"),
+                ("upgrade", "websocket"),
+                VERSION,
+                KEY,
+            ]),
+            handshake(&[
+                ("connection", "upgrade"),
+                ("upgrade", "WebSocket"),
+                VERSION,
+                KEY,
+            ]),
+            handshake(&[
+                ("connection", "keep-alive, Upgrade"),
+                ("upgrade", "websocket"),
+                VERSION,
+                KEY,
+            ]),
+            handshake(&[
+                ("connection", "keep-alive"),
+                ("connection", "Upgrade"),
+                ("upgrade", "h2c, websocket"),
+                VERSION,
+                KEY,
+            ]),
+        ];
+        for headers in accepted {
+            assert!(is_websocket_upgrade(&headers));
+            assert_eq!(
+                validate_websocket_upgrade(&headers),
+                Ok("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn upgrade_rejects_incomplete_handshakes() {
+        let rejected = [
+            (
+                handshake(&[("upgrade", "websocket"), VERSION, KEY]),
+                UpgradeError::MissingConnectionUpgrade,
+            ),
+            (
+                handshake(&[
+                    ("connection", "keep-alive"),
+                    ("upgrade", "websocket"),
+                    VERSION,
+                    KEY,
+                ]),
+                UpgradeError::MissingConnectionUpgrade,
+            ),
+            (
+                handshake(&[("connection", "Upgrade"), ("upgrade", "h2c"), VERSION, KEY]),
+                UpgradeError::MissingUpgradeWebsocket,
+            ),
+            (
+                handshake(&[("connection", "Upgrade"), ("upgrade", "websocket"), KEY]),
+                UpgradeError::UnsupportedVersion,
+            ),
+            (
+                handshake(&[
+                    ("connection", "Upgrade"),
+                    ("upgrade", "websocket"),
+                    ("sec-websocket-version", "8"),
+                    KEY,
+                ]),
+                UpgradeError::UnsupportedVersion,
+            ),
+            (
+                handshake(&[("connection", "Upgrade"), ("upgrade", "websocket"), VERSION]),
+                UpgradeError::MissingKey,
+            ),
+            (
+                handshake(&[
+                    ("connection", "Upgrade"),
+                    ("upgrade", "websocket"),
+                    VERSION,
+                    ("sec-websocket-key", "too-short"),
+                ]),
+                UpgradeError::InvalidKey,
+            ),
+        ];
+        for (headers, error) in rejected {
+            assert_eq!(validate_websocket_upgrade(&headers), Err(error));
+        }
+        assert_eq!(
+            IncomingRequest::Empty.validate_upgrade(),
+            Err(UpgradeError::RequestConsumed)
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Value(&'static str);
+
+    fn request_with_shared() -> ServiceRequest {
+        let mut shared = Extensions::new();
+        shared.insert(Value("shared"));
+        let mut request = Request::new(Full::new(Bytes::new()));
+        request
+            .extensions_mut()
+            .insert(SharedState(Arc::new(shared)));
+        ServiceRequest {
+            request: IncomingRequest::Sized(request),
+            path: Arc::new(Route::new("/".to_string())),
+        }
+    }
+
+    #[test]
+    fn request_values_win_over_shared_state() {
+        let mut request = request_with_shared();
+        assert_eq!(request.get::<Value>(), Some(&Value("shared")));
+        assert!(request.get_mut::<Value>().is_none());
+
+        request.insert(Value("request"));
+        assert_eq!(request.get::<Value>(), Some(&Value("request")));
+        request.get_mut::<Value>().unwrap().0 = "changed";
+        assert_eq!(request.get::<Value>(), Some(&Value("changed")));
+
+        assert_eq!(request.remove::<Value>(), Some(Value("changed")));
+        assert_eq!(request.get::<Value>(), Some(&Value("shared")));
+        assert_eq!(request.remove::<Value>(), None);
+    }
+}
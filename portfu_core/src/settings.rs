@@ -0,0 +1,676 @@
+use crate::editable::EditResult;
+use crate::profile::Profile;
+use crate::reload::{ReloadFn, ReloadReport};
+use crate::service::{Service, ServiceBuilder};
+use crate::{IntoStreamBody, ServiceData, ServiceHandler};
+use arc_swap::ArcSwap;
+use http::header::CONTENT_TYPE;
+use http::{HeaderValue, Method, StatusCode};
+use hyper::body::Bytes;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::watch;
+
+pub type SettingValidator = Arc<dyn Fn(&Value) -> Result<(), String> + Send + Sync>;
+
+#[derive(Default)]
+struct Schema {
+    kinds: HashMap<String, &'static str>,
+    validators: HashMap<String, SettingValidator>,
+}
+impl Schema {
+    fn check(&self, key: &str, value: &Value) -> Result<(), String> {
+        if let Some(kind) = self.kinds.get(key) {
+            if !value.is_null() && !kind_accepts(kind, value) {
+                return Err(format!("expected {kind}, found {}", schema_kind(value)));
+            }
+        }
+        match self.validators.get(key) {
+            Some(validator) => validator(value),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where the effective value of a setting came from, later sources take precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingSource {
+    Default,
+    ProfileDefault,
+    File,
+    ProfileOverride,
+    Env,
+    /// Updated while running, through the admin endpoints or Settings::update
+    Runtime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingReport {
+    pub key: String,
+    /// `"***"` for secrets
+    pub value: Value,
+    pub source: SettingSource,
+}
+
+/// Settings whose key contains one of these are redacted in reports
+const SECRET_MARKERS: [&str; 5] = ["secret", "password", "token", "credential", "private"];
+/// Key of the per profile sections in a settings file, `{"profiles": {"prod": {...}}}`
+pub const PROFILES_KEY: &str = "profiles";
+
+struct SettingsInner {
+    values: ArcSwap<HashMap<String, Value>>,
+    schema: RwLock<Schema>,
+    version: AtomicU64,
+    changes: watch::Sender<u64>,
+    profile: Profile,
+    sources: RwLock<HashMap<String, SettingSource>>,
+    profile_defaults: RwLock<HashMap<String, Value>>,
+    secrets: RwLock<HashSet<String>>,
+}
+
+/// Runtime adjustable settings stored as a JSON document of key -> value.
+/// Register it as shared state so admin endpoints and handlers share the same document,
+/// clones share the same values. Each value records its SettingSource, a source never
+/// replaces a value set by a later one.
+#[derive(Clone)]
+pub struct Settings {
+    inner: Arc<SettingsInner>,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Settings {
+    /// Settings for the profile selected by PROFILE_ENV
+    pub fn new() -> Self {
+        Self::with_profile(Profile::current().clone())
+    }
+    pub fn with_profile(profile: Profile) -> Self {
+        let (changes, _) = watch::channel(0);
+        Self {
+            inner: Arc::new(SettingsInner {
+                values: ArcSwap::from_pointee(HashMap::new()),
+                schema: RwLock::new(Schema::default()),
+                version: AtomicU64::new(0),
+                changes,
+                profile,
+                sources: Default::default(),
+                profile_defaults: Default::default(),
+                secrets: Default::default(),
+            }),
+        }
+    }
+    pub fn profile(&self) -> &Profile {
+        &self.inner.profile
+    }
+    /// Sets `default` when `key` has no value yet, updates to `key` must keep the type of `default`.
+    /// A profile_default for the active profile is used in place of `default`.
+    pub fn define<S: AsRef<str>, T: Serialize>(&self, key: S, default: T) -> &Self {
+        let key = key.as_ref().to_string();
+        let default = serde_json::to_value(default).unwrap_or(Value::Null);
+        if !default.is_null() {
+            self.inner
+                .schema
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .kinds
+                .insert(key.clone(), schema_kind(&default));
+        }
+        let profile_default = self
+            .inner
+            .profile_defaults
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+            .cloned();
+        let (default, source) = match profile_default {
+            Some(value) => (value, SettingSource::ProfileDefault),
+            None => (default, SettingSource::Default),
+        };
+        let mut inserted = false;
+        self.inner.values.rcu(|values| {
+            let mut values = values.as_ref().clone();
+            inserted = !values.contains_key(&key);
+            values.entry(key.clone()).or_insert_with(|| default.clone());
+            values
+        });
+        if inserted {
+            self.sources().insert(key, source);
+        }
+        self
+    }
+    /// Default for `key` used only when running as `profile`, replaces the plain default
+    /// but never a value from a file, the environment or an update
+    pub fn profile_default<S: AsRef<str>, T: Serialize>(
+        &self,
+        key: S,
+        profile: Profile,
+        value: T,
+    ) -> &Self {
+        if profile != self.inner.profile {
+            return self;
+        }
+        let key = key.as_ref().to_string();
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.inner
+            .profile_defaults
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.clone(), value.clone());
+        let mut sources = self.sources();
+        if sources
+            .get(&key)
+            .map(|source| *source <= SettingSource::ProfileDefault)
+            .unwrap_or(true)
+        {
+            self.inner.values.rcu(|values| {
+                let mut values = values.as_ref().clone();
+                values.insert(key.clone(), value.clone());
+                values
+            });
+            sources.insert(key, SettingSource::ProfileDefault);
+        }
+        self
+    }
+    /// Redacts `key` in reports, keys containing secret, password, token, credential or private always are
+    pub fn secret<S: AsRef<str>>(&self, key: S) -> &Self {
+        self.inner
+            .secrets
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.as_ref().to_string());
+        self
+    }
+    fn sources(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, SettingSource>> {
+        self.inner
+            .sources
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+    fn is_secret(&self, key: &str) -> bool {
+        let lower = key.to_ascii_lowercase();
+        SECRET_MARKERS.iter().any(|marker| lower.contains(marker))
+            || self
+                .inner
+                .secrets
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .contains(key)
+    }
+    /// Every setting with its effective value and source, sorted by key
+    pub fn report(&self) -> Vec<SettingReport> {
+        let values = self.snapshot();
+        let sources = self.inner.sources.read().unwrap_or_else(|e| e.into_inner());
+        let mut report: Vec<SettingReport> = values
+            .iter()
+            .map(|(key, value)| SettingReport {
+                key: key.clone(),
+                value: if self.is_secret(key) {
+                    Value::String("***".to_string())
+                } else {
+                    value.clone()
+                },
+                source: sources.get(key).copied().unwrap_or(SettingSource::Runtime),
+            })
+            .collect();
+        report.sort_by(|a, b| a.key.cmp(&b.key));
+        report
+    }
+    /// Logs the report at info level, called by ServerBuilder::build for Settings in shared state
+    pub fn log_report(&self) {
+        info!("Settings for profile {}", self.inner.profile);
+        for setting in self.report() {
+            info!(
+                "  {} = {} ({:?})",
+                setting.key, setting.value, setting.source
+            );
+        }
+    }
+    /// Applies `{prefix}{KEY}` environment variables to every defined key, KEY is the key
+    /// upper cased with `.` and `-` replaced by `_`. Values are parsed as JSON, falling back to a string.
+    pub fn apply_env<S: AsRef<str>>(&self, prefix: S) -> Result<(), HashMap<String, String>> {
+        let keys: Vec<String> = self
+            .inner
+            .schema
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .kinds
+            .keys()
+            .cloned()
+            .collect();
+        let updates: HashMap<String, Value> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let name = format!(
+                    "{}{}",
+                    prefix.as_ref(),
+                    key.to_ascii_uppercase().replace(['.', '-'], "_")
+                );
+                let raw = std::env::var(name).ok()?;
+                let value = serde_json::from_str(&raw).unwrap_or(Value::String(raw));
+                Some((key, value))
+            })
+            .collect();
+        if updates.is_empty() {
+            return Ok(());
+        }
+        self.update_from(updates, SettingSource::Env)
+    }
+    /// Adds a check run against every update to `key`
+    pub fn validate<S: AsRef<str>, F>(&self, key: S, validator: F) -> &Self
+    where
+        F: Fn(&Value) -> Result<(), String> + Send + Sync + 'static,
+    {
+        let key = key.as_ref().to_string();
+        let mut schema = self.inner.schema.write().unwrap_or_else(|e| e.into_inner());
+        let validator: SettingValidator = match schema.validators.remove(&key) {
+            Some(existing) => Arc::new(move |value| {
+                existing(value)?;
+                validator(value)
+            }),
+            None => Arc::new(validator),
+        };
+        schema.validators.insert(key, validator);
+        self
+    }
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.inner
+            .values
+            .load()
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.inner.values.load().get(key).and_then(Value::as_u64)
+    }
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.inner.values.load().get(key).and_then(Value::as_bool)
+    }
+    pub fn get_str(&self, key: &str) -> Option<String> {
+        self.inner
+            .values
+            .load()
+            .get(key)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    }
+    /// Incremented on every successful update
+    pub fn version(&self) -> u64 {
+        self.inner.version.load(Ordering::Acquire)
+    }
+    pub fn snapshot(&self) -> Arc<HashMap<String, Value>> {
+        self.inner.values.load_full()
+    }
+    /// Receives the new version after every successful update
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.inner.changes.subscribe()
+    }
+    /// Merges `updates` into the current document atomically, a null value removes the key.
+    /// Nothing is changed when any value fails validation, the failures are returned instead.
+    pub fn update(&self, updates: HashMap<String, Value>) -> Result<(), HashMap<String, String>> {
+        self.update_from(updates, SettingSource::Runtime)
+    }
+    /// Like update, keys whose value came from a later source than `source` are left as they are
+    pub fn update_from(
+        &self,
+        updates: HashMap<String, Value>,
+        source: SettingSource,
+    ) -> Result<(), HashMap<String, String>> {
+        self.update_layers(vec![(updates, source)]).map(|_| ())
+    }
+    /// Applies each layer in order as one update, nothing is changed when any layer fails validation.
+    /// Returns the keys left as they are because a later source set them.
+    pub fn update_layers(
+        &self,
+        layers: Vec<(HashMap<String, Value>, SettingSource)>,
+    ) -> Result<Vec<String>, HashMap<String, String>> {
+        {
+            let schema = self.inner.schema.read().unwrap_or_else(|e| e.into_inner());
+            let invalid: HashMap<String, String> = layers
+                .iter()
+                .flat_map(|(updates, _)| updates.iter())
+                .filter_map(|(key, value)| schema.check(key, value).err().map(|e| (key.clone(), e)))
+                .collect();
+            if !invalid.is_empty() {
+                return Err(invalid);
+            }
+        }
+        let mut sources = self.sources();
+        let mut updates = vec![];
+        let mut skipped = vec![];
+        for (layer, source) in layers {
+            for (key, value) in layer {
+                if sources.get(&key).map(|s| *s > source).unwrap_or_default() {
+                    skipped.push(key);
+                    continue;
+                }
+                if value.is_null() {
+                    sources.remove(&key);
+                } else {
+                    sources.insert(key.clone(), source);
+                }
+                updates.push((key, value));
+            }
+        }
+        self.inner.values.rcu(|values| {
+            let mut values = values.as_ref().clone();
+            for (key, value) in updates.iter() {
+                if value.is_null() {
+                    values.remove(key);
+                } else {
+                    values.insert(key.clone(), value.clone());
+                }
+            }
+            values
+        });
+        drop(sources);
+        let version = self.inner.version.fetch_add(1, Ordering::AcqRel) + 1;
+        self.inner.changes.send_replace(version);
+        skipped.sort();
+        skipped.dedup();
+        Ok(skipped)
+    }
+    /// Applies a JSON object document, see [`Settings::update`]
+    pub fn update_json(&self, document: &[u8]) -> Result<(), HashMap<String, String>> {
+        let updates: HashMap<String, Value> = serde_json::from_slice(document).map_err(|e| {
+            HashMap::from([(String::new(), format!("Invalid settings document: {e}"))])
+        })?;
+        self.update(updates)
+    }
+    /// Typed handle to `key` that only deserializes again after the settings change
+    pub fn setting<S: AsRef<str>, T>(&self, key: S, default: T) -> Setting<T>
+    where
+        T: Serialize + DeserializeOwned + Clone + 'static,
+    {
+        self.define(key.as_ref(), &default);
+        //Catches what the JSON kind cannot, like 70000 for a u16
+        self.validate(key.as_ref(), |value| match value {
+            Value::Null => Ok(()),
+            value => serde_json::from_value::<T>(value.clone())
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        });
+        Setting {
+            settings: self.clone(),
+            key: key.as_ref().to_string(),
+            default: default.clone(),
+            cached: ArcSwap::from_pointee((u64::MAX, default)),
+        }
+    }
+    /// An editable service at `path` serving the settings as a JSON document
+    pub fn service<S: AsRef<str>>(&self, path: S) -> Service {
+        ServiceBuilder::new(path.as_ref())
+            .name("settings")
+            .method(Method::GET)
+            .handler(Arc::new(SettingsService {
+                settings: self.clone(),
+            }))
+            .build()
+    }
+    /// ReloadFn merging the JSON document at `path` into the settings, the section under
+    /// PROFILES_KEY for the active profile is applied on top as ProfileOverride
+    pub fn reloader<P: AsRef<Path>>(&self, path: P) -> Arc<dyn ReloadFn + Send + Sync> {
+        Arc::new(SettingsFile {
+            settings: self.clone(),
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+struct SettingsFile {
+    settings: Settings,
+    path: PathBuf,
+}
+#[async_trait::async_trait]
+impl ReloadFn for SettingsFile {
+    fn name(&self) -> &str {
+        "settings"
+    }
+    async fn reload(&self) -> Result<ReloadReport, Error> {
+        let document = tokio::fs::read(&self.path).await?;
+        let mut updates: HashMap<String, Value> =
+            serde_json::from_slice(&document).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid settings document: {e}"),
+                )
+            })?;
+        let overrides: HashMap<String, Value> = match updates.remove(PROFILES_KEY) {
+            Some(Value::Object(mut profiles)) => {
+                match profiles.remove(self.settings.profile().name()) {
+                    Some(Value::Object(overrides)) => overrides.into_iter().collect(),
+                    _ => HashMap::new(),
+                }
+            }
+            _ => HashMap::new(),
+        };
+        let current = self.settings.snapshot();
+        let mut changed: Vec<String> = updates
+            .iter()
+            .chain(overrides.iter())
+            .filter(|(key, value)| current.get(*key) != Some(*value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.sort();
+        changed.dedup();
+        let invalid = |invalid: HashMap<String, String>| {
+            let mut invalid: Vec<String> = invalid
+                .into_iter()
+                .map(|(key, e)| format!("{key}: {e}"))
+                .collect();
+            invalid.sort();
+            Error::new(ErrorKind::InvalidInput, invalid.join(", "))
+        };
+        let skipped = self
+            .settings
+            .update_layers(vec![
+                (updates, SettingSource::File),
+                (overrides, SettingSource::ProfileOverride),
+            ])
+            .map_err(invalid)?;
+        let (skipped, applied): (Vec<String>, Vec<String>) =
+            changed.into_iter().partition(|key| skipped.contains(key));
+        if !skipped.is_empty() {
+            warn!(
+                "Settings file changes to {} are overridden by a later source",
+                skipped.join(", ")
+            );
+        }
+        Ok(ReloadReport {
+            applied: applied.iter().map(|key| format!("setting {key}")).collect(),
+            skipped: skipped.iter().map(|key| format!("setting {key}")).collect(),
+            ..Default::default()
+        })
+    }
+}
+
+pub struct Setting<T> {
+    settings: Settings,
+    key: String,
+    default: T,
+    cached: ArcSwap<(u64, T)>,
+}
+impl<T: DeserializeOwned + Clone> Setting<T> {
+    pub fn get(&self) -> T {
+        let version = self.settings.version();
+        let cached = self.cached.load();
+        if cached.0 == version {
+            return cached.1.clone();
+        }
+        let value = self
+            .settings
+            .get(&self.key)
+            .unwrap_or_else(|| self.default.clone());
+        self.cached.store(Arc::new((version, value.clone())));
+        value
+    }
+}
+
+/// json_kind with numbers split by the widest type they fit, so an integer setting rejects 1.5
+fn schema_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Number(n) if n.is_u64() => "unsigned integer",
+        Value::Number(n) if n.is_i64() => "integer",
+        value => json_kind(value),
+    }
+}
+fn kind_accepts(kind: &str, value: &Value) -> bool {
+    match (kind, value) {
+        ("number", Value::Number(_)) => true,
+        ("integer", Value::Number(n)) => n.is_i64(),
+        ("unsigned integer", Value::Number(n)) => n.is_u64(),
+        (kind, value) => kind == json_kind(value),
+    }
+}
+fn json_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+pub struct SettingsService {
+    settings: Settings,
+}
+impl SettingsService {
+    fn document(&self) -> Result<Vec<u8>, String> {
+        serde_json::to_vec_pretty(self.settings.snapshot().as_ref()).map_err(|e| format!("{e:?}"))
+    }
+}
+#[async_trait::async_trait]
+impl ServiceHandler for SettingsService {
+    fn name(&self) -> &str {
+        "settings"
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        match self.document() {
+            Ok(document) => {
+                data.response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                *data.response.body_mut() = document.stream_body();
+            }
+            Err(e) => {
+                *data.response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                *data.response.body_mut() = Bytes::from(e).stream_body();
+            }
+        }
+        Ok(data)
+    }
+    fn is_editable(&self) -> bool {
+        true
+    }
+    async fn current_value(&self) -> EditResult {
+        match self.document() {
+            Ok(document) => EditResult::Success(document),
+            Err(e) => EditResult::Failed(e),
+        }
+    }
+    async fn update_value(&self, new_value: Vec<u8>, current_value: Option<Vec<u8>>) -> EditResult {
+        if let Some(to_match) = current_value {
+            let expected: Result<HashMap<String, Value>, _> = serde_json::from_slice(&to_match);
+            if expected.ok().as_ref() != Some(self.settings.snapshot().as_ref()) {
+                return EditResult::Failed(
+                    "Expected Current Value does not match. Settings have been updated."
+                        .to_string(),
+                );
+            }
+        }
+        match self.settings.update_json(&new_value) {
+            Ok(_) => self.current_value().await,
+            Err(invalid) => EditResult::Invalid(
+                serde_json::to_string(&invalid).unwrap_or_else(|e| format!("{e:?}")),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn update(settings: &Settings, key: &str, value: Value) -> Result<(), HashMap<String, String>> {
+        settings.update(HashMap::from([(key.to_string(), value)]))
+    }
+
+    #[test]
+    fn numbers_keep_their_kind() {
+        let settings = Settings::with_profile(Profile::Dev);
+        let port = settings.setting("port", 8080u16);
+        settings.define("offset", -5i64).define("ratio", 0.5f64);
+        assert!(update(&settings, "port", json!(1.5)).is_err());
+        assert!(update(&settings, "port", json!(-1)).is_err());
+        assert!(update(&settings, "port", json!(70000)).is_err());
+        assert!(update(&settings, "offset", json!(2.5)).is_err());
+        assert!(update(&settings, "ratio", json!("high")).is_err());
+        update(&settings, "port", json!(9090)).unwrap();
+        update(&settings, "offset", json!(12)).unwrap();
+        update(&settings, "ratio", json!(2)).unwrap();
+        assert_eq!(port.get(), 9090);
+        assert_eq!(settings.get::<i64>("offset"), Some(12));
+    }
+
+    /// Reloads `document` through a settings file reloader
+    async fn reload(settings: &Settings, document: &str) -> Result<ReloadReport, Error> {
+        let path = std::env::temp_dir().join(format!("pf_settings_{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, document).unwrap();
+        let report = settings.reloader(&path).reload().await;
+        let _ = std::fs::remove_file(&path);
+        report
+    }
+
+    #[tokio::test]
+    async fn reload_reports_keys_overridden_at_runtime() {
+        let settings = Settings::with_profile(Profile::Dev);
+        let report = reload(&settings, r#"{"a": 1, "b": 1}"#).await.unwrap();
+        assert_eq!(report.applied, vec!["setting a", "setting b"]);
+        update(&settings, "a", json!(5)).unwrap();
+
+        let report = reload(&settings, r#"{"a": 2, "b": 2}"#).await.unwrap();
+        assert_eq!(report.applied, vec!["setting b"]);
+        assert_eq!(report.skipped, vec!["setting a"]);
+        assert_eq!(settings.get::<u64>("a"), Some(5));
+        assert_eq!(settings.get::<u64>("b"), Some(2));
+    }
+
+    #[tokio::test]
+    async fn reload_applies_the_file_and_profile_section_together() {
+        let settings = Settings::with_profile(Profile::Dev);
+        settings.define("limit", 1u64);
+        let version = settings.version();
+        let invalid = r#"{"a": 1, "profiles": {"dev": {"limit": "high"}}}"#;
+        assert!(reload(&settings, invalid).await.is_err());
+        assert_eq!(settings.get::<u64>("a"), None);
+        assert_eq!(settings.version(), version);
+
+        let valid = r#"{"a": 1, "limit": 2, "profiles": {"dev": {"limit": 3}}}"#;
+        reload(&settings, valid).await.unwrap();
+        assert_eq!(settings.get::<u64>("a"), Some(1));
+        assert_eq!(settings.get::<u64>("limit"), Some(3));
+        assert_eq!(settings.version(), version + 1);
+        let sources: HashMap<String, SettingSource> = settings
+            .report()
+            .into_iter()
+            .map(|report| (report.key, report.source))
+            .collect();
+        assert_eq!(sources["a"], SettingSource::File);
+        assert_eq!(sources["limit"], SettingSource::ProfileOverride);
+    }
+}
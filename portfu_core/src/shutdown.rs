@@ -0,0 +1,128 @@
+use log::info;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::io::Error;
+use std::time::Duration;
+
+/// Process exit codes for each ShutdownReason, taken from sysexits.h
+pub const EXIT_OK: i32 = 0;
+/// EX_CONFIG, the configuration or the task graph is invalid
+pub const EXIT_CONFIG: i32 = 78;
+/// EX_UNAVAILABLE, an address could not be resolved or bound
+pub const EXIT_BIND: i32 = 69;
+/// EX_NOINPUT, the certificates could not be loaded
+pub const EXIT_TLS: i32 = 66;
+/// EX_SOFTWARE, anything else
+pub const EXIT_FATAL: i32 = 70;
+
+/// The step of Server::run an error came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StartupStage {
+    Config,
+    Bind,
+    Tls,
+}
+
+/// Carried inside the io::Error of a failed startup step so ShutdownReason can classify it
+#[derive(Debug)]
+pub struct StartupError {
+    pub stage: StartupStage,
+    pub source: Error,
+}
+impl Display for StartupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} error: {}", self.stage, self.source)
+    }
+}
+impl std::error::Error for StartupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// For `map_err`, tags an error with the startup step it came from
+pub fn failed_at(stage: StartupStage) -> impl FnOnce(Error) -> Error {
+    move |source| Error::new(source.kind(), StartupError { stage, source })
+}
+
+/// Counts logged when the server stops
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShutdownSummary {
+    pub uptime: Duration,
+    pub requests_served: usize,
+    pub drained_connections: usize,
+    pub aborted_connections: usize,
+    pub tasks_completed: usize,
+    pub tasks_aborted: usize,
+}
+impl ShutdownSummary {
+    pub fn log(&self) {
+        info!(
+            "Shutdown after {:?}: {} requests served, {} connections drained, {} aborted, {} tasks completed, {} aborted",
+            self.uptime,
+            self.requests_served,
+            self.drained_connections,
+            self.aborted_connections,
+            self.tasks_completed,
+            self.tasks_aborted
+        );
+    }
+}
+
+/// Why Server::serve returned, `exit_code` maps it to a process exit code for supervisors
+#[derive(Debug)]
+pub enum ShutdownReason {
+    Ok {
+        drained_connections: usize,
+        duration: Duration,
+    },
+    ConfigError(Error),
+    BindError(Error),
+    TlsError(Error),
+    Fatal {
+        source: Error,
+    },
+}
+impl ShutdownReason {
+    pub fn clean(summary: &ShutdownSummary) -> Self {
+        Self::Ok {
+            drained_connections: summary.drained_connections,
+            duration: summary.uptime,
+        }
+    }
+    /// Errors tagged with `failed_at` keep their stage, every other error is Fatal
+    pub fn from_error(error: Error) -> Self {
+        if !error.get_ref().is_some_and(|e| e.is::<StartupError>()) {
+            return Self::Fatal { source: error };
+        }
+        match error.into_inner().map(|e| e.downcast::<StartupError>()) {
+            Some(Ok(startup)) => match startup.stage {
+                StartupStage::Config => Self::ConfigError(startup.source),
+                StartupStage::Bind => Self::BindError(startup.source),
+                StartupStage::Tls => Self::TlsError(startup.source),
+            },
+            Some(Err(e)) => Self::Fatal {
+                source: Error::other(e),
+            },
+            None => Self::Fatal {
+                source: Error::other("Startup failed"),
+            },
+        }
+    }
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Ok { .. } => EXIT_OK,
+            Self::ConfigError(_) => EXIT_CONFIG,
+            Self::BindError(_) => EXIT_BIND,
+            Self::TlsError(_) => EXIT_TLS,
+            Self::Fatal { .. } => EXIT_FATAL,
+        }
+    }
+    pub fn into_result(self) -> Result<(), Error> {
+        match self {
+            Self::Ok { .. } => Ok(()),
+            Self::ConfigError(e) | Self::BindError(e) | Self::TlsError(e) => Err(e),
+            Self::Fatal { source } => Err(source),
+        }
+    }
+}
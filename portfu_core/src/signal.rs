@@ -11,17 +11,32 @@ pub async fn await_termination() -> Result<(), Error> {
     let mut int_signal = signal(SignalKind::interrupt())?;
     let mut quit_signal = signal(SignalKind::quit())?;
     let mut alarm_signal = signal(SignalKind::alarm())?;
-    let mut hup_signal = signal(SignalKind::hangup())?;
     select! {
         _ = term_signal.recv() => (),
         _ = int_signal.recv() => (),
         _ = quit_signal.recv() => (),
-        _ = alarm_signal.recv() => (),
-        _ = hup_signal.recv() => ()
+        _ = alarm_signal.recv() => ()
     }
     Ok(())
 }
 
+/// Calls `on_hangup` for every SIGHUP received, runs until the future is dropped
+#[cfg(not(target_os = "windows"))]
+pub async fn listen_for_hangups<F: Fn()>(on_hangup: F) -> Result<(), Error> {
+    let mut hup_signal = signal(SignalKind::hangup())?;
+    while hup_signal.recv().await.is_some() {
+        on_hangup();
+    }
+    Ok(())
+}
+
+/// Windows has no SIGHUP, reloads are requested through Server::request_reload instead
+#[cfg(target_os = "windows")]
+pub async fn listen_for_hangups<F: Fn()>(_: F) -> Result<(), Error> {
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
 #[cfg(target_os = "windows")]
 pub async fn await_termination() -> Result<(), Error> {
     let mut ctrl_break_signal = ctrl_break()?;
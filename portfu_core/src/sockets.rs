@@ -1,22 +1,685 @@
 use futures_util::future::lazy;
-use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::{unfold, SplitSink, SplitStream};
+use futures_util::{SinkExt, Stream, StreamExt};
+use http::header::{AUTHORIZATION, COOKIE, HOST, ORIGIN};
+use http::HeaderMap;
 use hyper::upgrade::Upgraded;
 use hyper_util::rt::TokioIo;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::Poll;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch, RwLock};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Error as WsError;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
-pub type Peers = Arc<RwLock<HashMap<Uuid, Arc<WebsocketConnection>>>>;
+const PEER_EVENT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllowedOrigin {
+    Exact(String),
+    Subdomains(String),
+    SameHost,
+}
+impl AllowedOrigin {
+    /// Parses `same` as SameHost, `https://*.example.com` as any subdomain of example.com and anything else as an exact origin
+    pub fn parse(origin: &str) -> Self {
+        let origin = origin.trim().trim_end_matches('/');
+        if origin.eq_ignore_ascii_case("same") {
+            AllowedOrigin::SameHost
+        } else if let Some((scheme, domain)) = origin.split_once("://*.") {
+            AllowedOrigin::Subdomains(format!("{scheme}://.{domain}").to_ascii_lowercase())
+        } else {
+            AllowedOrigin::Exact(origin.to_ascii_lowercase())
+        }
+    }
+    fn matches(&self, origin: &str, headers: &HeaderMap, trusted_proxy: bool) -> bool {
+        match self {
+            AllowedOrigin::Exact(allowed) => origin.eq_ignore_ascii_case(allowed),
+            AllowedOrigin::Subdomains(suffix) => {
+                let origin = origin.to_ascii_lowercase();
+                match suffix.split_once("://") {
+                    Some((scheme, domain)) => origin
+                        .strip_prefix(scheme)
+                        .and_then(|o| o.strip_prefix("://"))
+                        .map(|host| host.ends_with(domain) && host.len() > domain.len())
+                        .unwrap_or_default(),
+                    None => false,
+                }
+            }
+            AllowedOrigin::SameHost => is_same_host(origin, headers, trusted_proxy),
+        }
+    }
+}
+
+/// Which browser origins may open a websocket.
+/// The default enforces same origin only when the request carries cookies, so cookie sessions
+/// cannot be ridden cross site while token authenticated clients without cookies keep working.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OriginPolicy {
+    #[default]
+    SameOriginWithCookies,
+    Any,
+    AllowList(Vec<AllowedOrigin>),
+}
+impl OriginPolicy {
+    pub fn from_origins<S: AsRef<str>>(origins: &[S]) -> Self {
+        if origins.is_empty() {
+            OriginPolicy::SameOriginWithCookies
+        } else if origins.iter().any(|o| o.as_ref().trim() == "*") {
+            OriginPolicy::Any
+        } else {
+            OriginPolicy::AllowList(
+                origins
+                    .iter()
+                    .map(|o| AllowedOrigin::parse(o.as_ref()))
+                    .collect(),
+            )
+        }
+    }
+    /// x-forwarded-host only counts as the request host when `trusted_proxy` is set, see ServerConfig::trusted_proxies
+    pub fn allows(&self, headers: &HeaderMap, trusted_proxy: bool) -> bool {
+        if has_bearer_token(headers) {
+            return true;
+        }
+        let origin = headers.get(ORIGIN).and_then(|o| o.to_str().ok());
+        match self {
+            OriginPolicy::Any => true,
+            OriginPolicy::SameOriginWithCookies => {
+                if !headers.contains_key(COOKIE) {
+                    return true;
+                }
+                match origin {
+                    Some(origin) => is_same_host(origin, headers, trusted_proxy),
+                    None => true,
+                }
+            }
+            OriginPolicy::AllowList(allowed) => match origin {
+                Some(origin) => allowed
+                    .iter()
+                    .any(|a| a.matches(origin, headers, trusted_proxy)),
+                //Non browser clients do not send an Origin
+                None => true,
+            },
+        }
+    }
+}
+
+fn has_bearer_token(headers: &HeaderMap) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.len() > 7 && v[..7].eq_ignore_ascii_case("bearer "))
+        .unwrap_or_default()
+}
+
+/// Set on requests from a peer in ServerConfig::trusted_proxies
+#[derive(Debug, Clone, Copy)]
+pub struct TrustedProxy;
+
+fn is_same_host(origin: &str, headers: &HeaderMap, trusted_proxy: bool) -> bool {
+    let origin_host = match origin.split_once("://") {
+        Some((_, host)) => host.trim_end_matches('/'),
+        None => return false,
+    };
+    let host_headers: &[&str] = if trusted_proxy {
+        &[HOST.as_str(), "x-forwarded-host"]
+    } else {
+        &[HOST.as_str()]
+    };
+    host_headers.iter().any(|header| {
+        headers
+            .get(*header)
+            .and_then(|h| h.to_str().ok())
+            .map(|host| {
+                host.split(',')
+                    .any(|h| h.trim().eq_ignore_ascii_case(origin_host))
+            })
+            .unwrap_or_default()
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    Closed,
+    Error(String),
+    Panicked(String),
+    Shutdown,
+    /// Closed with 1008 after repeatedly exceeding the FloodPolicy
+    Flooding,
+}
+
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Connected {
+        uuid: Uuid,
+        headers: Arc<HeaderMap>,
+        addr: Option<SocketAddr>,
+    },
+    Disconnected {
+        uuid: Uuid,
+        reason: DisconnectReason,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodAction {
+    /// Drops the message and pings the client with a warning
+    Drop,
+    /// Drops messages until the `after`th violation, then closes with 1008 policy violation.
+    /// Violations decay by one per second, so only a sustained flood is closed.
+    Close { after: u32 },
+}
+
+/// Inbound limits per connection, bursts of up to one second of traffic are allowed
+#[derive(Debug, Clone, Copy)]
+pub struct FloodPolicy {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u64,
+    pub action: FloodAction,
+}
+impl Default for FloodPolicy {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 100,
+            bytes_per_sec: 1024 * 1024,
+            action: FloodAction::Close { after: 10 },
+        }
+    }
+}
+impl FloodPolicy {
+    pub fn messages_per_sec(self, messages_per_sec: u32) -> Self {
+        let mut s = self;
+        s.messages_per_sec = messages_per_sec;
+        s
+    }
+    pub fn bytes_per_sec(self, bytes_per_sec: u64) -> Self {
+        let mut s = self;
+        s.bytes_per_sec = bytes_per_sec;
+        s
+    }
+    pub fn action(self, action: FloodAction) -> Self {
+        let mut s = self;
+        s.action = action;
+        s
+    }
+}
+
+/// Protocol limits applied when a connection is upgraded, a message over max_message_size
+/// closes the connection with 1009
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WebSocketOptions {
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    pub accept_unmasked_frames: bool,
+    pub write_buffer_size: usize,
+}
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            max_message_size: Some(64 << 20),
+            max_frame_size: Some(16 << 20),
+            accept_unmasked_frames: false,
+            write_buffer_size: 128 * 1024,
+        }
+    }
+}
+impl WebSocketOptions {
+    pub fn max_message_size(self, max_message_size: Option<usize>) -> Self {
+        let mut s = self;
+        s.max_message_size = max_message_size;
+        s
+    }
+    pub fn max_frame_size(self, max_frame_size: Option<usize>) -> Self {
+        let mut s = self;
+        s.max_frame_size = max_frame_size;
+        s
+    }
+    pub fn accept_unmasked_frames(self, accept_unmasked_frames: bool) -> Self {
+        let mut s = self;
+        s.accept_unmasked_frames = accept_unmasked_frames;
+        s
+    }
+    pub fn write_buffer_size(self, write_buffer_size: usize) -> Self {
+        let mut s = self;
+        s.write_buffer_size = write_buffer_size;
+        s
+    }
+    pub fn config(&self) -> WebSocketConfig {
+        let mut config = WebSocketConfig::default();
+        config.max_message_size = self.max_message_size;
+        config.max_frame_size = self.max_frame_size;
+        config.accept_unmasked_frames = self.accept_unmasked_frames;
+        config.write_buffer_size = self.write_buffer_size;
+        config.max_write_buffer_size = config.max_write_buffer_size.max(self.write_buffer_size);
+        config
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+}
+
+/// Flood counters for every connection sharing a Peers
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FloodStats {
+    pub dropped: u64,
+    pub kicked: u64,
+    /// Reads deferred because the aggregate cap was spent
+    pub throttled: u64,
+}
+
+#[derive(Default)]
+struct FloodCounters {
+    dropped: AtomicU64,
+    kicked: AtomicU64,
+    throttled: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FloodVerdict {
+    Allow,
+    /// `warn` is set for the first drop of a burst, later drops are silent so a flood is not answered in kind
+    Drop {
+        warn: bool,
+    },
+    Kick,
+}
+
+struct FloodState {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    /// One is forgiven for every whole second without a violation
+    violations: u32,
+    last_violation: Instant,
+}
+
+/// Enforces the FloodPolicy for a single connection
+pub struct FloodGuard {
+    policy: Option<FloodPolicy>,
+    state: Mutex<FloodState>,
+    kicked: AtomicBool,
+    aggregate: Option<Arc<Mutex<TokenBucket>>>,
+    counters: Arc<FloodCounters>,
+}
+impl FloodGuard {
+    fn new(
+        policy: Option<FloodPolicy>,
+        aggregate: Option<Arc<Mutex<TokenBucket>>>,
+        counters: Arc<FloodCounters>,
+    ) -> Self {
+        let limits = policy.unwrap_or_default();
+        Self {
+            policy,
+            state: Mutex::new(FloodState {
+                messages: TokenBucket::new(limits.messages_per_sec as f64),
+                bytes: TokenBucket::new(limits.bytes_per_sec as f64),
+                violations: 0,
+                last_violation: Instant::now(),
+            }),
+            kicked: AtomicBool::new(false),
+            aggregate,
+            counters,
+        }
+    }
+    pub fn kicked(&self) -> bool {
+        self.kicked.load(Ordering::Acquire)
+    }
+    /// How long until the aggregate cap has room for another message, None when it has room now
+    fn aggregate_wait(&self) -> Option<Duration> {
+        let mut aggregate = self
+            .aggregate
+            .as_ref()?
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        aggregate.refill();
+        if aggregate.tokens >= 1.0 {
+            return None;
+        }
+        self.counters.throttled.fetch_add(1, Ordering::Relaxed);
+        Some(Duration::from_secs_f64(
+            (1.0 - aggregate.tokens) / aggregate.rate,
+        ))
+    }
+    /// Charges a message that was read to the aggregate cap. Connections reading at once can
+    /// overdraw it, the following reads wait until it is paid back.
+    fn charge_aggregate(&self) {
+        if let Some(aggregate) = &self.aggregate {
+            aggregate.lock().unwrap_or_else(|e| e.into_inner()).tokens -= 1.0;
+        }
+    }
+    fn admit(&self, len: usize) -> FloodVerdict {
+        let policy = match &self.policy {
+            Some(policy) => policy,
+            None => return FloodVerdict::Allow,
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        //Both are checked before either is taken so a rejected message costs nothing
+        state.messages.refill();
+        state.bytes.refill();
+        if state.messages.tokens >= 1.0 && state.bytes.tokens >= len as f64 {
+            state.messages.tokens -= 1.0;
+            state.bytes.tokens -= len as f64;
+            return FloodVerdict::Allow;
+        }
+        let now = Instant::now();
+        let forgiven = now
+            .saturating_duration_since(state.last_violation)
+            .as_secs()
+            .min(u32::MAX as u64) as u32;
+        let previous = state.violations.saturating_sub(forgiven);
+        state.violations = previous.saturating_add(1);
+        state.last_violation = now;
+        match policy.action {
+            FloodAction::Close { after } if state.violations >= after => {
+                self.kicked.store(true, Ordering::Release);
+                self.counters.kicked.fetch_add(1, Ordering::Relaxed);
+                FloodVerdict::Kick
+            }
+            _ => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                FloodVerdict::Drop {
+                    warn: previous == 0,
+                }
+            }
+        }
+    }
+}
+
+/// Backoff between client websocket connection attempts, doubling from `backoff` up to
+/// `max_backoff` with up to half of each delay randomized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub reconnect: bool,
+    /// Consecutive failed attempts before giving up, None retries forever
+    pub max_retries: Option<u32>,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            reconnect: false,
+            max_retries: None,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+impl ReconnectPolicy {
+    pub fn reconnect(self, reconnect: bool) -> Self {
+        let mut s = self;
+        s.reconnect = reconnect;
+        s
+    }
+    pub fn max_retries(self, max_retries: u32) -> Self {
+        let mut s = self;
+        s.max_retries = Some(max_retries);
+        s
+    }
+    pub fn backoff(self, backoff: Duration) -> Self {
+        let mut s = self;
+        s.backoff = backoff;
+        s
+    }
+    pub fn max_backoff(self, max_backoff: Duration) -> Self {
+        let mut s = self;
+        s.max_backoff = max_backoff;
+        s
+    }
+    /// Whether attempt number `attempt` should be made after the connection was lost or failed
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.reconnect && self.max_retries.is_none_or(|max| attempt <= max)
+    }
+    /// The wait before attempt number `attempt`, starting at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .backoff
+            .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+            .min(self.max_backoff);
+        let half = exponential.as_millis() as u64 / 2;
+        let jitter = match half {
+            0 => 0,
+            half => (Uuid::new_v4().as_u128() % half as u128) as u64,
+        };
+        Duration::from_millis(half + jitter)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ClientState {
+    Connecting,
+    Connected,
+    /// Waiting to make attempt number `attempt`
+    Reconnecting {
+        attempt: u32,
+    },
+    Closed,
+}
+
+/// State of a client websocket, clones share it. Register it as shared state to read it from services.
+#[derive(Debug, Clone)]
+pub struct ClientConnection {
+    state: Arc<watch::Sender<ClientState>>,
+    url: Option<String>,
+}
+impl Default for ClientConnection {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(watch::channel(ClientState::Connecting).0),
+            url: None,
+        }
+    }
+}
+impl ClientConnection {
+    /// Connects to `url` instead of the url given to the macro
+    pub fn url<S: AsRef<str>>(self, url: S) -> Self {
+        let mut s = self;
+        s.url = Some(url.as_ref().to_string());
+        s
+    }
+    pub fn url_override(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+    pub fn state(&self) -> ClientState {
+        *self.state.borrow()
+    }
+    pub fn set_state(&self, state: ClientState) {
+        self.state.send_replace(state);
+    }
+    pub fn subscribe(&self) -> watch::Receiver<ClientState> {
+        self.state.subscribe()
+    }
+}
+
+/// The connections for one or more websocket endpoints, share a Peers between endpoints to let them message each other.
+/// Connects and disconnects are published to a bounded broadcast channel, slow listeners miss events rather than blocking connections.
+#[derive(Clone)]
+pub struct Peers {
+    connections: Arc<RwLock<HashMap<Uuid, Arc<WebsocketConnection>>>>,
+    events: broadcast::Sender<PeerEvent>,
+    flood_policy: Option<FloodPolicy>,
+    aggregate: Option<Arc<Mutex<TokenBucket>>>,
+    flood_counters: Arc<FloodCounters>,
+    options: WebSocketOptions,
+    /// The FloodGuard of every connection, kept apart so WebSocket has no field for it
+    guards: Arc<std::sync::RwLock<HashMap<Uuid, Arc<FloodGuard>>>>,
+}
+impl Default for Peers {
+    fn default() -> Self {
+        let (events, _) = broadcast::channel(PEER_EVENT_CAPACITY);
+        Self {
+            connections: Default::default(),
+            events,
+            flood_policy: None,
+            aggregate: None,
+            flood_counters: Default::default(),
+            options: WebSocketOptions::default(),
+            guards: Default::default(),
+        }
+    }
+}
+impl Deref for Peers {
+    type Target = RwLock<HashMap<Uuid, Arc<WebsocketConnection>>>;
+    fn deref(&self) -> &Self::Target {
+        &self.connections
+    }
+}
+impl Peers {
+    /// Limits inbound messages on each connection, unlimited by default
+    pub fn flood_policy(self, flood_policy: FloodPolicy) -> Self {
+        let mut s = self;
+        s.flood_policy = Some(flood_policy);
+        s
+    }
+    pub fn flood(&self) -> Option<FloodPolicy> {
+        self.flood_policy
+    }
+    /// Protocol limits for connections of every endpoint sharing this Peers,
+    /// limits given in the websocket macro take precedence
+    pub fn websocket_options(self, options: WebSocketOptions) -> Self {
+        let mut s = self;
+        s.options = options;
+        s
+    }
+    pub fn options(&self) -> WebSocketOptions {
+        self.options
+    }
+    /// Caps messages per second read across every connection sharing this Peers,
+    /// once spent reads wait so the kernel buffers fill and clients are slowed down
+    pub fn aggregate_messages_per_sec(self, messages_per_sec: u32) -> Self {
+        let mut s = self;
+        s.aggregate = Some(Arc::new(Mutex::new(TokenBucket::new(
+            messages_per_sec.max(1) as f64,
+        ))));
+        s
+    }
+    /// The FloodGuard of a connection added with insert
+    pub fn flood_guard(&self, uuid: &Uuid) -> Option<Arc<FloodGuard>> {
+        self.guards
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(uuid)
+            .cloned()
+    }
+    /// Whether the connection was closed for exceeding its FloodPolicy
+    pub fn kicked(&self, uuid: &Uuid) -> bool {
+        self.flood_guard(uuid).is_some_and(|guard| guard.kicked())
+    }
+    pub fn flood_stats(&self) -> FloodStats {
+        FloodStats {
+            dropped: self.flood_counters.dropped.load(Ordering::Relaxed),
+            kicked: self.flood_counters.kicked.load(Ordering::Relaxed),
+            throttled: self.flood_counters.throttled.load(Ordering::Relaxed),
+        }
+    }
+    pub async fn insert(
+        &self,
+        uuid: Uuid,
+        connection: Arc<WebsocketConnection>,
+        headers: HeaderMap,
+        addr: Option<SocketAddr>,
+    ) {
+        self.insert_with_policy(uuid, connection, headers, addr, self.flood_policy)
+            .await
+    }
+    /// Like insert, limiting the connection with `flood_policy` instead of the Peers policy
+    pub async fn insert_with_policy(
+        &self,
+        uuid: Uuid,
+        connection: Arc<WebsocketConnection>,
+        headers: HeaderMap,
+        addr: Option<SocketAddr>,
+        flood_policy: Option<FloodPolicy>,
+    ) {
+        let guard = FloodGuard::new(
+            flood_policy,
+            self.aggregate.clone(),
+            self.flood_counters.clone(),
+        );
+        self.guards
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(uuid, Arc::new(guard));
+        self.connections.write().await.insert(uuid, connection);
+        let _ = self.events.send(PeerEvent::Connected {
+            uuid,
+            headers: Arc::new(headers),
+            addr,
+        });
+    }
+    pub async fn remove(&self, uuid: &Uuid, reason: DisconnectReason) {
+        self.guards
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(uuid);
+        if self.connections.write().await.remove(uuid).is_some() {
+            let _ = self.events.send(PeerEvent::Disconnected {
+                uuid: *uuid,
+                reason,
+            });
+        }
+    }
+    pub async fn len(&self) -> usize {
+        self.connections.read().await.len()
+    }
+    pub async fn is_empty(&self) -> bool {
+        self.connections.read().await.is_empty()
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+    /// Stream of connect/disconnect events from the time of the call, lagged events are skipped
+    pub fn events(&self) -> impl Stream<Item = PeerEvent> {
+        unfold(self.events.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Peer event listener lagged, skipped {missed} events");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
 
 pub struct WebsocketConnection {
     pub write: RwLock<SplitSink<WebSocketStream<TokioIo<Upgraded>>, Message>>,
     pub read: RwLock<SplitStream<WebSocketStream<TokioIo<Upgraded>>>>,
+    /// Metadata set by the handler, such as the user or room, used to select peers
+    pub tags: RwLock<HashMap<String, String>>,
 }
 impl WebsocketConnection {
     pub fn new(websocket: WebSocketStream<TokioIo<Upgraded>>) -> Self {
@@ -24,8 +687,19 @@ impl WebsocketConnection {
         Self {
             write: RwLock::new(write),
             read: RwLock::new(read),
+            tags: Default::default(),
         }
     }
+    pub async fn tag(&self, key: &str) -> Option<String> {
+        self.tags.read().await.get(key).cloned()
+    }
+    pub async fn close(&self, frame: Option<CloseFrame<'static>>) -> Result<(), Error> {
+        let mut stream = self.write.write().await;
+        stream
+            .send(Message::Close(frame))
+            .await
+            .map_err(|e| Error::other(format!("Failed to Close Websocket: {e:?}")))
+    }
 }
 
 #[derive(Clone)]
@@ -33,30 +707,102 @@ pub struct WebSocket {
     pub connection: Arc<WebsocketConnection>,
     pub uuid: Arc<Uuid>,
     pub peers: Peers,
+    /// Subprotocol agreed on during the handshake
+    pub protocol: Option<Arc<str>>,
 }
 impl WebSocket {
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+    /// Messages over the FloodPolicy are dropped and read as None, while the aggregate cap of
+    /// the Peers is spent this waits for it without reading
     pub async fn next_message(&self) -> Result<Option<Message>, Error> {
-        let mut stream = self.connection.read.write().await;
-        lazy(|ctx| match (*stream).poll_next_unpin(ctx) {
-            Poll::Pending => Ok(None),
-            Poll::Ready(None) => Err(Error::new(ErrorKind::ConnectionAborted, "Stream Closed")),
-            Poll::Ready(Some(v)) => v.map(Some).map_err(|e| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to Read Websocket Message: {e:?}"),
-                )
-            }),
-        })
-        .await
+        let flood = self.peers.flood_guard(&self.uuid);
+        if let Some(flood) = &flood {
+            if flood.kicked() {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Kicked for flooding",
+                ));
+            }
+            if let Some(wait) = flood.aggregate_wait() {
+                tokio::time::sleep(wait).await;
+                return Ok(None);
+            }
+        }
+        let message = {
+            let mut stream = self.connection.read.write().await;
+            lazy(|ctx| match (*stream).poll_next_unpin(ctx) {
+                Poll::Pending => Ok(None),
+                Poll::Ready(None) => Err(Error::new(ErrorKind::ConnectionAborted, "Stream Closed")),
+                Poll::Ready(Some(Err(WsError::Capacity(e)))) => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Websocket Message Too Large: {e}"),
+                )),
+                Poll::Ready(Some(v)) => v
+                    .map(Some)
+                    .map_err(|e| Error::other(format!("Failed to Read Websocket Message: {e:?}"))),
+            })
+            .await
+        };
+        let message = match message {
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                warn!("Closing websocket {}, {e}", self.uuid);
+                let _ = self
+                    .connection
+                    .close(Some(CloseFrame {
+                        code: CloseCode::Size,
+                        reason: "Message too large".into(),
+                    }))
+                    .await;
+                return Err(e);
+            }
+            message => message?,
+        };
+        let (message, flood) = match (message, flood) {
+            (Some(message), Some(flood)) => {
+                flood.charge_aggregate();
+                (message, flood)
+            }
+            (message, _) => return Ok(message),
+        };
+        if let Message::Close(frame) = message {
+            return Ok(Some(Message::Close(frame)));
+        }
+        match flood.admit(message.len()) {
+            FloodVerdict::Allow => Ok(Some(message)),
+            FloodVerdict::Drop { warn } => {
+                if warn {
+                    warn!(
+                        "Dropping messages from websocket {}, over the rate limit",
+                        self.uuid
+                    );
+                    let _ = self.send(Message::Ping(b"rate limited".to_vec())).await;
+                }
+                Ok(None)
+            }
+            FloodVerdict::Kick => {
+                warn!("Closing websocket {}, kicked for flooding", self.uuid);
+                let _ = self
+                    .connection
+                    .close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "Message rate exceeded".into(),
+                    }))
+                    .await;
+                Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Kicked for flooding",
+                ))
+            }
+        }
     }
     pub async fn send(&self, msg: Message) -> Result<(), Error> {
         let mut stream = self.connection.write.write().await;
-        stream.send(msg).await.map_err(|e| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to Send Websocket Message: {e:?}"),
-            )
-        })
+        stream
+            .send(msg)
+            .await
+            .map_err(|e| Error::other(format!("Failed to Send Websocket Message: {e:?}")))
     }
     pub async fn send_to(&self, msg: Message, uuid: Uuid) -> Result<(), Error> {
         match self.peers.read().await.get(&uuid).cloned() {
@@ -66,35 +812,205 @@ impl WebSocket {
             )),
             Some(peer) => {
                 let mut stream = peer.write.write().await;
-                stream.send(msg).await.map_err(|e| {
-                    Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to Send Websocket Message: {e:?}"),
-                    )
-                })
+                stream
+                    .send(msg)
+                    .await
+                    .map_err(|e| Error::other(format!("Failed to Send Websocket Message: {e:?}")))
             }
         }
     }
     pub async fn broadcast(&self, msg: Message) -> Result<(), Error> {
         let mut stream = self.connection.write.write().await;
-        stream.send(msg.clone()).await.map_err(|e| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to Send Websocket Message: {e:?}"),
-            )
-        })?;
+        stream
+            .send(msg.clone())
+            .await
+            .map_err(|e| Error::other(format!("Failed to Send Websocket Message: {e:?}")))?;
         self.broadcast_others(msg).await
     }
+    /// Sends to every peer except this connection
     pub async fn broadcast_others(&self, msg: Message) -> Result<(), Error> {
-        for peer in self.peers.read().await.values().cloned() {
+        self.broadcast_filtered(msg, |uuid, _| uuid != self.uuid.as_ref())
+            .await
+    }
+    /// Sends to every peer, this connection included, for which `predicate` holds given its uuid and tags
+    pub async fn broadcast_filtered<F>(&self, msg: Message, predicate: F) -> Result<(), Error>
+    where
+        F: Fn(&Uuid, &HashMap<String, String>) -> bool,
+    {
+        let peers: Vec<(Uuid, Arc<WebsocketConnection>)> = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .map(|(uuid, peer)| (*uuid, peer.clone()))
+            .collect();
+        for (uuid, peer) in peers {
+            if !predicate(&uuid, &*peer.tags.read().await) {
+                continue;
+            }
             let mut stream = peer.write.write().await;
-            stream.send(msg.clone()).await.map_err(|e| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to Send Websocket Message: {e:?}"),
-                )
-            })?;
+            stream
+                .send(msg.clone())
+                .await
+                .map_err(|e| Error::other(format!("Failed to Send Websocket Message: {e:?}")))?;
         }
         Ok(())
     }
+    pub async fn set_tag<K: AsRef<str>, V: AsRef<str>>(&self, key: K, value: V) {
+        self.connection
+            .tags
+            .write()
+            .await
+            .insert(key.as_ref().to_string(), value.as_ref().to_string());
+    }
+    pub async fn remove_tag(&self, key: &str) -> Option<String> {
+        self.connection.tags.write().await.remove(key)
+    }
+    pub async fn tag(&self, key: &str) -> Option<String> {
+        self.connection.tag(key).await
+    }
+    /// Sends to every peer whose `key` tag is `value`, this connection included
+    pub async fn broadcast_tagged(
+        &self,
+        msg: Message,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        self.broadcast_filtered(msg, |_, tags| tags.get(key).is_some_and(|v| v == value))
+            .await
+    }
+    pub async fn send_json<T: Serialize>(&self, value: &T) -> Result<(), Error> {
+        self.send(json_message(value)?).await
+    }
+    /// Sends to this connection and every peer
+    pub async fn broadcast_json<T: Serialize>(&self, value: &T) -> Result<(), Error> {
+        self.broadcast(json_message(value)?).await
+    }
+    /// Like next_message, decoding text frames as JSON. Control frames and binary frames that
+    /// are not JSON read as None, a closed connection is an error.
+    pub async fn next_json<T: DeserializeOwned>(&self) -> Result<Option<T>, Error> {
+        let payload = match self.next_message().await? {
+            Some(Message::Text(text)) => text.into_bytes(),
+            Some(Message::Binary(bytes)) => {
+                return Ok(serde_json::from_slice(&bytes).ok());
+            }
+            Some(Message::Close(_)) => {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "Websocket Closed"))
+            }
+            _ => return Ok(None),
+        };
+        serde_json::from_slice(&payload).map(Some).map_err(|e| {
+            let snippet: String = String::from_utf8_lossy(&payload)
+                .chars()
+                .take(JSON_SNIPPET_LEN)
+                .collect();
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid JSON message {snippet:?}: {e}"),
+            )
+        })
+    }
+}
+
+/// Characters of an undecodable message included in the error
+const JSON_SNIPPET_LEN: usize = 64;
+
+fn json_message<T: Serialize>(value: &T) -> Result<Message, Error> {
+    serde_json::to_string(value)
+        .map(Message::Text)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn upgrade(origin: &str, host: &str, forwarded_host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers.insert(HOST, HeaderValue::from_str(host).unwrap());
+        headers.insert(COOKIE, HeaderValue::from_static("session=1"));
+        headers.insert(
+            "x-forwarded-host",
+            HeaderValue::from_str(forwarded_host).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn forwarded_host_needs_a_trusted_proxy() {
+        let headers = upgrade("https://evil.example", "internal:8080", "evil.example");
+        let policy = OriginPolicy::default();
+        assert!(!policy.allows(&headers, false));
+        assert!(policy.allows(&headers, true));
+        let same_host = OriginPolicy::from_origins(&["same"]);
+        assert!(!same_host.allows(&headers, false));
+        assert!(same_host.allows(&headers, true));
+    }
+
+    fn guard(action: FloodAction) -> FloodGuard {
+        let policy = FloodPolicy::default()
+            .messages_per_sec(2)
+            .bytes_per_sec(1024)
+            .action(action);
+        FloodGuard::new(Some(policy), None, Default::default())
+    }
+
+    #[test]
+    fn only_the_first_drop_of_a_burst_warns() {
+        let guard = guard(FloodAction::Drop);
+        assert_eq!(guard.admit(1), FloodVerdict::Allow);
+        assert_eq!(guard.admit(1), FloodVerdict::Allow);
+        assert_eq!(guard.admit(1), FloodVerdict::Drop { warn: true });
+        for _ in 0..100 {
+            assert_eq!(guard.admit(1), FloodVerdict::Drop { warn: false });
+        }
+        assert!(!guard.kicked());
+        assert_eq!(guard.counters.dropped.load(Ordering::Relaxed), 101);
+    }
+
+    #[test]
+    fn violations_decay() {
+        let guard = guard(FloodAction::Close { after: 3 });
+        guard.admit(1);
+        guard.admit(1);
+        assert_eq!(guard.admit(1), FloodVerdict::Drop { warn: true });
+        assert_eq!(guard.admit(1), FloodVerdict::Drop { warn: false });
+        //Two seconds forgive both violations
+        guard.state.lock().unwrap().last_violation -= Duration::from_secs(2);
+        assert_eq!(guard.admit(1), FloodVerdict::Drop { warn: true });
+        assert_eq!(guard.admit(1), FloodVerdict::Drop { warn: false });
+        assert_eq!(guard.admit(1), FloodVerdict::Kick);
+        assert!(guard.kicked());
+    }
+
+    #[test]
+    fn byte_limit_rejects_without_spending_messages() {
+        let guard = guard(FloodAction::Drop);
+        assert_eq!(guard.admit(2048), FloodVerdict::Drop { warn: true });
+        assert_eq!(guard.admit(512), FloodVerdict::Allow);
+        assert_eq!(guard.admit(512), FloodVerdict::Allow);
+    }
+
+    #[test]
+    fn aggregate_is_charged_for_read_messages_only() {
+        let aggregate = Arc::new(Mutex::new(TokenBucket::new(1.0)));
+        let guard = FloodGuard::new(None, Some(aggregate), Default::default());
+        for _ in 0..10 {
+            assert_eq!(guard.aggregate_wait(), None);
+        }
+        guard.charge_aggregate();
+        let wait = guard.aggregate_wait().unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        assert_eq!(guard.counters.throttled.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn host_header_matches_without_a_proxy() {
+        let headers = upgrade("https://app.example", "app.example", "other.example");
+        assert!(OriginPolicy::default().allows(&headers, false));
+        let headers = upgrade("https://other.example", "app.example", "other.example");
+        assert!(!OriginPolicy::default().allows(&headers, false));
+    }
 }
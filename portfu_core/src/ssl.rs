@@ -1,6 +1,7 @@
+use crate::secrets::SecretString;
 use crate::server::ServerConfig;
 use log::error;
-use rustls::crypto::aws_lc_rs::sign::RsaSigningKey;
+use rustls::crypto::aws_lc_rs::sign::any_supported_type;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use rustls::server::ResolvesServerCertUsingSni;
 use rustls::sign::CertifiedKey;
@@ -14,17 +15,17 @@ pub fn load_ssl_certs(config: &ServerConfig) -> Result<Arc<rustls::ServerConfig>
     let (certs, key, root_certs) = if let Some(ssl_info) = &config.ssl_config {
         (
             load_certs(ssl_info.certs.as_bytes())?,
-            load_private_key(ssl_info.key.as_bytes())?,
+            load_private_key(ssl_info.key.expose().as_bytes())?,
             load_certs(ssl_info.root_certs.as_bytes())?,
         )
     } else if let (Some(certs), Some(key), Some(root_certs)) = (
         env::var("SSL_CERTS").ok(),
-        env::var("SSL_PRIVATE_KEY").ok(),
+        SecretString::from_env("SSL_PRIVATE_KEY"),
         env::var("SSL_ROOT_CERTS").ok(),
     ) {
         (
             load_certs(certs.as_bytes())?,
-            load_private_key(key.as_bytes())?,
+            load_private_key(key.expose().as_bytes())?,
             load_certs(root_certs.as_bytes())?,
         )
     } else {
@@ -48,12 +49,12 @@ pub fn load_ssl_certs(config: &ServerConfig) -> Result<Arc<rustls::ServerConfig>
 
     let cer_key = CertifiedKey::new(
         certs,
-        Arc::new(RsaSigningKey::new(&key).map_err(|e| {
+        any_supported_type(&key).map_err(|e| {
             Error::new(
                 ErrorKind::InvalidInput,
                 format!("Private Key is not Valid SigningKey: {:?}", e),
             )
-        })?),
+        })?,
     );
     resolver.add(name, cer_key).map_err(|e| {
         Error::new(
@@ -62,11 +63,15 @@ pub fn load_ssl_certs(config: &ServerConfig) -> Result<Arc<rustls::ServerConfig>
         )
     })?;
     let resolver = Arc::new(resolver);
-    Ok(Arc::new(
-        rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_cert_resolver(resolver),
-    ))
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = if config.http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(Arc::new(server_config))
 }
 pub fn load_certs(bytes: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
     let mut reader = BufReader::new(bytes);
@@ -0,0 +1,370 @@
+use crate::data_store::DataStore;
+use crate::filters::{FilterFn, FilterResult};
+use crate::service::ServiceBuilder;
+use crate::ServiceResponse;
+use crate::{IntoStreamBody, ServiceData, ServiceHandler, ServiceRegister, ServiceRegistry};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::header::RETRY_AFTER;
+use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+use hyper::body::{Bytes, Incoming};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StaticBody {
+    Text(String),
+    Base64(String),
+    /// Read once when the response is built
+    File(PathBuf),
+}
+impl StaticBody {
+    pub fn load(&self) -> Result<Bytes, Error> {
+        match self {
+            StaticBody::Text(text) => Ok(Bytes::from(text.clone())),
+            StaticBody::Base64(encoded) => STANDARD.decode(encoded).map(Bytes::from).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("Invalid base64 body: {e}"))
+            }),
+            StaticBody::File(path) => std::fs::read(path).map(Bytes::from),
+        }
+    }
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+/// Status, headers and body of a fixed response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixedResponse {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: StaticBody,
+}
+impl FixedResponse {
+    fn build(&self) -> Result<(StatusCode, HeaderMap, Bytes), Error> {
+        let status = StatusCode::from_u16(self.status)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid status: {e}")))?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid header {name}: {e}"),
+                )
+            })?;
+            let value = HeaderValue::from_str(value).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid value for {name}: {e}"),
+                )
+            })?;
+            headers.append(name, value);
+        }
+        Ok((status, headers, self.body.load()?))
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// A StaticResponse as it appears in configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticResponseConfig {
+    pub name: String,
+    pub path: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub response: FixedResponse,
+}
+
+/// A service answering `path` with a fixed response, registered like any other service.
+/// While disabled it does not match so routing falls through to the services after it.
+pub struct StaticResponse {
+    name: String,
+    path: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    enabled: Arc<AtomicBool>,
+}
+impl StaticResponse {
+    pub fn from_config(config: &StaticResponseConfig) -> Result<Self, Error> {
+        let (status, headers, body) = config.response.build()?;
+        Ok(Self {
+            name: config.name.clone(),
+            path: config.path.clone(),
+            status,
+            headers,
+            body,
+            enabled: Arc::new(AtomicBool::new(config.enabled)),
+        })
+    }
+    pub fn toggle(&self) -> StaticResponseToggle {
+        StaticResponseToggle {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            enabled: self.enabled.clone(),
+        }
+    }
+}
+#[async_trait]
+impl ServiceHandler for StaticResponse {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+        write_fixed(&mut data.response, self.status, &self.headers, &self.body);
+        Ok(data)
+    }
+}
+impl ServiceRegister for StaticResponse {
+    fn register(self, service_registry: &mut ServiceRegistry) {
+        let filter = Arc::new(EnabledFilter(self.enabled.clone()));
+        service_registry.register(
+            ServiceBuilder::new(&self.path.clone())
+                .name(self.name.clone())
+                .filter(filter)
+                .handler(Arc::new(self))
+                .build(),
+        );
+    }
+}
+
+fn write_fixed(
+    response: &mut ServiceResponse,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &Bytes,
+) {
+    *response.status_mut() = status;
+    for (name, value) in headers.iter() {
+        response.headers_mut().append(name, value.clone());
+    }
+    *response.body_mut() = body.clone().stream_body();
+}
+
+struct EnabledFilter(Arc<AtomicBool>);
+#[async_trait]
+impl FilterFn for EnabledFilter {
+    fn name(&self) -> &str {
+        "static_response_enabled"
+    }
+    async fn filter(&self, _: &Request<Incoming>) -> FilterResult {
+        self.0.load(Ordering::Relaxed).into()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StaticResponseStatus {
+    pub name: String,
+    pub path: String,
+    pub enabled: bool,
+}
+
+/// Switches a registered StaticResponse on and off at runtime
+#[derive(Clone)]
+pub struct StaticResponseToggle {
+    name: String,
+    path: String,
+    enabled: Arc<AtomicBool>,
+}
+impl StaticResponseToggle {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+    pub fn status(&self) -> StaticResponseStatus {
+        StaticResponseStatus {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            enabled: self.enabled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// DataStore key of the maintenance switch
+pub const MAINTENANCE_KEY: &str = "maintenance";
+/// DataStore key of the static response flags, an object of name to enabled
+pub const STATIC_RESPONSES_KEY: &str = "static_responses";
+
+/// The toggles of every StaticResponse registered through ServerBuilder::static_response
+#[derive(Clone, Default)]
+pub struct StaticResponses {
+    toggles: Vec<StaticResponseToggle>,
+}
+impl StaticResponses {
+    pub fn push(&mut self, toggle: StaticResponseToggle) {
+        self.toggles.push(toggle);
+    }
+    pub fn get(&self, name: &str) -> Option<&StaticResponseToggle> {
+        self.toggles.iter().find(|t| t.name == name)
+    }
+    pub fn statuses(&self) -> Vec<StaticResponseStatus> {
+        self.toggles
+            .iter()
+            .map(StaticResponseToggle::status)
+            .collect()
+    }
+    /// Applies the flags saved by persist, names no longer registered are ignored
+    pub fn restore(&self, store: &dyn DataStore) -> Result<(), Error> {
+        if let Some(Value::Object(saved)) = store.load(STATIC_RESPONSES_KEY)? {
+            for (name, enabled) in saved.iter() {
+                if let (Some(toggle), Some(enabled)) = (self.get(name), enabled.as_bool()) {
+                    toggle.set_enabled(enabled);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn persist(&self, store: &dyn DataStore) -> Result<(), Error> {
+        let saved = self
+            .toggles
+            .iter()
+            .map(|t| {
+                (
+                    t.name.clone(),
+                    Value::Bool(t.enabled.load(Ordering::Relaxed)),
+                )
+            })
+            .collect();
+        store.store(STATIC_RESPONSES_KEY, Value::Object(saved))
+    }
+}
+
+/// Maintenance mode as it appears in configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Path prefixes still served, defaults to MAINTENANCE_ALLOW
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(flatten)]
+    pub response: FixedResponse,
+}
+
+/// Paths served during maintenance unless the config lists its own
+pub const MAINTENANCE_ALLOW: [&str; 2] = ["/healthz", "/pf_admin/maintenance"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    pub allow: Vec<String>,
+}
+
+/// While enabled every request outside the allowed prefixes gets the maintenance response,
+/// checked before server filters and routing so it overrides every service.
+pub struct Maintenance {
+    enabled: AtomicBool,
+    allow: Vec<String>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+impl Default for Maintenance {
+    fn default() -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from(300));
+        Self {
+            enabled: AtomicBool::new(false),
+            allow: MAINTENANCE_ALLOW.iter().map(|p| p.to_string()).collect(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            body: Bytes::from_static(b"Down for maintenance"),
+        }
+    }
+}
+impl Maintenance {
+    pub fn from_config(config: &MaintenanceConfig) -> Result<Self, Error> {
+        let (status, headers, body) = config.response.build()?;
+        let allow = if config.allow.is_empty() {
+            MAINTENANCE_ALLOW.iter().map(|p| p.to_string()).collect()
+        } else {
+            config.allow.clone()
+        };
+        Ok(Self {
+            enabled: AtomicBool::new(config.enabled),
+            allow,
+            status,
+            headers,
+            body,
+        })
+    }
+    pub fn retry_after(self, retry_after: Duration) -> Self {
+        let mut s = self;
+        s.headers
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+        s
+    }
+    pub fn allow<S: AsRef<str>>(self, prefix: S) -> Self {
+        let mut s = self;
+        s.allow.push(prefix.as_ref().to_string());
+        s
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+    pub fn status(&self) -> MaintenanceStatus {
+        MaintenanceStatus {
+            enabled: self.is_enabled(),
+            allow: self.allow.clone(),
+        }
+    }
+    pub fn restore(&self, store: &dyn DataStore) -> Result<(), Error> {
+        if let Some(enabled) = store.load(MAINTENANCE_KEY)?.and_then(|v| v.as_bool()) {
+            self.set_enabled(enabled);
+        }
+        Ok(())
+    }
+    pub fn persist(&self, store: &dyn DataStore) -> Result<(), Error> {
+        store.store(MAINTENANCE_KEY, Value::Bool(self.is_enabled()))
+    }
+    /// True when maintenance is on and `path` is not allowed through,
+    /// prefixes match whole segments so `/healthz` does not let `/healthzAnything` through
+    pub fn intercepts(&self, path: &str) -> bool {
+        self.is_enabled()
+            && !self.allow.iter().any(|prefix| {
+                path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+                })
+            })
+    }
+    pub fn write_response(&self, response: &mut ServiceResponse) {
+        write_fixed(response, self.status, &self.headers, &self.body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_prefixes_match_whole_segments() {
+        let maintenance = Maintenance::default().allow("/assets/");
+        maintenance.set_enabled(true);
+        assert!(!maintenance.intercepts("/healthz"));
+        assert!(!maintenance.intercepts("/healthz/ready"));
+        assert!(maintenance.intercepts("/healthzAnything"));
+        assert!(!maintenance.intercepts("/pf_admin/maintenance/disable"));
+        assert!(maintenance.intercepts("/pf_admin/maintenanceX"));
+        assert!(!maintenance.intercepts("/assets/app.css"));
+        assert!(maintenance.intercepts("/users"));
+        maintenance.set_enabled(false);
+        assert!(!maintenance.intercepts("/users"));
+    }
+}
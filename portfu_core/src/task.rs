@@ -1,22 +1,93 @@
+use crate::service::ServiceGroup;
 use async_trait::async_trait;
 use http::Extensions;
+use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DependencyWait {
+    Started,
+    Completed,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskDependency {
+    pub name: String,
+    pub wait: DependencyWait,
+}
 
 #[derive(Debug)]
 pub struct Task {
     pub name: String,
     pub task_fn: Arc<dyn TaskFn + Sync + Send>,
+    pub after: Vec<TaskDependency>,
+}
+impl Task {
+    pub fn new<S: AsRef<str>>(name: S, task_fn: Arc<dyn TaskFn + Sync + Send>) -> Self {
+        Self {
+            name: name.as_ref().to_string(),
+            task_fn,
+            after: vec![],
+        }
+    }
+    /// Waits for the named task to complete before starting
+    pub fn after<S: AsRef<str>>(self, name: S) -> Self {
+        self.depends_on(name, DependencyWait::Completed)
+    }
+    /// Waits for the named task to be started before starting, use this for intervals and long running tasks
+    pub fn after_started<S: AsRef<str>>(self, name: S) -> Self {
+        self.depends_on(name, DependencyWait::Started)
+    }
+    pub fn depends_on<S: AsRef<str>>(self, name: S, wait: DependencyWait) -> Self {
+        let mut s = self;
+        s.after.push(TaskDependency {
+            name: name.as_ref().to_string(),
+            wait,
+        });
+        s
+    }
+}
+
+/// Set once the server stops accepting, tasks get ServerConfig::shutdown_timeout to finish.
+/// Found in the shared state passed to TaskFn::run.
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal(pub watch::Receiver<bool>);
+impl ShutdownSignal {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.0.borrow()
+    }
+    /// Resolves when shutdown starts
+    pub async fn wait(&self) {
+        let mut receiver = self.0.clone();
+        let _ = receiver.wait_for(|shutdown| *shutdown).await;
+    }
+    /// Resolves when the server sharing `state` starts shutting down, or on a termination signal
+    /// when there is no server
+    pub async fn wait_in(state: &Extensions) {
+        match state.get::<ShutdownSignal>() {
+            Some(shutdown) => shutdown.wait().await,
+            None => {
+                if crate::signal::await_termination().await.is_err() {
+                    std::future::pending::<()>().await
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
 pub trait TaskFn {
     fn name(&self) -> &str;
     async fn run(&self, state: Arc<Extensions>) -> Result<(), Error>;
+    /// Called with every group the task is added to, innermost first, so tasks registering
+    /// services at runtime can give them the group's filters, wrappers and header policy
+    fn joined_group(&self, _group: &ServiceGroup) {}
 }
 
-impl Debug for (dyn TaskFn + Send + Sync + 'static) {
+impl Debug for dyn TaskFn + Send + Sync + 'static {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.name())
     }
@@ -31,4 +102,147 @@ impl TaskFn for Task {
     async fn run(&self, state: Arc<Extensions>) -> Result<(), Error> {
         self.task_fn.run(state).await
     }
+
+    fn joined_group(&self, group: &ServiceGroup) {
+        self.task_fn.joined_group(group)
+    }
+}
+
+/// Checks task names are unique, every dependency names a registered task and that there are no
+/// cycles, returning the task indexes in an order where dependencies come first.
+pub fn order_tasks(tasks: &[Arc<Task>]) -> Result<Vec<usize>, Error> {
+    let mut indexes: HashMap<&str, usize> = HashMap::with_capacity(tasks.len());
+    for (i, task) in tasks.iter().enumerate() {
+        if indexes.insert(task.name.as_str(), i).is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Task {} is registered more than once", task.name),
+            ));
+        }
+    }
+    for task in tasks {
+        for dependency in &task.after {
+            if !indexes.contains_key(dependency.name.as_str()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Task {} depends on unknown task {}",
+                        task.name, dependency.name
+                    ),
+                ));
+            }
+        }
+    }
+    #[derive(Copy, Clone, PartialEq)]
+    enum Mark {
+        New,
+        Visiting,
+        Done,
+    }
+    fn visit(
+        index: usize,
+        tasks: &[Arc<Task>],
+        indexes: &HashMap<&str, usize>,
+        marks: &mut [Mark],
+        stack: &mut Vec<usize>,
+        order: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        match marks[index] {
+            Mark::Done => return Ok(()),
+            Mark::Visiting => {
+                let start = stack.iter().position(|i| *i == index).unwrap_or_default();
+                let mut members: Vec<&str> = stack[start..]
+                    .iter()
+                    .map(|i| tasks[*i].name.as_str())
+                    .collect();
+                members.push(tasks[index].name.as_str());
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Task dependency cycle: {}", members.join(" -> ")),
+                ));
+            }
+            Mark::New => {}
+        }
+        marks[index] = Mark::Visiting;
+        stack.push(index);
+        for dependency in &tasks[index].after {
+            visit(
+                indexes[dependency.name.as_str()],
+                tasks,
+                indexes,
+                marks,
+                stack,
+                order,
+            )?;
+        }
+        stack.pop();
+        marks[index] = Mark::Done;
+        order.push(index);
+        Ok(())
+    }
+    let mut marks = vec![Mark::New; tasks.len()];
+    let mut order = Vec::with_capacity(tasks.len());
+    for index in 0..tasks.len() {
+        visit(index, tasks, &indexes, &mut marks, &mut vec![], &mut order)?;
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerBuilder;
+    use crate::shutdown::ShutdownReason;
+
+    struct Noop;
+    #[async_trait]
+    impl TaskFn for Noop {
+        fn name(&self) -> &str {
+            "noop"
+        }
+        async fn run(&self, _: Arc<Extensions>) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn task(name: &str) -> Task {
+        Task::new(name, Arc::new(Noop))
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let tasks: Vec<Arc<Task>> = [task("c").after("b"), task("b").after("a"), task("a")]
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        assert_eq!(order_tasks(&tasks).unwrap(), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn unknown_dependencies_and_cycles_are_rejected() {
+        let unknown = [Arc::new(task("a").after("missing"))];
+        assert!(order_tasks(&unknown).is_err());
+        let cycle = [
+            Arc::new(task("a").after("b")),
+            Arc::new(task("b").after("a")),
+        ];
+        let e = order_tasks(&cycle).unwrap_err();
+        assert!(e.to_string().contains("cycle"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_fail_startup() {
+        let reason = ServerBuilder::default()
+            .task(task("cleanup"))
+            .task(task("cleanup"))
+            .build()
+            .serve()
+            .await;
+        match reason {
+            ShutdownReason::ConfigError(e) => {
+                assert!(e.to_string().contains("more than once"), "{e}")
+            }
+            other => panic!("expected a config error, got {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,162 @@
+use crate::service::ServiceRequest;
+use crate::{FromRequestRef, PathVars};
+use async_trait::async_trait;
+use rustls::pki_types::CertificateDer;
+use rustls::server::ServerConnection;
+use rustls::ProtocolVersion;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use x509_cert::der::Decode;
+use x509_cert::Certificate;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerCertificate {
+    pub subject: String,
+    pub issuer: String,
+    /// Seconds since the unix epoch
+    pub not_after: u64,
+    /// Hex sha256 of the DER encoded certificate
+    pub fingerprint: String,
+}
+impl PeerCertificate {
+    pub fn parse(der: &CertificateDer<'_>) -> Option<Self> {
+        let cert = Certificate::from_der(der.as_ref()).ok()?;
+        let tbs = &cert.tbs_certificate;
+        Some(Self {
+            subject: tbs.subject.to_string(),
+            issuer: tbs.issuer.to_string(),
+            not_after: tbs.validity.not_after.to_unix_duration().as_secs(),
+            fingerprint: hex::encode(Sha256::digest(der.as_ref())),
+        })
+    }
+}
+
+/// How a TLS client connected, captured once per connection at accept time.
+/// Only present in the request extensions for TLS connections.
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsInfo {
+    #[serde(skip)]
+    pub version: Option<ProtocolVersion>,
+    pub protocol: Option<String>,
+    pub cipher_suite: Option<String>,
+    pub alpn: Option<String>,
+    pub server_name: Option<String>,
+    /// Client certificate chain, leaf first, empty unless client auth is enabled.
+    /// Stops at the first certificate that fails to parse.
+    pub peer_certificates: Vec<PeerCertificate>,
+    /// Hex sha256 of the client leaf certificate, set even when the leaf does not parse
+    peer_id: Option<String>,
+}
+impl TlsInfo {
+    pub fn from_connection(connection: &ServerConnection) -> Self {
+        let version = connection.protocol_version();
+        Self {
+            version,
+            protocol: version.and_then(|v| v.as_str()).map(str::to_string),
+            cipher_suite: connection
+                .negotiated_cipher_suite()
+                .and_then(|c| c.suite().as_str())
+                .map(str::to_string),
+            alpn: connection
+                .alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).to_string()),
+            server_name: connection.server_name().map(str::to_string),
+            peer_certificates: vec![],
+            peer_id: None,
+        }
+        .with_peer_chain(connection.peer_certificates().unwrap_or_default())
+    }
+    fn with_peer_chain(self, chain: &[CertificateDer<'_>]) -> Self {
+        let mut s = self;
+        s.peer_id = chain
+            .first()
+            .map(|leaf| hex::encode(Sha256::digest(leaf.as_ref())));
+        s.peer_certificates = chain.iter().map_while(PeerCertificate::parse).collect();
+        s
+    }
+    /// Fingerprint of the client leaf certificate, identifies the peer across connections
+    pub fn peer_id(&self) -> Option<&str> {
+        self.peer_id.as_deref()
+    }
+    /// False when the version is unknown
+    pub fn at_least(&self, version: ProtocolVersion) -> bool {
+        match self.version {
+            Some(current) => u16::from(current) >= u16::from(version),
+            None => false,
+        }
+    }
+}
+
+impl PathVars for Arc<TlsInfo> {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+impl PathVars for Option<Arc<TlsInfo>> {
+    const PATH_VARS: &'static [&'static str] = &[];
+}
+#[async_trait]
+impl<'a> FromRequestRef<'a> for Arc<TlsInfo> {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        request.get::<Arc<TlsInfo>>().cloned().ok_or(Error::new(
+            ErrorKind::PermissionDenied,
+            "Connection is not using TLS",
+        ))
+    }
+}
+
+/// None for plaintext connections
+#[async_trait]
+impl<'a> FromRequestRef<'a> for Option<Arc<TlsInfo>> {
+    async fn from_request_ref(request: &'a ServiceRequest, _: &'a str) -> Result<Self, Error> {
+        Ok(request.get::<Arc<TlsInfo>>().cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssl::load_certs;
+
+    fn plain() -> TlsInfo {
+        TlsInfo {
+            version: None,
+            protocol: None,
+            cipher_suite: None,
+            alpn: None,
+            server_name: None,
+            peer_certificates: vec![],
+            peer_id: None,
+        }
+    }
+
+    fn chain() -> Vec<CertificateDer<'static>> {
+        load_certs(include_bytes!("../test_data/localhost.pem")).unwrap()
+    }
+
+    #[test]
+    fn peer_id_is_the_leaf_fingerprint() {
+        let chain = chain();
+        let info = plain().with_peer_chain(&chain);
+        assert_eq!(info.peer_certificates.len(), 2);
+        assert_eq!(info.peer_certificates[0].subject, "CN=localhost");
+        assert_eq!(info.peer_certificates[1].subject, "CN=portfu test ca");
+        assert_eq!(
+            info.peer_id(),
+            Some(hex::encode(Sha256::digest(chain[0].as_ref())).as_str())
+        );
+    }
+
+    #[test]
+    fn unparsable_leaf_is_not_replaced_by_the_issuer() {
+        let mut chain = chain();
+        chain[0] = CertificateDer::from(b"not a certificate".to_vec());
+        let info = plain().with_peer_chain(&chain);
+        assert!(info.peer_certificates.is_empty());
+        assert_eq!(
+            info.peer_id(),
+            Some(hex::encode(Sha256::digest(b"not a certificate")).as_str())
+        );
+        assert_eq!(plain().with_peer_chain(&[]).peer_id(), None);
+    }
+}
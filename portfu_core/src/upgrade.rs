@@ -0,0 +1,229 @@
+#[cfg(not(target_os = "windows"))]
+use log::{error, info};
+use std::io::Error;
+#[cfg(not(target_os = "windows"))]
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+#[cfg(not(target_os = "windows"))]
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(target_os = "windows"))]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(not(target_os = "windows"))]
+use tokio::net::{UnixListener, UnixStream};
+
+const READY: &str = "ready";
+const DRAINING: &str = "draining";
+
+/// Handover between two versions of a server sharing a port through SO_REUSEPORT.
+/// The new process binds next to the old one and warms up, then sends `ready` on the control socket.
+/// The old process stops accepting and exits once its connections finish or drain_timeout passes.
+///
+/// Under systemd run two instances of a template unit, `portfu@blue` and `portfu@green`, with the same
+/// control socket and start the idle one to upgrade, the running one exits on its own once drained.
+/// The old process accepts the connections already queued on its listener before closing it, set
+/// net.ipv4.tcp_migrate_req=1 so the kernel moves connections arriving while it closes to the new one.
+/// Open websockets are sent a Going Away close and count towards the drain like connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeConfig {
+    pub control_socket: PathBuf,
+    pub drain_timeout: Duration,
+}
+impl UpgradeConfig {
+    pub fn new<P: AsRef<Path>>(control_socket: P) -> Self {
+        Self {
+            control_socket: control_socket.as_ref().to_path_buf(),
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+    pub fn drain_timeout(self, drain_timeout: Duration) -> Self {
+        let mut s = self;
+        s.drain_timeout = drain_timeout;
+        s
+    }
+}
+
+/// Tells the process listening on `control_socket` to drain, false when no process was listening
+#[cfg(not(target_os = "windows"))]
+pub async fn take_over(control_socket: &Path) -> Result<bool, Error> {
+    let stream = match UnixStream::connect(control_socket).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(format!("{READY}\n").as_bytes()).await?;
+    let mut reply = String::new();
+    BufReader::new(reader).read_line(&mut reply).await?;
+    if reply.trim() == DRAINING {
+        Ok(true)
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected upgrade reply: {}", reply.trim()),
+        ))
+    }
+}
+
+/// Binds `control_socket`, replacing the socket file of a previous process
+#[cfg(not(target_os = "windows"))]
+pub fn listen(control_socket: &Path) -> Result<UnixListener, Error> {
+    match std::fs::remove_file(control_socket) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(control_socket)
+}
+
+/// Waits for a successor to send `ready`, acknowledges it so it can start serving
+#[cfg(not(target_os = "windows"))]
+pub async fn await_successor(listener: &UnixListener) -> Result<(), Error> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut message = String::new();
+        if BufReader::new(reader)
+            .read_line(&mut message)
+            .await
+            .is_err()
+            || message.trim() != READY
+        {
+            continue;
+        }
+        writer.write_all(format!("{DRAINING}\n").as_bytes()).await?;
+        return Ok(());
+    }
+}
+
+/// Drains the previous process then listens for a successor, clearing `run` once one is ready
+#[cfg(not(target_os = "windows"))]
+pub async fn hand_over(config: &UpgradeConfig, run: Arc<AtomicBool>) -> Result<(), Error> {
+    if take_over(&config.control_socket).await? {
+        info!("Previous process is draining, taking over");
+    }
+    let listener = listen(&config.control_socket)?;
+    tokio::spawn(async move {
+        match await_successor(&listener).await {
+            Ok(()) => {
+                info!("Successor is ready, draining");
+                run.store(false, Ordering::Relaxed);
+            }
+            Err(e) => error!("Failed to wait for a successor: {e:?}"),
+        }
+    });
+    Ok(())
+}
+
+/// Windows has no SO_REUSEPORT, upgrades are not supported
+#[cfg(target_os = "windows")]
+pub async fn hand_over(_: &UpgradeConfig, _: Arc<AtomicBool>) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Socket handover is not supported on windows",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::server::ServerBuilder;
+    use crate::service::ServiceBuilder;
+    use crate::{IntoStreamBody, ServiceData, ServiceHandler};
+
+    /// Answers with its version, after `delay` for /slow
+    struct Version(&'static str);
+    #[async_trait::async_trait]
+    impl ServiceHandler for Version {
+        fn name(&self) -> &str {
+            self.0
+        }
+        async fn handle(&self, mut data: ServiceData) -> Result<ServiceData, (ServiceData, Error)> {
+            if data.request.request.uri().path() == "/slow" {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            *data.response.body_mut() = self.0.stream_body();
+            Ok(data)
+        }
+    }
+
+    fn server(version: &'static str, port: u16, control_socket: &Path) -> ServerBuilder {
+        ServerBuilder::default()
+            .host("127.0.0.1".to_string())
+            .port(port)
+            .upgrade(Some(UpgradeConfig::new(control_socket)))
+            .register(
+                ServiceBuilder::new("/*")
+                    .name(version)
+                    .handler(Arc::new(Version(version)))
+                    .build(),
+            )
+    }
+
+    async fn get(client: &reqwest::Client, url: String) -> String {
+        client.get(url).send().await.unwrap().text().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_on_the_old_process_finish_while_new_ones_reach_the_successor() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let control_socket =
+            std::env::temp_dir().join(format!("pf_upgrade_{}.sock", uuid::Uuid::new_v4()));
+        let base = format!("http://127.0.0.1:{port}");
+        let fresh = || {
+            reqwest::Client::builder()
+                .pool_max_idle_per_host(0)
+                .build()
+                .unwrap()
+        };
+
+        let old = tokio::spawn(server("old", port, &control_socket).build().run());
+        let mut ready = false;
+        for _ in 0..100 {
+            if let Ok(response) = fresh().get(format!("{base}/")).send().await {
+                ready = response.text().await.unwrap() == "old";
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(ready);
+        let slow = {
+            let (client, url) = (fresh(), format!("{base}/slow"));
+            tokio::spawn(async move { get(&client, url).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let new = tokio::spawn(server("new", port, &control_socket).build().run());
+        //The successor's listener takes connections as soon as it is bound
+        let mut answered = String::new();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            answered = get(&fresh(), format!("{base}/")).await;
+            if answered == "new" {
+                break;
+            }
+        }
+        assert_eq!(answered, "new");
+        //Both listeners share the port until the old accept loop sees the handover
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        for _ in 0..20 {
+            assert_eq!(get(&fresh(), format!("{base}/")).await, "new");
+        }
+        assert_eq!(slow.await.unwrap(), "old");
+        tokio::time::timeout(Duration::from_secs(5), old)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(get(&fresh(), format!("{base}/")).await, "new");
+        new.abort();
+        let _ = std::fs::remove_file(&control_socket);
+    }
+}
@@ -0,0 +1,276 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Process wide table of request bodies being read
+pub static UPLOADS: Lazy<Uploads> = Lazy::new(Uploads::default);
+
+pub struct UploadProgress {
+    pub id: Uuid,
+    pub expected_bytes: Option<u64>,
+    started: Instant,
+    bytes_read: AtomicU64,
+    last_activity_ms: AtomicU64,
+    cancelled: AtomicBool,
+    cancel: Notify,
+}
+impl UploadProgress {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.cancel.notify_one();
+    }
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+    pub fn idle(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(
+            self.last_activity_ms.load(Ordering::Relaxed),
+        ))
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+    pub fn snapshot(&self) -> UploadSnapshot {
+        let elapsed = self.started.elapsed();
+        let bytes_read = self.bytes_read();
+        UploadSnapshot {
+            id: self.id,
+            bytes_read,
+            expected_bytes: self.expected_bytes,
+            elapsed_ms: elapsed.as_millis() as u64,
+            idle_ms: self.idle().as_millis() as u64,
+            bytes_per_second: (bytes_read as f64 / elapsed.as_secs_f64().max(0.001)) as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSnapshot {
+    pub id: Uuid,
+    pub bytes_read: u64,
+    pub expected_bytes: Option<u64>,
+    pub elapsed_ms: u64,
+    pub idle_ms: u64,
+    pub bytes_per_second: u64,
+}
+
+/// Upper bounds of the size histogram buckets, the last bucket counts everything larger
+pub const UPLOAD_SIZE_BUCKETS: [u64; 6] =
+    [1 << 20, 8 << 20, 64 << 20, 256 << 20, 1 << 30, u64::MAX];
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UploadStats {
+    pub in_flight: usize,
+    pub in_flight_bytes: u64,
+    pub completed: u64,
+    pub cancelled: u64,
+    pub failed: u64,
+    pub total_bytes: u64,
+    pub total_duration_ms: u64,
+    /// Completed upload counts per UPLOAD_SIZE_BUCKETS entry
+    pub size_buckets: [u64; 6],
+}
+
+pub struct Uploads {
+    active: RwLock<HashMap<Uuid, Arc<UploadProgress>>>,
+    min_bytes: AtomicU64,
+    min_duration_ms: AtomicU64,
+    completed: AtomicU64,
+    cancelled: AtomicU64,
+    failed: AtomicU64,
+    total_bytes: AtomicU64,
+    total_duration_ms: AtomicU64,
+    size_buckets: [AtomicU64; 6],
+}
+impl Default for Uploads {
+    fn default() -> Self {
+        Self {
+            active: Default::default(),
+            min_bytes: AtomicU64::new(1 << 20),
+            min_duration_ms: AtomicU64::new(1000),
+            completed: Default::default(),
+            cancelled: Default::default(),
+            failed: Default::default(),
+            total_bytes: Default::default(),
+            total_duration_ms: Default::default(),
+            size_buckets: Default::default(),
+        }
+    }
+}
+impl Uploads {
+    /// Bodies are only tracked once they pass `min_bytes` or take longer than `min_duration` to read
+    pub fn set_thresholds(&self, min_bytes: u64, min_duration: Duration) {
+        self.min_bytes.store(min_bytes, Ordering::Relaxed);
+        self.min_duration_ms
+            .store(min_duration.as_millis() as u64, Ordering::Relaxed);
+    }
+    pub fn active(&self) -> Vec<UploadSnapshot> {
+        self.active
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .map(|p| p.snapshot())
+            .collect()
+    }
+    /// Cancels the upload, its reader stops with an Interrupted error
+    pub fn cancel(&self, id: &Uuid) -> bool {
+        match self
+            .active
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(id)
+        {
+            Some(progress) => {
+                progress.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+    /// Cancels every upload that has not received data for `idle`, returning their ids
+    pub fn cancel_stalled(&self, idle: Duration) -> Vec<Uuid> {
+        self.active
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .filter(|p| p.idle() >= idle)
+            .map(|p| {
+                p.cancel();
+                p.id
+            })
+            .collect()
+    }
+    pub fn stats(&self) -> UploadStats {
+        let active = self.active.read().unwrap_or_else(|e| e.into_inner());
+        let mut size_buckets = [0; 6];
+        for (bucket, count) in size_buckets.iter_mut().zip(self.size_buckets.iter()) {
+            *bucket = count.load(Ordering::Relaxed);
+        }
+        UploadStats {
+            in_flight: active.len(),
+            in_flight_bytes: active.values().map(|p| p.bytes_read()).sum(),
+            completed: self.completed.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            total_duration_ms: self.total_duration_ms.load(Ordering::Relaxed),
+            size_buckets,
+        }
+    }
+    fn register(&self, progress: Arc<UploadProgress>) {
+        self.active
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(progress.id, progress);
+    }
+    fn unregister(&self, progress: &UploadProgress, outcome: UploadOutcome) {
+        self.active
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&progress.id);
+        match outcome {
+            UploadOutcome::Completed => {
+                let size = progress.bytes_read();
+                self.completed.fetch_add(1, Ordering::Relaxed);
+                self.total_bytes.fetch_add(size, Ordering::Relaxed);
+                self.total_duration_ms.fetch_add(
+                    progress.started.elapsed().as_millis() as u64,
+                    Ordering::Relaxed,
+                );
+                if let Some(index) = UPLOAD_SIZE_BUCKETS.iter().position(|max| size <= *max) {
+                    self.size_buckets[index].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            UploadOutcome::Cancelled => {
+                self.cancelled.fetch_add(1, Ordering::Relaxed);
+            }
+            UploadOutcome::Failed => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadOutcome {
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+/// Follows a single body read, it only shows up in UPLOADS once the thresholds are passed
+pub struct UploadTracker {
+    progress: Arc<UploadProgress>,
+    registered: bool,
+    outcome: UploadOutcome,
+}
+impl UploadTracker {
+    pub fn new(expected_bytes: Option<u64>) -> Self {
+        Self {
+            progress: Arc::new(UploadProgress {
+                id: Uuid::new_v4(),
+                expected_bytes,
+                started: Instant::now(),
+                bytes_read: AtomicU64::new(0),
+                last_activity_ms: AtomicU64::new(0),
+                cancelled: AtomicBool::new(false),
+                cancel: Notify::new(),
+            }),
+            registered: false,
+            outcome: UploadOutcome::Failed,
+        }
+    }
+    /// Records `len` more bytes, returns false once the upload has been cancelled
+    pub fn record(&mut self, len: usize) -> bool {
+        let progress = &self.progress;
+        let total = progress.bytes_read.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+        let elapsed = progress.started.elapsed();
+        progress
+            .last_activity_ms
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+        if total >= UPLOADS.min_bytes.load(Ordering::Relaxed) || self.register_in().is_zero() {
+            self.register();
+        }
+        !self.progress.is_cancelled()
+    }
+    /// Time left until a slow body is tracked even if it is small
+    pub fn register_in(&self) -> Duration {
+        Duration::from_millis(UPLOADS.min_duration_ms.load(Ordering::Relaxed))
+            .saturating_sub(self.progress.started.elapsed())
+    }
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+    pub fn register(&mut self) {
+        if !self.registered {
+            UPLOADS.register(self.progress.clone());
+            self.registered = true;
+        }
+    }
+    /// Resolves once the upload is cancelled
+    pub async fn cancelled(&self) {
+        if !self.progress.is_cancelled() {
+            self.progress.cancel.notified().await;
+        }
+    }
+    pub fn complete(&mut self) {
+        self.outcome = UploadOutcome::Completed;
+    }
+}
+impl Drop for UploadTracker {
+    fn drop(&mut self) {
+        if self.registered {
+            let outcome = match self.outcome {
+                UploadOutcome::Completed => UploadOutcome::Completed,
+                _ if self.progress.is_cancelled() => UploadOutcome::Cancelled,
+                outcome => outcome,
+            };
+            UPLOADS.unregister(&self.progress, outcome);
+        }
+    }
+}
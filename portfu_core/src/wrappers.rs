@@ -1,6 +1,11 @@
-use crate::ServiceData;
+use crate::{ServiceData, ServiceResponse};
 use async_trait::async_trait;
+use http::Request;
+use hyper::body::Incoming;
 use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io::Error;
+use std::pin::Pin;
 use std::sync::Arc;
 
 pub enum WrapperResult {
@@ -11,10 +16,35 @@ pub enum WrapperResult {
 #[async_trait]
 pub trait WrapperFn {
     fn name(&self) -> &str;
+    /// Infrastructure wrappers run before the HeaderPolicy hides headers, put them ahead of the others
+    fn infrastructure(&self) -> bool {
+        false
+    }
+    /// The role this wrapper requires of callers, reported by Server::route_plan
+    fn required_role(&self) -> Option<&str> {
+        None
+    }
     async fn before(&self, data: &mut ServiceData) -> WrapperResult;
     async fn after(&self, data: &mut ServiceData) -> WrapperResult;
 }
-impl Debug for (dyn WrapperFn + Send + Sync + 'static) {
+
+pub type ScopedFuture = Pin<Box<dyn Future<Output = Result<ServiceResponse, Error>> + Send>>;
+/// Answers the request, wrappers and the handler included
+pub type ScopeNext = Box<dyn FnOnce(Request<Incoming>) -> ScopedFuture + Send>;
+
+/// Runs each request of the server inside a scope such as a task local, added with ServerBuilder::request_scope.
+/// Sees the routed request before any wrapper and has to hand it to `next`.
+pub trait RequestScope {
+    fn name(&self) -> &str;
+    fn scope(&self, request: Request<Incoming>, next: ScopeNext) -> ScopedFuture;
+}
+
+impl Debug for dyn WrapperFn + Send + Sync + 'static {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+impl Debug for dyn RequestScope + Send + Sync + 'static {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(self.name())
     }
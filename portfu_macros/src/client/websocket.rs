@@ -1,10 +1,11 @@
 use proc_macro2::{Ident, TokenStream as TokenStream2};
 use quote::{quote, ToTokens};
-use syn::{parse_quote, FnArg, Pat, Type};
+use syn::{punctuated::Punctuated, FnArg, Pat, Token, Type};
 use url::Url;
 
 pub struct UrlArgs {
     pub url: syn::LitStr,
+    pub options: Punctuated<syn::MetaNameValue, Token![,]>,
 }
 
 impl syn::parse::Parse for UrlArgs {
@@ -18,12 +19,73 @@ impl syn::parse::Parse for UrlArgs {
         })?;
 
         // verify that path pattern is valid
-        let _ = Url::parse(&url.value()).unwrap();
+        if let Err(e) = Url::parse(&url.value()) {
+            return Err(syn::Error::new(url.span(), format!("invalid url: {e}")));
+        }
 
-        Ok(Self { url })
+        // if there's no comma, assume that no options are provided
+        if !input.peek(Token![,]) {
+            return Ok(Self {
+                url,
+                options: Punctuated::new(),
+            });
+        }
+        input.parse::<Token![,]>()?;
+        let options = input.parse_terminated(syn::MetaNameValue::parse, Token![,])?;
+        Ok(Self { url, options })
     }
 }
 
+/// A literal given directly or as a string, `reconnect = true` or `reconnect = "true"`
+fn literal_arg<T: std::str::FromStr>(nv: &syn::MetaNameValue) -> syn::Result<T> {
+    let parsed = match &nv.value {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(lit),
+            ..
+        }) => lit.value().parse::<T>().ok(),
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(lit),
+            ..
+        }) => lit.base10_digits().parse::<T>().ok(),
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Bool(lit),
+            ..
+        }) => lit.value.to_string().parse::<T>().ok(),
+        _ => None,
+    };
+    parsed.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &nv.value,
+            format!("Invalid value for {}", nv.path.to_token_stream()),
+        )
+    })
+}
+
+/// ReconnectPolicy builder calls for the macro options
+fn reconnect_policy(
+    options: &Punctuated<syn::MetaNameValue, Token![,]>,
+) -> syn::Result<Vec<TokenStream2>> {
+    let mut policy = vec![];
+    for nv in options {
+        if nv.path.is_ident("reconnect") {
+            let reconnect: bool = literal_arg(nv)?;
+            policy.push(quote! { .reconnect(#reconnect) });
+        } else if nv.path.is_ident("max_retries") {
+            let max_retries: u32 = literal_arg(nv)?;
+            policy.push(quote! { .max_retries(#max_retries) });
+        } else if nv.path.is_ident("backoff_ms") {
+            let backoff: u64 = literal_arg(nv)?;
+            policy.push(quote! { .backoff(::std::time::Duration::from_millis(#backoff)) });
+        } else {
+            return Err(syn::Error::new_spanned(
+                &nv.path,
+                "Unknown attribute key is specified; allowed: reconnect, max_retries and backoff_ms",
+            ));
+        }
+    }
+    Ok(policy)
+}
+
 pub struct WebSocketClient {
     /// Name of the handler function being annotated.
     name: Ident,
@@ -33,6 +95,10 @@ pub struct WebSocketClient {
     ast: syn::ItemFn,
     /// The doc comment attributes to copy to generated struct, if any.
     doc_attributes: Vec<syn::Attribute>,
+    /// ReconnectPolicy builder calls.
+    policy: Vec<TokenStream2>,
+    /// What is passed for each of the handler's arguments.
+    handler_args: Vec<TokenStream2>,
 }
 impl WebSocketClient {
     pub fn new(args: UrlArgs, ast: syn::ItemFn) -> syn::Result<Self> {
@@ -45,15 +111,59 @@ impl WebSocketClient {
             .filter(|attr| attr.path().is_ident("doc"))
             .cloned()
             .collect();
+        let policy = reconnect_policy(&args.options)?;
+        let handler_args = handler_args(&ast)?;
         Ok(Self {
             name,
             args,
             ast,
             doc_attributes,
+            policy,
+            handler_args,
         })
     }
 }
 
+/// The connected websocket, the handshake response or the ClientConnection, by argument type
+fn handler_args(ast: &syn::ItemFn) -> syn::Result<Vec<TokenStream2>> {
+    let mut handler_args = vec![];
+    for arg in ast.sig.inputs.iter() {
+        let (ty, ident) = match arg {
+            FnArg::Typed(typed) => match typed.pat.as_ref() {
+                Pat::Ident(pat_ident) => (typed.ty.as_ref(), &pat_ident.ident),
+                _ => continue,
+            },
+            FnArg::Receiver(_) => continue,
+        };
+        let segment = match ty {
+            Type::Path(path) => path.path.segments.first(),
+            _ => None,
+        };
+        let segment = segment.ok_or_else(|| {
+            syn::Error::new_spanned(
+                ty,
+                format!(
+                    "Invalid Type({ident}) Found in Function Definition {}",
+                    ast.sig.ident
+                ),
+            )
+        })?;
+        if segment.ident == "WebSocket" || segment.ident == "ClientWebSocket" {
+            handler_args.push(quote! { _websocket, });
+        } else if segment.ident == "Response" {
+            handler_args.push(quote! { _response, });
+        } else if segment.ident == "ClientConnection" {
+            handler_args.push(quote! { self.connection.clone(), });
+        } else {
+            return Err(syn::Error::new_spanned(
+                ty,
+                format!("Invalid Input Type for Websocket Client {}", segment.ident),
+            ));
+        }
+    }
+    Ok(handler_args)
+}
+
 impl ToTokens for WebSocketClient {
     fn to_tokens(&self, output: &mut TokenStream2) {
         let Self {
@@ -61,88 +171,91 @@ impl ToTokens for WebSocketClient {
             ast,
             args,
             doc_attributes,
+            policy,
+            handler_args,
         } = self;
         let url = &args.url;
-        let mut additional_function_vars = vec![];
-        for arg in ast.sig.inputs.iter() {
-            let (ident_type, ident_val): (Type, Ident) = match arg {
-                FnArg::Receiver(_) => {
-                    continue;
-                }
-                FnArg::Typed(typed) => {
-                    if let Pat::Ident(pat_ident) = typed.pat.as_ref() {
-                        let ty = &typed.ty;
-                        let ident = &pat_ident.ident;
-                        (parse_quote! { #ty }, parse_quote! { #ident })
-                    } else {
-                        continue;
-                    }
-                }
-            };
-            if let Type::Path(path) = &ident_type {
-                if let Some(segment) = path.path.segments.first() {
-                    let ws_ident: Ident = Ident::new("WebSocket", segment.ident.span());
-                    let reponse_ident: Ident = Ident::new("Response", segment.ident.span());
-                    if ws_ident == segment.ident {
-                        additional_function_vars.push(quote! {
-                            _websocket,
-                        });
-                        continue;
-                    } else if reponse_ident == segment.ident {
-                        additional_function_vars.push(quote! {
-                            _response,
-                        });
-                        continue;
-                    } else {
-                        panic!("Invalid Input Type for Websocket Client {}", segment.ident);
-                    }
-                } else {
-                    panic!(
-                        "Invalid Type({}) Found in Function Definition {}",
-                        ident_val, name
-                    );
-                }
-            } else {
-                panic!(
-                    "Invalid Type({}) Found in Function Definition {}",
-                    ident_val, name
-                );
-            }
-        }
         let stream = quote! {
             #(#doc_attributes)*
             #[allow(non_camel_case_types, missing_docs)]
-            pub async fn #name -> Result<(), std::io::Error> {
-                #ast
-                let request = #url.into_client_request()
-                    .map_err(|e| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            format!("Failed to Parse Request: {}", e),
-                        )
-                    })?;
-                let (_websocket, _response) = ::portfu::prelude::tokio_tungstenite::connect::connect_async_tls_with_config(
-                    request,
-                    None,
-                    false,
-                    Some(::portfu::prelude::tokio_tungstenite::tls::Connector::Rustls(::std::sync::Arc::new(
-                        ::portfu::prelude::rustls::client::client_conn::ClientConfig::builder()
-                            .with_safe_default_cipher_suites()
-                            .with_safe_default_kx_groups()
-                            .with_safe_default_protocol_versions()
+            #[derive(Default)]
+            pub struct #name {
+                pub connection: ::portfu::prelude::ClientConnection,
+            }
+            impl From<#name> for ::portfu::pfcore::task::Task {
+                fn from(task: #name) -> ::portfu::pfcore::task::Task {
+                    use ::portfu::pfcore::task::TaskFn;
+                    let name = task.name().to_string();
+                    ::portfu::pfcore::task::Task::new(name, ::std::sync::Arc::new(task))
+                }
+            }
+            #[::portfu::prelude::async_trait::async_trait]
+            impl ::portfu::pfcore::task::TaskFn for #name {
+                fn name(&self) -> &str {
+                    stringify!(#name)
+                }
+                async fn run(
+                    &self,
+                    _state: ::std::sync::Arc< ::portfu::prelude::http::Extensions >
+                ) -> Result<(), ::std::io::Error> {
+                    use ::portfu::prelude::tokio_tungstenite::tungstenite::client::IntoClientRequest;
+                    #ast
+                    let policy = ::portfu::prelude::ReconnectPolicy::default()#(#policy)*;
+                    let url = self.connection.url_override().unwrap_or(#url);
+                    let shutdown = ::portfu::pfcore::task::ShutdownSignal::wait_in(&_state);
+                    ::tokio::pin!(shutdown);
+                    let mut attempt: u32 = 0;
+                    loop {
+                        let request = url.into_client_request()
                             .map_err(|e| {
-                                std::io::Error::new(std::io::ErrorKind::Other, format!("Error Building Client: {:?}", e))
-                            })?,
-                    ))),
-                )
-                .await
-                .map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Error Connecting Client: {:?}", e),
-                    )
-                })?;
-                let _ = #name(#(#additional_function_vars)*).await;
+                                std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("Failed to Parse Request: {}", e),
+                                )
+                            })?;
+                        let connected = ::tokio::select! {
+                            connected = ::portfu::prelude::tokio_tungstenite::connect_async(request) => connected,
+                            _ = &mut shutdown => {
+                                self.connection.set_state(::portfu::prelude::ClientState::Closed);
+                                return Ok(());
+                            }
+                        };
+                        match connected {
+                            Ok((_websocket, _response)) => {
+                                attempt = 0;
+                                self.connection.set_state(::portfu::prelude::ClientState::Connected);
+                                ::tokio::select! {
+                                    result = #name(#(#handler_args)*) => {
+                                        if let Err(e) = result {
+                                            ::portfu::prelude::log::error!("Websocket Client {} failed: {e:?}", stringify!(#name));
+                                        }
+                                    }
+                                    _ = &mut shutdown => {
+                                        self.connection.set_state(::portfu::prelude::ClientState::Closed);
+                                        return Ok(());
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                ::portfu::prelude::log::error!("Error Connecting Client {}: {e:?}", stringify!(#name));
+                            }
+                        }
+                        attempt += 1;
+                        if !policy.allows(attempt) {
+                            self.connection.set_state(::portfu::prelude::ClientState::Closed);
+                            return Ok(());
+                        }
+                        self.connection.set_state(::portfu::prelude::ClientState::Reconnecting { attempt });
+                        ::tokio::select! {
+                            _ = ::tokio::time::sleep(policy.delay(attempt)) => {}
+                            _ = &mut shutdown => {
+                                self.connection.set_state(::portfu::prelude::ClientState::Closed);
+                                return Ok(());
+                            }
+                        }
+                        self.connection.set_state(::portfu::prelude::ClientState::Connecting);
+                    }
+                }
             }
         };
         output.extend(stream);
@@ -0,0 +1,150 @@
+use proc_macro2::{Ident, TokenStream as TokenStream2};
+use quote::{quote, ToTokens};
+use syn::{Data, DeriveInput, Fields, LitStr, Type};
+
+struct ExtractField {
+    ident: Ident,
+    ty: Type,
+    /// Name handed to FromRequest, the path variable for Path fields
+    var_name: String,
+    flatten: bool,
+}
+
+/// `#[derive(FromRequest)]` for structs whose fields are all extractors
+pub struct DeriveFromRequest {
+    name: Ident,
+    fields: Vec<ExtractField>,
+}
+impl DeriveFromRequest {
+    pub fn new(input: DeriveInput) -> syn::Result<Self> {
+        if !input.generics.params.is_empty() {
+            return Err(syn::Error::new_spanned(
+                &input.generics,
+                "FromRequest can not be derived for generic structs",
+            ));
+        }
+        let named = match input.data {
+            Data::Struct(data) => match data.fields {
+                Fields::Named(named) => named.named,
+                Fields::Unit => Default::default(),
+                fields => {
+                    return Err(syn::Error::new_spanned(
+                        fields,
+                        "FromRequest can only be derived for structs with named fields",
+                    ))
+                }
+            },
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "FromRequest can only be derived for structs",
+                ))
+            }
+        };
+        let mut fields = Vec::with_capacity(named.len());
+        for field in named {
+            let ident = field.ident.clone().expect("named fields have idents");
+            let mut var_name = ident.to_string();
+            let mut flatten = false;
+            for attr in field
+                .attrs
+                .iter()
+                .filter(|a| a.path().is_ident("from_request"))
+            {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("var") {
+                        var_name = meta.value()?.parse::<LitStr>()?.value();
+                        Ok(())
+                    } else if meta.path.is_ident("flatten") {
+                        flatten = true;
+                        Ok(())
+                    } else {
+                        Err(meta.error("Unknown from_request option; allowed: var and flatten"))
+                    }
+                })?;
+            }
+            fields.push(ExtractField {
+                ident,
+                ty: field.ty,
+                var_name,
+                flatten,
+            });
+        }
+        Ok(Self {
+            name: input.ident,
+            fields,
+        })
+    }
+}
+
+impl ToTokens for DeriveFromRequest {
+    fn to_tokens(&self, output: &mut TokenStream2) {
+        let name = &self.name;
+        let extractions = self.fields.iter().map(|field| {
+            let ExtractField {
+                ident,
+                ty,
+                var_name,
+                flatten,
+            } = field;
+            let error = if *flatten {
+                quote! { e }
+            } else {
+                quote! {
+                    match ::portfu::pfcore::errors::HttpError::find(&e) {
+                        Some(http_error) => {
+                            let mut http_error = http_error.clone();
+                            http_error.message = format!("Failed to extract {}: {}", stringify!(#ident), http_error.message);
+                            ::std::io::Error::from(http_error)
+                        }
+                        None => ::std::io::Error::new(e.kind(), format!("Failed to extract {}: {e}", stringify!(#ident))),
+                    }
+                }
+            };
+            quote! {
+                let #ident = match <#ty as ::portfu::pfcore::FromRequest<'_>>::from_request(&mut *request, #var_name).await {
+                    Ok(v) => v,
+                    Err(e) => return Err(#error),
+                };
+            }
+        });
+        let idents = self.fields.iter().map(|f| &f.ident);
+        // Path fields read their variable, flattened structs whatever they declare themselves
+        let path_vars = self.fields.iter().filter_map(|field| {
+            let is_path = match &field.ty {
+                Type::Path(ty) => ty.path.segments.last().is_some_and(|s| s.ident == "Path"),
+                _ => false,
+            };
+            let ty = &field.ty;
+            let var_name = &field.var_name;
+            if is_path {
+                Some((quote! { 1 }, quote! { &[#var_name] }))
+            } else if field.flatten {
+                let vars = quote! { <#ty as ::portfu::pfcore::PathVars>::PATH_VARS };
+                Some((quote! { #vars.len() }, vars))
+            } else {
+                None
+            }
+        });
+        let (lengths, parts): (Vec<_>, Vec<_>) = path_vars.unzip();
+        let stream = quote! {
+            #[::portfu::prelude::async_trait::async_trait]
+            impl<'a> ::portfu::pfcore::FromRequest<'a> for #name {
+                async fn from_request(
+                    request: &'a mut ::portfu::pfcore::service::ServiceRequest,
+                    _: &'a str,
+                ) -> Result<Self, ::std::io::Error> {
+                    #(#extractions)*
+                    Ok(Self { #(#idents),* })
+                }
+            }
+            impl ::portfu::pfcore::PathVars for #name {
+                const PATH_VARS: &'static [&'static str] = &{
+                    const LEN: usize = 0 #(+ #lengths)*;
+                    ::portfu::pfcore::concat_path_vars::<LEN>(&[#(#parts),*])
+                };
+            }
+        };
+        output.extend(stream);
+    }
+}
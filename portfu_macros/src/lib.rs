@@ -1,8 +1,10 @@
 mod client;
+mod from_request;
 mod method;
 mod server;
 
 use crate::client::websocket::WebSocketClient;
+use crate::from_request::DeriveFromRequest;
 use crate::method::Method;
 use crate::server::endpoints::Endpoint;
 use crate::server::files::Files;
@@ -13,9 +15,10 @@ use crate::server::websocket::WebSocketRoute;
 use portfu_core::routes::PathSegment;
 use proc_macro::TokenStream;
 use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
-use quote::{quote, ToTokens};
+use quote::{quote, quote_spanned, ToTokens};
 use std::collections::HashSet;
-use syn::LitStr;
+use syn::punctuated::Punctuated;
+use syn::{parse_quote, FnArg, LitStr, Pat, Token, Type};
 
 /// Converts the error to a token stream and appends it to the original input.
 ///
@@ -124,12 +127,16 @@ pub fn client_websocket(args: TokenStream, input: TokenStream) -> TokenStream {
 }
 
 #[proc_macro_attribute]
-pub fn task(_: TokenStream, input: TokenStream) -> TokenStream {
+pub fn task(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = match syn::parse(args) {
+        Ok(args) => args,
+        Err(err) => return input_and_compile_error(input, err),
+    };
     let ast = match syn::parse::<syn::ItemFn>(input.clone()) {
         Ok(ast) => ast,
         Err(err) => return input_and_compile_error(input, err),
     };
-    match Task::new(ast) {
+    match Task::new(args, ast) {
         Ok(task) => task.into_token_stream().into(),
         Err(err) => input_and_compile_error(input, err),
     }
@@ -151,21 +158,79 @@ pub fn interval(args: TokenStream, input: TokenStream) -> TokenStream {
     }
 }
 
-fn parse_path_variables(path: &LitStr) -> (Vec<TokenStream2>, Vec<String>) {
+/// Implements FromRequest for a struct by extracting each field in declaration order.
+/// Path fields read the variable named after the field, `#[from_request(var = "id")]` picks another,
+/// and `#[from_request(flatten)]` passes errors of a nested derived struct through unchanged.
+/// The derived PathVars lets endpoints take their path variables through such a struct.
+#[proc_macro_derive(FromRequest, attributes(from_request))]
+pub fn derive_from_request(input: TokenStream) -> TokenStream {
+    let ast = match syn::parse::<syn::DeriveInput>(input) {
+        Ok(ast) => ast,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    match DeriveFromRequest::new(ast) {
+        Ok(derived) => derived.into_token_stream().into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Binds a handler argument through FromRequest, shared by every server macro so extraction
+/// failures produce the same response everywhere: the HttpError carried by the error, or a 500.
+fn extract_argument(ident_val: &Ident, ident_type: &Type) -> TokenStream2 {
+    quote! {
+        let #ident_val: #ident_type = match ::portfu::pfcore::FromRequest::from_request(&mut handle_data.request, stringify!(#ident_val)).await {
+            Ok(v) => v,
+            Err(e) => {
+                if let Err(e) = ::portfu::pfcore::errors::render_error(e, &mut handle_data).await {
+                    if !::portfu::pfcore::errors::HttpError::apply(&e, &mut handle_data.response) {
+                        *handle_data.response.status_mut() = ::portfu::pfcore::errors::extraction_status(e.kind());
+                        *handle_data.response.body_mut() = ::portfu::prelude::hyper::body::Bytes::from(format!("Failed to extract {} as {}, {e:?}", stringify!(#ident_val), stringify!(#ident_type).replace(' ',""))).stream_body();
+                    }
+                }
+                return Ok(handle_data);
+            }
+        };
+    }
+}
+
+/// The route of a macro's path, malformed patterns are a compile error at the path
+fn route(path: &LitStr) -> syn::Result<portfu_core::routes::Route> {
+    portfu_core::routes::Route::try_new(path.value()).map_err(|e| syn::Error::new(path.span(), e))
+}
+
+/// Extracts each path variable as the type its argument declares, plain Path when unused
+fn parse_path_variables(
+    path: &LitStr,
+    inputs: &Punctuated<FnArg, Token![,]>,
+) -> (Vec<TokenStream2>, Vec<String>) {
     let mut path_vars = vec![];
-    match portfu_core::routes::Route::new(path.value()) {
-        portfu_core::routes::Route::Static(_, _) => (vec![quote! {}], vec![]),
-        portfu_core::routes::Route::Segmented(segments, _) => {
-            let mut variables = vec![];
+    match route(path) {
+        //Reported by validate_path_arguments before tokens are generated
+        Err(_) => (vec![quote! {}], vec![]),
+        Ok(portfu_core::routes::Route::Static(_, _)) => (vec![quote! {}], vec![]),
+        Ok(portfu_core::routes::Route::Segmented(segments, _)) => {
+            let mut variables = vec![quote! {
+                handle_data.request.capture_path_params();
+            }];
             for segment in segments.iter().filter_map(|v| match v {
                 PathSegment::Static(_) => None,
-                PathSegment::Variable(v) => Some(Ident::new(v.name.as_str(), Span::call_site())),
+                PathSegment::Variable(v) | PathSegment::Tail(v) => {
+                    Some(Ident::new(v.name.as_str(), Span::call_site()))
+                }
             }) {
-                variables.push(
-                    quote! {
-                            let #segment: ::portfu::prelude::Path = ::portfu::pfcore::FromRequest::from_request(&mut handle_data.request, stringify!(#segment)).await.unwrap();
+                let declared = inputs.iter().find_map(|arg| match arg {
+                    FnArg::Typed(typed) => match typed.pat.as_ref() {
+                        Pat::Ident(pat_ident) if pat_ident.ident == segment => {
+                            Some(typed.ty.as_ref().clone())
                         }
-                );
+                        _ => None,
+                    },
+                    FnArg::Receiver(_) => None,
+                });
+                variables.push(extract_argument(
+                    &segment,
+                    &declared.unwrap_or(parse_quote! { ::portfu::prelude::Path }),
+                ));
                 path_vars.push(format!("{segment}"));
             }
             (variables, path_vars)
@@ -173,27 +238,141 @@ fn parse_path_variables(path: &LitStr) -> (Vec<TokenStream2>, Vec<String>) {
     }
 }
 
-fn extract_method_filters(methods: &HashSet<Method>) -> TokenStream2 {
-    debug_assert!(!methods.is_empty(), "Args::methods should not be empty");
-    let mut others = methods.iter();
-    let first = others.next().unwrap();
-    if methods.len() > 1 {
-        let other_method_guards: Vec<TokenStream2> = others
-            .map(|method| {
-                quote! {
-                    .or(::portfu::filters::method::#method.clone())
-                }
+fn path_variable_names(path: &LitStr) -> syn::Result<Vec<String>> {
+    Ok(match route(path)? {
+        portfu_core::routes::Route::Static(_, _) => vec![],
+        portfu_core::routes::Route::Segmented(segments, _) => segments
+            .iter()
+            .filter_map(|v| match v {
+                PathSegment::Static(_) => None,
+                PathSegment::Variable(v) | PathSegment::Tail(v) => Some(v.name.clone()),
             })
+            .collect(),
+    })
+}
+
+/// Checks handler arguments against the path variables in `path`: a Path argument must name
+/// one of them and every variable must be consumed unless `allow_unused` is set. Variables without
+/// an argument of their own may still be read by an extractor, those are returned as const
+/// assertions on the PathVars of the other arguments.
+fn validate_path_arguments(
+    path: &LitStr,
+    inputs: &Punctuated<FnArg, Token![,]>,
+    allow_unused: bool,
+) -> syn::Result<TokenStream2> {
+    let path_vars = path_variable_names(path)?;
+    let mut errors: Option<syn::Error> = None;
+    let mut push_error = |error: syn::Error| match errors.as_mut() {
+        Some(errors) => errors.combine(error),
+        None => errors = Some(error),
+    };
+    let mut consumed = HashSet::new();
+    let mut extractors = vec![];
+    for arg in inputs.iter() {
+        let FnArg::Typed(typed) = arg else {
+            continue;
+        };
+        let Pat::Ident(pat_ident) = typed.pat.as_ref() else {
+            continue;
+        };
+        let name = pat_ident.ident.to_string();
+        if path_vars.contains(&name) {
+            consumed.insert(name);
+            continue;
+        }
+        let (is_path, is_extractor) = match typed.ty.as_ref() {
+            Type::Path(ty) => (
+                ty.path.segments.last().is_some_and(|s| s.ident == "Path"),
+                ty.path
+                    .segments
+                    .first()
+                    .is_some_and(|s| s.ident != "Response" && s.ident != "ServiceData"),
+            ),
+            _ => (false, false),
+        };
+        if !is_path && is_extractor {
+            extractors.push(typed.ty.as_ref().clone());
+        }
+        if is_path {
+            let available = if path_vars.is_empty() {
+                "the path has no variables".to_string()
+            } else {
+                format!("available: {}", path_vars.join(", "))
+            };
+            let suggestion = path_vars
+                .iter()
+                .filter(|v| !consumed.contains(*v))
+                .map(|v| (edit_distance(&name, v), v))
+                .filter(|(distance, v)| *distance <= (v.len().max(name.len()) / 3).max(1))
+                .min_by_key(|(distance, _)| *distance)
+                .map(|(_, v)| format!(", did you mean `{v}`?"))
+                .unwrap_or_default();
+            push_error(syn::Error::new_spanned(
+                &pat_ident.ident,
+                format!(
+                    "`{name}` does not match a path variable in \"{}\", {available}{suggestion}",
+                    path.value()
+                ),
+            ));
+        }
+    }
+    let mut assertions = quote! {};
+    if !allow_unused {
+        let unused: Vec<&String> = path_vars
+            .iter()
+            .filter(|v| !consumed.contains(*v))
             .collect();
-        quote! {
-            .filter(
-                ::portfu::filters::any(::portfu::filters::method::#first.clone())
-                    #(#other_method_guards)*
-            )
+        if !unused.is_empty() && !extractors.is_empty() {
+            for var in unused {
+                let message = format!(
+                    "Path variable `{var}` of \"{}\" is not read by any argument; add one named `{var}` or set allow_unused_path_vars = true",
+                    path.value()
+                )
+                // assert! reads the message as a format string
+                .replace('{', "{{")
+                .replace('}', "}}");
+                assertions.extend(quote_spanned! { path.span() =>
+                    const _: () = ::std::assert!(
+                        ::portfu::pfcore::uses_path_var(&[#(<#extractors as ::portfu::pfcore::PathVars>::PATH_VARS),*], #var),
+                        #message
+                    );
+                });
+            }
+        } else if !unused.is_empty() {
+            push_error(syn::Error::new_spanned(
+                path,
+                format!(
+                    "Path variables not consumed by the handler: {}; add arguments with matching names or set allow_unused_path_vars = true",
+                    unused.iter().map(|v| format!("`{v}`")).collect::<Vec<_>>().join(", ")
+                ),
+            ));
         }
-    } else {
-        quote! {
-            .filter(::portfu::filters::method::#first.clone())
+    }
+    match errors {
+        Some(errors) => Err(errors),
+        None => Ok(assertions),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
         }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn extract_methods(methods: &HashSet<Method>) -> TokenStream2 {
+    debug_assert!(!methods.is_empty(), "Args::methods should not be empty");
+    let mut methods: Vec<&Method> = methods.iter().collect();
+    methods.sort_by_key(|method| method.as_str());
+    quote! {
+        #(.method(::portfu::prelude::http::Method::#methods))*
     }
 }
@@ -1,5 +1,5 @@
 use crate::method::Method;
-use crate::{extract_method_filters, parse_path_variables};
+use crate::{extract_argument, extract_methods, parse_path_variables, validate_path_arguments};
 use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
 use quote::{quote, ToTokens};
 use std::collections::HashSet;
@@ -21,7 +21,8 @@ impl syn::parse::Parse for EndpointArgs {
         })?;
 
         // verify that path pattern is valid
-        let _ = portfu_core::routes::Route::new(path.value());
+        portfu_core::routes::Route::validate(&path.value())
+            .map_err(|e| syn::Error::new(path.span(), e))?;
 
         // if there's no comma, assume that no options are provided
         if !input.peek(Token![,]) {
@@ -58,6 +59,8 @@ pub struct Endpoint {
     ast: syn::ItemFn,
     /// The doc comment attributes to copy to generated struct, if any.
     doc_attributes: Vec<syn::Attribute>,
+    /// Const assertions for path variables left to the other arguments' PathVars.
+    path_checks: TokenStream2,
 }
 impl Endpoint {
     pub fn new(args: EndpointArgs, ast: syn::ItemFn, method: Option<Method>) -> syn::Result<Self> {
@@ -73,6 +76,9 @@ impl Endpoint {
             .collect();
 
         let args = Args::new(args, method)?;
+        let path_checks =
+            validate_path_arguments(&args.path, &ast.sig.inputs, args.allow_unused_path_vars)?;
+        validate_body_arguments(&ast.sig.inputs)?;
 
         if args.methods.is_empty() {
             return Err(syn::Error::new(
@@ -93,6 +99,7 @@ impl Endpoint {
             args,
             ast,
             doc_attributes,
+            path_checks,
         })
     }
 }
@@ -104,6 +111,7 @@ impl ToTokens for Endpoint {
             ast,
             args,
             doc_attributes,
+            path_checks,
         } = self;
         let Args {
             path,
@@ -111,16 +119,18 @@ impl ToTokens for Endpoint {
             filters,
             wrappers,
             methods,
+            duplicates,
+            ..
         } = args;
         let resource_name = resource_name
             .as_ref()
             .map_or_else(|| name.to_string(), LitStr::value);
         let filters_name = format!("{resource_name}_filters");
-        let method_filters = extract_method_filters(methods);
+        let methods = extract_methods(methods);
         let registrations = quote! {
             let __resource = ::portfu::pfcore::service::ServiceBuilder::new(#path)
                 .name(#resource_name)
-                #method_filters
+                #methods
                 #(.filter(::portfu::pfcore::filters::all(#filters_name, #filters)))*
                 #(.wrap(#wrappers))*
                 .handler(std::sync::Arc::new(self)).build();
@@ -129,13 +139,22 @@ impl ToTokens for Endpoint {
         let service_def = quote! {
             ::portfu::pfcore::service::ServiceBuilder::new(#path)
                 .name(#resource_name)
-                #method_filters
+                #methods
                 #(.filter(::portfu::pfcore::filters::all(#filters_name, #filters)))*
                 #(.wrap(#wrappers))*
                 .handler(std::sync::Arc::new(service)).build()
         };
         let mut additional_function_vars = vec![];
-        let (mut dyn_vars, path_vars) = parse_path_variables(path);
+        let mut body_vars = vec![];
+        let (mut dyn_vars, path_vars) = parse_path_variables(path, &ast.sig.inputs);
+        if let Some(duplicates) = duplicates {
+            dyn_vars.insert(
+                0,
+                quote! {
+                    handle_data.request.insert(::portfu::pfcore::duplicates::DuplicateKeys::#duplicates);
+                },
+            );
+        }
         for arg in ast.sig.inputs.iter() {
             let (ident_type, ident_val): (Type, Ident) = match arg {
                 FnArg::Receiver(_) => {
@@ -198,21 +217,19 @@ impl ToTokens for Endpoint {
                     }
                 }
             }
-            dyn_vars.push(quote! {
-                let #ident_val: #ident_type = match ::portfu::pfcore::FromRequest::from_request(&mut handle_data.request, stringify!(#ident_val)).await {
-                    Ok(v) => v,
-                    Err(e) => {
-                        *handle_data.response.status_mut() = ::portfu::prelude::http::StatusCode::INTERNAL_SERVER_ERROR;
-                        *handle_data.response.body_mut() = ::portfu::prelude::hyper::body::Bytes::from(format!("Failed to extract {} as {}, {e:?}", stringify!(#ident_val), stringify!(#ident_type).replace(' ',""))).stream_body();
-                        return Ok(handle_data);
-                    }
-                };
-            });
+            if is_body(&ident_type) {
+                body_vars.push(extract_argument(&ident_val, &ident_type));
+            } else {
+                dyn_vars.push(extract_argument(&ident_val, &ident_type));
+            }
             additional_function_vars.push(quote! {
                 #ident_val,
             });
         }
+        // the body is read after every other argument so none of them sees a consumed request
+        dyn_vars.extend(body_vars);
         let stream = quote! {
+            #path_checks
             #(#doc_attributes)*
             #[allow(non_camel_case_types, missing_docs)]
             pub struct #name;
@@ -240,14 +257,18 @@ impl ToTokens for Endpoint {
                     #(#dyn_vars)*
                     match #name(#(#additional_function_vars)*).await {
                         Ok(t) => {
-                            let bytes: ::portfu::prelude::hyper::body::Bytes = t.into();
-                            *handle_data.response.body_mut() = bytes.stream_body();
+                            ::portfu::pfcore::responders::Responder::respond(t, &mut handle_data.response);
                             Ok(handle_data)
                         }
                         Err(e) => {
-                            let err = format!("{e:?}");
-                            let bytes: ::portfu::prelude::hyper::body::Bytes = err.into();
-                            *handle_data.response.body_mut() = bytes.stream_body();
+                            if let Err(e) = ::portfu::pfcore::errors::render_error(e, &mut handle_data).await {
+                                if !::portfu::pfcore::errors::HttpError::apply(&e, &mut handle_data.response) {
+                                    *handle_data.response.status_mut() = ::portfu::pfcore::errors::status_for(e.kind());
+                                    let err = format!("{e:?}");
+                                    let bytes: ::portfu::prelude::hyper::body::Bytes = err.into();
+                                    *handle_data.response.body_mut() = bytes.stream_body();
+                                }
+                            }
                             Ok(handle_data)
                         }
                     }
@@ -258,12 +279,44 @@ impl ToTokens for Endpoint {
     }
 }
 
+fn is_body(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Body"),
+        _ => false,
+    }
+}
+
+/// Only the first Body extractor would see the request body, the rest would read nothing
+fn validate_body_arguments(inputs: &Punctuated<FnArg, Token![,]>) -> syn::Result<()> {
+    let mut body_args = inputs.iter().filter_map(|arg| match arg {
+        FnArg::Typed(typed) if is_body(&typed.ty) => Some(typed),
+        _ => None,
+    });
+    if let (Some(first), Some(second)) = (body_args.next(), body_args.next()) {
+        return Err(syn::Error::new_spanned(
+            second,
+            format!(
+                "`{}` and `{}` both read the request body, only one Body argument is allowed",
+                first.pat.to_token_stream(),
+                second.pat.to_token_stream()
+            ),
+        ));
+    }
+    Ok(())
+}
+
 struct Args {
     path: syn::LitStr,
     resource_name: Option<syn::LitStr>,
     filters: Vec<Path>,
     wrappers: Vec<syn::Expr>,
     methods: HashSet<Method>,
+    allow_unused_path_vars: bool,
+    duplicates: Option<Ident>,
 }
 
 impl Args {
@@ -272,6 +325,8 @@ impl Args {
         let mut filters = Vec::new();
         let mut wrappers = Vec::new();
         let mut methods = HashSet::new();
+        let mut allow_unused_path_vars = false;
+        let mut duplicates = None;
 
         let is_route_macro = method.is_none();
         if let Some(method) = method {
@@ -341,10 +396,46 @@ impl Args {
                         "Attribute method expects literal string",
                     ));
                 }
+            } else if nv.path.is_ident("allow_unused_path_vars") {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Bool(lit),
+                    ..
+                }) = nv.value
+                {
+                    allow_unused_path_vars = lit.value;
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        nv.value,
+                        "Attribute allow_unused_path_vars expects a bool",
+                    ));
+                }
+            } else if nv.path.is_ident("duplicates") {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit),
+                    ..
+                }) = &nv.value
+                {
+                    let variant = match lit.value().as_str() {
+                        "first" => "First",
+                        "last" => "Last",
+                        "reject" => "Reject",
+                        "collect" => "Collect",
+                        _ => return Err(syn::Error::new_spanned(
+                            lit,
+                            "Attribute duplicates expects one of first, last, reject or collect",
+                        )),
+                    };
+                    duplicates = Some(Ident::new(variant, lit.span()));
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        nv.value,
+                        "Attribute duplicates expects literal string",
+                    ));
+                }
             } else {
                 return Err(syn::Error::new_spanned(
                     nv.path,
-                    "Unknown attribute key is specified; allowed: filter, method and wrap",
+                    "Unknown attribute key is specified; allowed: name, filter, method, wrap, duplicates and allow_unused_path_vars",
                 ));
             }
         }
@@ -355,6 +446,8 @@ impl Args {
             filters,
             wrappers,
             methods,
+            allow_unused_path_vars,
+            duplicates,
         })
     }
 }
@@ -1,9 +1,11 @@
+use crate::server::task::parse_dependencies;
 use proc_macro2::{Ident, TokenStream};
 use quote::{quote, ToTokens};
-use syn::{parse_quote, FnArg, GenericArgument, Pat, PathArguments, Type};
+use syn::{parse_quote, FnArg, GenericArgument, Pat, PathArguments, Token, Type};
 
 pub struct IntervalArgs {
     interval: u64,
+    dependencies: Vec<TokenStream>,
 }
 
 impl syn::parse::Parse for IntervalArgs {
@@ -11,12 +13,24 @@ impl syn::parse::Parse for IntervalArgs {
         let interval = input.parse::<syn::LitInt>().map_err(|mut err| {
             err.combine(syn::Error::new(
                 err.span(),
-                r#"invalid interval definition, expected #[interval(<interval>)]"#,
+                r#"invalid interval definition, expected #[interval(<interval>, options...)]"#,
             ));
             err
         })?;
         let interval: u64 = interval.base10_parse()?;
-        Ok(Self { interval })
+        if !input.peek(Token![,]) {
+            return Ok(Self {
+                interval,
+                dependencies: vec![],
+            });
+        }
+        input.parse::<Token![,]>()?;
+        let options = input.parse_terminated(syn::MetaNameValue::parse, Token![,])?;
+        let dependencies = parse_dependencies(options)?;
+        Ok(Self {
+            interval,
+            dependencies,
+        })
     }
 }
 
@@ -119,6 +133,7 @@ impl ToTokens for Interval {
             }
         }
         let interval = args.interval;
+        let dependencies = &args.dependencies;
         let out = quote! {
             #(#doc_attributes)*
             #[allow(non_camel_case_types, missing_docs)]
@@ -126,10 +141,9 @@ impl ToTokens for Interval {
             impl From<#name> for ::portfu::pfcore::task::Task {
                 fn from(interval: #name) -> ::portfu::pfcore::task::Task {
                     use ::portfu::pfcore::task::TaskFn;
-                    ::portfu::pfcore::task::Task {
-                        name: interval.name().to_string(),
-                        task_fn: Arc::new(interval)
-                    }
+                    let name = interval.name().to_string();
+                    ::portfu::pfcore::task::Task::new(name, Arc::new(interval))
+                        #(#dependencies)*
                 }
             }
             #[::portfu::prelude::async_trait::async_trait]
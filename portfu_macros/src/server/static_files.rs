@@ -5,7 +5,8 @@ use std::env;
 use std::path::{Path, PathBuf};
 
 pub struct StaticFileArgs {
-    files: HashMap<String, String>,
+    /// Served path to the file on disk and its length
+    files: HashMap<String, (String, usize)>,
 }
 
 impl syn::parse::Parse for StaticFileArgs {
@@ -28,7 +29,12 @@ impl syn::parse::Parse for StaticFileArgs {
             path.join(as_str)
         };
         let mut files = HashMap::new();
-        read_directory(path.as_path(), path.as_path(), &mut files);
+        read_directory(path.as_path(), path.as_path(), &mut files).map_err(|e| {
+            syn::Error::new(
+                root_path.span(),
+                format!("failed to read static files from {}: {e}", path.display()),
+            )
+        })?;
         Ok(Self { files })
     }
 }
@@ -50,8 +56,7 @@ impl ToTokens for StaticFiles {
             .args
             .files
             .iter()
-            .map(|(key, value)| {
-                let file_len = Path::new(value).metadata().unwrap().len() as usize;
+            .map(|(key, (value, file_len))| {
                 let key_name = key
                     .replace(['/', '.', ')', '(', '-', ' ', '+'], "_")
                     .replace("__", "_");
@@ -66,7 +71,7 @@ impl ToTokens for StaticFiles {
                         ::portfu::pfcore::files::StaticFile {
                             name: #key,
                             mime: ::portfu::pfcore::files::get_mime_type(#key),
-                            file_contents: #static_bytes_name.as_ref()
+                            file_contents: #static_bytes_name.as_ref(),
                         }
                     )).build()
                 }
@@ -88,7 +93,9 @@ impl ToTokens for StaticFiles {
                         ]
                     ))
                 ],
-                wrappers: vec![]
+                wrappers: vec![],
+                header_policy: None,
+                tasks: vec![]
             }
         };
         let out = quote! {
@@ -113,25 +120,37 @@ impl ToTokens for StaticFiles {
     }
 }
 
-fn read_directory(root: &Path, path: &Path, file_map: &mut HashMap<String, String>) {
-    let mut dir_reader = path.read_dir().unwrap();
-    while let Some(Ok(entry)) = dir_reader.next() {
-        let entry_path = entry.path();
-        if entry.path().is_dir() {
-            read_directory(root, entry_path.as_path(), file_map);
+fn read_directory(
+    root: &Path,
+    path: &Path,
+    file_map: &mut HashMap<String, (String, usize)>,
+) -> std::io::Result<()> {
+    for entry in path.read_dir()? {
+        let entry_path = entry?.path();
+        if entry_path.is_dir() {
+            read_directory(root, entry_path.as_path(), file_map)?;
         } else {
-            read_file(root, entry_path.as_path(), file_map);
+            read_file(root, entry_path.as_path(), file_map)?;
         }
     }
+    Ok(())
 }
 
-fn read_file(root: &'_ Path, starting_path: &'_ Path, file_map: &'_ mut HashMap<String, String>) {
+fn read_file(
+    root: &'_ Path,
+    starting_path: &'_ Path,
+    file_map: &'_ mut HashMap<String, (String, usize)>,
+) -> std::io::Result<()> {
     let mut new_root = PathBuf::from("/");
-    let path = starting_path.canonicalize().unwrap();
-    let path = path.strip_prefix(root).unwrap();
+    let path = starting_path.canonicalize()?;
+    let path = path.strip_prefix(root).map_err(std::io::Error::other)?;
     new_root.extend(path);
     file_map.insert(
         new_root.to_string_lossy().to_string(),
-        starting_path.to_string_lossy().to_string(),
+        (
+            starting_path.to_string_lossy().to_string(),
+            starting_path.metadata()?.len() as usize,
+        ),
     );
+    Ok(())
 }
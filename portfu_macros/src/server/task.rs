@@ -1,6 +1,51 @@
 use proc_macro2::{Ident, TokenStream as TokenStream2};
 use quote::{quote, ToTokens};
-use syn::{parse_quote, FnArg, GenericArgument, Pat, PathArguments, Type};
+use syn::{
+    parse_quote, punctuated::Punctuated, FnArg, GenericArgument, Pat, PathArguments, Token, Type,
+};
+
+pub struct TaskArgs {
+    pub options: Punctuated<syn::MetaNameValue, Token![,]>,
+}
+
+impl syn::parse::Parse for TaskArgs {
+    fn parse(input: syn::parse::ParseStream<'_>) -> syn::Result<Self> {
+        let options = input.parse_terminated(syn::MetaNameValue::parse, Token![,])?;
+        Ok(Self { options })
+    }
+}
+
+/// Converts `after = "<task>"` and `after_started = "<task>"` options into Task builder calls
+pub fn parse_dependencies(
+    options: Punctuated<syn::MetaNameValue, Token![,]>,
+) -> syn::Result<Vec<TokenStream2>> {
+    let mut dependencies = vec![];
+    for nv in options {
+        let method = if nv.path.is_ident("after") {
+            quote! { after }
+        } else if nv.path.is_ident("after_started") {
+            quote! { after_started }
+        } else {
+            return Err(syn::Error::new_spanned(
+                nv.path,
+                "Unknown attribute key is specified; allowed: after and after_started",
+            ));
+        };
+        if let syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(lit),
+            ..
+        }) = nv.value
+        {
+            dependencies.push(quote! { .#method(#lit) });
+        } else {
+            return Err(syn::Error::new_spanned(
+                nv.value,
+                "Task dependencies expect a literal string",
+            ));
+        }
+    }
+    Ok(dependencies)
+}
 
 pub struct Task {
     /// Name of the handler function being annotated.
@@ -9,9 +54,12 @@ pub struct Task {
     ast: syn::ItemFn,
     /// The doc comment attributes to copy to generated struct, if any.
     doc_attributes: Vec<syn::Attribute>,
+    /// Builder calls adding the tasks this one waits for.
+    dependencies: Vec<TokenStream2>,
 }
 impl Task {
-    pub fn new(ast: syn::ItemFn) -> syn::Result<Self> {
+    pub fn new(args: TaskArgs, ast: syn::ItemFn) -> syn::Result<Self> {
+        let dependencies = parse_dependencies(args.options)?;
         let name = ast.sig.ident.clone();
         // Try and pull out the doc comments so that we can reapply them to the generated struct.
         // Note that multi line doc comments are converted to multiple doc attributes.
@@ -33,6 +81,7 @@ impl Task {
             name,
             ast,
             doc_attributes,
+            dependencies,
         })
     }
 }
@@ -43,6 +92,7 @@ impl ToTokens for Task {
             name,
             ast,
             doc_attributes,
+            dependencies,
         } = self;
         let mut additional_function_vars = vec![];
         let mut dyn_vars = vec![];
@@ -101,10 +151,9 @@ impl ToTokens for Task {
             impl From<#name> for ::portfu::pfcore::task::Task {
                 fn from(task: #name) -> ::portfu::pfcore::task::Task {
                     use ::portfu::pfcore::task::TaskFn;
-                    ::portfu::pfcore::task::Task {
-                        name: task.name().to_string(),
-                        task_fn: Arc::new(task)
-                    }
+                    let name = task.name().to_string();
+                    ::portfu::pfcore::task::Task::new(name, Arc::new(task))
+                        #(#dependencies)*
                 }
             }
             #[::portfu::prelude::async_trait::async_trait]
@@ -116,22 +165,20 @@ impl ToTokens for Task {
                     &self,
                     state: std::sync::Arc< ::portfu::prelude::http::Extensions >
                 ) -> Result<(), ::std::io::Error> {
-                    ::tokio::spawn( async move {
-                        select! {
-                            _ = async {
-                                #ast
-                                #(#dyn_vars)*
-                                let _ = #name(#(#additional_function_vars)*).await;
-                                Ok::<(), ::std::io::Error>(())
-                            } => {
-                                 Ok::<(), ::std::io::Error>(())
-                            }
-                            _ = ::portfu::pfcore::signal::await_termination() => {
-                                Ok::<(), ::std::io::Error>(())
-                            }
+                    select! {
+                        result = async {
+                            #ast
+                            #(#dyn_vars)*
+                            #name(#(#additional_function_vars)*).await
+                                .map(|_| ())
+                                .map_err(|e| ::std::io::Error::other(format!("{e:?}")))
+                        } => {
+                            result
                         }
-                    });
-                    Ok::<(), ::std::io::Error>(())
+                        _ = ::portfu::pfcore::signal::await_termination() => {
+                            Ok::<(), ::std::io::Error>(())
+                        }
+                    }
                 }
             }
         };
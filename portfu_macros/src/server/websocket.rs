@@ -1,5 +1,5 @@
-use crate::parse_path_variables;
 use crate::server::endpoints::EndpointArgs;
+use crate::{extract_argument, parse_path_variables, validate_path_arguments};
 use proc_macro2::{Ident, TokenStream as TokenStream2};
 use quote::{quote, ToTokens};
 use syn::{parse_quote, FnArg, LitStr, Pat, Path, Type};
@@ -13,6 +13,8 @@ pub struct WebSocketRoute {
     ast: syn::ItemFn,
     /// The doc comment attributes to copy to generated struct, if any.
     doc_attributes: Vec<syn::Attribute>,
+    /// Const assertions for path variables left to the other arguments' PathVars.
+    path_checks: TokenStream2,
 }
 impl WebSocketRoute {
     pub fn new(args: EndpointArgs, ast: syn::ItemFn) -> syn::Result<Self> {
@@ -27,6 +29,8 @@ impl WebSocketRoute {
             .collect();
 
         let args = WsArgs::new(args)?;
+        let path_checks =
+            validate_path_arguments(&args.path, &ast.sig.inputs, args.allow_unused_path_vars)?;
 
         if matches!(ast.sig.output, syn::ReturnType::Default) {
             return Err(syn::Error::new_spanned(
@@ -40,6 +44,7 @@ impl WebSocketRoute {
             args,
             ast,
             doc_attributes,
+            path_checks,
         })
     }
 }
@@ -51,19 +56,30 @@ impl ToTokens for WebSocketRoute {
             ast,
             args,
             doc_attributes,
+            path_checks,
         } = self;
         let WsArgs {
             path,
             resource_name,
             filters,
             wrappers,
+            origins,
+            protocols,
+            options,
+            flood,
+            ..
         } = args;
+        let flood_policy = if flood.is_empty() {
+            quote! { peers.flood() }
+        } else {
+            quote! { Some(peers.flood().unwrap_or_default()#(#flood)*) }
+        };
 
         let resource_name = resource_name
             .as_ref()
             .map_or_else(|| name.to_string(), LitStr::value);
         let mut additional_function_vars = vec![];
-        let (mut dyn_vars, path_vars) = parse_path_variables(path);
+        let (mut dyn_vars, path_vars) = parse_path_variables(path, &ast.sig.inputs);
         for arg in ast.sig.inputs.iter() {
             let (ident_type, ident_val): (Type, Ident) = match arg {
                 FnArg::Receiver(_) => {
@@ -90,29 +106,9 @@ impl ToTokens for WebSocketRoute {
             if let Type::Path(path) = &ident_type {
                 if let Some(segment) = path.path.segments.first() {
                     let body_ident: Ident = Ident::new("Body", segment.ident.span());
-                    let state_ident: Ident = Ident::new("State", segment.ident.span());
                     let ws_ident: Ident = Ident::new("WebSocket", segment.ident.span());
                     if body_ident == segment.ident {
                         panic!("Body Not Supported for Websocket");
-                    } else if state_ident == segment.ident {
-                        dyn_vars.push(quote! {
-                            let #ident_val: #ident_type = match ::portfu::prelude::State::extract(&mut request).await {
-                                Some(v) => v,
-                                None => {
-                                    *response.status_mut() = ::portfu::prelude::http::StatusCode::INTERNAL_SERVER_ERROR;
-                                    let bytes =::portfu::prelude::hyper::body::Bytes::from(format!("Failed to find {}", stringify!(#ident_type).replace(' ',"")));
-                                    *handle_data.response.body_mut() = bytes.stream_body();
-                                    return Err(ServiceResponse {
-                                        request,
-                                        response
-                                    });
-                                }
-                            };
-                        });
-                        additional_function_vars.push(quote! {
-                            #ident_val,
-                        });
-                        continue;
                     } else if ws_ident == segment.ident {
                         additional_function_vars.push(quote! {
                             websocket,
@@ -121,23 +117,13 @@ impl ToTokens for WebSocketRoute {
                     }
                 }
             }
-            let function_name = &ast.sig.ident;
+            dyn_vars.push(extract_argument(&ident_val, &ident_type));
             additional_function_vars.push(quote! {
-                match request.get() {
-                    Some(v) => v,
-                    None => {
-                        *response.status_mut() = ::portfu::prelude::http::StatusCode::INTERNAL_SERVER_ERROR;
-                        let bytes =::portfu::prelude::hyper::body::Bytes::from(format!("Failed to find {} for {}", stringify!(#ident_type).replace(' ',""), stringify!(#function_name)));
-                        *handle_data.response.body_mut() = bytes.stream_body();
-                        return Err(ServiceResponse {
-                            request,
-                            response
-                        });
-                    }
-                },
+                #ident_val,
             });
         }
         let stream = quote! {
+            #path_checks
             #(#doc_attributes)*
             #[allow(non_camel_case_types, missing_docs)]
             pub struct #name {
@@ -147,7 +133,7 @@ impl ToTokens for WebSocketRoute {
                 fn register(self, service_registry: &mut portfu::prelude::ServiceRegistry) {
                     let __resource = ::portfu::pfcore::service::ServiceBuilder::new(#path)
                         .name(#resource_name)
-                        .filter(::portfu::filters::method::GET.clone())
+                        .method(::portfu::prelude::http::Method::GET)
                         #(.filter(::portfu::pfcore::filters::fn_guard(#filters)))*
                         #(.wrap(#wrappers))*
                         .handler(std::sync::Arc::new(self)).build();
@@ -158,7 +144,7 @@ impl ToTokens for WebSocketRoute {
                 fn from(service: #name) -> ::portfu::prelude::Service {
                     ::portfu::pfcore::service::ServiceBuilder::new(#path)
                         .name(#resource_name)
-                        .filter(::portfu::filters::method::GET.clone())
+                        .method(::portfu::prelude::http::Method::GET)
                         #(.filter(::portfu::pfcore::filters::fn_guard(#filters)))*
                         #(.wrap(#wrappers))*
                         .handler(std::sync::Arc::new(service)).build()
@@ -174,61 +160,106 @@ impl ToTokens for WebSocketRoute {
                     mut handle_data: ::portfu::prelude::ServiceData
                 ) -> Result<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)> {
                     use ::portfu::pfcore::IntoStreamBody;
-                    if handle_data.request.request.is_upgrade_request() {
-                        #ast
-                        #(#dyn_vars)*
-                        log::info!("Upgrading Websocket");
-                        let (response, websocket) = match handle_data.request.request.upgrade() {
-                            Ok((response, websocket)) => (response, websocket),
+                    if let Err(e) = handle_data.request.request.validate_upgrade() {
+                        log::debug!("Rejecting Websocket Upgrade: {e}");
+                        *handle_data.response.status_mut() = ::portfu::prelude::http::StatusCode::BAD_REQUEST;
+                        *handle_data.response.body_mut() = e.message().stream_body();
+                        return Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data);
+                    }
+                    let origins: &[&str] = &[#(#origins),*];
+                    if let Err(e) = handle_data.request.request.check_origin(
+                        &::portfu::pfcore::sockets::OriginPolicy::from_origins(origins)
+                    ) {
+                        log::warn!("Rejecting Websocket Upgrade: {e}");
+                        *handle_data.response.status_mut() = ::portfu::prelude::http::StatusCode::FORBIDDEN;
+                        *handle_data.response.body_mut() = e.message().stream_body();
+                        return Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data);
+                    }
+                    #ast
+                    #(#dyn_vars)*
+                    log::info!("Upgrading Websocket");
+                    let protocols: &[&str] = &[#(#protocols),*];
+                    let (response, websocket, protocol) = match handle_data.request.request.upgrade_with_subprotocols(protocols) {
+                        Ok(upgrade) => upgrade,
+                        Err(e) => {
+                            *handle_data.response.status_mut() = ::portfu::prelude::http::StatusCode::BAD_REQUEST;
+                            *handle_data.response.body_mut() = e.message().stream_body();
+                            return Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data);
+                        }
+                    };
+                    let peers = self.peers.clone();
+                    let options = peers.options()#(#options)*;
+                    let headers = handle_data.request.request.headers().cloned().unwrap_or_default();
+                    let addr = handle_data.request.get::<::std::net::SocketAddr>().copied();
+                    //Keeps the server draining until the websocket closes
+                    let open = handle_data.request
+                        .get::<::portfu::pfcore::server::OpenConnections>()
+                        .map(|open| open.track());
+                    let shutdown = handle_data.request.get::<::portfu::pfcore::task::ShutdownSignal>().cloned();
+                    ::tokio::spawn( async move {
+                        let _open = open;
+                        let websocket = match websocket.await {
+                            Ok(ws) => ::portfu::prelude::tokio_tungstenite::WebSocketStream::from_raw_socket(
+                                ::portfu::prelude::hyper_util::rt::tokio::TokioIo::new(ws),
+                                ::portfu::prelude::tokio_tungstenite::tungstenite::protocol::Role::Server,
+                                Some(options.config())
+                            ).await,
                             Err(e) => {
-                                let bytes = ::portfu::prelude::hyper::body::Bytes::from("Failed to Upgrade Request");
-                                *handle_data.response.body_mut() = bytes.stream_body();
-                                return Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data);
+                                log::error!("{e:?}");
+                                return;
                             }
                         };
-                        let peers = self.peers.clone();
-                        ::tokio::spawn( async move {
-                            select! {
-                                _ = async {
-                                    let websocket = match websocket.await {
-                                        Ok(ws) => ::portfu::prelude::tokio_tungstenite::WebSocketStream::from_raw_socket(
-                                            ::portfu::prelude::hyper_util::rt::tokio::TokioIo::new(ws),
-                                            ::portfu::prelude::tokio_tungstenite::tungstenite::protocol::Role::Server,
-                                            None
-                                        ).await,
-                                        Err(e) => {
-                                            log::error!("{e:?}");
-                                            return Ok::<(), ::std::io::Error>(());
-                                        }
-                                    };
-                                    let uuid = ::std::sync::Arc::new(::portfu::prelude::uuid::Uuid::new_v4());
-                                    let connection = ::std::sync::Arc::new(::portfu::prelude::WebsocketConnection::new(websocket));
-                                    peers.write().await.insert(*uuid.as_ref(), connection.clone());
-                                    let websocket = ::portfu::prelude::WebSocket {
-                                        connection,
-                                        uuid: uuid.clone(),
-                                        peers: peers.clone()
-                                    };
-                                    let _ = #name(#(#additional_function_vars)*).await;
-                                    peers.write().await.remove(uuid.as_ref());
-                                    Ok::<(), ::std::io::Error>(())
-                                } => {
-                                     Ok::<(), ::std::io::Error>(())
+                        let uuid = ::std::sync::Arc::new(::portfu::prelude::uuid::Uuid::new_v4());
+                        let connection = ::std::sync::Arc::new(::portfu::prelude::WebsocketConnection::new(websocket));
+                        peers.insert_with_policy(*uuid.as_ref(), connection.clone(), headers, addr, #flood_policy).await;
+                        let websocket = ::portfu::prelude::WebSocket {
+                            connection: connection.clone(),
+                            uuid: uuid.clone(),
+                            peers: peers.clone(),
+                            protocol: protocol.map(::std::sync::Arc::from),
+                        };
+                        let reason = select! {
+                            result = ::portfu::prelude::futures_util::FutureExt::catch_unwind(
+                                ::std::panic::AssertUnwindSafe(#name(#(#additional_function_vars)*))
+                            ) => {
+                                match result {
+                                    Ok(_) if peers.kicked(uuid.as_ref()) => ::portfu::prelude::DisconnectReason::Flooding,
+                                    Ok(Ok(_)) => ::portfu::prelude::DisconnectReason::Closed,
+                                    Ok(Err(e)) => ::portfu::prelude::DisconnectReason::Error(e.to_string()),
+                                    Err(payload) => {
+                                        let message = ::portfu::pfcore::panic_message(payload.as_ref());
+                                        log::error!("Websocket {} panicked - {}", stringify!(#name), message);
+                                        let _ = connection.close(Some(
+                                            ::portfu::prelude::tokio_tungstenite::tungstenite::protocol::CloseFrame {
+                                                code: ::portfu::prelude::tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Error,
+                                                reason: "Internal Server Error".into(),
+                                            }
+                                        )).await;
+                                        ::portfu::prelude::DisconnectReason::Panicked(message)
+                                    }
                                 }
-                                _ = ::portfu::pfcore::signal::await_termination() => {
-                                    Ok::<(), ::std::io::Error>(())
+                            }
+                            _ = async {
+                                match &shutdown {
+                                    Some(shutdown) => shutdown.wait().await,
+                                    None => ::std::future::pending::<()>().await,
                                 }
+                            } => {
+                                let _ = connection.close(Some(
+                                    ::portfu::prelude::tokio_tungstenite::tungstenite::protocol::CloseFrame {
+                                        code: ::portfu::prelude::tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Away,
+                                        reason: "Server is shutting down".into(),
+                                    }
+                                )).await;
+                                ::portfu::prelude::DisconnectReason::Shutdown
                             }
-                        });
-                        log::info!("Sending Upgrade Response");
-                        let (parts, body) = response.into_parts();
-                        handle_data.response = Response::from_parts(parts, body.stream_body());
-                        Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data)
-                    } else {
-                        let bytes = ::portfu::prelude::hyper::body::Bytes::from("HTTP NOT SUPPORTED ON THIS ENDPOINT");
-                        *handle_data.response.body_mut() = bytes.stream_body();
-                        Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data)
-                    }
+                        };
+                        peers.remove(uuid.as_ref(), reason).await;
+                    });
+                    log::info!("Sending Upgrade Response");
+                    let (parts, body) = response.into_parts();
+                    handle_data.response = Response::from_parts(parts, body.stream_body());
+                    Ok::<::portfu::prelude::ServiceData, (::portfu::prelude::ServiceData, ::std::io::Error)>(handle_data)
                 }
             }
         };
@@ -241,6 +272,51 @@ struct WsArgs {
     resource_name: Option<syn::LitStr>,
     filters: Vec<Path>,
     wrappers: Vec<syn::Expr>,
+    origins: Vec<LitStr>,
+    protocols: Vec<LitStr>,
+    /// WebSocketOptions builder calls overriding the Peers options
+    options: Vec<TokenStream2>,
+    /// FloodPolicy builder calls overriding the Peers flood policy
+    flood: Vec<TokenStream2>,
+    allow_unused_path_vars: bool,
+}
+
+/// A byte size given as an integer or a string holding one
+fn size_arg(nv: &syn::MetaNameValue) -> syn::Result<usize> {
+    let parsed = match &nv.value {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(lit),
+            ..
+        }) => lit.value().parse::<usize>().ok(),
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(lit),
+            ..
+        }) => lit.base10_parse::<usize>().ok(),
+        _ => None,
+    };
+    parsed.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &nv.value,
+            format!(
+                "Attribute {} expects a size in bytes",
+                nv.path.to_token_stream()
+            ),
+        )
+    })
+}
+
+/// A count given as an integer or a string holding one, at most `max`
+fn count_arg(nv: &syn::MetaNameValue, max: u64) -> syn::Result<proc_macro2::Literal> {
+    match size_arg(nv) {
+        Ok(count) if count as u64 <= max => Ok(proc_macro2::Literal::u64_unsuffixed(count as u64)),
+        _ => Err(syn::Error::new_spanned(
+            &nv.value,
+            format!(
+                "Attribute {} expects a count of at most {max}",
+                nv.path.to_token_stream()
+            ),
+        )),
+    }
 }
 
 impl WsArgs {
@@ -248,6 +324,13 @@ impl WsArgs {
         let mut resource_name = None;
         let mut filters = Vec::new();
         let mut wrappers = Vec::new();
+        let mut origins = Vec::new();
+        let mut protocols = Vec::new();
+        let mut options = Vec::new();
+        let mut flood = Vec::new();
+        let mut flood_action: Option<LitStr> = None;
+        let mut flood_close_after = None;
+        let mut allow_unused_path_vars = false;
 
         for nv in args.options {
             if nv.path.is_ident("name") {
@@ -289,12 +372,115 @@ impl WsArgs {
                         "Attribute wrap expects type",
                     ));
                 }
+            } else if nv.path.is_ident("origin") {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit),
+                    ..
+                }) = nv.value
+                {
+                    origins.push(lit);
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        nv.value,
+                        "Attribute origin expects literal string",
+                    ));
+                }
+            } else if nv.path.is_ident("protocol") {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit),
+                    ..
+                }) = nv.value
+                {
+                    protocols.push(lit);
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        nv.value,
+                        "Attribute protocol expects literal string",
+                    ));
+                }
+            } else if nv.path.is_ident("max_message_size") {
+                let size = size_arg(&nv)?;
+                options.push(quote! { .max_message_size(Some(#size)) });
+            } else if nv.path.is_ident("max_frame_size") {
+                let size = size_arg(&nv)?;
+                options.push(quote! { .max_frame_size(Some(#size)) });
+            } else if nv.path.is_ident("write_buffer_size") {
+                let size = size_arg(&nv)?;
+                options.push(quote! { .write_buffer_size(#size) });
+            } else if nv.path.is_ident("accept_unmasked_frames") {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Bool(lit),
+                    ..
+                }) = nv.value
+                {
+                    options.push(quote! { .accept_unmasked_frames(#lit) });
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        nv.value,
+                        "Attribute accept_unmasked_frames expects a bool",
+                    ));
+                }
+            } else if nv.path.is_ident("messages_per_sec") {
+                let count = count_arg(&nv, u32::MAX as u64)?;
+                flood.push(quote! { .messages_per_sec(#count) });
+            } else if nv.path.is_ident("bytes_per_sec") {
+                let count = count_arg(&nv, u64::MAX)?;
+                flood.push(quote! { .bytes_per_sec(#count) });
+            } else if nv.path.is_ident("flood_action") {
+                match nv.value {
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(lit),
+                        ..
+                    }) if lit.value() == "drop" || lit.value() == "close" => {
+                        flood_action = Some(lit);
+                    }
+                    value => {
+                        return Err(syn::Error::new_spanned(
+                            value,
+                            "Attribute flood_action expects \"drop\" or \"close\"",
+                        ));
+                    }
+                }
+            } else if nv.path.is_ident("flood_close_after") {
+                flood_close_after = Some((count_arg(&nv, u32::MAX as u64)?, nv.value));
+            } else if nv.path.is_ident("allow_unused_path_vars") {
+                if let syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Bool(lit),
+                    ..
+                }) = nv.value
+                {
+                    allow_unused_path_vars = lit.value;
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        nv.value,
+                        "Attribute allow_unused_path_vars expects a bool",
+                    ));
+                }
             } else {
                 return Err(syn::Error::new_spanned(
                     nv.path,
-                    "Unknown attribute key is specified; allowed: filter, method and wrap",
+                    "Unknown attribute key is specified; allowed: name, filter, wrap, origin, protocol, max_message_size, max_frame_size, write_buffer_size, accept_unmasked_frames, messages_per_sec, bytes_per_sec, flood_action, flood_close_after and allow_unused_path_vars",
+                ));
+            }
+        }
+        let action = quote! { ::portfu::pfcore::sockets::FloodAction };
+        match (
+            flood_action.as_ref().map(LitStr::value).as_deref(),
+            flood_close_after,
+        ) {
+            (Some("drop"), Some((_, value))) => {
+                return Err(syn::Error::new_spanned(
+                    value,
+                    "Attribute flood_close_after needs flood_action = \"close\"",
                 ));
             }
+            (Some("drop"), None) => flood.push(quote! { .action(#action::Drop) }),
+            (_, Some((after, _))) => {
+                flood.push(quote! { .action(#action::Close { after: #after }) })
+            }
+            (Some(_), None) => flood
+                .push(quote! { .action(::portfu::pfcore::sockets::FloodPolicy::default().action) }),
+            (None, None) => {}
         }
 
         Ok(WsArgs {
@@ -302,6 +488,11 @@ impl WsArgs {
             resource_name,
             filters,
             wrappers,
+            origins,
+            protocols,
+            options,
+            flood,
+            allow_unused_path_vars,
         })
     }
 }